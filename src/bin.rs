@@ -1,29 +1,72 @@
+pub mod error;
 pub mod overpass_parser;
-use std::io;
+pub mod plan;
+use std::{io, process};
 
 use overpass_parser::{parse_query, request::Request};
 use sql_dialect::sql_dialect::SqlDialect;
 pub mod sql_dialect;
 
+/// Parse the `--bbox` option value, as `south,west,north,east`.
+fn parse_bbox(value: &str) -> Option<(f64, f64, f64, f64)> {
+    let coords = value
+        .split(',')
+        .map(|s| s.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<f64>>>()?;
+    match coords[..] {
+        [s, w, n, e] => Some((s, w, n, e)),
+        _ => None,
+    }
+}
+
 pub fn main() {
-    let dialect = std::env::args()
-        .nth(1)
-        .unwrap_or_else(|| "postgres".to_string());
+    let mut dialect = "postgres".to_string();
+    let mut bbox = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if let Some(value) = arg
+            .strip_prefix("--bbox=")
+            .map(|value| value.to_string())
+            .or_else(|| (arg == "--bbox").then(|| args.next().unwrap_or_default()))
+        {
+            bbox = Some(parse_bbox(&value).unwrap_or_else(|| {
+                eprintln!("Invalid bbox {value:?}, expected south,west,north,east");
+                process::exit(2);
+            }));
+        } else {
+            dialect = arg;
+        }
+    }
     let sql_dialect: &(dyn SqlDialect + Send + Sync) = match dialect.as_str() {
         "postgres" => &sql_dialect::postgres::postgres::Postgres::default(),
         "duckdb" => &sql_dialect::duckdb::duckdb::Duckdb,
-        _ => panic!("Unsupported SQL dialect: {dialect}"),
+        "sqlite" => &sql_dialect::sqlite::sqlite::Sqlite,
+        _ => {
+            eprintln!("Unsupported SQL dialect: {dialect}");
+            process::exit(2);
+        }
     };
 
     // read stdin
     match io::read_to_string(io::stdin()).ok() {
         Some(query0) => {
             let query = query0.as_str();
-            let out = match parse_query(query) {
-                Ok(request) => Request::to_sql(&request, sql_dialect, "4326", None),
-                Err(e) => panic!("Error parsing query: {e}"),
-            };
-            println!("{}", out.join("\n"));
+            let out = parse_query(query).and_then(|mut request| {
+                if bbox.is_some() {
+                    request.bbox = bbox;
+                }
+                for warning in request.validate().warnings {
+                    eprintln!("Warning at {}: {}", warning.span, warning.message);
+                }
+                Request::to_sql(&request, sql_dialect, "4326", None)
+            });
+            match out {
+                Ok(out) => println!("{}", out.join("\n")),
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    process::exit(1);
+                }
+            }
         }
         None => {
             eprintln!("Failed to read from stdin");
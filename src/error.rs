@@ -0,0 +1,126 @@
+use std::fmt;
+
+use crate::overpass_parser::Rule;
+
+/// Byte offsets of a fragment of the source query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span<'_>) -> Self {
+        Span {
+            start: span.start(),
+            end: span.end(),
+        }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.start, self.end)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OverpassError {
+    /// The query does not follow the Overpass QL grammar.
+    Syntax { message: String, span: Span },
+    /// The query is well formed but meaningless, like a reference to an undefined set.
+    Semantic { message: String, span: Span },
+    /// The query is valid Overpass QL but uses a feature not implemented here.
+    Unsupported { message: String, span: Span },
+}
+
+impl OverpassError {
+    pub fn syntax(message: impl Into<String>, span: impl Into<Span>) -> Self {
+        OverpassError::Syntax {
+            message: message.into(),
+            span: span.into(),
+        }
+    }
+
+    pub fn semantic(message: impl Into<String>, span: impl Into<Span>) -> Self {
+        OverpassError::Semantic {
+            message: message.into(),
+            span: span.into(),
+        }
+    }
+
+    pub fn unsupported(message: impl Into<String>, span: impl Into<Span>) -> Self {
+        OverpassError::Unsupported {
+            message: message.into(),
+            span: span.into(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            OverpassError::Syntax { message, .. }
+            | OverpassError::Semantic { message, .. }
+            | OverpassError::Unsupported { message, .. } => message,
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            OverpassError::Syntax { span, .. }
+            | OverpassError::Semantic { span, .. }
+            | OverpassError::Unsupported { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for OverpassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            OverpassError::Syntax { .. } => "Syntax error",
+            OverpassError::Semantic { .. } => "Semantic error",
+            OverpassError::Unsupported { .. } => "Unsupported",
+        };
+        write!(f, "{kind} at {}: {}", self.span(), self.message())
+    }
+}
+
+impl std::error::Error for OverpassError {}
+
+impl From<pest::error::Error<Rule>> for OverpassError {
+    fn from(error: pest::error::Error<Rule>) -> Self {
+        let span = match error.location {
+            pest::error::InputLocation::Pos(pos) => Span {
+                start: pos,
+                end: pos,
+            },
+            pest::error::InputLocation::Span((start, end)) => Span { start, end },
+        };
+        OverpassError::Syntax {
+            message: error.variant.message().to_string(),
+            span,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_syntax_error() {
+        match parse_query("node[a=b];\nway(;") {
+            Err(OverpassError::Syntax { span, .. }) => assert_eq!(span.start, 15),
+            other => panic!("Expected a syntax error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            "Unsupported at 1..3: foo",
+            OverpassError::unsupported("foo", Span { start: 1, end: 3 }).to_string()
+        );
+    }
+}
@@ -1,2 +1,5 @@
+pub mod error;
+pub mod memory;
 pub mod overpass_parser;
+pub mod plan;
 pub mod sql_dialect;
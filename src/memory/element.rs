@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum OsmType {
+    Node,
+    Way,
+    Relation,
+}
+
+impl OsmType {
+    /// Single letter type, as in the `osm_type` column of the SQL schema.
+    pub fn as_char(&self) -> char {
+        match self {
+            OsmType::Node => 'n',
+            OsmType::Way => 'w',
+            OsmType::Relation => 'r',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    pub osm_type: OsmType,
+    pub reference: i64,
+    pub role: Box<str>,
+}
+
+impl Member {
+    pub fn new(osm_type: OsmType, reference: i64, role: &str) -> Self {
+        Member {
+            osm_type,
+            reference,
+            role: role.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsmElement {
+    pub osm_type: OsmType,
+    pub id: i64,
+    pub tags: HashMap<Box<str>, Box<str>>,
+    /// Latitude and longitude, only for nodes.
+    pub coordinates: Option<(f64, f64)>,
+    pub nodes: Vec<i64>,
+    pub members: Vec<Member>,
+}
+
+impl OsmElement {
+    fn new(osm_type: OsmType, id: i64, tags: &[(&str, &str)]) -> Self {
+        OsmElement {
+            osm_type,
+            id,
+            tags: tags
+                .iter()
+                .map(|(key, value)| ((*key).into(), (*value).into()))
+                .collect(),
+            coordinates: None,
+            nodes: Vec::new(),
+            members: Vec::new(),
+        }
+    }
+
+    pub fn node(id: i64, lat: f64, lon: f64, tags: &[(&str, &str)]) -> Self {
+        OsmElement {
+            coordinates: Some((lat, lon)),
+            ..Self::new(OsmType::Node, id, tags)
+        }
+    }
+
+    pub fn way(id: i64, nodes: &[i64], tags: &[(&str, &str)]) -> Self {
+        OsmElement {
+            nodes: nodes.to_vec(),
+            ..Self::new(OsmType::Way, id, tags)
+        }
+    }
+
+    pub fn relation(id: i64, members: Vec<Member>, tags: &[(&str, &str)]) -> Self {
+        OsmElement {
+            members,
+            ..Self::new(OsmType::Relation, id, tags)
+        }
+    }
+
+    pub fn key(&self) -> (OsmType, i64) {
+        (self.osm_type, self.id)
+    }
+
+    /// Tags in the form expected by `Selectors::matches`.
+    pub fn tag_map(&self) -> HashMap<&str, &str> {
+        self.tags
+            .iter()
+            .map(|(key, value)| (key.as_ref(), value.as_ref()))
+            .collect()
+    }
+}
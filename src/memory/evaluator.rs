@@ -0,0 +1,683 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    error::{OverpassError, Span},
+    overpass_parser::{
+        filters::{Filter, FilterRecurse},
+        query_complete::QueryComplete,
+        query_difference::QueryDifference,
+        query_for::QueryFor,
+        query_foreach::QueryForeach,
+        query_if::QueryIf,
+        query_objects::QueryObjects,
+        query_recurse::QueryRecurse,
+        query_union::QueryUnion,
+        request::Request,
+        subrequest::{QueryType, Subrequest, SubrequestType},
+    },
+};
+
+use super::{
+    element::{OsmElement, OsmType},
+    geometry::{Point, bbox_polygon, line_intersects_polygon},
+    store::OsmStore,
+};
+
+/// Elements ordered by type and id, without duplicates.
+pub type ElementSet<'a> = Vec<&'a OsmElement>;
+
+fn normalize(mut set: ElementSet) -> ElementSet {
+    set.sort_by_key(|element| element.key());
+    set.dedup_by_key(|element| element.key());
+    set
+}
+
+/// Run a request against an `OsmStore`, without database.
+pub struct MemoryEvaluator<'a> {
+    store: &'a dyn OsmStore,
+    sets: HashMap<Box<str>, ElementSet<'a>>,
+    outputs: Vec<ElementSet<'a>>,
+}
+
+impl<'a> MemoryEvaluator<'a> {
+    /// Return the element set of each `out` statement, or the last set when
+    /// there is none.
+    pub fn evaluate(
+        store: &'a dyn OsmStore,
+        request: &Request,
+    ) -> Result<Vec<ElementSet<'a>>, OverpassError> {
+        if let Some(error) = request.validate().errors.into_iter().next() {
+            return Err(error);
+        }
+        let mut evaluator = MemoryEvaluator {
+            store,
+            sets: HashMap::new(),
+            outputs: Vec::new(),
+        };
+        let last = evaluator.subrequest(&request.subrequest_with_settings())?;
+        if evaluator.outputs.is_empty() {
+            evaluator.outputs.push(last);
+        }
+        Ok(evaluator.outputs)
+    }
+
+    fn set(&self, set: &str) -> ElementSet<'a> {
+        self.sets.get(set).cloned().unwrap_or_default()
+    }
+
+    fn assign(&mut self, asignation: Option<&str>, set: ElementSet<'a>) {
+        self.sets.insert(asignation.unwrap_or("_").into(), set);
+    }
+
+    fn subrequest(&mut self, subrequest: &Subrequest) -> Result<ElementSet<'a>, OverpassError> {
+        let mut last = Vec::new();
+        for query in &subrequest.queries {
+            match query.as_ref() {
+                SubrequestType::QueryType(query_type) => last = self.query(query_type)?,
+                SubrequestType::Out(out) => {
+                    if out.level_of_details.as_ref() == "count" {
+                        return Err(OverpassError::unsupported(
+                            "out count is not supported by the memory evaluator",
+                            out.span,
+                        ));
+                    }
+                    let mut set = self.set(out.set.as_deref().unwrap_or("_"));
+                    if let Some(limit) = out.limit {
+                        set.truncate(limit as usize);
+                    }
+                    self.outputs.push(set);
+                }
+            }
+        }
+        Ok(last)
+    }
+
+    fn query(&mut self, query: &QueryType) -> Result<ElementSet<'a>, OverpassError> {
+        let (asignation, set) = match query {
+            QueryType::QueryObjects(query) => {
+                (query.asignation.as_deref(), self.query_objects(query)?)
+            }
+            QueryType::QueryUnion(query) => (query.asignation.as_deref(), self.query_union(query)?),
+            QueryType::QueryDifference(query) => {
+                (query.asignation.as_deref(), self.query_difference(query)?)
+            }
+            QueryType::QueryRecurse(query) => {
+                (query.asignation.as_deref(), self.query_recurse(query)?)
+            }
+            QueryType::QueryForeach(query) => return self.query_foreach(query),
+            QueryType::QueryFor(query) => return self.query_for(query),
+            QueryType::QueryIf(query) => return self.query_if(query),
+            QueryType::QueryComplete(query) => {
+                (query.asignation.as_deref(), self.query_complete(query)?)
+            }
+            QueryType::QueryRetro(query) => {
+                return Err(OverpassError::unsupported(
+                    "retro is not supported by the memory evaluator",
+                    query.span,
+                ));
+            }
+            QueryType::QueryCompare(query) => {
+                return Err(OverpassError::unsupported(
+                    "compare is not supported by the memory evaluator",
+                    query.span,
+                ));
+            }
+            QueryType::QueryIsIn(query) => {
+                return Err(OverpassError::unsupported(
+                    "is_in is not supported by the memory evaluator",
+                    query.span,
+                ));
+            }
+            QueryType::QueryConvert(_) => {
+                return Err(OverpassError::unsupported(
+                    "convert is not supported by the memory evaluator",
+                    Span::default(),
+                ));
+            }
+            QueryType::QueryMake(query) => {
+                return Err(OverpassError::unsupported(
+                    "make is not supported by the memory evaluator",
+                    query.span,
+                ));
+            }
+        };
+        self.assign(asignation, set.clone());
+        Ok(set)
+    }
+
+    fn query_objects(&self, query: &QueryObjects) -> Result<ElementSet<'a>, OverpassError> {
+        let types: &[OsmType] = match query.object_type.as_ref() {
+            "node" => &[OsmType::Node],
+            "way" => &[OsmType::Way],
+            "relation" | "rel" => &[OsmType::Relation],
+            "nwr" => &[OsmType::Node, OsmType::Way, OsmType::Relation],
+            object_type => {
+                return Err(OverpassError::unsupported(
+                    format!("Object type {object_type} is not supported by the memory evaluator"),
+                    query.span,
+                ));
+            }
+        };
+        let mut set: ElementSet = match query.sets.first() {
+            Some(set) => self
+                .set(set)
+                .into_iter()
+                .filter(|element| types.contains(&element.osm_type))
+                .collect(),
+            None => types
+                .iter()
+                .flat_map(|osm_type| self.store.elements(*osm_type))
+                .collect(),
+        };
+        for other in query.sets.iter().skip(1) {
+            let other = self
+                .set(other)
+                .iter()
+                .map(|element| element.key())
+                .collect::<HashSet<(OsmType, i64)>>();
+            set.retain(|element| other.contains(&element.key()));
+        }
+        for filter in &query.filters.filters {
+            set = self.filter(filter, set)?;
+        }
+        let mut result = Vec::new();
+        for element in set {
+            if query.selectors.matches(&element.tag_map())?.is_some() {
+                result.push(element);
+            }
+        }
+        Ok(normalize(result))
+    }
+
+    fn filter(
+        &self,
+        filter: &Filter,
+        mut set: ElementSet<'a>,
+    ) -> Result<ElementSet<'a>, OverpassError> {
+        if filter.area_id.is_some() || filter.around.is_some() {
+            return Err(OverpassError::unsupported(
+                "Area and around filters are not supported by the memory evaluator",
+                filter.span,
+            ));
+        }
+        if filter.newer.is_some() || filter.changed.is_some() {
+            return Err(OverpassError::unsupported(
+                "Date filters are not supported by the memory evaluator",
+                filter.span,
+            ));
+        }
+        if let Some(ids) = &filter.ids {
+            set.retain(|element| ids.contains(&element.id));
+        }
+        if let Some(bbox) = filter.bbox {
+            let polygon = bbox_polygon(bbox);
+            set.retain(|element| self.intersects(element, &polygon));
+        }
+        if let Some(poly) = &filter.poly {
+            set.retain(|element| self.intersects(element, poly));
+        }
+        if let Some(recurse) = &filter.recurse {
+            let related = self
+                .recurse_filter(recurse, filter.span)?
+                .iter()
+                .map(|element| element.key())
+                .collect::<HashSet<(OsmType, i64)>>();
+            set.retain(|element| related.contains(&element.key()));
+        }
+        if let Some(condition) = &filter.condition {
+            let mut result = Vec::new();
+            for element in set {
+                if condition.matches(&element.tag_map())? {
+                    result.push(element);
+                }
+            }
+            set = result;
+        }
+        Ok(set)
+    }
+
+    fn recurse_filter(
+        &self,
+        recurse: &FilterRecurse,
+        span: Span,
+    ) -> Result<ElementSet<'a>, OverpassError> {
+        let input = self.set(recurse.set.as_deref().unwrap_or("_"));
+        let (input_type, up) = match recurse.recurse_type.as_ref() {
+            "w" => (OsmType::Way, false),
+            "r" => (OsmType::Relation, false),
+            "bn" => (OsmType::Node, true),
+            "bw" => (OsmType::Way, true),
+            "br" => (OsmType::Relation, true),
+            recurse_type => {
+                return Err(OverpassError::unsupported(
+                    format!("Recurse filter ({recurse_type}) is not supported"),
+                    span,
+                ));
+            }
+        };
+        let role = recurse.role.as_deref();
+        let has_role = |relation: &OsmElement, member: &OsmElement| {
+            role.is_none_or(|role| {
+                relation.members.iter().any(|m| {
+                    m.osm_type == member.osm_type && m.reference == member.id && *m.role == *role
+                })
+            })
+        };
+        Ok(input
+            .into_iter()
+            .filter(|element| element.osm_type == input_type)
+            .flat_map(|element| {
+                if up {
+                    self.parents(element)
+                        .into_iter()
+                        .filter(|parent| has_role(parent, element))
+                        .collect::<ElementSet>()
+                } else {
+                    self.members(element)
+                        .into_iter()
+                        .filter(|member| has_role(element, member))
+                        .collect()
+                }
+            })
+            .collect())
+    }
+
+    fn intersects(&self, element: &OsmElement, polygon: &[Point]) -> bool {
+        self.lines(element, &mut HashSet::new())
+            .iter()
+            .any(|line| line_intersects_polygon(line, polygon))
+    }
+
+    fn lines(
+        &self,
+        element: &OsmElement,
+        visited: &mut HashSet<(OsmType, i64)>,
+    ) -> Vec<Vec<Point>> {
+        if !visited.insert(element.key()) {
+            return Vec::new();
+        }
+        match element.osm_type {
+            OsmType::Node => element
+                .coordinates
+                .map(|coordinates| vec![vec![coordinates]])
+                .unwrap_or_default(),
+            OsmType::Way => vec![
+                self.members(element)
+                    .iter()
+                    .filter_map(|node| node.coordinates)
+                    .collect(),
+            ],
+            OsmType::Relation => {
+                let mut lines = Vec::new();
+                for member in self.members(element) {
+                    lines.extend(self.lines(member, visited));
+                }
+                lines
+            }
+        }
+    }
+
+    /// Nodes of a way or members of a relation.
+    fn members(&self, element: &OsmElement) -> ElementSet<'a> {
+        match element.osm_type {
+            OsmType::Node => Vec::new(),
+            OsmType::Way => element
+                .nodes
+                .iter()
+                .filter_map(|id| self.store.get(OsmType::Node, *id))
+                .collect(),
+            OsmType::Relation => element
+                .members
+                .iter()
+                .filter_map(|member| self.store.get(member.osm_type, member.reference))
+                .collect(),
+        }
+    }
+
+    fn parents(&self, element: &OsmElement) -> ElementSet<'a> {
+        self.store.parents(element)
+    }
+
+    fn query_union(&mut self, query: &QueryUnion) -> Result<ElementSet<'a>, OverpassError> {
+        let mut set = Vec::new();
+        for member in &query.queries {
+            set.extend(self.query(member)?);
+        }
+        Ok(normalize(set))
+    }
+
+    fn query_difference(
+        &mut self,
+        query: &QueryDifference,
+    ) -> Result<ElementSet<'a>, OverpassError> {
+        let set = self.query(&query.base)?;
+        let minus = self
+            .query(&query.minus)?
+            .iter()
+            .map(|element| element.key())
+            .collect::<HashSet<(OsmType, i64)>>();
+        Ok(set
+            .into_iter()
+            .filter(|element| !minus.contains(&element.key()))
+            .collect())
+    }
+
+    fn query_recurse(&self, query: &QueryRecurse) -> Result<ElementSet<'a>, OverpassError> {
+        let input = self.set(query.set.as_deref().unwrap_or("_"));
+        Ok(normalize(match query.recurse.as_ref() {
+            ">" => self.recurse_down(input, false),
+            ">>" => self.recurse_down(input, true),
+            "<" => self.recurse_up(input, false),
+            "<<" => self.recurse_up(input, true),
+            recurse => {
+                return Err(OverpassError::unsupported(
+                    format!("Recurse {recurse:?} is not supported"),
+                    query.span,
+                ));
+            }
+        }))
+    }
+
+    /// Follow links from elements of the set until no new element is found.
+    fn closure(
+        &self,
+        mut set: ElementSet<'a>,
+        next: impl Fn(&OsmElement) -> ElementSet<'a>,
+    ) -> ElementSet<'a> {
+        let mut seen = set
+            .iter()
+            .map(|element| element.key())
+            .collect::<HashSet<(OsmType, i64)>>();
+        let mut frontier = set.clone();
+        while !frontier.is_empty() {
+            frontier = frontier
+                .iter()
+                .flat_map(|element| next(element))
+                .filter(|element| seen.insert(element.key()))
+                .collect();
+            set.extend(&frontier);
+        }
+        set
+    }
+
+    /// `>`: nodes of ways, node and way members of relations and nodes of
+    /// these member ways. `>>`: all members, recursively.
+    fn recurse_down(&self, input: ElementSet<'a>, transitive: bool) -> ElementSet<'a> {
+        let mut set = input
+            .iter()
+            .flat_map(|element| self.members(element))
+            .filter(|element| transitive || element.osm_type != OsmType::Relation)
+            .collect::<ElementSet>();
+        if transitive {
+            set = self.closure(set, |element| self.members(element));
+        } else {
+            let way_nodes = set
+                .iter()
+                .filter(|element| element.osm_type == OsmType::Way)
+                .flat_map(|way| self.members(way))
+                .collect::<ElementSet>();
+            set.extend(way_nodes);
+        }
+        set
+    }
+
+    /// `<`: ways and relations using nodes or ways of the set, and relations
+    /// of these ways. `<<`: all parents, recursively.
+    fn recurse_up(&self, input: ElementSet<'a>, transitive: bool) -> ElementSet<'a> {
+        let mut set = input
+            .iter()
+            .filter(|element| transitive || element.osm_type != OsmType::Relation)
+            .flat_map(|element| self.parents(element))
+            .collect::<ElementSet>();
+        if transitive {
+            set = self.closure(set, |element| self.parents(element));
+        } else {
+            let way_relations = set
+                .iter()
+                .filter(|element| element.osm_type == OsmType::Way)
+                .flat_map(|way| self.parents(way))
+                .collect::<ElementSet>();
+            set.extend(way_relations);
+        }
+        set
+    }
+
+    /// Run the body once per element of the input set, with the loop variable
+    /// set to this element.
+    fn query_foreach(&mut self, query: &QueryForeach) -> Result<ElementSet<'a>, OverpassError> {
+        let input = self.set(query.input_set.as_deref().unwrap_or("_"));
+        let mut set = Vec::new();
+        for element in input {
+            self.assign(query.loop_var.as_deref(), vec![element]);
+            set.extend(self.subrequest(&query.body)?);
+        }
+        Ok(normalize(set))
+    }
+
+    fn query_for(&mut self, query: &QueryFor) -> Result<ElementSet<'a>, OverpassError> {
+        let input = self.set(query.input_set.as_deref().unwrap_or("_"));
+        let mut groups: BTreeMap<String, ElementSet<'a>> = BTreeMap::new();
+        for element in input {
+            let value = query.value.evaluate(&element.tag_map())?;
+            groups.entry(value).or_default().push(element);
+        }
+        let mut set = Vec::new();
+        for group in groups.into_values() {
+            self.assign(query.loop_var.as_deref(), group);
+            set.extend(self.subrequest(&query.body)?);
+        }
+        Ok(normalize(set))
+    }
+
+    /// Only conditions without element and set are supported.
+    fn query_if(&mut self, query: &QueryIf) -> Result<ElementSet<'a>, OverpassError> {
+        if query.condition.matches(&HashMap::new())? {
+            self.subrequest(&query.then)
+        } else if let Some(otherwise) = &query.otherwise {
+            self.subrequest(otherwise)
+        } else {
+            Ok(Vec::new())
+        }
+    }
+
+    fn query_complete(&mut self, query: &QueryComplete) -> Result<ElementSet<'a>, OverpassError> {
+        let mut set = self.set(query.input_set.as_deref().unwrap_or("_"));
+        for _ in 0..query.limit.unwrap_or(u64::MAX) {
+            self.assign(None, set.clone());
+            let mut next = self.subrequest(&query.body)?;
+            next.extend(set.iter());
+            let next = normalize(next);
+            if next.len() == set.len() {
+                break;
+            }
+            set = next;
+        }
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        memory::{element::Member, store::MemoryStore},
+        overpass_parser::parse_query,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn store() -> MemoryStore {
+        MemoryStore::new([
+            OsmElement::node(1, 1.0, 1.0, &[("amenity", "bench")]),
+            OsmElement::node(2, 1.0, 2.0, &[]),
+            OsmElement::node(3, 2.0, 2.0, &[("amenity", "cafe"), ("name", "A")]),
+            OsmElement::node(4, 5.0, 5.0, &[("amenity", "cafe")]),
+            OsmElement::way(10, &[1, 2, 3], &[("highway", "footway")]),
+            OsmElement::way(11, &[3, 4], &[("highway", "path")]),
+            OsmElement::relation(
+                20,
+                vec![
+                    Member::new(OsmType::Way, 10, ""),
+                    Member::new(OsmType::Node, 4, "stop"),
+                ],
+                &[("type", "route")],
+            ),
+            OsmElement::relation(
+                21,
+                vec![Member::new(OsmType::Relation, 20, "")],
+                &[("type", "route_master")],
+            ),
+        ])
+    }
+
+    fn evaluate(query: &str) -> Vec<Vec<String>> {
+        let store = store();
+        let request = parse_query(query).unwrap();
+        MemoryEvaluator::evaluate(&store, &request)
+            .unwrap()
+            .iter()
+            .map(|set| {
+                set.iter()
+                    .map(|element| format!("{}{}", element.osm_type.as_char(), element.id))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_query_objects() {
+        assert_eq!(vec![vec!["n3", "n4"]], evaluate("node[amenity=cafe];"));
+        assert_eq!(vec![vec!["n1"]], evaluate("node[amenity](0,0,1.5,1.5);"));
+        assert_eq!(
+            vec![vec!["n1"]],
+            evaluate("[out:json][bbox:0,0,1.5,1.5];node[amenity];")
+        );
+        assert_eq!(vec![vec!["w11"]], evaluate("way(3,3,4,4);"));
+        assert_eq!(vec![vec!["r20", "r21"]], evaluate("rel(0,0,1.5,1.5);"));
+        assert_eq!(
+            vec![vec!["n1", "n2", "n3"]],
+            evaluate("node(poly:\"0 0 0 3 3 3 3 0\");")
+        );
+        assert_eq!(
+            vec![vec!["n1", "w10", "r20"]],
+            evaluate("nwr(id:1,10,20,30);")
+        );
+        assert_eq!(vec![vec!["n1", "n2", "n3"]], evaluate("way(10);node(w);"));
+        assert_eq!(
+            vec![vec!["n4"]],
+            evaluate("rel(20)->.rels; node; node(r.rels:stop);")
+        );
+        assert_eq!(
+            vec![vec!["r20"]],
+            evaluate("node(4)->.stops; rel(bn.stops:\"stop\");")
+        );
+        assert_eq!(
+            vec![Vec::<&str>::new()],
+            evaluate("node(3)->.stops; nwr(bn.stops:\"stop\");")
+        );
+        assert_eq!(vec![vec!["r20"]], evaluate("way(10)->.ways; rel(bw.ways);"));
+        assert_eq!(
+            vec![vec!["n3"]],
+            evaluate("node[amenity](if: t[name] == \"A\" || is_number(t[amenity]));")
+        );
+        assert_eq!(
+            vec![vec!["w11"]],
+            evaluate("way(if: is_tag(highway) && t[highway] != footway);")
+        );
+    }
+
+    #[test]
+    fn test_sets() {
+        assert_eq!(
+            vec![vec!["n3"], vec!["n3", "n4"]],
+            evaluate("node[amenity=cafe]->.a; node.a[name]; out; .a out;")
+        );
+        assert_eq!(vec![vec!["n3"]], evaluate("node[amenity=cafe]; out 1;"));
+        assert_eq!(
+            vec![vec!["n1", "n2", "n3", "w10"]],
+            evaluate("(way(10); >;);")
+        );
+    }
+
+    #[test]
+    fn test_intersection() {
+        assert_eq!(
+            vec![vec!["n3"]],
+            evaluate("node[amenity]->.a; node(2,2,3,3)->.b; node.a.b;")
+        );
+        assert_eq!(
+            vec![vec!["n3"]],
+            evaluate("node[amenity]->.a; node(2,2,3,3)->.b; nwr.a.b[name];")
+        );
+    }
+
+    #[test]
+    fn test_difference() {
+        assert_eq!(
+            vec![vec!["n1", "n4"]],
+            evaluate("(node[amenity]; - node[name];);")
+        );
+        assert_eq!(
+            vec![vec!["r20", "r21"]],
+            evaluate("(nwr; - (way; >;);)->.a; .a out;")
+        );
+    }
+
+    #[test]
+    fn test_recurse() {
+        assert_eq!(vec![vec!["n1", "n2", "n3"]], evaluate("way(10); >;"));
+        assert_eq!(vec![Vec::<&str>::new()], evaluate("rel(21); >;"));
+        assert_eq!(
+            vec![vec!["n1", "n2", "n3", "n4", "w10"]],
+            evaluate("rel(20); >;")
+        );
+        assert_eq!(
+            vec![vec!["n1", "n2", "n3", "n4", "w10", "r20"]],
+            evaluate("rel(21); >>;")
+        );
+        assert_eq!(vec![vec!["w11", "r20"]], evaluate("node(4); <;"));
+        assert_eq!(vec![vec!["w10", "w11", "r20"]], evaluate("node(3); <;"));
+        assert_eq!(vec![vec!["w11", "r20", "r21"]], evaluate("node(4); <<;"));
+    }
+
+    #[test]
+    fn test_foreach() {
+        assert_eq!(
+            vec![vec!["n1", "n2", "n3"], vec!["n3", "n4"]],
+            evaluate("way[highway]; foreach->.w(.w >; out;);")
+        );
+    }
+
+    #[test]
+    fn test_for() {
+        assert_eq!(
+            vec![vec!["n1"], vec!["n3", "n4"]],
+            evaluate("node[amenity]; for (t[amenity]) { out; };")
+        );
+    }
+
+    #[test]
+    fn test_if() {
+        assert_eq!(
+            vec![vec!["n2"]],
+            evaluate("if (1 > 2) { node(1); out; } else { node(2); out; };")
+        );
+    }
+
+    #[test]
+    fn test_complete() {
+        assert_eq!(
+            vec![vec!["n1", "n2", "n3", "n4"]],
+            evaluate("node(1); complete->.c { way(bn); node(w); }; .c out;")
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let store = store();
+        let request = parse_query("area[name];").unwrap();
+        match MemoryEvaluator::evaluate(&store, &request) {
+            Err(OverpassError::Unsupported { span, .. }) => assert_eq!(0, span.start),
+            other => panic!("Expected an unsupported error, got {other:?}"),
+        }
+    }
+}
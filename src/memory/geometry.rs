@@ -0,0 +1,89 @@
+/// Latitude and longitude.
+pub type Point = (f64, f64);
+
+pub fn bbox_polygon((s, w, n, e): (f64, f64, f64, f64)) -> Vec<Point> {
+    vec![(s, w), (s, e), (n, e), (n, w)]
+}
+
+/// Ray casting, points on the boundary may be reported on either side.
+pub fn point_in_polygon((lat, lon): Point, polygon: &[Point]) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+    for (i, &(lat_i, lon_i)) in polygon.iter().enumerate() {
+        let (lat_j, lon_j) = polygon[j];
+        if (lat_i > lat) != (lat_j > lat)
+            && lon < (lon_j - lon_i) * (lat - lat_i) / (lat_j - lat_i) + lon_i
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+fn orientation(a: Point, b: Point, c: Point) -> f64 {
+    (b.1 - a.1) * (c.0 - a.0) - (b.0 - a.0) * (c.1 - a.1)
+}
+
+fn on_segment(a: Point, b: Point, p: Point) -> bool {
+    p.0 >= a.0.min(b.0) && p.0 <= a.0.max(b.0) && p.1 >= a.1.min(b.1) && p.1 <= a.1.max(b.1)
+}
+
+/// Segments intersection, including touching ends and degenerated segments.
+pub fn segments_intersect(p1: Point, p2: Point, q1: Point, q2: Point) -> bool {
+    let d1 = orientation(q1, q2, p1);
+    let d2 = orientation(q1, q2, p2);
+    let d3 = orientation(p1, p2, q1);
+    let d4 = orientation(p1, p2, q2);
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0))
+        && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0))
+    {
+        return true;
+    }
+    (d1 == 0.0 && on_segment(q1, q2, p1))
+        || (d2 == 0.0 && on_segment(q1, q2, p2))
+        || (d3 == 0.0 && on_segment(p1, p2, q1))
+        || (d4 == 0.0 && on_segment(p1, p2, q2))
+}
+
+/// Whether a point or a linestring intersects the polygon area or boundary.
+pub fn line_intersects_polygon(line: &[Point], polygon: &[Point]) -> bool {
+    if line.iter().any(|&point| point_in_polygon(point, polygon)) {
+        return true;
+    }
+    let segments = match line {
+        [] => return false,
+        [point] => vec![(*point, *point)],
+        _ => line.windows(2).map(|w| (w[0], w[1])).collect(),
+    };
+    let mut j = polygon.len().wrapping_sub(1);
+    for (i, &edge_end) in polygon.iter().enumerate() {
+        let edge_start = polygon[j];
+        if segments
+            .iter()
+            .any(|&(a, b)| segments_intersect(a, b, edge_start, edge_end))
+        {
+            return true;
+        }
+        j = i;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_line_intersects_polygon() {
+        let square = bbox_polygon((0.0, 0.0, 2.0, 2.0));
+        assert!(line_intersects_polygon(&[(1.0, 1.0)], &square));
+        assert!(line_intersects_polygon(&[(0.0, 1.0)], &square));
+        assert!(!line_intersects_polygon(&[(3.0, 1.0)], &square));
+        assert!(line_intersects_polygon(&[(-1.0, 1.0), (3.0, 1.0)], &square));
+        assert!(!line_intersects_polygon(
+            &[(-1.0, 3.0), (3.0, 3.0)],
+            &square
+        ));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod element;
+pub mod evaluator;
+pub mod geometry;
+pub mod store;
@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+
+use super::element::{OsmElement, OsmType};
+
+pub trait OsmStore {
+    fn get(&self, osm_type: OsmType, id: i64) -> Option<&OsmElement>;
+
+    /// All the elements of a type, ordered by id.
+    fn elements(&self, osm_type: OsmType) -> Box<dyn Iterator<Item = &OsmElement> + '_>;
+
+    /// Ways and relations having the element as node or member.
+    fn parents(&self, element: &OsmElement) -> Vec<&OsmElement> {
+        let mut parents = Vec::new();
+        if element.osm_type == OsmType::Node {
+            parents.extend(
+                self.elements(OsmType::Way)
+                    .filter(|way| way.nodes.contains(&element.id)),
+            );
+        }
+        parents.extend(self.elements(OsmType::Relation).filter(|relation| {
+            relation
+                .members
+                .iter()
+                .any(|member| member.osm_type == element.osm_type && member.reference == element.id)
+        }));
+        parents
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    elements: BTreeMap<(OsmType, i64), OsmElement>,
+}
+
+impl MemoryStore {
+    pub fn new(elements: impl IntoIterator<Item = OsmElement>) -> Self {
+        let mut store = MemoryStore::default();
+        for element in elements {
+            store.insert(element);
+        }
+        store
+    }
+
+    pub fn insert(&mut self, element: OsmElement) {
+        self.elements.insert(element.key(), element);
+    }
+}
+
+impl OsmStore for MemoryStore {
+    fn get(&self, osm_type: OsmType, id: i64) -> Option<&OsmElement> {
+        self.elements.get(&(osm_type, id))
+    }
+
+    fn elements(&self, osm_type: OsmType) -> Box<dyn Iterator<Item = &OsmElement> + '_> {
+        Box::new(
+            self.elements
+                .range((osm_type, i64::MIN)..=(osm_type, i64::MAX))
+                .map(|(_, element)| element),
+        )
+    }
+}
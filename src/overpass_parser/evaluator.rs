@@ -1,121 +1,900 @@
 use derivative::Derivative;
 use pest::iterators::Pair;
+use regex::Regex;
+use std::collections::HashMap;
 
-use crate::{overpass_parser::selectors::Selector, sql_dialect::sql_dialect::SqlDialect};
+use crate::{
+    error::{OverpassError, Span},
+    overpass_parser::selectors::Selector,
+    sql_dialect::sql_dialect::SqlDialect,
+};
 
 use super::Rule;
 
+/// Functions on a set, `set.name(...)`, or on the default set when called
+/// without set.
+const AGGREGATES: [&str; 6] = ["set", "u", "min", "max", "sum", "count"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    /// Unquoted number.
+    Number(Box<str>),
+    String(Box<str>),
+    /// `t[key]`
+    Tag(Box<str>),
+    /// Per element function, `id()`, `is_tag(key)`...
+    Function {
+        name: Box<str>,
+        params: Vec<Evaluator>,
+    },
+    /// `set.name(param)`, on the default set when `set` is not set.
+    Aggregate {
+        set: Option<Box<str>>,
+        name: Box<str>,
+        param: Option<Box<Evaluator>>,
+    },
+    Unary {
+        operator: Box<str>,
+        operand: Box<Evaluator>,
+    },
+    Binary {
+        operator: Box<str>,
+        left: Box<Evaluator>,
+        right: Box<Evaluator>,
+    },
+    Ternary {
+        condition: Box<Evaluator>,
+        then: Box<Evaluator>,
+        otherwise: Box<Evaluator>,
+    },
+}
+
 #[derive(Derivative)]
-#[derivative(Default)]
+#[derivative(PartialEq)]
 #[derive(Debug, Clone)]
 pub struct Evaluator {
-    pub fn_call: Option<Box<str>>,
-    pub agg_set_call: Option<Box<str>>,
-    pub param: Option<Box<Evaluator>>,
-    pub tag_call: Option<Box<str>>,
-    pub static_value: Option<Box<str>>,
+    pub expression: Expression,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+/// Type of the SQL expressions. Overpass values are texts, numbers and
+/// booleans are converted on use.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SqlType {
+    Text,
+    Number,
+    Boolean,
+    /// In the `srid` of the request, only usable by the geometry functions.
+    Geometry,
+}
+
+fn binding_power(operator: &str) -> u8 {
+    match operator {
+        "||" => 1,
+        "&&" => 2,
+        "==" | "!=" => 3,
+        "<" | "<=" | ">" | ">=" => 4,
+        "+" | "-" => 5,
+        _ => 6,
+    }
 }
 
 impl Evaluator {
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
-        let mut evaluator = Evaluator::default();
-        for inner_pair in pair.into_inner() {
-            match inner_pair.as_rule() {
-                Rule::eval_fn => {
-                    evaluator.fn_call = Some(
-                        inner_pair
-                            .into_inner()
-                            .find(|p| p.as_rule() == Rule::ID)
-                            .map(|p| p.as_str())
-                            .unwrap()
-                            .into(),
-                    );
-                }
-                Rule::eval_tag => {
-                    let tag = Selector::unquote(
-                        inner_pair
-                            .into_inner()
-                            .find(|p| p.as_rule() == Rule::key)
-                            .map(|p| p.as_str())
-                            .unwrap(),
-                    );
-                    evaluator.tag_call = Some(tag.into());
-                }
-                Rule::eval_agg_set => {
-                    evaluator.agg_set_call = Some(
-                        inner_pair
-                            .clone()
-                            .into_inner()
-                            .find(|p| p.as_rule() == Rule::ID)
-                            .map(|p| p.as_str())
-                            .unwrap()
-                            .into(),
-                    );
-                    evaluator.param = Some(Box::new(Evaluator::from_pest(
-                        inner_pair
-                            .into_inner()
-                            .find(|p| p.as_rule() == Rule::eval)
-                            .unwrap(),
-                    )?));
-                }
-                Rule::STRING => {
-                    evaluator.static_value = Some(inner_pair.as_str().to_string().into());
-                }
-                Rule::number => {
-                    evaluator.static_value = Some(inner_pair.as_str().to_string().into());
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
+        let span: Span = pair.as_span().into();
+        let expression = match pair.as_rule() {
+            Rule::eval => {
+                let mut inner = pair.into_inner();
+                let condition = Evaluator::from_pest(inner.next().unwrap())?;
+                match (inner.next(), inner.next()) {
+                    (Some(then), Some(otherwise)) => Expression::Ternary {
+                        condition: Box::new(condition),
+                        then: Box::new(Evaluator::from_pest(then)?),
+                        otherwise: Box::new(Evaluator::from_pest(otherwise)?),
+                    },
+                    _ => return Ok(condition),
                 }
-                _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!(
-                                "Invalid rule {:?} for Evaluator",
-                                inner_pair.as_rule()
-                            ),
+            }
+            Rule::eval_or
+            | Rule::eval_and
+            | Rule::eval_eq
+            | Rule::eval_cmp
+            | Rule::eval_add
+            | Rule::eval_mul => {
+                // Left associative
+                let mut inner = pair.into_inner();
+                let mut left = Evaluator::from_pest(inner.next().unwrap())?;
+                while let (Some(operator), Some(right)) = (inner.next(), inner.next()) {
+                    let right = Evaluator::from_pest(right)?;
+                    left = Evaluator {
+                        span: Span {
+                            start: left.span.start,
+                            end: right.span.end,
+                        },
+                        expression: Expression::Binary {
+                            operator: operator.as_str().into(),
+                            left: Box::new(left),
+                            right: Box::new(right),
+                        },
+                    };
+                }
+                return Ok(left);
+            }
+            Rule::eval_unary => {
+                let mut pairs = pair.into_inner().collect::<Vec<Pair<Rule>>>();
+                let mut operand = Evaluator::from_pest(pairs.pop().unwrap())?;
+                for operator in pairs.into_iter().rev() {
+                    operand = Evaluator {
+                        span: Span {
+                            start: operator.as_span().start(),
+                            end: operand.span.end,
                         },
-                        inner_pair.as_span(),
+                        expression: Expression::Unary {
+                            operator: operator.as_str().into(),
+                            operand: Box::new(operand),
+                        },
+                    };
+                }
+                return Ok(operand);
+            }
+            Rule::eval_fn => {
+                let mut inner = pair.into_inner();
+                let name: Box<str> = inner.next().unwrap().as_str().into();
+                let mut params = inner
+                    .map(Evaluator::from_pest)
+                    .collect::<Result<Vec<Evaluator>, OverpassError>>()?;
+                if AGGREGATES.contains(&name.as_ref()) {
+                    if params.len() > 1 {
+                        return Err(OverpassError::syntax(
+                            format!("Too many parameters for {name}()"),
+                            span,
+                        ));
+                    }
+                    Expression::Aggregate {
+                        set: None,
+                        name,
+                        param: params.pop().map(Box::new),
+                    }
+                } else {
+                    Expression::Function { name, params }
+                }
+            }
+            Rule::eval_agg_set => {
+                let mut inner = pair.into_inner();
+                let set = inner.next().unwrap().as_str().into();
+                let name: Box<str> = inner.next().unwrap().as_str().into();
+                if !AGGREGATES.contains(&name.as_ref()) {
+                    return Err(OverpassError::syntax(
+                        format!("Unknown aggregate function {name}"),
+                        span,
                     ));
                 }
+                Expression::Aggregate {
+                    set: Some(set),
+                    name,
+                    param: inner
+                        .next()
+                        .map(Evaluator::from_pest)
+                        .transpose()?
+                        .map(Box::new),
+                }
+            }
+            Rule::eval_tag => {
+                let key = pair
+                    .into_inner()
+                    .find(|p| p.as_rule() == Rule::key)
+                    .map(|p| p.as_str())
+                    .unwrap();
+                Expression::Tag(Selector::unquote(key).as_ref().into())
+            }
+            Rule::eval_number => Expression::Number(pair.as_str().into()),
+            Rule::eval_string => Expression::String(Selector::unquote(pair.as_str()).into()),
+            _ => {
+                return Err(OverpassError::syntax(
+                    format!("Invalid rule {:?} for Evaluator", pair.as_rule()),
+                    pair.as_span(),
+                ));
+            }
+        };
+        Ok(Evaluator { expression, span })
+    }
+
+    /// Sets read by the aggregates, with the span of the aggregate.
+    pub fn sets<'a>(&'a self, sets: &mut Vec<(&'a str, Span)>) {
+        match &self.expression {
+            Expression::Number(_) | Expression::String(_) | Expression::Tag(_) => {}
+            Expression::Function { params, .. } => {
+                params.iter().for_each(|param| param.sets(sets));
+            }
+            Expression::Aggregate { set, param, .. } => {
+                sets.push((set.as_deref().unwrap_or("_"), self.span));
+                if let Some(param) = param {
+                    param.sets(sets);
+                }
+            }
+            Expression::Unary { operand, .. } => operand.sets(sets),
+            Expression::Binary { left, right, .. } => {
+                left.sets(sets);
+                right.sets(sets);
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+            } => {
+                condition.sets(sets);
+                then.sets(sets);
+                otherwise.sets(sets);
             }
         }
-        Ok(evaluator)
     }
 
+    /// Span of the first value read from an element outside of an aggregate.
+    pub fn element_span(&self) -> Option<Span> {
+        match &self.expression {
+            Expression::Number(_) | Expression::String(_) | Expression::Aggregate { .. } => None,
+            Expression::Tag(_) => Some(self.span),
+            Expression::Function { name, params } => match name.as_ref() {
+                "number" | "is_number" | "date" => params.iter().find_map(Evaluator::element_span),
+                // On the element geometry by default
+                "center" | "length" | "area" | "is_closed" if !params.is_empty() => {
+                    params.iter().find_map(Evaluator::element_span)
+                }
+                _ => Some(self.span),
+            },
+            Expression::Unary { operand, .. } => operand.element_span(),
+            Expression::Binary { left, right, .. } => {
+                left.element_span().or_else(|| right.element_span())
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+            } => condition
+                .element_span()
+                .or_else(|| then.element_span())
+                .or_else(|| otherwise.element_span()),
+        }
+    }
+
+    /// SQL value, numbers are kept as SQL numbers.
     pub fn to_sql(
         &self,
         sql_dialect: &(dyn SqlDialect + Send + Sync),
-        _srid: &str,
-        _default_set: &str,
-    ) -> String {
-        if self.static_value.is_some() {
-            sql_dialect.escape_literal(self.static_value.as_ref().unwrap())
-        } else if let Some(fn_call) = &self.fn_call {
-            let mut name = fn_call.as_ref();
-            if name == "type" {
-                name = "osm_type"
-            } else if name == "timestamp" {
-                name = "created"
-            } else if name == "lon" {
-                name = "ST_Y(ST_PointOnSurface(geom))"
-            } else if name == "lat" {
-                name = "ST_X(ST_PointOnSurface(geom))"
-            }
-            name.to_string()
-        } else if self.tag_call.is_some() {
-            format!(
-                "tags ->> {}",
-                sql_dialect.escape_literal(self.tag_call.as_ref().unwrap())
-            )
-        } else if self.agg_set_call.is_some() {
-            format!(
-                "(SELECT string_agg({}, ',') FROM _{})",
-                self.param
-                    .as_ref()
-                    .unwrap()
-                    .to_sql(sql_dialect, _srid, _default_set),
-                self.agg_set_call.as_ref().unwrap()
-            )
+        srid: &str,
+        default_set: &str,
+    ) -> Result<String, OverpassError> {
+        let table = format!("_{default_set}");
+        let (sql, sql_type) = self.to_sql_typed(sql_dialect, srid, &table, default_set)?;
+        match sql_type {
+            SqlType::Boolean | SqlType::Geometry => {
+                self.cast(sql_dialect, &sql, sql_type, SqlType::Text)
+            }
+            _ => Ok(sql),
+        }
+    }
+
+    /// SQL value as text, as Overpass tag values.
+    pub fn to_sql_text(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        default_set: &str,
+    ) -> Result<String, OverpassError> {
+        let table = format!("_{default_set}");
+        self.to_sql_as(sql_dialect, srid, &table, default_set, SqlType::Text)
+    }
+
+    /// SQL geometry, in the `srid`.
+    pub fn to_sql_geom(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        default_set: &str,
+    ) -> Result<String, OverpassError> {
+        let table = format!("_{default_set}");
+        self.to_sql_as(sql_dialect, srid, &table, default_set, SqlType::Geometry)
+    }
+
+    /// SQL condition on the elements of `table`.
+    pub fn to_sql_condition(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        table: &str,
+        default_set: &str,
+    ) -> Result<String, OverpassError> {
+        self.to_sql_as(sql_dialect, srid, table, default_set, SqlType::Boolean)
+    }
+
+    /// Convert the SQL of this expression from a type to an other.
+    fn cast(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        sql: &str,
+        from: SqlType,
+        to: SqlType,
+    ) -> Result<String, OverpassError> {
+        Ok(match (from, to) {
+            (SqlType::Text, SqlType::Text)
+            | (SqlType::Number, SqlType::Number)
+            | (SqlType::Boolean, SqlType::Boolean)
+            | (SqlType::Geometry, SqlType::Geometry) => sql.to_string(),
+            (SqlType::Geometry, _) | (_, SqlType::Geometry) => {
+                return Err(OverpassError::semantic(
+                    "Geometry can only be used by geometry functions and ::geom",
+                    self.span,
+                ));
+            }
+            (SqlType::Number, SqlType::Text) => format!("CAST({sql} AS text)"),
+            (SqlType::Boolean, SqlType::Text) => format!("CASE WHEN {sql} THEN '1' ELSE '0' END"),
+            (SqlType::Text, SqlType::Number) => sql_dialect.try_numeric(sql),
+            (SqlType::Boolean, SqlType::Number) => format!("CASE WHEN {sql} THEN 1 ELSE 0 END"),
+            (SqlType::Number, SqlType::Boolean) => format!("coalesce({sql} <> 0, false)"),
+            (SqlType::Text, SqlType::Boolean) => {
+                format!("coalesce({sql} NOT IN ('', '0'), false)")
+            }
+        })
+    }
+
+    fn to_sql_as(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        table: &str,
+        default_set: &str,
+        to: SqlType,
+    ) -> Result<String, OverpassError> {
+        let (sql, sql_type) = self.to_sql_typed(sql_dialect, srid, table, default_set)?;
+        self.cast(sql_dialect, &sql, sql_type, to)
+    }
+
+    /// The key of `t[]` and `is_tag()` as a literal.
+    fn key(&self) -> Result<&str, OverpassError> {
+        match &self.expression {
+            Expression::String(key) | Expression::Number(key) => Ok(key),
+            _ => Err(OverpassError::semantic(
+                "Tag key must be a constant",
+                self.span,
+            )),
+        }
+    }
+
+    /// SQL of the expression on the element of `table`.
+    fn to_sql_typed(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        table: &str,
+        default_set: &str,
+    ) -> Result<(String, SqlType), OverpassError> {
+        Ok(match &self.expression {
+            Expression::Number(number) => (number.to_string(), SqlType::Number),
+            Expression::String(string) => (sql_dialect.escape_literal(string), SqlType::Text),
+            Expression::Tag(key) => (sql_dialect.hash_get(table, key), SqlType::Text),
+            Expression::Function { name, params } => {
+                let param = |i: usize, to: SqlType| match params.get(i) {
+                    Some(param) => param.to_sql_as(sql_dialect, srid, table, default_set, to),
+                    None => Err(OverpassError::semantic(
+                        format!("Missing parameter for {name}()"),
+                        self.span,
+                    )),
+                };
+                // The element geometry by default
+                let geometry = || match params.first() {
+                    Some(param) => {
+                        param.to_sql_as(sql_dialect, srid, table, default_set, SqlType::Geometry)
+                    }
+                    None => Ok(format!("{table}.geom")),
+                };
+                match name.as_ref() {
+                    "id" | "version" | "changeset" | "uid" => {
+                        (format!("{table}.{name}"), SqlType::Number)
+                    }
+                    "type" => (format!("{table}.osm_type"), SqlType::Text),
+                    "timestamp" => (format!("{table}.created"), SqlType::Text),
+                    "user" => (format!("{table}.\"user\""), SqlType::Text),
+                    "lon" => (
+                        format!(
+                            "ST_X(ST_PointOnSurface({}))",
+                            sql_dialect.st_transform_reverse(&format!("{table}.geom"), srid)
+                        ),
+                        SqlType::Number,
+                    ),
+                    "lat" => (
+                        format!(
+                            "ST_Y(ST_PointOnSurface({}))",
+                            sql_dialect.st_transform_reverse(&format!("{table}.geom"), srid)
+                        ),
+                        SqlType::Number,
+                    ),
+                    "geom" => (format!("{table}.geom"), SqlType::Geometry),
+                    "center" => (
+                        format!("ST_PointOnSurface({})", geometry()?),
+                        SqlType::Geometry,
+                    ),
+                    "length" => (
+                        sql_dialect.st_length_meters(&geometry()?, srid),
+                        SqlType::Number,
+                    ),
+                    "area" => (
+                        sql_dialect.st_area_meters(&geometry()?, srid),
+                        SqlType::Number,
+                    ),
+                    "is_closed" => (format!("ST_IsClosed({})", geometry()?), SqlType::Boolean),
+                    "count_tags" => (
+                        format!("(SELECT count(*) FROM {})", sql_dialect.hash_each(table)),
+                        SqlType::Number,
+                    ),
+                    "count_members" => match sql_dialect.relation_members(table) {
+                        Some(members) => {
+                            (format!("(SELECT count(*) FROM {members})"), SqlType::Number)
+                        }
+                        None => {
+                            return Err(OverpassError::unsupported(
+                                "count_members() is not supported by this SQL dialect",
+                                self.span,
+                            ));
+                        }
+                    },
+                    "is_tag" => match params.first() {
+                        Some(key) => (sql_dialect.hash_exists(table, key.key()?), SqlType::Boolean),
+                        None => {
+                            return Err(OverpassError::semantic(
+                                "Missing parameter for is_tag()",
+                                self.span,
+                            ));
+                        }
+                    },
+                    "number" => (param(0, SqlType::Number)?, SqlType::Number),
+                    "is_number" => (
+                        format!("{} IS NOT NULL", param(0, SqlType::Number)?),
+                        SqlType::Boolean,
+                    ),
+                    "date" => (
+                        sql_dialect.date_to_number(&param(0, SqlType::Text)?),
+                        SqlType::Number,
+                    ),
+                    _ => {
+                        return Err(OverpassError::semantic(
+                            format!("Unknown function {name}()"),
+                            self.span,
+                        ));
+                    }
+                }
+            }
+            Expression::Aggregate {
+                set,
+                name,
+                param: aggregate_param,
+            } => {
+                let set = set.as_deref().unwrap_or(default_set);
+                let set_table = format!("_{set}");
+                let param = |to: SqlType| match aggregate_param {
+                    Some(param) => param.to_sql_as(sql_dialect, srid, &set_table, set, to),
+                    None => Err(OverpassError::semantic(
+                        format!("Missing parameter for {name}()"),
+                        self.span,
+                    )),
+                };
+                let (aggregate, sql_type) = match name.as_ref() {
+                    "set" => {
+                        return Ok((
+                            format!(
+                                "({})",
+                                sql_dialect.set_agg(&param(SqlType::Text)?, &set_table)
+                            ),
+                            SqlType::Text,
+                        ));
+                    }
+                    "u" => {
+                        let value = param(SqlType::Text)?;
+                        (
+                            format!(
+                                "CASE count(DISTINCT {value}) WHEN 0 THEN '' WHEN 1 THEN min({value}) ELSE '< multiple values found >' END"
+                            ),
+                            SqlType::Text,
+                        )
+                    }
+                    "min" | "max" => match aggregate_param {
+                        Some(p)
+                            if p.to_sql_typed(sql_dialect, srid, &set_table, set)?.1
+                                == SqlType::Number =>
+                        {
+                            (
+                                format!("{name}({})", param(SqlType::Number)?),
+                                SqlType::Number,
+                            )
+                        }
+                        _ => {
+                            // Numeric when all the values are numbers
+                            let value = param(SqlType::Text)?;
+                            let number = sql_dialect.try_numeric(&value);
+                            (
+                                format!(
+                                    "CASE WHEN count({value}) = count({number}) THEN CAST({name}({number}) AS text) ELSE {name}({value}) END"
+                                ),
+                                SqlType::Text,
+                            )
+                        }
+                    },
+                    "sum" => (
+                        format!("coalesce(sum({}), 0)", param(SqlType::Number)?),
+                        SqlType::Number,
+                    ),
+                    _ => {
+                        let osm_type = match aggregate_param {
+                            None => None,
+                            Some(p) => match p.key()? {
+                                "nodes" => Some("n"),
+                                "ways" => Some("w"),
+                                "relations" => Some("r"),
+                                "areas" => Some("a"),
+                                "nwr" | "deriveds" => None,
+                                other => {
+                                    return Err(OverpassError::semantic(
+                                        format!("Invalid element type {other} for count()"),
+                                        p.span,
+                                    ));
+                                }
+                            },
+                        };
+                        let filter = osm_type
+                            .map(|osm_type| format!(" FILTER (WHERE osm_type = '{osm_type}')"))
+                            .unwrap_or_default();
+                        (format!("count(*){filter}"), SqlType::Number)
+                    }
+                };
+                (format!("(SELECT {aggregate} FROM {set_table})"), sql_type)
+            }
+            Expression::Unary { operator, operand } => match operator.as_ref() {
+                "!" => (
+                    format!(
+                        "NOT {}",
+                        operand.to_sql_as(
+                            sql_dialect,
+                            srid,
+                            table,
+                            default_set,
+                            SqlType::Boolean
+                        )?
+                    ),
+                    SqlType::Boolean,
+                ),
+                _ => (
+                    format!(
+                        "(-{})",
+                        operand.to_sql_as(
+                            sql_dialect,
+                            srid,
+                            table,
+                            default_set,
+                            SqlType::Number
+                        )?
+                    ),
+                    SqlType::Number,
+                ),
+            },
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let (left_sql, left_type) =
+                    left.to_sql_typed(sql_dialect, srid, table, default_set)?;
+                let (right_sql, right_type) =
+                    right.to_sql_typed(sql_dialect, srid, table, default_set)?;
+                let both = |to: SqlType| {
+                    Ok::<_, OverpassError>((
+                        left.cast(sql_dialect, &left_sql, left_type, to)?,
+                        right.cast(sql_dialect, &right_sql, right_type, to)?,
+                    ))
+                };
+                // As numbers when both sides are numbers, else as texts, as
+                // in memory. Missing tags are empty texts.
+                let operand = |side: &Evaluator, sql: &str, sql_type: SqlType| {
+                    let text = side.cast(sql_dialect, sql, sql_type, SqlType::Text)?;
+                    Ok::<_, OverpassError>(match (&side.expression, sql_type) {
+                        (Expression::String(string), _) => match Self::parse_number(string) {
+                            Some(number) => (text, number.to_string(), Some(true)),
+                            None => (text, "NULL".to_string(), Some(false)),
+                        },
+                        (Expression::Number(number), _) => (
+                            sql_dialect.escape_literal(number),
+                            number.to_string(),
+                            Some(true),
+                        ),
+                        (_, SqlType::Text) => (
+                            format!("coalesce({text}, '')"),
+                            sql_dialect.try_numeric(&text),
+                            None,
+                        ),
+                        _ => (
+                            text,
+                            side.cast(sql_dialect, sql, sql_type, SqlType::Number)?,
+                            Some(true),
+                        ),
+                    })
+                };
+                let numbers_or_texts =
+                    |numbers: &dyn Fn(&str, &str) -> String,
+                     texts: &dyn Fn(&str, &str) -> String| {
+                        let (l_text, l_number, l_numeric) = operand(left, &left_sql, left_type)?;
+                        let (r_text, r_number, r_numeric) = operand(right, &right_sql, right_type)?;
+                        Ok::<_, OverpassError>(match (l_numeric, r_numeric) {
+                            (Some(false), _) | (_, Some(false)) => texts(&l_text, &r_text),
+                            (Some(true), Some(true)) => numbers(&l_number, &r_number),
+                            _ => {
+                                let condition = [(l_numeric, &l_number), (r_numeric, &r_number)]
+                                    .into_iter()
+                                    .filter(|(numeric, _)| numeric.is_none())
+                                    .map(|(_, number)| format!("{number} IS NOT NULL"))
+                                    .collect::<Vec<String>>()
+                                    .join(" AND ");
+                                format!(
+                                    "CASE WHEN {condition} THEN {} ELSE {} END",
+                                    numbers(&l_number, &r_number),
+                                    texts(&l_text, &r_text)
+                                )
+                            }
+                        })
+                    };
+                match operator.as_ref() {
+                    "||" | "&&" => {
+                        let (l, r) = both(SqlType::Boolean)?;
+                        let operator = if operator.as_ref() == "||" {
+                            "OR"
+                        } else {
+                            "AND"
+                        };
+                        (format!("({l} {operator} {r})"), SqlType::Boolean)
+                    }
+                    "==" | "!=" | "<" | "<=" | ">" | ">=" => {
+                        let operator = match operator.as_ref() {
+                            "==" => "=",
+                            "!=" => "<>",
+                            operator => operator,
+                        };
+                        let compare = |l: &str, r: &str| format!("{l} {operator} {r}");
+                        (
+                            format!("coalesce({}, false)", numbers_or_texts(&compare, &compare)?),
+                            SqlType::Boolean,
+                        )
+                    }
+                    "+" if left_type == SqlType::Number && right_type == SqlType::Number => {
+                        let (l, r) = both(SqlType::Number)?;
+                        (format!("({l} + {r})"), SqlType::Number)
+                    }
+                    "+" => {
+                        let sum = |l: &str, r: &str| format!("CAST(({l} + {r}) AS text)");
+                        let concat = |l: &str, r: &str| format!("({l} || {r})");
+                        (numbers_or_texts(&sum, &concat)?, SqlType::Text)
+                    }
+                    "/" => {
+                        let (l, r) = both(SqlType::Number)?;
+                        (format!("({l} / NULLIF({r}, 0))"), SqlType::Number)
+                    }
+                    _ => {
+                        let (l, r) = both(SqlType::Number)?;
+                        (format!("({l} {operator} {r})"), SqlType::Number)
+                    }
+                }
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let condition =
+                    condition.to_sql_as(sql_dialect, srid, table, default_set, SqlType::Boolean)?;
+                let (then_sql, then_type) =
+                    then.to_sql_typed(sql_dialect, srid, table, default_set)?;
+                let (otherwise_sql, otherwise_type) =
+                    otherwise.to_sql_typed(sql_dialect, srid, table, default_set)?;
+                let sql_type = if then_type == otherwise_type {
+                    then_type
+                } else {
+                    SqlType::Text
+                };
+                (
+                    format!(
+                        "CASE WHEN {condition} THEN {} ELSE {} END",
+                        then.cast(sql_dialect, &then_sql, then_type, sql_type)?,
+                        otherwise.cast(sql_dialect, &otherwise_sql, otherwise_type, sql_type)?
+                    ),
+                    sql_type,
+                )
+            }
+        })
+    }
+
+    /// Test the expression on the tags of an element.
+    pub fn matches(&self, tags: &HashMap<&str, &str>) -> Result<bool, OverpassError> {
+        Ok(Self::is_true(&self.evaluate(tags)?))
+    }
+
+    /// Overpass value of the expression on the tags of an element, as text.
+    pub fn evaluate(&self, tags: &HashMap<&str, &str>) -> Result<String, OverpassError> {
+        let boolean = |b: bool| if b { "1" } else { "0" }.to_string();
+        Ok(match &self.expression {
+            Expression::Number(number) => number.to_string(),
+            Expression::String(string) => string.to_string(),
+            Expression::Tag(key) => tags.get(key.as_ref()).unwrap_or(&"").to_string(),
+            Expression::Function { name, params } => match (name.as_ref(), params.first()) {
+                ("is_tag", Some(key)) => boolean(tags.contains_key(key.key()?)),
+                ("number", Some(param)) => Self::parse_number(&param.evaluate(tags)?)
+                    .map(Self::number_to_string)
+                    .unwrap_or("NaN".to_string()),
+                ("is_number", Some(param)) => {
+                    boolean(Self::parse_number(&param.evaluate(tags)?).is_some())
+                }
+                _ => {
+                    return Err(OverpassError::unsupported(
+                        format!("Function {name}() is not supported by the memory evaluator"),
+                        self.span,
+                    ));
+                }
+            },
+            Expression::Aggregate { name, .. } => {
+                return Err(OverpassError::unsupported(
+                    format!("Aggregate {name}() is not supported by the memory evaluator"),
+                    self.span,
+                ));
+            }
+            Expression::Unary { operator, operand } => {
+                let value = operand.evaluate(tags)?;
+                match operator.as_ref() {
+                    "!" => boolean(!Self::is_true(&value)),
+                    _ => Self::parse_number(&value)
+                        .map(|number| Self::number_to_string(-number))
+                        .unwrap_or("NaN".to_string()),
+                }
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let left = left.evaluate(tags)?;
+                let right = right.evaluate(tags)?;
+                // Numeric operations when both sides are numbers
+                let numbers = Self::parse_number(&left).zip(Self::parse_number(&right));
+                match (operator.as_ref(), numbers) {
+                    ("||", _) => boolean(Self::is_true(&left) || Self::is_true(&right)),
+                    ("&&", _) => boolean(Self::is_true(&left) && Self::is_true(&right)),
+                    ("==" | "!=" | "<" | "<=" | ">" | ">=", _) => {
+                        let ordering = match numbers {
+                            Some((l, r)) => l.partial_cmp(&r),
+                            None => Some(left.cmp(&right)),
+                        };
+                        let result = ordering.is_some_and(|ordering| match operator.as_ref() {
+                            "==" => ordering.is_eq(),
+                            "!=" => ordering.is_ne(),
+                            "<" => ordering.is_lt(),
+                            "<=" => ordering.is_le(),
+                            ">" => ordering.is_gt(),
+                            _ => ordering.is_ge(),
+                        });
+                        boolean(result)
+                    }
+                    ("+", None) => format!("{left}{right}"),
+                    (_, None) => "NaN".to_string(),
+                    (operator, Some((l, r))) => Self::number_to_string(match operator {
+                        "+" => l + r,
+                        "-" => l - r,
+                        "*" => l * r,
+                        _ => l / r,
+                    }),
+                }
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+            } => {
+                if condition.matches(tags)? {
+                    then.evaluate(tags)?
+                } else {
+                    otherwise.evaluate(tags)?
+                }
+            }
+        })
+    }
+
+    fn parse_number(value: &str) -> Option<f64> {
+        value.trim().parse::<f64>().ok().filter(|n| n.is_finite())
+    }
+
+    fn number_to_string(number: f64) -> String {
+        if number.is_finite() {
+            number.to_string()
         } else {
-            panic!("Evaluator must have either a static value or a function call")
+            "NaN".to_string()
+        }
+    }
+
+    /// Empty and zero values are false.
+    fn is_true(value: &str) -> bool {
+        !value.is_empty() && Self::parse_number(value) != Some(0.0)
+    }
+
+    pub fn to_overpass(&self) -> String {
+        self.to_overpass_with(0)
+    }
+
+    /// Parenthesis around the operations binding less than
+    /// `min_binding_power`.
+    fn to_overpass_with(&self, min_binding_power: u8) -> String {
+        match &self.expression {
+            Expression::Number(number) => number.to_string(),
+            // Words are not quoted, as `count(nodes)`
+            Expression::String(string)
+                if Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$")
+                    .unwrap()
+                    .is_match(string) =>
+            {
+                string.to_string()
+            }
+            Expression::String(string) => {
+                format!("\"{}\"", string.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            Expression::Tag(key) => format!("t[{}]", Selector::overpass_quote(key)),
+            Expression::Function { name, params } => format!(
+                "{name}({})",
+                params
+                    .iter()
+                    .map(|param| param.to_overpass())
+                    .collect::<Vec<String>>()
+                    .join(",")
+            ),
+            Expression::Aggregate { set, name, param } => format!(
+                "{}{name}({})",
+                set.as_ref()
+                    .map(|set| format!("{set}."))
+                    .unwrap_or_default(),
+                param
+                    .as_ref()
+                    .map(|param| param.to_overpass())
+                    .unwrap_or_default()
+            ),
+            Expression::Unary { operator, operand } => {
+                format!("{operator}{}", operand.to_overpass_with(u8::MAX))
+            }
+            Expression::Binary {
+                operator,
+                left,
+                right,
+            } => {
+                let binding_power = binding_power(operator);
+                let s = format!(
+                    "{} {operator} {}",
+                    left.to_overpass_with(binding_power),
+                    right.to_overpass_with(binding_power + 1)
+                );
+                if binding_power < min_binding_power {
+                    format!("({s})")
+                } else {
+                    s
+                }
+            }
+            Expression::Ternary {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let s = format!(
+                    "{} ? {} : {}",
+                    condition.to_overpass_with(1),
+                    then.to_overpass(),
+                    otherwise.to_overpass()
+                );
+                if min_binding_power > 0 {
+                    format!("({s})")
+                } else {
+                    s
+                }
+            }
         }
     }
 }
@@ -125,7 +904,11 @@ mod tests {
     use super::*;
     use crate::overpass_parser::parse_query;
     use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::{
+        duckdb::duckdb::Duckdb, postgres::postgres::Postgres, sqlite::sqlite::Sqlite,
+    };
     use pretty_assertions::assert_eq;
+    use rusqlite::Connection;
 
     fn parse(query: &str) -> Evaluator {
         match parse_query(format!("convert node a={query};").as_str()) {
@@ -146,32 +929,242 @@ mod tests {
         }
     }
 
+    fn to_sql(query: &str) -> String {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        parse(query).to_sql(d, "9999", "x").unwrap()
+    }
+
     #[test]
     fn test_parse_constant() {
-        let query = "a";
-        let eval = parse(query);
-        assert_eq!(eval.static_value, Some("a".into()));
+        assert_eq!(Expression::String("a".into()), parse("a").expression);
+        assert_eq!(Expression::String("a b".into()), parse("'a b'").expression);
+        assert_eq!(Expression::Number("1.5".into()), parse("1.5").expression);
     }
 
     #[test]
     fn test_parse_function() {
-        let query = "id()";
-        let eval = parse(query);
-        assert_eq!(eval.fn_call, Some("id".into()));
+        assert_eq!(
+            Expression::Function {
+                name: "id".into(),
+                params: vec![]
+            },
+            parse("id()").expression
+        );
     }
 
     #[test]
     fn test_parse_tag() {
-        let query = "t['h']";
-        let eval = parse(query);
-        assert_eq!(eval.tag_call, Some("h".into()));
+        assert_eq!(Expression::Tag("h".into()), parse("t['h']").expression);
     }
 
     #[test]
     fn test_parse_agg() {
-        let query = "r.set(t['ref'])";
-        let eval = parse(query);
-        assert_eq!(eval.agg_set_call, Some("r".into()));
-        assert_eq!(eval.param.unwrap().tag_call, Some("ref".into()));
+        let Expression::Aggregate { set, name, param } = parse("r.set(t['ref'])").expression else {
+            panic!("Expected an aggregate");
+        };
+        assert_eq!(Some("r".into()), set);
+        assert_eq!("set", name.as_ref());
+        assert_eq!(Expression::Tag("ref".into()), param.unwrap().expression);
+
+        let Expression::Aggregate { set, name, .. } = parse("count(nodes)").expression else {
+            panic!("Expected an aggregate");
+        };
+        assert_eq!(None, set);
+        assert_eq!("count", name.as_ref());
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            "1 + 2 * 3 < 4 || !is_tag(a) && 5 == 6 ? x : y",
+            parse("1+2*3<4||!is_tag(a)&&5==6?x:y").to_overpass()
+        );
+        assert_eq!("(1 + 2) * 3", parse("(1 + 2) * 3").to_overpass());
+        assert_eq!("1 - (2 - 3)", parse("1 - (2 - 3)").to_overpass());
+        assert_eq!("1 - 2 - 3", parse("(1 - 2) - 3").to_overpass());
+    }
+
+    #[test]
+    fn test_to_sql() {
+        assert_eq!("(1 + (2 * 3))", to_sql("1 + 2 * 3"));
+        assert_eq!(
+            "(coalesce(_x.tags->>'name', '') || ' x')",
+            to_sql("t[name] + ' x'")
+        );
+        assert_eq!(
+            "CASE WHEN coalesce(CASE WHEN CASE WHEN _x.tags->>'level' ~ '^\\s*-?[0-9]+(\\.[0-9]+)?([eE][-+]?[0-9]+)?\\s*$' THEN CAST(_x.tags->>'level' AS numeric) END IS NOT NULL THEN CASE WHEN _x.tags->>'level' ~ '^\\s*-?[0-9]+(\\.[0-9]+)?([eE][-+]?[0-9]+)?\\s*$' THEN CAST(_x.tags->>'level' AS numeric) END > 2 ELSE coalesce(_x.tags->>'level', '') > '2' END, false) THEN 'high' ELSE 'low' END",
+            to_sql("t[level] > 2 ? 'high' : 'low'")
+        );
+        assert_eq!(
+            "CASE WHEN (_x.tags?'name' AND NOT _x.tags?'ref') THEN '1' ELSE '0' END",
+            to_sql("is_tag(name) && !is_tag(ref)")
+        );
+        assert_eq!(
+            "(SELECT count(*) FILTER (WHERE osm_type = 'w') FROM _a)",
+            to_sql("a.count(ways)")
+        );
+        assert_eq!(
+            "(SELECT coalesce(sum(ST_Length(CAST(ST_Transform(_x.geom, 4326) AS geography))), 0) FROM _x)",
+            to_sql("sum(length())")
+        );
+        assert_eq!(
+            "(SELECT string_agg(DISTINCT _r.tags->>'ref', ';' ORDER BY _r.tags->>'ref') FROM _r)",
+            to_sql("r.set(t[ref])")
+        );
+
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert!(matches!(
+            parse("foo()").to_sql(d, "9999", "_"),
+            Err(OverpassError::Semantic { .. })
+        ));
+    }
+
+    #[test]
+    fn test_geometry_to_sql() {
+        let pg = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let duckdb = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        let to_sql = |d: &(dyn SqlDialect + Send + Sync), query: &str| {
+            parse(query).to_sql(d, "9999", "x").unwrap()
+        };
+
+        assert_eq!(
+            "ST_X(ST_PointOnSurface(ST_Transform(_x.geom, 4326)))",
+            to_sql(pg, "lon()")
+        );
+        assert_eq!(
+            "ST_Y(ST_PointOnSurface(ST_Transform(_x.geom, 'EPSG:9999', 'EPSG:4326')))",
+            to_sql(duckdb, "lat()")
+        );
+        assert_eq!(
+            "ST_Length(CAST(ST_Transform(_x.geom, 4326) AS geography))",
+            to_sql(pg, "length()")
+        );
+        assert_eq!(
+            "ST_Length_Spheroid(ST_FlipCoordinates(ST_Transform(_x.geom, 'EPSG:9999', 'EPSG:4326')))",
+            to_sql(duckdb, "length()")
+        );
+        assert_eq!(
+            "ST_Area(CAST(ST_Transform(_x.geom, 4326) AS geography))",
+            to_sql(pg, "area(geom())")
+        );
+        assert_eq!(
+            "ST_Area_Spheroid(ST_FlipCoordinates(ST_Transform(_x.geom, 'EPSG:9999', 'EPSG:4326')))",
+            to_sql(duckdb, "area()")
+        );
+        assert_eq!(
+            "CASE WHEN ST_IsClosed(_x.geom) THEN '1' ELSE '0' END",
+            to_sql(pg, "is_closed()")
+        );
+        assert_eq!(
+            "ST_PointOnSurface(_x.geom)",
+            parse("center(geom())")
+                .to_sql_geom(duckdb, "9999", "x")
+                .unwrap()
+        );
+
+        assert!(matches!(
+            parse("geom()").to_sql_text(pg, "9999", "x"),
+            Err(OverpassError::Semantic { .. })
+        ));
+        assert!(matches!(
+            parse("center(t[name])").to_sql_geom(pg, "9999", "x"),
+            Err(OverpassError::Semantic { .. })
+        ));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let tags = HashMap::from([("name", "A"), ("level", "3"), ("note", "x")]);
+        let evaluate = |expression: &str| parse(expression).evaluate(&tags).unwrap();
+        assert_eq!("7", evaluate("1 + 2 * t[level]"));
+        assert_eq!("Ab", evaluate("t[name] + b"));
+        assert_eq!("1", evaluate("t[level] > 10 == 0"));
+        assert_eq!("1", evaluate("t[level] < \"10\""));
+        assert_eq!("0", evaluate("\"3\" < \"10a\""));
+        assert_eq!("NaN", evaluate("t[note] * 2"));
+        assert_eq!("low", evaluate("t[level] >= 4 ? high : low"));
+
+        assert!(
+            parse("is_tag(name) && !is_tag(ref)")
+                .matches(&tags)
+                .unwrap()
+        );
+        assert!(!parse("t[ref]").matches(&tags).unwrap());
+        assert!(matches!(
+            parse("id()").matches(&tags),
+            Err(OverpassError::Unsupported { .. })
+        ));
+    }
+
+    /// In memory evaluation and the SQL translation of the `(if:)`
+    /// conditions, run on SQLite, select the same elements.
+    #[test]
+    fn test_conformance_memory_sql() {
+        let connection = Connection::open_in_memory().unwrap();
+        let conditions = [
+            "t[a] > 5",
+            "t[a] < t[b]",
+            "t[a] == t[b]",
+            "t[a] != \"\"",
+            "t[a] >= \"abc\"",
+            "t[a] + t[b] == 15",
+            "t[a] + t[b] == \"510\"",
+            "t[a] + \"x\" == \"5x\"",
+            "is_tag(a) == 1",
+            "-(-1) < t[a]",
+            "- -t[a] == 5",
+        ];
+        let tags = [
+            vec![],
+            vec![("a", "5")],
+            vec![("a", "10"), ("b", "5")],
+            vec![("a", "5"), ("b", "10")],
+            vec![("a", " 7"), ("b", "7")],
+            vec![("a", "2.5"), ("b", "abc")],
+            vec![("a", "abc"), ("b", "abd")],
+            vec![("a", ""), ("b", "0")],
+        ];
+        let d = &Sqlite as &(dyn SqlDialect + Send + Sync);
+        for condition in conditions {
+            let condition = parse(condition);
+            let sql = format!(
+                "SELECT coalesce({}, false) FROM (SELECT ? AS tags) AS _",
+                condition.to_sql_condition(d, "4326", "_", "_").unwrap()
+            );
+            for tags in &tags {
+                let json = format!(
+                    "{{{}}}",
+                    tags.iter()
+                        .map(|(key, value)| format!("\"{key}\":\"{value}\""))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                );
+                let in_memory = condition
+                    .matches(&HashMap::from_iter(tags.iter().copied()))
+                    .unwrap();
+                let in_sql = connection
+                    .query_row(&sql, [json], |row| row.get::<_, bool>(0))
+                    .unwrap();
+                assert_eq!(in_memory, in_sql, "{} on {tags:?}", condition.to_overpass());
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_sqlite() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE _r AS SELECT '{\"ref\":\"b,c\"}' AS tags UNION ALL SELECT '{\"ref\":\"a\"}' UNION ALL SELECT '{\"ref\":\"a\"}' UNION ALL SELECT '{}';",
+            )
+            .unwrap();
+        let d = &Sqlite as &(dyn SqlDialect + Send + Sync);
+        let sql = parse("r.set(t[ref])").to_sql(d, "4326", "x").unwrap();
+        assert_eq!(
+            "a;b,c",
+            connection
+                .query_row(&format!("SELECT {sql}"), [], |row| row.get::<_, String>(0))
+                .unwrap()
+        );
     }
 }
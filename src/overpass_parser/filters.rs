@@ -2,23 +2,51 @@ use pest::iterators::Pair;
 
 use derivative::Derivative;
 use regex::Regex;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
 
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{
+    error::{OverpassError, Span},
+    plan::{Geometry, Location, Node, Predicate},
+    sql_dialect::sql_dialect::SqlDialect,
+};
 
-use super::{Rule, subrequest::SubrequestJoin};
+use super::{Rule, evaluator::Evaluator, selectors::Selector};
 
 #[derive(Derivative)]
 #[derivative(Default)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FilterAround {
-    pub core: Box<str>,
+    /// Input set, the default set when `None` and without coordinates.
+    pub core: Option<Box<str>>,
     pub radius: f64,
+    /// (lat, lon) of a point or a polyline.
+    pub coordinates: Option<Vec<(f64, f64)>>,
 }
 
 #[derive(Derivative)]
 #[derivative(Default)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterRecurse {
+    /// One of `n`, `w`, `r`, `bn`, `bw` and `br`.
+    pub recurse_type: Box<str>,
+    /// Input set, the default set when `None`.
+    pub set: Option<Box<str>>,
+    /// Role of the relation members.
+    pub role: Option<Box<str>>,
+}
+
+/// Elements with a last change in `(since, until]`.
+#[derive(Derivative)]
+#[derivative(Default)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterChanged {
+    /// `None` for the state at `until` of the `[date:]` setting.
+    pub since: Option<Box<str>>,
+    /// Now when `None`.
+    pub until: Option<Box<str>>,
+}
+
+#[derive(Derivative)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct Filter {
     pub bbox: Option<(f64, f64, f64, f64)>,
@@ -26,52 +54,109 @@ pub struct Filter {
     pub ids: Option<Vec<i64>>,
     pub area_id: Option<Box<str>>,
     pub around: Option<FilterAround>,
-    pub recurse: Option<Box<str>>,
+    pub recurse: Option<FilterRecurse>,
+    pub newer: Option<Box<str>>,
+    pub changed: Option<FilterChanged>,
+    /// `(if: ...)`, elements for which the expression is true.
+    pub condition: Option<Evaluator>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+/// Format a number so it parses back to the same value. Negative numbers are
+/// only accepted as float by the grammar.
+pub fn number_to_overpass(number: f64) -> String {
+    let s = number.to_string();
+    if number.is_sign_negative() && !s.contains('.') {
+        format!("{s}.0")
+    } else {
+        s
+    }
+}
+
+/// ISO 8601 UTC date of a `date` rule, as `2024-01-01T00:00:00Z`.
+pub fn date_from_pest(pair: Pair<Rule>) -> Result<Box<str>, OverpassError> {
+    let date = Selector::unquote(pair.as_str());
+    let format = Regex::new(r"^(\d{4})-(\d{2})-(\d{2})T(\d{2}):(\d{2}):(\d{2})Z$").unwrap();
+    let Some(captures) = format.captures(&date) else {
+        return Err(OverpassError::semantic(
+            format!("Invalid date {date:?}, expected YYYY-MM-DDThh:mm:ssZ"),
+            pair.as_span(),
+        ));
+    };
+    let [year, month, day, hour, minute, second] =
+        [1, 2, 3, 4, 5, 6].map(|i| captures[i].parse::<u32>().unwrap());
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=month_days).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return Err(OverpassError::semantic(
+            format!("Invalid date {date:?}"),
+            pair.as_span(),
+        ));
+    }
+    Ok(date.into())
 }
 
 impl Filter {
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
-        let mut filter = Filter::default();
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
+        let mut filter = Filter {
+            span: pair.as_span().into(),
+            ..Filter::default()
+        };
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
                 Rule::filter_bbox => {
-                    let coords: Vec<f64> = inner_pair
-                        .as_str()
-                        .split(',')
-                        .filter_map(|s| s.trim().parse().ok())
-                        .collect();
-                    if coords.len() == 4 {
-                        filter.bbox = Some((coords[0], coords[1], coords[2], coords[3]));
-                    }
+                    filter.bbox = Self::bbox_from_pest(inner_pair);
                 }
                 Rule::filter_poly => {
+                    let span = inner_pair.as_span();
                     let a = inner_pair.into_inner().next().unwrap().as_str();
-                    let points: Vec<(f64, f64)> = Regex::new(r"\s+")
-                        .unwrap()
-                        .split(&(a[1..a.len() - 1]))
-                        .map(|s| s.parse::<f64>().ok().unwrap())
-                        .collect::<Vec<f64>>()
-                        .chunks(2)
-                        .map(|chunk| {
-                            if chunk.len() == 2 {
-                                (chunk[0], chunk[1])
-                            } else {
-                                panic!("Invalid point in poly filter: {chunk:?}");
-                            }
+                    let coords = a[1..a.len() - 1]
+                        .split_whitespace()
+                        .map(|s| {
+                            s.parse::<f64>().map_err(|_| {
+                                OverpassError::syntax(
+                                    format!("Invalid coordinate {s:?} in poly filter"),
+                                    span,
+                                )
+                            })
                         })
-                        .collect::<Vec<(f64, f64)>>();
-                    filter.poly = Some(points);
-                }
-                Rule::filter_osm_id => {
-                    if let Ok(id) = inner_pair.as_str().parse::<i64>() {
-                        filter.ids = Some(vec![id]);
+                        .collect::<Result<Vec<f64>, OverpassError>>()?;
+                    if coords.len() % 2 != 0 {
+                        return Err(OverpassError::syntax(
+                            "Odd number of coordinates in poly filter",
+                            span,
+                        ));
                     }
+                    filter.poly = Some(
+                        coords
+                            .chunks(2)
+                            .map(|chunk| (chunk[0], chunk[1]))
+                            .collect::<Vec<(f64, f64)>>(),
+                    );
                 }
-                Rule::filter_osm_ids => {
-                    let ids: Vec<i64> = inner_pair
+                Rule::filter_osm_id | Rule::filter_osm_ids => {
+                    let ids = inner_pair
                         .into_inner()
-                        .filter_map(|id_pair| id_pair.as_str().parse().ok())
-                        .collect();
+                        .map(|id_pair| {
+                            id_pair.as_str().parse::<i64>().map_err(|e| {
+                                OverpassError::syntax(
+                                    format!("Invalid id {}: {e}", id_pair.as_str()),
+                                    id_pair.as_span(),
+                                )
+                            })
+                        })
+                        .collect::<Result<Vec<i64>, OverpassError>>()?;
                     filter.ids = Some(ids);
                 }
                 Rule::filter_area => {
@@ -88,9 +173,26 @@ impl Filter {
                                 around.core = around_inner
                                     .into_inner()
                                     .find(|p| p.as_rule() == Rule::ID)
-                                    .map(|p| p.as_str())
-                                    .unwrap()
-                                    .into();
+                                    .map(|p| p.as_str().into());
+                            }
+                            Rule::filter_around_coordinates => {
+                                let coords = around_inner
+                                    .into_inner()
+                                    .map(|p| {
+                                        p.as_str().parse::<f64>().map_err(|_| {
+                                            OverpassError::syntax(
+                                                format!("Invalid coordinate {:?}", p.as_str()),
+                                                p.as_span(),
+                                            )
+                                        })
+                                    })
+                                    .collect::<Result<Vec<f64>, OverpassError>>()?;
+                                around.coordinates = Some(
+                                    coords
+                                        .chunks(2)
+                                        .map(|chunk| (chunk[0], chunk[1]))
+                                        .collect::<Vec<(f64, f64)>>(),
+                                );
                             }
                             Rule::filter_around_radius => {
                                 if let Ok(radius) = around_inner.as_str().parse::<f64>() {
@@ -98,13 +200,11 @@ impl Filter {
                                 }
                             }
                             _ => {
-                                return Err(pest::error::Error::new_from_span(
-                                    pest::error::ErrorVariant::CustomError {
-                                        message: format!(
-                                            "Invalid rule {:?} for FilterAround",
-                                            around_inner.as_rule()
-                                        ),
-                                    },
+                                return Err(OverpassError::syntax(
+                                    format!(
+                                        "Invalid rule {:?} for FilterAround",
+                                        around_inner.as_rule()
+                                    ),
                                     around_inner.as_span(),
                                 ));
                             }
@@ -112,14 +212,63 @@ impl Filter {
                     }
                     filter.around = Some(around);
                 }
+                Rule::filter_newer => {
+                    filter.newer = inner_pair
+                        .into_inner()
+                        .next()
+                        .map(date_from_pest)
+                        .transpose()?;
+                }
+                Rule::filter_changed => {
+                    let mut dates = inner_pair.into_inner().map(date_from_pest);
+                    filter.changed = Some(FilterChanged {
+                        since: dates.next().transpose()?,
+                        until: dates.next().transpose()?,
+                    });
+                }
+                Rule::filter_if => {
+                    filter.condition = inner_pair
+                        .into_inner()
+                        .next()
+                        .map(Evaluator::from_pest)
+                        .transpose()?;
+                }
                 Rule::filter_recurse => {
-                    filter.recurse = Some(inner_pair.as_str().into());
+                    let mut recurse = FilterRecurse::default();
+                    for recurse_inner in inner_pair.into_inner() {
+                        match recurse_inner.as_rule() {
+                            Rule::filter_recurse_type => {
+                                recurse.recurse_type = recurse_inner.as_str().into();
+                            }
+                            Rule::ID => {
+                                recurse.set = Some(recurse_inner.as_str().into());
+                            }
+                            Rule::filter_recurse_role => {
+                                recurse.role =
+                                    Some(Selector::unquote(recurse_inner.as_str()).into());
+                            }
+                            _ => {
+                                return Err(OverpassError::syntax(
+                                    format!(
+                                        "Invalid rule {:?} for FilterRecurse",
+                                        recurse_inner.as_rule()
+                                    ),
+                                    recurse_inner.as_span(),
+                                ));
+                            }
+                        }
+                    }
+                    if recurse.role.is_some() && recurse.recurse_type.as_ref() == "w" {
+                        return Err(OverpassError::semantic(
+                            "Recurse filter (w) does not accept a role",
+                            filter.span,
+                        ));
+                    }
+                    filter.recurse = Some(recurse);
                 }
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!("Invalid rule {:?} for Filter", inner_pair.as_rule()),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for Filter", inner_pair.as_rule()),
                         inner_pair.as_span(),
                     ));
                 }
@@ -128,230 +277,191 @@ impl Filter {
         Ok(filter)
     }
 
-    fn bbox_clauses(
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        table: &str,
-        bbox: (f64, f64, f64, f64),
-        srid: &str,
-    ) -> String {
-        sql_dialect.st_intersects_extent_with_geom(
-            table,
-            sql_dialect
-                .st_transform(
-                    &format!(
-                        "ST_Envelope('SRID=4326;LINESTRING({} {}, {} {})'::geometry)",
-                        bbox.1, bbox.0, bbox.3, bbox.2
-                    ),
-                    srid,
-                )
-                .as_str(),
-        )
-    }
-
-    fn poly_clauses(
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        set: &str,
-        poly: &[(f64, f64)],
-        srid: &str,
-    ) -> (SubrequestJoin, SubrequestJoin) {
-        let coords = poly
-            .iter()
-            .map(|&(lat, lon)| format!("{lon} {lat}"))
-            .collect::<Vec<String>>()
-            .join(", ");
-        let poly =
-            &sql_dialect.st_transform(&format!("'SRID=4326;POLYGON(({coords}))'::geometry"), srid);
-
-        let mut hasher = DefaultHasher::new();
-        poly.hash(&mut hasher);
-        let poly_id = format!("poly_{}", hasher.finish());
-
-        (
-            SubrequestJoin {
-                precompute_set: Some(poly_id.to_string()),
-                precompute: None,
-                from: None,
-                clauses: format!(
-                    "SELECT
-    {}
-FROM
-    (VALUES ({poly})) AS p(geom)",
-                    sql_dialect.make_geom_fields()
-                )
-                .to_string(),
-            },
-            SubrequestJoin {
-                precompute_set: None,
-                precompute: sql_dialect
-                    .is_precompute()
-                    .then(|| vec![poly_id.to_string()]),
-                from: (!sql_dialect.is_precompute()).then(|| format!("JOIN _{poly_id} ON true")),
-                clauses: sql_dialect.st_intersects_with_geom(
-                    set,
-                    sql_dialect.table_precompute_geom(poly_id.as_str()).as_str(),
-                ),
-            },
-        )
+    /// (south, west, north, east) of a `filter_bbox`.
+    pub fn bbox_from_pest(pair: Pair<Rule>) -> Option<(f64, f64, f64, f64)> {
+        let coords: Vec<f64> = pair
+            .as_str()
+            .split(',')
+            .filter_map(|s| s.trim().parse().ok())
+            .collect();
+        match coords[..] {
+            [s, w, n, e] => Some((s, w, n, e)),
+            _ => None,
+        }
     }
 
-    fn around_clause(
+    /// Envelope geometry of the bbox, in `srid`.
+    pub fn bbox_geom(
         sql_dialect: &(dyn SqlDialect + Send + Sync),
-        set: &str,
+        bbox: (f64, f64, f64, f64),
         srid: &str,
-        around: &FilterAround,
     ) -> String {
-        let join = sql_dialect.st_subdivide(
-            &sql_dialect.st_dump_geom(&sql_dialect.st_transform_reverse(
-                &sql_dialect.st_buffer(
-                    &sql_dialect.st_transform(&sql_dialect.st_union_agg("geom"), srid),
-                    around.radius,
-                ),
-                srid,
-            )),
-            1000,
-        );
-        let on = sql_dialect.st_intersects_with_geom("subdivided_geom", &format!("{set}.geom"));
-
-        format!(
-            "JOIN (SELECT {join} AS geom FROM _{}) AS subdivided_geom ON
-    {on}",
-            around.core
+        sql_dialect.st_transform(
+            &format!(
+                "ST_Envelope({})",
+                sql_dialect.geom_from_text(&format!(
+                    "LINESTRING({} {}, {} {})",
+                    bbox.1, bbox.0, bbox.3, bbox.2
+                ))
+            ),
+            srid,
         )
     }
 
-    fn area_id_clause(
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        set: &str,
-        area_id: &str,
-    ) -> SubrequestJoin {
-        SubrequestJoin {
-            precompute_set: None,
-            precompute: sql_dialect
-                .is_precompute()
-                .then(|| vec![area_id.to_string()]),
-            from: (!sql_dialect.is_precompute()).then(|| format!("JOIN _{area_id} ON true")),
-            clauses: sql_dialect
-                .st_intersects_with_geom(set, sql_dialect.table_precompute_geom(area_id).as_str()),
-        }
+    pub fn is_spatial(&self) -> bool {
+        self.bbox.is_some()
+            || self.poly.is_some()
+            || self.area_id.is_some()
+            || self.around.is_some()
     }
 
-    fn recurse_clause(recurse: &str, set: &str, default_set: &str) -> String {
-        match recurse {
-            // forward from ways: select nodes that are members of ways in the input set
-            "w" => format!(
-                "JOIN _{default_set} AS w ON w.osm_type = 'w' AND {set}.osm_type = 'n' AND array[{set}.id] <@ w.nodes"
-            ),
-            // forward from relations: select elements that are members of relations in the input set
-                "r" => format!(
-                "JOIN _{default_set} AS r ON r.osm_type = 'r'
-    JOIN LATERAL jsonb_to_recordset(r.members) AS m(type text, ref bigint, role text) ON m.type = {set}.osm_type AND m.ref = {set}.id"
-            ),
-            // backward from nodes: select ways/relations that contain nodes from the input set
-            "bn" => format!(
-                "JOIN _{default_set} AS bn ON _.osm_type = 'n' AND (
-    bn.osm_type = 'w' AND array[bn.id] <@ {set}.nodes OR
-    bn.osm_type = 'r' AND array[bn.id] <@ osm_base_idx_nodes_members({set}.members, 'n')
-)"
-            ),
-            // backward from ways: select relations that contain ways from the input set
-            "bw" => format!(
-                "JOIN _{default_set} AS bw ON {set}.osm_type = 'w' AND bw.osm_type = 'r' AND array[bw.id] <@ osm_base_idx_nodes_members({set}.members, 'w')"
-            ),
-            // backward from relations: select relations that contain relations from the input set
-            "br" => format!(
-                "JOIN _{default_set} AS br ON {set}.osm_type = 'r' AND br.osm_type = 'r' AND array[br.id] <@ osm_base_idx_nodes_members({set}.members, 'r')"
-            ),
-            _ => panic!("Invalid or Not Implemented recurse type: {recurse}"),
-        }
-    }
-
-    pub fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        set: &str,
-        default_set: &str,
-        srid: &str,
-    ) -> (Option<SubrequestJoin>, SubrequestJoin) {
-        let mut pre: Option<SubrequestJoin> = None;
-        let mut clauses = Vec::new();
-
+    /// Apply the filter to the node, with the constant nodes it uses.
+    pub fn to_plan(&self, mut node: Node, default_set: &str) -> (Vec<Node>, Node) {
+        let mut constants = Vec::new();
         if let Some(bbox) = self.bbox {
-            clauses.push(SubrequestJoin {
-                precompute_set: None,
-                precompute: None,
-                from: None,
-                clauses: Self::bbox_clauses(sql_dialect, set, bbox, srid),
-            });
+            node = node.spatial_join(Geometry::Bbox(bbox));
         }
         if let Some(poly) = &self.poly {
-            let (preee, clause) = Self::poly_clauses(sql_dialect, set, poly, srid);
-            pre = Some(preee);
-            clauses.push(clause);
+            let polygon = Node::polygon(poly.clone());
+            if let Some(set) = polygon.constant_set() {
+                node = node.spatial_join(Geometry::Set(set));
+            }
+            constants.push(polygon);
         }
         if let Some(ids) = &self.ids {
-            clauses.push(SubrequestJoin {
-                precompute_set: None,
-                precompute: None,
-                from: None,
-                clauses: sql_dialect.id_in_list(set, "id", ids),
-            })
+            node = node.filter(Predicate::Ids(ids.clone()));
         }
         if let Some(area_id) = &self.area_id {
-            clauses.push(Self::area_id_clause(sql_dialect, set, area_id));
+            node = node.spatial_join(Geometry::Set(area_id.to_string()));
         }
         if let Some(around) = &self.around {
-            clauses.push(SubrequestJoin {
-                precompute_set: None,
-                precompute: None,
-                from: Some(Self::around_clause(sql_dialect, set, srid, around)),
-                clauses: "true".to_string(),
+            let location = match &around.coordinates {
+                Some(coordinates) => Location::Coordinates(coordinates.clone()),
+                None => Location::Set(around.core.as_deref().unwrap_or(default_set).to_string()),
+            };
+            node = node.spatial_join(Geometry::Around {
+                location,
+                radius: around.radius,
             });
         }
-        if let Some(recurse_type) = &self.recurse {
-            clauses.push(SubrequestJoin {
-                precompute_set: None,
-                precompute: None,
-                from: Some(Self::recurse_clause(recurse_type, set, default_set)),
-                clauses: "true".to_string(),
+        if let Some(newer) = &self.newer {
+            node = node.filter(Predicate::Newer(newer.clone()));
+        }
+        if let Some(changed) = &self.changed {
+            node = node.filter(Predicate::Changed(changed.clone()));
+        }
+        if let Some(recurse) = &self.recurse {
+            node = node.filter(Predicate::Recurse {
+                recurse: recurse.clone(),
+                input: recurse.set.as_deref().unwrap_or(default_set).to_string(),
+                span: self.span,
+            });
+        }
+        if let Some(condition) = &self.condition {
+            node = node.filter(Predicate::Condition {
+                condition: condition.clone(),
+                default_set: default_set.to_string(),
             });
         }
+        (constants, node)
+    }
 
-        let precompute = clauses
-            .iter()
-            .filter_map(|c| c.precompute.clone())
-            .flatten()
-            .collect();
-        let from = clauses
-            .iter()
-            .filter_map(|c| c.from.clone())
-            .collect::<Vec<String>>();
-        let clauses_join = clauses
-            .into_iter()
-            .map(|c| c.clauses.replace("\n", "\n    "))
-            .collect::<Vec<String>>()
-            .join(" AND ");
-
-        (
-            pre,
-            SubrequestJoin {
-                precompute_set: None,
-                precompute: Some(precompute),
-                from: (!from.is_empty()).then(|| from.join("\n")),
-                clauses: clauses_join,
-            },
-        )
+    pub fn to_overpass(&self) -> String {
+        let mut s = String::new();
+        if let Some((s_lat, w_lon, n_lat, e_lon)) = self.bbox {
+            s.push_str(&format!(
+                "({},{},{},{})",
+                number_to_overpass(s_lat),
+                number_to_overpass(w_lon),
+                number_to_overpass(n_lat),
+                number_to_overpass(e_lon)
+            ));
+        }
+        if let Some(poly) = &self.poly {
+            let coords = poly
+                .iter()
+                .map(|(lat, lon)| format!("{lat} {lon}"))
+                .collect::<Vec<String>>()
+                .join(" ");
+            s.push_str(&format!("(poly:\"{coords}\")"));
+        }
+        if let Some(ids) = &self.ids {
+            let list = ids
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+            if ids.len() == 1 {
+                s.push_str(&format!("({list})"));
+            } else {
+                s.push_str(&format!("(id:{list})"));
+            }
+        }
+        if let Some(area_id) = &self.area_id {
+            s.push_str(&format!("(area.{area_id})"));
+        }
+        if let Some(around) = &self.around {
+            let core = around
+                .core
+                .as_ref()
+                .map(|core| format!(".{core}"))
+                .unwrap_or_default();
+            let coordinates = around
+                .coordinates
+                .iter()
+                .flatten()
+                .map(|&(lat, lon)| {
+                    format!(",{},{}", number_to_overpass(lat), number_to_overpass(lon))
+                })
+                .collect::<String>();
+            s.push_str(&format!(
+                "(around{core}:{}{coordinates})",
+                number_to_overpass(around.radius)
+            ));
+        }
+        if let Some(newer) = &self.newer {
+            s.push_str(&format!("(newer:\"{newer}\")"));
+        }
+        if let Some(FilterChanged {
+            since: Some(since),
+            until,
+        }) = &self.changed
+        {
+            let until = until
+                .as_ref()
+                .map(|until| format!(",\"{until}\""))
+                .unwrap_or_default();
+            s.push_str(&format!("(changed:\"{since}\"{until})"));
+        }
+        if let Some(recurse) = &self.recurse {
+            let set = recurse
+                .set
+                .as_ref()
+                .map(|set| format!(".{set}"))
+                .unwrap_or_default();
+            let role = recurse
+                .role
+                .as_ref()
+                .map(|role| format!(":{}", Selector::overpass_quote(role)))
+                .unwrap_or_default();
+            s.push_str(&format!("({}{set}{role})", recurse.recurse_type));
+        }
+        if let Some(condition) = &self.condition {
+            s.push_str(&format!("(if:{})", condition.to_overpass()));
+        }
+        s
     }
 }
 
 #[derive(Derivative)]
 #[derivative(Default)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Filters {
     pub filters: Vec<Filter>,
 }
 
 impl Filters {
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
         let mut filters = Vec::new();
         for inner_pair in pair.into_inner() {
             filters.push(Filter::from_pest(inner_pair)?);
@@ -363,50 +473,27 @@ impl Filters {
         self.filters.iter().any(|f| f.ids.is_some())
     }
 
-    pub fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        set: &str,
-        default_set: &str,
-        srid: &str,
-    ) -> (Option<SubrequestJoin>, SubrequestJoin) {
-        let mut pre: Option<SubrequestJoin> = None;
-        let s = self
-            .filters
-            .iter()
-            .map(|filter| {
-                let (preee, clause) = filter.to_sql(sql_dialect, set, default_set, srid);
-                if preee.is_some() {
-                    pre = preee;
-                }
-                clause
-            })
-            .collect::<Vec<SubrequestJoin>>();
-        let from = s
-            .iter()
-            .filter_map(|sj| sj.from.clone())
-            .collect::<Vec<String>>()
-            .join("\n");
-        let clauses = s
+    pub fn is_spatial(&self) -> bool {
+        self.filters.iter().any(|f| f.is_spatial())
+    }
+
+    /// Apply the filters to the node, with the constant nodes they use.
+    pub fn to_plan(&self, mut node: Node, default_set: &str) -> (Vec<Node>, Node) {
+        let mut constants = Vec::new();
+        for filter in &self.filters {
+            let (filter_constants, filtered) = filter.to_plan(node, default_set);
+            constants.extend(filter_constants);
+            node = filtered;
+        }
+        (constants, node)
+    }
+
+    pub fn to_overpass(&self) -> String {
+        self.filters
             .iter()
-            .map(|sj| sj.clauses.clone())
+            .map(|filter| filter.to_overpass())
             .collect::<Vec<String>>()
-            .join(" AND\n    ");
-
-        (
-            pre,
-            SubrequestJoin {
-                precompute_set: None,
-                precompute: Some(
-                    s.iter()
-                        .filter_map(|c| c.precompute.clone())
-                        .flatten()
-                        .collect(),
-                ),
-                from: (!from.is_empty()).then_some(from),
-                clauses,
-            },
-        )
+            .join("")
     }
 }
 
@@ -414,11 +501,15 @@ impl Filters {
 mod tests {
     use super::*;
     use crate::{
+        error::OverpassError,
         overpass_parser::{
             parse_query,
             subrequest::{QueryType, SubrequestType},
         },
-        sql_dialect::postgres::postgres::Postgres,
+        plan::{Source, sql::Select},
+        sql_dialect::{
+            duckdb::duckdb::Duckdb, postgres::postgres::Postgres, sqlite::sqlite::Sqlite,
+        },
     };
     use pretty_assertions::assert_eq;
 
@@ -441,6 +532,26 @@ mod tests {
         }
     }
 
+    fn to_select(
+        filters: &Filters,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        default_set: &str,
+    ) -> Result<Select, OverpassError> {
+        let (_, node) = filters.to_plan(Node::Scan(Source::View("_".into())), default_set);
+        Select::from_node(&node, sql_dialect, "9999")
+    }
+
+    fn conditions(filters: &Filters, sql_dialect: &(dyn SqlDialect + Send + Sync)) -> String {
+        to_select(filters, sql_dialect, "d").unwrap().conditions()
+    }
+
+    fn joins(filters: &Filters, sql_dialect: &(dyn SqlDialect + Send + Sync)) -> String {
+        to_select(filters, sql_dialect, "d")
+            .unwrap()
+            .joins
+            .join("\n")
+    }
+
     #[test]
     fn test_matches_to_sql() {
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
@@ -450,83 +561,264 @@ mod tests {
         ST_Transform(ST_Envelope('SRID=4326;LINESTRING(2 -1.1, 4 3)'::geometry), 9999),
         _.geom
     )",
-            parse("(-1.1,2,3,4)").to_sql(d, "_", "_d", "9999").1.clauses
+            conditions(&parse("(-1.1,2,3,4)"), d)
         );
         assert_eq!(
             "ST_Intersects(
-        _poly_11689077968748950118.geom,
+        _poly_17101990801976832561.geom,
         _.geom
     )",
-            parse("(poly:\"1 2 3 4\")")
-                .to_sql(d, "_", "_d", "9999")
-                .1
-                .clauses
+            conditions(&parse("(poly:\"1 2 3 4\")"), d)
         );
         assert_eq!(
             "_.id = ANY (ARRAY[11111111111111])",
-            parse("(11111111111111)")
-                .to_sql(d, "_", "_d", "9999")
-                .1
-                .clauses
+            conditions(&parse("(11111111111111)"), d)
         );
         assert_eq!(
             "_.id = ANY (ARRAY[1, 2, 3])",
-            parse("(id:1,2,3)").to_sql(d, "_", "_d", "9999").1.clauses
+            conditions(&parse("(id:1,2,3)"), d)
         );
         assert_eq!(
             "ST_Intersects(
         _a.geom,
         _.geom
     )",
-            parse("(area.a)").to_sql(d, "_", "_d", "9999").1.clauses
+            conditions(&parse("(area.a)"), d)
         );
         assert_eq!(
             "JOIN (SELECT ST_Subdivide((ST_Dump(ST_Transform(ST_Buffer(ST_Transform(ST_Union(geom), 9999), 12.3), 4326))).geom, 1000) AS geom FROM _a) AS subdivided_geom ON
     ST_Intersects(
-    _.geom,
-    subdivided_geom.geom
-)",
-            parse("(around.a:12.3)")
-                .to_sql(d, "_", "_d", "9999")
-                .1
-                .from
-                .unwrap()
+        subdivided_geom.geom,
+        _.geom
+    )",
+            joins(&parse("(around.a:12.3)"), d)
+        );
+        assert_eq!(
+            "JOIN (SELECT ST_Subdivide((ST_Dump(ST_Transform(ST_Buffer(ST_Transform(ST_Union(geom), 9999), 12.3), 4326))).geom, 1000) AS geom FROM _d) AS subdivided_geom ON
+    ST_Intersects(
+        subdivided_geom.geom,
+        _.geom
+    )",
+            joins(&parse("(around:12.3)"), d)
+        );
+        assert_eq!(
+            "JOIN (SELECT ST_Subdivide((ST_Dump(ST_Transform(ST_Buffer(ST_Transform('SRID=4326;POINT(2 1)'::geometry, 9999), 10), 4326))).geom, 1000) AS geom) AS subdivided_geom ON
+    ST_Intersects(
+        subdivided_geom.geom,
+        _.geom
+    )",
+            joins(&parse("(around:10,1,2)"), d)
+        );
+        assert_eq!(
+            "JOIN (SELECT ST_Subdivide((ST_Dump(ST_Transform(ST_Buffer(ST_Transform('SRID=4326;LINESTRING(2 1, 4 3)'::geometry, 9999), 10), 4326))).geom, 1000) AS geom) AS subdivided_geom ON
+    ST_Intersects(
+        subdivided_geom.geom,
+        _.geom
+    )",
+            joins(&parse("(around:10,1,2,3,4)"), d)
+        );
+
+        // date filters
+        assert_eq!(
+            "_.created > '2024-01-01T00:00:00Z'::timestamp",
+            conditions(&parse("(newer:\"2024-01-01T00:00:00Z\")"), d)
+        );
+        assert_eq!(
+            "_.created > '2024-01-01T00:00:00Z'::timestamp AND _.created <= '2024-02-01T00:00:00Z'::timestamp",
+            conditions(
+                &parse("(changed:\"2024-01-01T00:00:00Z\",\"2024-02-01T00:00:00Z\")"),
+                d
+            )
         );
 
-        // recurse filters — use table-prefixed set so object type can be inferred
+        // recurse filters
+        assert_eq!(
+            "EXISTS (
+        SELECT
+            1
+        FROM
+            _d AS br
+        WHERE
+            br.osm_type = 'r' AND
+            _.osm_type = 'r' AND
+            _.members @> jsonb_build_array(jsonb_build_object('type', 'r', 'ref', br.id))
+    )",
+            conditions(&parse("(br)"), d)
+        );
         assert_eq!(
-            "JOIN _d AS br ON _.osm_type = 'r' AND br.osm_type = 'r' AND array[br.id] <@ osm_base_idx_nodes_members(_.members, 'r')",
-            parse("(br)").to_sql(d, "_", "d", "9999").1.from.unwrap()
+            "EXISTS (
+        SELECT
+            1
+        FROM
+            _d AS bn
+        WHERE
+            bn.osm_type = 'n' AND
+            (_.osm_type = 'w' AND
+            _.nodes @> ARRAY[bn.id] OR
+            _.osm_type = 'r' AND
+            _.members @> jsonb_build_array(jsonb_build_object('type', 'n', 'ref', bn.id)))
+    )",
+            conditions(&parse("(bn)"), d)
         );
         assert_eq!(
-            "JOIN _d AS bn ON _.osm_type = 'n' AND (
-    bn.osm_type = 'w' AND array[bn.id] <@ _.nodes OR
-    bn.osm_type = 'r' AND array[bn.id] <@ osm_base_idx_nodes_members(_.members, 'n')
-)",
-            parse("(bn)").to_sql(d, "_", "d", "9999").1.from.unwrap()
+            "EXISTS (
+        SELECT
+            1
+        FROM
+            _stops AS bn
+        WHERE
+            bn.osm_type = 'n' AND
+            _.osm_type = 'r' AND
+            _.members @> jsonb_build_array(jsonb_build_object('type', 'n', 'ref', bn.id, 'role', 'stop'))
+    )",
+            conditions(&parse("(bn.stops:\"stop\")"), d)
+        );
+        assert_eq!(
+            "EXISTS (
+        SELECT
+            1
+        FROM
+            _ways AS w
+        WHERE
+            w.osm_type = 'w' AND
+            _.osm_type = 'n' AND
+            w.nodes @> ARRAY[_.id]
+    )",
+            conditions(&parse("(w.ways)"), d)
         );
 
-        println!(
-            "{}",
-            parse("(poly:\"1 2 3 4\")(area.a)")
-                .to_sql(d, "_", "d", "9999")
-                .1
-                .clauses
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "EXISTS (
+        SELECT
+            1
+        FROM
+            _rels AS r
+        WHERE
+            r.osm_type = 'r' AND
+            EXISTS (SELECT 1 FROM (SELECT unnest(from_json(r.members, '[{\"type\":\"VARCHAR\",\"ref\":\"BIGINT\",\"role\":\"VARCHAR\"}]'), recursive := true)) AS member WHERE member.type = _.osm_type AND member.ref = _.id AND member.role = 'outer')
+    )",
+            conditions(&parse("(r.rels:outer)"), d)
         );
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+
+        println!("{}", conditions(&parse("(poly:\"1 2 3 4\")(area.a)"), d));
         // Combined filters
         assert_eq!(
             "ST_Intersects(
-        _poly_11689077968748950118.geom,
+        _poly_17101990801976832561.geom,
         _.geom
     ) AND
     ST_Intersects(
         _a.geom,
         _.geom
     )",
-            parse("(poly:\"1 2 3 4\")(area.a)")
-                .to_sql(d, "_", "d", "9999")
-                .1
-                .clauses
+            conditions(&parse("(poly:\"1 2 3 4\")(area.a)"), d)
+        );
+
+        assert_eq!(
+            "coalesce(CASE WHEN CASE WHEN _.tags->>'building:levels' ~ '^\\s*-?[0-9]+(\\.[0-9]+)?([eE][-+]?[0-9]+)?\\s*$' THEN CAST(_.tags->>'building:levels' AS numeric) END IS NOT NULL THEN CASE WHEN _.tags->>'building:levels' ~ '^\\s*-?[0-9]+(\\.[0-9]+)?([eE][-+]?[0-9]+)?\\s*$' THEN CAST(_.tags->>'building:levels' AS numeric) END > 5 ELSE coalesce(_.tags->>'building:levels', '') > '5' END, false)",
+            conditions(&parse("(if: t[\"building:levels\"] > 5)"), d)
+        );
+        assert_eq!(
+            "coalesce(coalesce(json_extract(_.tags, '$.\"name\"'), '') <> '', false)",
+            conditions(&parse("(if: t[name] != \"\")"), &Sqlite)
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        match parse_query("node(poly:\"1 2 3\");") {
+            Err(OverpassError::Syntax { message, .. }) => {
+                assert_eq!("Odd number of coordinates in poly filter", message)
+            }
+            other => panic!("Expected a syntax error, got {other:?}"),
+        }
+
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        match to_select(&parse("(n)"), d, "d") {
+            Err(OverpassError::Unsupported { span, .. }) => assert_eq!(4, span.start),
+            other => panic!("Expected an unsupported error, got {other:?}"),
+        }
+
+        assert!(matches!(
+            parse_query("node(w:\"outer\");"),
+            Err(OverpassError::Semantic { .. })
+        ));
+
+        match parse_query("node(newer:\"2024-01-01\");") {
+            Err(OverpassError::Semantic { message, span }) => {
+                assert_eq!(
+                    "Invalid date \"2024-01-01\", expected YYYY-MM-DDThh:mm:ssZ",
+                    message
+                );
+                assert_eq!(11, span.start);
+            }
+            other => panic!("Expected a semantic error, got {other:?}"),
+        }
+
+        for date in [
+            "2020-13-45T99:00:00Z",
+            "2023-02-29T00:00:00Z",
+            "2024-04-31T00:00:00Z",
+            "2024-01-01T24:00:00Z",
+        ] {
+            match parse_query(&format!("[date:\"{date}\"];node;")) {
+                Err(OverpassError::Semantic { message, span }) => {
+                    assert_eq!(format!("Invalid date \"{date}\""), message);
+                    assert_eq!(Span { start: 6, end: 28 }, span);
+                }
+                other => panic!("Expected a semantic error, got {other:?}"),
+            }
+        }
+        assert!(parse_query("[date:\"2024-02-29T23:59:59Z\"];node;").is_ok());
+
+        for (query, start) in [
+            ("node(99999999999999999999);", 5),
+            ("node(id:1,99999999999999999999);", 10),
+        ] {
+            match parse_query(query) {
+                Err(OverpassError::Syntax { message, span }) => {
+                    assert!(message.starts_with("Invalid id 99999999999999999999"));
+                    assert_eq!(
+                        Span {
+                            start,
+                            end: start + 20
+                        },
+                        span
+                    );
+                }
+                other => panic!("Expected a syntax error, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "(-1.1,2,3,4.5)(poly:\"1 2 3 -4\")",
+            parse("( -1.1 , 2, 3, 4.50 )(poly:'1 2  3 -4')").to_overpass()
+        );
+        assert_eq!("(42)(id:1,2)", parse("(42)(id: 1, 2)").to_overpass());
+        assert_eq!(
+            "(area.a)(around.b:-12.0)(bn)",
+            parse("(area.a)(around.b:-12.0)(bn)").to_overpass()
+        );
+        assert_eq!(
+            "(around:10)(around:5.5,1,-2.0,3.5,4)",
+            parse("(around:10)(around:5.5, 1, -2.0, 3.5, 4)").to_overpass()
+        );
+        assert_eq!(
+            "(newer:\"2024-01-01T00:00:00Z\")(changed:\"2024-01-01T00:00:00Z\")",
+            parse("(newer:'2024-01-01T00:00:00Z')(changed:\"2024-01-01T00:00:00Z\")").to_overpass()
+        );
+        assert_eq!(
+            "(r.rels:outer)(bn:\"\")(w.ways)",
+            parse("(r.rels:'outer')(bn:\"\")(w.ways)").to_overpass()
+        );
+        assert_eq!(
+            "(if:length() > 1000 && t[\"building:levels\"] != 1)",
+            parse("(if: length()>1000 && t['building:levels']!=1)").to_overpass()
         );
     }
 }
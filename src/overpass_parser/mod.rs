@@ -2,27 +2,38 @@ pub mod evaluator;
 pub mod filters;
 pub mod out;
 pub mod query;
+pub mod query_compare;
+pub mod query_complete;
 pub mod query_convert;
+pub mod query_difference;
+pub mod query_for;
 pub mod query_foreach;
+pub mod query_if;
+pub mod query_is_in;
+pub mod query_make;
 pub mod query_objects;
 pub mod query_recurse;
+pub mod query_retro;
 pub mod query_union;
 pub mod request;
 pub mod selectors;
 pub mod subrequest;
+pub mod validator;
 
 use pest::Parser;
 use pest_derive::Parser;
 use request::Request;
 
+use crate::error::OverpassError;
+
 #[derive(Parser)]
 #[grammar = "overpass.pest"]
 pub struct OverpassParser;
 
-pub fn parse_query(query: &str) -> Result<Request, pest::error::Error<Rule>> {
+pub fn parse_query(query: &str) -> Result<Request, OverpassError> {
     match OverpassParser::parse(Rule::request, query) {
         Ok(mut pairs) => Request::from_pest(pairs.next().unwrap()),
-        Err(e) => Err(e),
+        Err(e) => Err(e.into()),
     }
 }
 
@@ -32,10 +43,12 @@ mod tests {
         overpass_parser::parse_query,
         sql_dialect::{
             duckdb::duckdb::Duckdb, postgres::postgres::Postgres, sql_dialect::SqlDialect,
+            sqlite::sqlite::Sqlite,
         },
     };
     use pretty_assertions::assert_eq;
     use regex::Regex;
+    use rusqlite::{Connection, functions::FunctionFlags};
 
     #[test]
     fn test_to_sql() {
@@ -50,8 +63,8 @@ mod tests {
         let request = parse_query(query).expect("Failed to parse query");
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
 
-        let sql = request.to_sql(d, "9999", None);
-        assert_eq!(vec!["SET statement_timeout = 25000;",
+        let sql = request.to_sql(d, "9999", None).unwrap();
+        assert_eq!(["SET statement_timeout = 25000;",
 "WITH
 _a AS (
     SELECT
@@ -61,7 +74,7 @@ _a AS (
     WHERE
         area_by_id.id = ANY (ARRAY[3600166718])
 ),
-_poly_11689077968748950118 AS (
+_poly_17101990801976832561 AS (
     SELECT
         geom
     FROM
@@ -74,12 +87,12 @@ _k AS (
         nwr_by_geom.*
     FROM
         nwr_by_geom
-        JOIN _poly_11689077968748950118 ON true
-    JOIN _a ON true
+        JOIN _poly_17101990801976832561 ON true
+        JOIN _a ON true
     WHERE
         (nwr_by_geom.tags?'a' AND nwr_by_geom.tags->>'a' = 'Ñ''') AND (nwr_by_geom.tags?'b' AND nwr_by_geom.tags->>'b' = '\"') AND
         ST_Intersects(
-            _poly_11689077968748950118.geom,
+            _poly_17101990801976832561.geom,
             nwr_by_geom.geom
         ) AND
         ST_Intersects(
@@ -112,7 +125,7 @@ _k AS (
 _out_k AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -139,8 +152,8 @@ sql.join("\n"));
 
         let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
 
-        let sql = request.to_sql(d, "9999", None);
-        assert_eq!(vec!["CREATE TEMP TABLE _a AS
+        let sql = request.to_sql(d, "9999", None).unwrap();
+        assert_eq!(["CREATE TEMP TABLE _a AS
 SELECT
     area_by_id.*
 FROM
@@ -159,7 +172,7 @@ WHERE
     FROM
         _a
 )
-;", "CREATE TEMP TABLE _poly_17221393697116889690 AS
+;", "CREATE TEMP TABLE _poly_17101990801976832561 AS
 SELECT
     geom,
     STRUCT_PACK(
@@ -170,7 +183,7 @@ SELECT
     ) AS bbox
 FROM
     (VALUES (ST_Transform('SRID=4326;POLYGON((2 1, 4 3))'::geometry, 'EPSG:4326', 'EPSG:9999'))) AS p(geom)
-;", "SET variable _poly_17221393697116889690_bbox = (
+;", "SET variable _poly_17101990801976832561_bbox = (
     SELECT
         STRUCT_PACK(
             xmin := min(bbox.xmin),
@@ -180,7 +193,7 @@ FROM
             geom := ST_Union_Agg(geom)
         ) AS bbox_geom
     FROM
-        _poly_17221393697116889690
+        _poly_17101990801976832561
 )
 ;", "WITH
 _k AS (
@@ -192,12 +205,12 @@ _k AS (
         nwr_by_geom
     WHERE
         ((nwr_by_geom.tags->>'a') IS NOT NULL AND (nwr_by_geom.tags->>'a') = 'Ñ''') AND ((nwr_by_geom.tags->>'b') IS NOT NULL AND (nwr_by_geom.tags->>'b') = '\"') AND
-        nwr_by_geom.bbox.xmin <= getvariable('_poly_17221393697116889690_bbox').xmax AND
-        nwr_by_geom.bbox.xmax >= getvariable('_poly_17221393697116889690_bbox').xmin AND
-        nwr_by_geom.bbox.ymin <= getvariable('_poly_17221393697116889690_bbox').ymax AND
-        nwr_by_geom.bbox.ymax >= getvariable('_poly_17221393697116889690_bbox').ymin AND
+        nwr_by_geom.bbox.xmin <= getvariable('_poly_17101990801976832561_bbox').xmax AND
+        nwr_by_geom.bbox.xmax >= getvariable('_poly_17101990801976832561_bbox').xmin AND
+        nwr_by_geom.bbox.ymin <= getvariable('_poly_17101990801976832561_bbox').ymax AND
+        nwr_by_geom.bbox.ymax >= getvariable('_poly_17101990801976832561_bbox').ymin AND
         ST_Intersects(
-            getvariable('_poly_17221393697116889690_bbox').geom,
+            getvariable('_poly_17101990801976832561_bbox').geom,
             nwr_by_geom.geom
         ) AND
         nwr_by_geom.bbox.xmin <= getvariable('_a_bbox').xmax AND
@@ -237,7 +250,7 @@ _k AS (
 _out_k AS (
     SELECT
         (json_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 'EPSG:9999', 'EPSG:4326'))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 'EPSG:9999', 'EPSG:4326'))::numeric END,
@@ -282,10 +295,10 @@ sql.join("\n"));
         let request = parse_query(query).expect("Failed to parse query");
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
 
-        let sql = request.to_sql(d, "9999", None);
+        let sql = request.to_sql(d, "9999", None).unwrap();
         let re = Regex::new(r"_[0-9]+").unwrap();
         assert_eq!(
-            re.replace_all(&vec!["SET statement_timeout = 25000;",
+            re.replace_all(&["SET statement_timeout = 25000;",
 "WITH
 _a AS (
     SELECT
@@ -298,7 +311,7 @@ _a AS (
 _out_a AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -336,37 +349,44 @@ _k AS (
     SELECT
         _body.*
     FROM
-        _127 AS _input
+        _127 AS _element
         JOIN LATERAL (
-            WITH _input AS (SELECT _input.*),
+            WITH
+            _k AS (
+                SELECT _element.*
+            ),
             _r AS (
                 SELECT
                     relation_by_geom.*
                 FROM
                     relation_by_geom
-                    JOIN _input AS bn ON _.osm_type = 'n' AND (
-                    bn.osm_type = 'w' AND array[bn.id] <@ relation_by_geom.nodes OR
-                    bn.osm_type = 'r' AND array[bn.id] <@ osm_base_idx_nodes_members(relation_by_geom.members, 'n')
-                )
                 WHERE
                     relation_by_geom.osm_type = 'r' AND
-                    true
+                    EXISTS (
+                        SELECT
+                            1
+                        FROM
+                            _k AS bn
+                        WHERE
+                            bn.osm_type = 'n' AND
+                            (relation_by_geom.osm_type = 'w' AND
+                            relation_by_geom.nodes @> ARRAY[bn.id] OR
+                            relation_by_geom.osm_type = 'r' AND
+                            relation_by_geom.members @> jsonb_build_array(jsonb_build_object('type', 'n', 'ref', bn.id)))
+                    )
             ),
             _125 AS (
                 SELECT
-                    tags - 'highway' || jsonb_build_object('osm_type', osm_type) || jsonb_build_object('route_ref', (SELECT string_agg(tags ->> 'ref', ',') FROM _r)) AS tags,
-                    changeset AS changeset,
+                    tags - 'highway' || jsonb_build_object('osm_type', _k.osm_type, 'route_ref', (SELECT string_agg(DISTINCT _r.tags->>'ref', ';' ORDER BY _r.tags->>'ref') FROM _r)) AS tags,
+                    _k.id AS id,
                     created AS created,
-                    id AS id,
                     osm_type AS osm_type,
-                    uid AS uid,
-                    user AS user,
                     version AS version,
                     nodes,
                     members,
                     geom AS geom
                 FROM
-                    _input
+                    _k
             )
             SELECT * FROM _125
         ) AS _body ON true
@@ -374,7 +394,7 @@ _k AS (
 _out_k AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -411,11 +431,11 @@ SELECT * FROM _out_k
         let request = parse_query(query).expect("Failed to parse query");
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
 
-        let sql = request.to_sql(d, "9999", None);
+        let sql = request.to_sql(d, "9999", None).unwrap();
         let re = Regex::new(r"_[0-9]+").unwrap();
-        println!("{}", re.replace_all(&sql.join("\n"), "_999").to_string());
+        println!("{}", re.replace_all(&sql.join("\n"), "_999"));
         assert_eq!(
-            re.replace_all(&vec!["SET statement_timeout = 25000;",
+            re.replace_all(&["SET statement_timeout = 25000;",
 "WITH
 _133 AS (
     SELECT
@@ -431,17 +451,23 @@ _999 AS (
         node_by_geom.*
     FROM
         node_by_geom
-        JOIN _999 AS r ON r.osm_type = 'r'
-        JOIN LATERAL jsonb_to_recordset(r.members) AS m(type text, ref bigint, role text) ON m.type = node_by_geom.osm_type AND m.ref = node_by_geom.id
     WHERE
         node_by_geom.osm_type = 'n' AND
         (node_by_geom.tags?'highway' AND node_by_geom.tags->>'highway' = 'bus_stop') AND
-        true
+        EXISTS (
+            SELECT
+                1
+            FROM
+                _999 AS r
+            WHERE
+                r.osm_type = 'r' AND
+                r.members @> jsonb_build_array(jsonb_build_object('type', node_by_geom.osm_type, 'ref', node_by_geom.id))
+        )
 ),
 _out_999 AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -467,4 +493,188 @@ SELECT * FROM _out_999
             re.replace_all(&sql.join("\n"), "_999").to_string()
         );
     }
+
+    #[test]
+    fn test_to_sql_sqlite() {
+        let query = "[out:json][timeout:25];
+        way[highway](1,2,3,4)->.w;
+        .w out geom;";
+
+        let request = parse_query(query).expect("Failed to parse query");
+        let d = &Sqlite as &(dyn SqlDialect + Send + Sync);
+
+        let sql = request.to_sql(d, "4326", None).unwrap();
+        assert_eq!(
+            "WITH
+_w AS (
+    SELECT
+        way_by_geom.*
+    FROM
+        way_by_geom
+    WHERE
+        way_by_geom.osm_type = 'w' AND
+        json_extract(way_by_geom.tags, '$.\"highway\"') IS NOT NULL AND
+        way_by_geom.xmin <= MbrMaxX(Transform(ST_Envelope(GeomFromText('LINESTRING(2 1, 4 3)', 4326)), 4326)) AND
+        way_by_geom.xmax >= MbrMinX(Transform(ST_Envelope(GeomFromText('LINESTRING(2 1, 4 3)', 4326)), 4326)) AND
+        way_by_geom.ymin <= MbrMaxY(Transform(ST_Envelope(GeomFromText('LINESTRING(2 1, 4 3)', 4326)), 4326)) AND
+        way_by_geom.ymax >= MbrMinY(Transform(ST_Envelope(GeomFromText('LINESTRING(2 1, 4 3)', 4326)), 4326)) AND
+        ST_Intersects(
+            Transform(ST_Envelope(GeomFromText('LINESTRING(2 1, 4 3)', 4326)), 4326),
+            way_by_geom.geom
+        )
+),
+_out_w AS (
+    SELECT
+        (json_object(
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
+        'id', id,
+        'lon', CASE osm_type WHEN 'n' THEN ST_X(Transform(geom, 4326)) END,
+        'lat', CASE osm_type WHEN 'n' THEN ST_Y(Transform(geom, 4326)) END,
+        'bounds', CASE osm_type = 'w' OR osm_type = 'r'
+        WHEN true THEN json_object(
+            'minlon', MbrMinX(Transform(geom, 4326)),
+            'minlat', MbrMinY(Transform(geom, 4326)),
+            'maxlon', MbrMaxX(Transform(geom, 4326)),
+            'maxlat', MbrMaxY(Transform(geom, 4326))
+        )
+        END,
+        'geometry', CASE osm_type
+            WHEN 'w' THEN (SELECT json_group_array(json_object(
+                'lon', json_extract(p.value, '$[0]'),
+                'lat', json_extract(p.value, '$[1]')
+            )) FROM json_each(
+                AsGeoJSON(Transform(geom, 4326), 7),
+                CASE GeometryType(geom) WHEN 'LINESTRING' THEN '$.coordinates' ELSE '$.coordinates[0]' END
+            ) AS p)
+        END,
+        'nodes', json(nodes),
+        'members', json(members),
+        'tags', json(tags))) AS j
+    FROM
+        _w
+)
+SELECT * FROM _out_w
+;",
+            sql.join("\n")
+        );
+    }
+
+    /// Minimal SpatiaLite functions on WKT texts, without projection. The
+    /// first geometry of `ST_Intersects` is taken by its bounds, as the bbox
+    /// filters.
+    fn register_spatialite(connection: &Connection) {
+        type TextFn = fn(&str) -> String;
+        type NumberFn = fn(&str) -> f64;
+        fn points(wkt: &str) -> Vec<(f64, f64)> {
+            Regex::new(r"(-?[\d.]+) (-?[\d.]+)")
+                .unwrap()
+                .captures_iter(wkt)
+                .map(|c| (c[1].parse().unwrap(), c[2].parse().unwrap()))
+                .collect()
+        }
+        fn bounds(wkt: &str) -> [f64; 4] {
+            points(wkt).iter().fold(
+                [f64::MAX, f64::MAX, f64::MIN, f64::MIN],
+                |[xmin, ymin, xmax, ymax], &(x, y)| {
+                    [xmin.min(x), ymin.min(y), xmax.max(x), ymax.max(y)]
+                },
+            )
+        }
+        fn crosses(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+            let side = |p: (f64, f64), q: (f64, f64), r: (f64, f64)| {
+                ((q.0 - p.0) * (r.1 - p.1) - (q.1 - p.1) * (r.0 - p.0)).signum()
+            };
+            side(a, b, c) * side(a, b, d) <= 0.0 && side(c, d, a) * side(c, d, b) <= 0.0
+        }
+        let texts: [(&str, TextFn); 5] = [
+            ("GeomFromText", |wkt| wkt.to_string()),
+            ("Transform", |wkt| wkt.to_string()),
+            ("ST_Envelope", |wkt| {
+                let [xmin, ymin, xmax, ymax] = bounds(wkt);
+                format!(
+                    "POLYGON(({xmin} {ymin}, {xmax} {ymin}, {xmax} {ymax}, {xmin} {ymax}, {xmin} {ymin}))"
+                )
+            }),
+            ("GeometryType", |wkt| {
+                wkt.split('(').next().unwrap().to_string()
+            }),
+            ("AsGeoJSON", |wkt| {
+                let coordinates = points(wkt)
+                    .iter()
+                    .map(|(x, y)| format!("[{x},{y}]"))
+                    .collect::<Vec<String>>()
+                    .join(",");
+                format!("{{\"coordinates\":[{coordinates}]}}")
+            }),
+        ];
+        for (name, f) in texts {
+            connection
+                .create_scalar_function(name, -1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+                    Ok(f(&ctx.get::<String>(0)?))
+                })
+                .unwrap();
+        }
+        let numbers: [(&str, NumberFn); 6] = [
+            ("MbrMinX", |wkt| bounds(wkt)[0]),
+            ("MbrMinY", |wkt| bounds(wkt)[1]),
+            ("MbrMaxX", |wkt| bounds(wkt)[2]),
+            ("MbrMaxY", |wkt| bounds(wkt)[3]),
+            ("ST_X", |wkt| points(wkt)[0].0),
+            ("ST_Y", |wkt| points(wkt)[0].1),
+        ];
+        for (name, f) in numbers {
+            connection
+                .create_scalar_function(name, -1, FunctionFlags::SQLITE_UTF8, move |ctx| {
+                    Ok(f(&ctx.get::<String>(0)?))
+                })
+                .unwrap();
+        }
+        connection
+            .create_scalar_function("ST_Intersects", 2, FunctionFlags::SQLITE_UTF8, |ctx| {
+                let [xmin, ymin, xmax, ymax] = bounds(&ctx.get::<String>(0)?);
+                let corners = [(xmin, ymin), (xmax, ymin), (xmax, ymax), (xmin, ymax)];
+                let points = points(&ctx.get::<String>(1)?);
+                let inside =
+                    |&(x, y): &(f64, f64)| xmin <= x && x <= xmax && ymin <= y && y <= ymax;
+                Ok(points.iter().any(inside)
+                    || points.windows(2).any(|segment| {
+                        (0..4).any(|i| {
+                            crosses(segment[0], segment[1], corners[i], corners[(i + 1) % 4])
+                        })
+                    }))
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_sqlite() {
+        let connection = Connection::open_in_memory().unwrap();
+        register_spatialite(&connection);
+        connection
+            .execute_batch(
+                "CREATE TABLE way_by_geom AS
+                SELECT 1 AS id, 'w' AS osm_type, '{\"highway\":\"a\"}' AS tags, '[]' AS nodes, NULL AS members,
+                    'LINESTRING(3 0, 0 3)' AS geom, 0.0 AS xmin, 3.0 AS xmax, 0.0 AS ymin, 3.0 AS ymax
+                UNION ALL
+                SELECT 2, 'w', '{\"highway\":\"b\"}', '[]', NULL,
+                    'LINESTRING(0.5 0.5, 2 2)', 0.5, 2.0, 0.5, 2.0;",
+            )
+            .unwrap();
+
+        // The bounds of the diagonal way 1 overlap the bbox, not the way.
+        let request = parse_query("way[highway](0,0,1,1);out geom;").unwrap();
+        let sql = request.to_sql(&Sqlite, "4326", None).unwrap().join("\n");
+        let mut statement = connection.prepare(&sql).unwrap();
+        let rows = statement
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<Result<Vec<String>, _>>()
+            .unwrap();
+        assert_eq!(
+            vec![
+                r#"{"type":"way","id":2,"lon":null,"lat":null,"bounds":{"minlon":0.5,"minlat":0.5,"maxlon":2.0,"maxlat":2.0},"geometry":[{"lon":0.5,"lat":0.5},{"lon":2,"lat":2}],"nodes":[],"members":null,"tags":{"highway":"b"}}"#
+            ],
+            rows
+        );
+    }
 }
@@ -2,12 +2,111 @@ use pest::iterators::Pair;
 
 use derivative::Derivative;
 
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{
+    error::{OverpassError, Span},
+    sql_dialect::sql_dialect::SqlDialect,
+};
 
-use super::Rule;
+use super::{
+    Rule,
+    filters::{Filter, number_to_overpass},
+    selectors::Selector,
+};
 
+const OSM_TYPE_NAME: &str = "CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END";
+
+/// Overpass output order of the types, also the CSV `::otype`.
+const OSM_TYPE_ORDER: &str =
+    "CASE osm_type WHEN 'n' THEN 1 WHEN 'w' THEN 2 WHEN 'r' THEN 3 WHEN 'a' THEN 4 END";
+
+/// Output format, from the `[out:]` setting.
 #[derive(Derivative)]
 #[derivative(Default)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutFormat {
+    #[derivative(Default)]
+    Json,
+    /// One XML element text per OSM element.
+    Xml,
+    /// One FeatureCollection per `out` statement.
+    Geojson,
+    Csv(OutCsv),
+}
+
+/// One SQL column per field. The header and the separator are to be used by
+/// the client writing the CSV.
+#[derive(Derivative)]
+#[derivative(Default)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutCsv {
+    /// Metadata, as `::id`, or tag keys.
+    pub fields: Vec<Box<str>>,
+    #[derivative(Default(value = "true"))]
+    pub header: bool,
+    #[derivative(Default(value = "\"\\t\".into()"))]
+    pub separator: Box<str>,
+}
+
+impl OutFormat {
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
+        let Some(csv_pair) = pair.clone().into_inner().next() else {
+            return Ok(match pair.as_str() {
+                "xml" => OutFormat::Xml,
+                "geojson" => OutFormat::Geojson,
+                _ => OutFormat::Json,
+            });
+        };
+        let mut csv = OutCsv::default();
+        for inner_pair in csv_pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::out_csv_field => {
+                    let field = inner_pair.as_str();
+                    csv.fields.push(match field.strip_prefix("::") {
+                        Some(metadata_key) => format!("::{}", metadata_key.trim()).into(),
+                        None => Selector::unquote(field).into(),
+                    });
+                }
+                Rule::out_csv_header => {
+                    csv.header = inner_pair.as_str() == "true";
+                }
+                Rule::out_csv_separator => {
+                    csv.separator = Selector::unquote(inner_pair.as_str()).into();
+                }
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for OutFormat", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        Ok(OutFormat::Csv(csv))
+    }
+
+    pub fn to_overpass(&self) -> String {
+        match self {
+            OutFormat::Json => "json".to_string(),
+            OutFormat::Xml => "xml".to_string(),
+            OutFormat::Geojson => "geojson".to_string(),
+            OutFormat::Csv(csv) => format!(
+                "csv({};{};\"{}\")",
+                csv.fields
+                    .iter()
+                    .map(|field| match field.strip_prefix("::") {
+                        Some(_) => field.to_string(),
+                        None => Selector::overpass_quote(field),
+                    })
+                    .collect::<Vec<String>>()
+                    .join(","),
+                csv.header,
+                csv.separator.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+        }
+    }
+}
+
+#[derive(Derivative)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct Out {
     pub set: Option<Box<str>>,
@@ -15,29 +114,66 @@ pub struct Out {
     #[derivative(Default(value = "\"geom\".into()"))]
     pub geom: Box<str>,
 
+    /// `geom(s,w,n,e)`, (south, west, north, east) the geometry is clipped to.
+    pub bbox: Option<(f64, f64, f64, f64)>,
+
+    /// `ids`, `skel`, `body`, `tags`, `meta` or `count`.
     #[derivative(Default(value = "\"body\".into()"))]
     pub level_of_details: Box<str>,
+
+    /// `asc` or `qt`, the order of the set when not set.
+    pub sort: Option<Box<str>>,
+
+    pub limit: Option<u64>,
+
+    pub noids: bool,
+
+    /// Set from `Request::format` before the SQL generation.
+    #[derivative(PartialEq = "ignore")]
+    pub format: OutFormat,
+
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
 }
 
 impl Out {
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
-        let mut out = Out::default();
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
+        let mut out = Out {
+            span: pair.as_span().into(),
+            ..Out::default()
+        };
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
                 Rule::ID => {
                     out.set = Some(inner_pair.as_str().into());
                 }
                 Rule::out_geom => {
-                    out.geom = inner_pair.as_str().into();
+                    out.geom = inner_pair.as_str().split('(').next().unwrap().trim().into();
+                    out.bbox = inner_pair
+                        .into_inner()
+                        .next()
+                        .and_then(Filter::bbox_from_pest);
                 }
                 Rule::out_level_of_details => {
                     out.level_of_details = inner_pair.as_str().into();
                 }
+                Rule::out_sort => {
+                    out.sort = Some(inner_pair.as_str().into());
+                }
+                Rule::out_limit => {
+                    out.limit = Some(inner_pair.as_str().parse::<u64>().map_err(|_| {
+                        OverpassError::syntax(
+                            format!("Invalid limit {:?}", inner_pair.as_str()),
+                            inner_pair.as_span(),
+                        )
+                    })?);
+                }
+                Rule::out_noids => {
+                    out.noids = true;
+                }
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!("Invalid rule {:?} for Out", inner_pair.as_rule()),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for Out", inner_pair.as_rule()),
                         inner_pair.as_span(),
                     ));
                 }
@@ -46,23 +182,396 @@ impl Out {
         Ok(out)
     }
 
+    /// `geom` is always written, as it is the default here but not in Overpass.
+    pub fn to_overpass(&self) -> String {
+        let mut s = String::new();
+        if let Some(set) = &self.set {
+            s.push_str(&format!(".{set} "));
+        }
+        s.push_str(&format!("out {}", self.geom));
+        if let Some((s_lat, w_lon, n_lat, e_lon)) = self.bbox {
+            s.push_str(&format!(
+                "({},{},{},{})",
+                number_to_overpass(s_lat),
+                number_to_overpass(w_lon),
+                number_to_overpass(n_lat),
+                number_to_overpass(e_lon)
+            ));
+        }
+        if self.level_of_details.as_ref() != "body" {
+            s.push_str(&format!(" {}", self.level_of_details));
+        }
+        if let Some(sort) = &self.sort {
+            s.push_str(&format!(" {sort}"));
+        }
+        if let Some(limit) = self.limit {
+            s.push_str(&format!(" {limit}"));
+        }
+        if self.noids {
+            s.push_str(" noids");
+        }
+        s
+    }
+
     pub fn to_sql(
         &self,
         sql_dialect: &(dyn SqlDialect + Send + Sync),
         srid: &str,
         default_set: &str,
+    ) -> Result<String, OverpassError> {
+        let from = format!("_{}", self.set.as_deref().unwrap_or(default_set));
+        if self.level_of_details.as_ref() == "count" {
+            if let OutFormat::Csv(_) = self.format {
+                return Err(OverpassError::unsupported(
+                    "out count is not supported with [out:csv]",
+                    self.span,
+                ));
+            }
+            return Ok(self.to_sql_count(sql_dialect, &from));
+        }
+        Ok(match &self.format {
+            OutFormat::Json => self.to_sql_json(sql_dialect, srid, &from),
+            OutFormat::Xml => self.to_sql_xml(sql_dialect, srid, &from),
+            OutFormat::Geojson => self.to_sql_geojson(sql_dialect, srid, &from),
+            OutFormat::Csv(csv) => self.to_sql_csv(sql_dialect, srid, &from, csv),
+        })
+    }
+
+    /// The `geom` column, clipped to the `bbox` when set.
+    fn clipped_geom(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        geom: &str,
+        srid: &str,
+    ) -> String {
+        match self.bbox {
+            Some(bbox) => {
+                sql_dialect.st_clip_by_box(geom, &Filter::bbox_geom(sql_dialect, bbox, srid))
+            }
+            None => geom.to_string(),
+        }
+    }
+
+    /// Relation members with the node coordinates and the way geometries,
+    /// as in Overpass `out geom`. `None` when the dialect has no members table.
+    fn json_members_geometry(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        from: &str,
+    ) -> Option<String> {
+        let members = sql_dialect.relation_members(from)?;
+        let member_node = sql_dialect.st_transform_reverse("member_node.geom", srid);
+        let way_geometry = sql_dialect.json_way_geometry(
+            &self.clipped_geom(sql_dialect, "member_way.geom", srid),
+            srid,
+        );
+        Some(format!(
+            "(
+    SELECT
+        {}({}(
+            'type', {},
+            'ref', member.ref,
+            'role', member.role,
+            'lon', {},
+            'lat', {},
+            'geometry', CASE member.type WHEN 'w' THEN {way_geometry} END
+        ))
+    FROM
+        {members}
+        LEFT JOIN node_by_id AS member_node ON
+            member.type = 'n' AND
+            member_node.id = member.ref
+        LEFT JOIN way_by_id AS member_way ON
+            member.type = 'w' AND
+            member_way.id = member.ref
+)",
+            sql_dialect.jsonb_agg(),
+            sql_dialect.json_build_object(),
+            OSM_TYPE_NAME.replace("osm_type", "member.type"),
+            sql_dialect.to_numeric(&format!("ST_X({member_node})")),
+            sql_dialect.to_numeric(&format!("ST_Y({member_node})")),
+        ))
+    }
+
+    /// `ORDER BY` and `LIMIT` clauses, with a leading new line, or empty.
+    fn order_limit(&self, sql_dialect: &(dyn SqlDialect + Send + Sync), srid: &str) -> String {
+        let mut s = String::new();
+        match self.sort.as_deref() {
+            Some("asc") => s.push_str(&format!("\nORDER BY\n    {OSM_TYPE_ORDER}, id")),
+            Some(_) => s.push_str(&format!(
+                "\nORDER BY\n    {OSM_TYPE_ORDER}, {}, id",
+                sql_dialect.st_geohash(&sql_dialect.st_transform_reverse("geom", srid))
+            )),
+            None => {}
+        }
+        if let Some(limit) = self.limit {
+            s.push_str(&format!("\nLIMIT {limit}"));
+        }
+        s
+    }
+
+    /// Totals per type, as the Overpass `count` element. XML for `[out:xml]`,
+    /// JSON otherwise.
+    fn to_sql_count(&self, sql_dialect: &(dyn SqlDialect + Send + Sync), from: &str) -> String {
+        let totals = [
+            ("nodes", " FILTER (WHERE osm_type = 'n')"),
+            ("ways", " FILTER (WHERE osm_type = 'w')"),
+            ("relations", " FILTER (WHERE osm_type = 'r')"),
+            ("areas", " FILTER (WHERE osm_type = 'a')"),
+            ("total", ""),
+        ];
+        let j = if self.format == OutFormat::Xml {
+            format!(
+                "'<count id=\"0\">' ||\n    {} ||\n    '</count>'",
+                totals
+                    .iter()
+                    .map(|(key, filter)| format!(
+                        "'<tag k=\"{key}\" v=\"' || CAST(count(*){filter} AS text) || '\"/>'"
+                    ))
+                    .collect::<Vec<String>>()
+                    .join(" ||\n    ")
+            )
+        } else {
+            let json_build_object = sql_dialect.json_build_object();
+            format!(
+                "{json_build_object}(
+        'type', 'count',
+        'id', 0,
+        'tags', {json_build_object}(
+            {}
+        )
+    )",
+                totals
+                    .iter()
+                    .map(|(key, filter)| format!("'{key}', CAST(count(*){filter} AS text)"))
+                    .collect::<Vec<String>>()
+                    .join(",\n            ")
+            )
+        };
+        format!(
+            "SELECT
+    {j} AS j
+FROM
+    {from}"
+        )
+    }
+
+    fn to_sql_geojson(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        from: &str,
+    ) -> String {
+        let json_build_object = sql_dialect.json_build_object();
+        let id = if self.noids {
+            String::new()
+        } else {
+            format!("\n            'id', {OSM_TYPE_NAME} || '/' || CAST(id AS text),")
+        };
+        let st_transform_reverse = sql_dialect.st_transform_reverse("geom", srid);
+        let geom = match self.geom.as_ref() {
+            "center" => format!("ST_PointOnSurface({st_transform_reverse})"),
+            "bb" => format!("ST_Envelope({st_transform_reverse})"),
+            _ if self.bbox.is_some() => sql_dialect.st_transform_reverse(
+                &format!(
+                    "CASE osm_type WHEN 'n' THEN geom ELSE {} END",
+                    self.clipped_geom(sql_dialect, "geom", srid)
+                ),
+                srid,
+            ),
+            _ => st_transform_reverse,
+        };
+        let properties = if matches!(self.level_of_details.as_ref(), "body" | "tags" | "meta") {
+            sql_dialect.json_column("tags")
+        } else {
+            format!("{json_build_object}()")
+        };
+        format!(
+            "SELECT
+    {json_build_object}(
+        'type', 'FeatureCollection',
+        'features', coalesce({}(feature), {})
+    ) AS j
+FROM (
+    SELECT
+        {json_build_object}(
+            'type', 'Feature',{id}
+            'properties', {properties},
+            'geometry', {}
+        ) AS feature
+    FROM
+        {from}{}
+) AS t",
+            sql_dialect.jsonb_agg(),
+            sql_dialect.json_parse("'[]'"),
+            sql_dialect.json_parse(&sql_dialect.st_asgeojson(&geom, 7)),
+            self.order_limit(sql_dialect, srid).replace('\n', "\n    "),
+        )
+    }
+
+    /// XML elements, with the node coordinates and the `center` of ways and
+    /// relations as geometry.
+    fn to_sql_xml(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        from: &str,
+    ) -> String {
+        let members = matches!(self.level_of_details.as_ref(), "skel" | "body" | "meta");
+        let tags = matches!(self.level_of_details.as_ref(), "body" | "tags" | "meta");
+        let meta = self.level_of_details.as_ref() == "meta";
+        let st_transform_reverse = sql_dialect.st_transform_reverse("geom", srid);
+        let attribute = |name: &str, value: &str| {
+            format!(
+                "' {name}=\"' || coalesce({}, '') || '\"'",
+                sql_dialect.xml_escape(&format!("CAST({value} AS text)"))
+            )
+        };
+        let lat_lon = |point: &str| {
+            format!(
+                "{} || {}",
+                attribute("lat", &format!("ST_Y({point})")),
+                attribute("lon", &format!("ST_X({point})"))
+            )
+        };
+
+        let mut parts = vec![format!("'<' || {OSM_TYPE_NAME}")];
+        if !self.noids {
+            parts.push(attribute("id", "id"));
+        }
+        parts.push(format!(
+            "CASE osm_type WHEN 'n' THEN {} ELSE '' END",
+            lat_lon(&st_transform_reverse)
+        ));
+        if meta {
+            parts.extend([
+                attribute("version", "version"),
+                attribute("timestamp", "created"),
+                attribute("changeset", "changeset"),
+                attribute("uid", "uid"),
+                attribute("user", "\"user\""),
+            ]);
+        }
+        parts.push("'>'".to_string());
+        if self.geom.as_ref() == "center" {
+            parts.push(format!(
+                "CASE osm_type WHEN 'n' THEN '' ELSE '<center' || {} || '/>' END",
+                lat_lon(&format!("ST_PointOnSurface({st_transform_reverse})"))
+            ));
+        }
+        let group = |element: String, table: Option<String>| {
+            table.map(|table| {
+                format!(
+                    "coalesce((SELECT {} FROM {table}), '')",
+                    sql_dialect.string_agg(&element)
+                )
+            })
+        };
+        if members {
+            parts.extend(group(
+                "'<nd ref=\"' || CAST(way_node.ref AS text) || '\"/>'".to_string(),
+                sql_dialect.way_nodes(from),
+            ));
+            parts.extend(group(
+                format!(
+                    "'<member type=\"' || CASE member.type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' ELSE member.type END || '\" ref=\"' || CAST(member.ref AS text) || '\" role=\"' || {} || '\"/>'",
+                    sql_dialect.xml_escape("member.role")
+                ),
+                sql_dialect.relation_members(from),
+            ));
+        }
+        if tags {
+            parts.extend(group(
+                format!(
+                    "'<tag k=\"' || {} || '\" v=\"' || {} || '\"/>'",
+                    sql_dialect.xml_escape("tag.key"),
+                    sql_dialect.xml_escape("tag.value")
+                ),
+                Some(sql_dialect.hash_each(from)),
+            ));
+        }
+        parts.push(format!("'</' || {OSM_TYPE_NAME} || '>'"));
+
+        format!(
+            "SELECT
+    {} AS j
+FROM
+    {from}{}",
+            parts.join(" ||\n    "),
+            self.order_limit(sql_dialect, srid)
+        )
+    }
+
+    /// One column per field, named as in the Overpass CSV header. The header
+    /// and separator settings are left to the client.
+    fn to_sql_csv(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        from: &str,
+        csv: &OutCsv,
+    ) -> String {
+        // Nodes, and the center of ways and relations in `center` mode
+        let st_transform_reverse = sql_dialect.st_transform_reverse("geom", srid);
+        let point = if self.geom.as_ref() == "center" {
+            format!(
+                "CASE osm_type WHEN 'n' THEN {st_transform_reverse} ELSE ST_PointOnSurface({st_transform_reverse}) END"
+            )
+        } else {
+            format!("CASE osm_type WHEN 'n' THEN {st_transform_reverse} END")
+        };
+        let columns = csv
+            .fields
+            .iter()
+            .map(|field| {
+                let (expr, name) = match field.strip_prefix("::") {
+                    Some(metadata_key) => (
+                        match metadata_key {
+                            "id" => "id".to_string(),
+                            "type" => OSM_TYPE_NAME.to_string(),
+                            "otype" => OSM_TYPE_ORDER.to_string(),
+                            "lat" => sql_dialect.to_numeric(&format!("ST_Y({point})")),
+                            "lon" => sql_dialect.to_numeric(&format!("ST_X({point})")),
+                            "timestamp" => "created".to_string(),
+                            "user" => "\"user\"".to_string(),
+                            metadata_key => metadata_key.to_string(),
+                        },
+                        format!("@{metadata_key}"),
+                    ),
+                    None => (sql_dialect.hash_get(from, field), field.to_string()),
+                };
+                format!("{expr} AS \"{}\"", name.replace('"', "\"\""))
+            })
+            .collect::<Vec<String>>()
+            .join(",\n    ");
+        format!(
+            "SELECT
+    {columns}
+FROM
+    {from}{}",
+            self.order_limit(sql_dialect, srid)
+        )
+    }
+
+    fn to_sql_json(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        from: &str,
     ) -> String {
         let way_member_nodes = matches!(self.level_of_details.as_ref(), "skel" | "body" | "meta");
         let relations_members = matches!(self.level_of_details.as_ref(), "skel" | "body" | "meta");
         let tags = matches!(self.level_of_details.as_ref(), "body" | "tags" | "meta");
         let meta = self.level_of_details.as_ref() == "meta";
 
+        let id_field = if self.noids { "" } else { "\n    'id', id," };
+        let order_limit = self.order_limit(sql_dialect, srid);
         let json_strip_nulls = sql_dialect.json_strip_nulls();
         let json_build_object = sql_dialect.json_build_object();
-        let st_dump_points = sql_dialect.st_dump_points();
         let st_transform_reverse = sql_dialect.st_transform_reverse("geom", srid);
-        let st_asgeojson = sql_dialect.st_asgeojson(&st_transform_reverse, 7);
-        let jsonb_agg = sql_dialect.jsonb_agg();
+        let x = sql_dialect.to_numeric(&format!("ST_X({st_transform_reverse})"));
+        let y = sql_dialect.to_numeric(&format!("ST_Y({st_transform_reverse})"));
 
         let meta_fields = if meta {
             ",\n    'timestamp', created,
@@ -79,10 +588,12 @@ impl Out {
                 ",
     'center', CASE osm_type = 'w' OR osm_type = 'r'
         WHEN true THEN {json_build_object}(
-            'lon', ST_X(ST_PointOnSurface({st_transform_reverse}))::numeric,
-            'lat', ST_Y(ST_PointOnSurface({st_transform_reverse}))::numeric
+            'lon', {},
+            'lat', {}
         )
-    END"
+    END",
+                sql_dialect.to_numeric(&format!("ST_X(ST_PointOnSurface({st_transform_reverse}))")),
+                sql_dialect.to_numeric(&format!("ST_Y(ST_PointOnSurface({st_transform_reverse}))")),
             )
         } else {
             "".to_string()
@@ -106,32 +617,8 @@ impl Out {
     'geometry', CASE osm_type
         WHEN 'w' THEN ";
 
-            let w = if st_dump_points.is_some() {
-                let st_dump_points = st_dump_points.unwrap();
-                format!(
-                    "(SELECT \
-{jsonb_agg}({json_build_object}(\
-'lon', ST_X({st_transform_reverse})::numeric, \
-'lat', ST_Y({st_transform_reverse})::numeric)) \
-FROM {st_dump_points}(geom))",
-                )
-                .to_string()
-            } else {
-                format!(
-                    "
-        replace(replace(replace(replace(replace((
-            CASE ST_GeometryType(geom)
-            WHEN 'LINESTRING' THEN {st_asgeojson}->'coordinates'
-            ELSE {st_asgeojson}->'coordinates'->0
-            END
-        )::text, {}",
-                    " '[', '{\"lon\":'), \
-',', ',\"lat\":'), \
-'{\"lon\":{\"lon\":', '[{\"lon\":'), \
-'],\"lat\":{\"lon\":', '},{\"lon\":'), \
-']]', '}]')::json"
-                )
-            };
+            let w =
+                sql_dialect.json_way_geometry(&self.clipped_geom(sql_dialect, "geom", srid), srid);
             format!(
                 "{a}{w}
     END"
@@ -140,33 +627,47 @@ FROM {st_dump_points}(geom))",
             "".to_string()
         };
         let way_member_nodes_field = if way_member_nodes {
-            ",\n    'nodes', nodes"
+            format!(",\n    'nodes', {}", sql_dialect.json_column("nodes"))
         } else {
-            ""
+            "".to_string()
         };
 
-        let relations_members_field = if relations_members {
-            ",\n    'members', members"
+        let members_geometry = if self.geom.as_ref() == "geom" {
+            self.json_members_geometry(sql_dialect, srid, from)
         } else {
-            ""
+            None
+        };
+        let relations_members_field = match (relations_members, members_geometry) {
+            (false, _) => "".to_string(),
+            (true, Some(members_geometry)) => format!(
+                ",\n    'members', CASE osm_type\n        WHEN 'r' THEN {}\n    END",
+                members_geometry.replace('\n', "\n        ")
+            ),
+            (true, None) => format!(",\n    'members', {}", sql_dialect.json_column("members")),
         };
 
-        let tags_field = if tags { ",\n    'tags', tags" } else { "" };
+        let tags_field = if tags {
+            format!(",\n    'tags', {}", sql_dialect.json_column("tags"))
+        } else {
+            "".to_string()
+        };
 
         format!("SELECT
     {json_strip_nulls}({json_build_object}(
-    'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
-    'id', id,
-    'lon', CASE osm_type WHEN 'n' THEN ST_X({st_transform_reverse})::numeric END,
-    'lat', CASE osm_type WHEN 'n' THEN ST_Y({st_transform_reverse})::numeric END{meta_fields}{geom_center}{geom_bb_geom}{geom}{way_member_nodes_field}{relations_members_field}{tags_field})) AS j
+    'type', {OSM_TYPE_NAME},{id_field}
+    'lon', CASE osm_type WHEN 'n' THEN {x} END,
+    'lat', CASE osm_type WHEN 'n' THEN {y} END{meta_fields}{geom_center}{geom_bb_geom}{geom}{way_member_nodes_field}{relations_members_field}{tags_field})) AS j
 FROM
-    _{}", self.set.clone().unwrap_or(default_set.into()))
+    {from}{order_limit}")
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{overpass_parser::parse_query, sql_dialect::postgres::postgres::Postgres};
+    use crate::{
+        overpass_parser::{parse_query, subrequest::SubrequestType},
+        sql_dialect::{duckdb::duckdb::Duckdb, postgres::postgres::Postgres},
+    };
 
     use super::*;
     use pretty_assertions::assert_eq;
@@ -183,7 +684,7 @@ mod tests {
         match parse_query(query) {
             Ok(request) => {
                 let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
-                let sql = request.to_sql(d, "9999", None);
+                let sql = request.to_sql(d, "9999", None).unwrap();
                 assert_eq!(vec!["SET statement_timeout = 25000;", "WITH
 _a AS (
     SELECT
@@ -197,7 +698,7 @@ _a AS (
 _out_a AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -213,7 +714,27 @@ _out_a AS (
             WHEN 'w' THEN (SELECT jsonb_agg(jsonb_build_object('lon', ST_X(ST_Transform(geom, 4326))::numeric, 'lat', ST_Y(ST_Transform(geom, 4326))::numeric)) FROM ST_DumpPoints(geom))
         END,
         'nodes', nodes,
-        'members', members,
+        'members', CASE osm_type
+            WHEN 'r' THEN (
+                SELECT
+                    jsonb_agg(jsonb_build_object(
+                        'type', CASE member.type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE member.type END,
+                        'ref', member.ref,
+                        'role', member.role,
+                        'lon', ST_X(ST_Transform(member_node.geom, 4326))::numeric,
+                        'lat', ST_Y(ST_Transform(member_node.geom, 4326))::numeric,
+                        'geometry', CASE member.type WHEN 'w' THEN (SELECT jsonb_agg(jsonb_build_object('lon', ST_X(ST_Transform(geom, 4326))::numeric, 'lat', ST_Y(ST_Transform(geom, 4326))::numeric)) FROM ST_DumpPoints(member_way.geom)) END
+                    ))
+                FROM
+                    jsonb_to_recordset(_a.members) AS member(type text, ref bigint, role text)
+                    LEFT JOIN node_by_id AS member_node ON
+                        member.type = 'n' AND
+                        member_node.id = member.ref
+                    LEFT JOIN way_by_id AS member_way ON
+                        member.type = 'w' AND
+                        member_way.id = member.ref
+            )
+        END,
         'tags', tags)) AS j
     FROM
         _a
@@ -230,7 +751,7 @@ _b AS (
 _out_b AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -246,7 +767,27 @@ _out_b AS (
             WHEN 'w' THEN (SELECT jsonb_agg(jsonb_build_object('lon', ST_X(ST_Transform(geom, 4326))::numeric, 'lat', ST_Y(ST_Transform(geom, 4326))::numeric)) FROM ST_DumpPoints(geom))
         END,
         'nodes', nodes,
-        'members', members,
+        'members', CASE osm_type
+            WHEN 'r' THEN (
+                SELECT
+                    jsonb_agg(jsonb_build_object(
+                        'type', CASE member.type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE member.type END,
+                        'ref', member.ref,
+                        'role', member.role,
+                        'lon', ST_X(ST_Transform(member_node.geom, 4326))::numeric,
+                        'lat', ST_Y(ST_Transform(member_node.geom, 4326))::numeric,
+                        'geometry', CASE member.type WHEN 'w' THEN (SELECT jsonb_agg(jsonb_build_object('lon', ST_X(ST_Transform(geom, 4326))::numeric, 'lat', ST_Y(ST_Transform(geom, 4326))::numeric)) FROM ST_DumpPoints(member_way.geom)) END
+                    ))
+                FROM
+                    jsonb_to_recordset(_b.members) AS member(type text, ref bigint, role text)
+                    LEFT JOIN node_by_id AS member_node ON
+                        member.type = 'n' AND
+                        member_node.id = member.ref
+                    LEFT JOIN way_by_id AS member_way ON
+                        member.type = 'w' AND
+                        member_way.id = member.ref
+            )
+        END,
         'tags', tags)) AS j
     FROM
         _b
@@ -262,4 +803,216 @@ SELECT * FROM _out_b
             }
         };
     }
+
+    fn out_sql(query: &str) -> String {
+        let request = parse_query(query).unwrap();
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let sql = request.to_sql(d, "9999", None).unwrap().join("\n");
+        sql[sql.find("_out_").unwrap()..].to_string()
+    }
+
+    #[test]
+    fn test_format() {
+        let request =
+            parse_query("[out:csv(::id, name, \"addr:street\"; false; \";\")];node;").unwrap();
+        assert_eq!(
+            OutFormat::Csv(OutCsv {
+                fields: vec!["::id".into(), "name".into(), "addr:street".into()],
+                header: false,
+                separator: ";".into(),
+            }),
+            request.format
+        );
+        assert_eq!(
+            "[out:csv(::id,name,\"addr:street\";false;\";\")];",
+            request.to_overpass().lines().next().unwrap()
+        );
+        let request = parse_query("[out:csv(name)][timeout:5];node;").unwrap();
+        assert_eq!(
+            "[out:csv(name;true;\"\t\")][timeout:5];",
+            request.to_overpass().lines().next().unwrap()
+        );
+        assert_eq!(
+            OutFormat::Xml,
+            parse_query("[out:xml];node;").unwrap().format
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let request = parse_query("out 10 qt noids meta;").unwrap();
+        let SubrequestType::Out(out) = request.subrequest.queries[0].as_ref() else {
+            panic!("Expected Out");
+        };
+        assert_eq!(Some("qt".into()), out.sort);
+        assert_eq!(Some(10), out.limit);
+        assert!(out.noids);
+        assert_eq!("out geom meta qt 10 noids", out.to_overpass());
+    }
+
+    #[test]
+    fn test_bbox() {
+        let request = parse_query("way->.a;.a out geom(43.5,-1.5,44,-1.0) tags;").unwrap();
+        let SubrequestType::Out(out) = request.subrequest.queries[1].as_ref() else {
+            panic!("Expected Out");
+        };
+        assert_eq!(Some((43.5, -1.5, 44.0, -1.0)), out.bbox);
+        assert_eq!(".a out geom(43.5,-1.5,44,-1.0) tags", out.to_overpass());
+
+        assert!(out_sql("way->.a;.a out geom(43.5,-1.5,44,-1.0) tags;").contains(
+            "WHEN 'w' THEN (SELECT jsonb_agg(jsonb_build_object('lon', ST_X(ST_Transform(geom, 4326))::numeric, 'lat', ST_Y(ST_Transform(geom, 4326))::numeric)) FROM ST_DumpPoints(ST_ClipByBox2D(geom, ST_Transform(ST_Envelope('SRID=4326;LINESTRING(-1.5 43.5, -1 44)'::geometry), 9999))))"
+        ));
+
+        let request = parse_query("way->.a;.a out geom(43.5,-1.5,44,-1.0);").unwrap();
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        let sql = request.to_sql(d, "4326", None).unwrap().join("\n");
+        assert!(sql.contains(
+            "CASE ST_GeometryType(ST_Intersection(geom, ST_Transform(ST_Envelope('SRID=4326;LINESTRING(-1.5 43.5, -1 44)'::geometry), 'EPSG:4326', 'EPSG:4326')))"
+        ));
+    }
+
+    #[test]
+    fn test_members_geometry() {
+        let request = parse_query("rel->.a;.a out geom(43.5,-1.5,44,-1.0);").unwrap();
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        let sql = request.to_sql(d, "4326", None).unwrap().join("\n");
+        assert!(sql.contains(
+            "                    LEFT JOIN way_by_id AS member_way ON
+                        member.type = 'w' AND
+                        member_way.id = member.ref"
+        ));
+        assert!(sql.contains(
+            "CASE ST_GeometryType(ST_Intersection(member_way.geom, ST_Transform(ST_Envelope('SRID=4326;LINESTRING(-1.5 43.5, -1 44)'::geometry), 'EPSG:4326', 'EPSG:4326')))"
+        ));
+
+        assert!(out_sql("rel->.a;.a out center;").contains("'members', members,"));
+    }
+
+    #[test]
+    fn test_to_sql_count() {
+        assert_eq!(
+            "_out_a AS (
+    SELECT
+        jsonb_build_object(
+            'type', 'count',
+            'id', 0,
+            'tags', jsonb_build_object(
+                'nodes', CAST(count(*) FILTER (WHERE osm_type = 'n') AS text),
+                'ways', CAST(count(*) FILTER (WHERE osm_type = 'w') AS text),
+                'relations', CAST(count(*) FILTER (WHERE osm_type = 'r') AS text),
+                'areas', CAST(count(*) FILTER (WHERE osm_type = 'a') AS text),
+                'total', CAST(count(*) AS text)
+            )
+        ) AS j
+    FROM
+        _a
+)
+SELECT * FROM _out_a
+;",
+            out_sql("node->.a;.a out count;")
+        );
+        assert!(
+            out_sql("[out:xml];node->.a;.a out count;")
+                .contains("'<tag k=\"total\" v=\"' || CAST(count(*) AS text) || '\"/>'")
+        );
+    }
+
+    #[test]
+    fn test_to_sql_sort_limit() {
+        let sql = out_sql("node->.a;.a out ids asc 10 noids;");
+        assert!(!sql.contains("'id', id"));
+        assert!(sql.ends_with(
+            "    FROM
+        _a
+    ORDER BY
+        CASE osm_type WHEN 'n' THEN 1 WHEN 'w' THEN 2 WHEN 'r' THEN 3 WHEN 'a' THEN 4 END, id
+    LIMIT 10
+)
+SELECT * FROM _out_a
+;"
+        ));
+        assert!(out_sql("node->.a;.a out qt;").contains(
+            "ORDER BY
+        CASE osm_type WHEN 'n' THEN 1 WHEN 'w' THEN 2 WHEN 'r' THEN 3 WHEN 'a' THEN 4 END, ST_GeoHash(ST_Transform(geom, 4326)), id"
+        ));
+    }
+
+    #[test]
+    fn test_to_sql_csv() {
+        assert_eq!(
+            "_out_a AS (
+    SELECT
+        id AS \"@id\",
+        CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END AS \"@type\",
+        ST_Y(CASE osm_type WHEN 'n' THEN ST_Transform(geom, 4326) ELSE ST_PointOnSurface(ST_Transform(geom, 4326)) END)::numeric AS \"@lat\",
+        _a.tags->>'addr:street' AS \"addr:street\"
+    FROM
+        _a
+)
+SELECT * FROM _out_a
+;",
+            out_sql("[out:csv(::id,::type,::lat,\"addr:street\")];node->.a;.a out center;")
+        );
+
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert!(matches!(
+            parse_query("[out:csv(::id)];node;out count;")
+                .unwrap()
+                .to_sql(d, "9999", None),
+            Err(OverpassError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn test_to_sql_xml() {
+        assert_eq!(
+            "_out_a AS (
+    SELECT
+        '<' || CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END ||
+        ' id=\"' || coalesce(replace(replace(replace(replace(CAST(id AS text), '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;'), '') || '\"' ||
+        CASE osm_type WHEN 'n' THEN ' lat=\"' || coalesce(replace(replace(replace(replace(CAST(ST_Y(ST_Transform(geom, 4326)) AS text), '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;'), '') || '\"' || ' lon=\"' || coalesce(replace(replace(replace(replace(CAST(ST_X(ST_Transform(geom, 4326)) AS text), '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;'), '') || '\"' ELSE '' END ||
+        '>' ||
+        coalesce((SELECT string_agg('<tag k=\"' || replace(replace(replace(replace(tag.key, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;') || '\" v=\"' || replace(replace(replace(replace(tag.value, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;') || '\"/>', '') FROM jsonb_each_text(_a.tags) AS tag(key, value)), '') ||
+        '</' || CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END || '>' AS j
+    FROM
+        _a
+)
+SELECT * FROM _out_a
+;",
+            out_sql("[out:xml];node->.a;.a out tags;")
+        );
+        assert!(out_sql("[out:xml];way->.a;.a out;").contains(
+            "coalesce((SELECT string_agg('<nd ref=\"' || CAST(way_node.ref AS text) || '\"/>', '') FROM unnest(_a.nodes) AS way_node(ref)), '')"
+        ));
+        assert!(out_sql("[out:xml];relation->.a;.a out;").contains(
+            "CASE member.type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' ELSE member.type END"
+        ));
+    }
+
+    #[test]
+    fn test_to_sql_geojson() {
+        assert_eq!(
+            "_out_a AS (
+    SELECT
+        jsonb_build_object(
+            'type', 'FeatureCollection',
+            'features', coalesce(jsonb_agg(feature), '[]'::jsonb)
+        ) AS j
+    FROM (
+        SELECT
+            jsonb_build_object(
+                'type', 'Feature',
+                'id', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END || '/' || CAST(id AS text),
+                'properties', tags,
+                'geometry', ST_AsGeoJSON(ST_PointOnSurface(ST_Transform(geom, 4326)), 7)::jsonb
+            ) AS feature
+        FROM
+            _a
+    ) AS t
+)
+SELECT * FROM _out_a
+;",
+            out_sql("[out:geojson];way->.a;.a out center;")
+        );
+    }
 }
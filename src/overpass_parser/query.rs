@@ -1,15 +1,40 @@
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{error::OverpassError, plan::Node};
 use pest::iterators::Pair;
+use regex::Regex;
 
-use super::{Rule, subrequest::SubrequestJoin};
+use super::Rule;
 
 pub trait Query {
-    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, pest::error::Error<Rule>>;
-
-    fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        srid: &str,
-        default_set: &str,
-    ) -> Vec<SubrequestJoin>;
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError>;
+
+    /// Nodes of the query, the constant ones first, then the result.
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError>;
+
+    fn to_overpass(&self) -> String;
+}
+
+/// Append the `->.set` asignation to a statement. A space is required after
+/// a set name, as `-` is a valid identifier character.
+pub fn asignation_to_overpass(statement: String, asignation: &Option<Box<str>>) -> String {
+    match asignation {
+        None => statement,
+        Some(asignation) => {
+            let separator = if statement
+                .ends_with(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                " "
+            } else {
+                ""
+            };
+            format!("{statement}{separator}->.{asignation}")
+        }
+    }
+}
+
+/// Indent the lines of a block body.
+pub fn indent(body: &str) -> String {
+    Regex::new(r"(?m)^")
+        .unwrap()
+        .replace_all(body, "  ")
+        .into_owned()
 }
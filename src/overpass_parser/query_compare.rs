@@ -0,0 +1,123 @@
+use pest::iterators::Pair;
+
+use crate::{
+    error::{OverpassError, Span},
+    plan::Node,
+};
+
+use derivative::Derivative;
+
+use super::{
+    Rule,
+    evaluator::Evaluator,
+    query::{Query, asignation_to_overpass, indent},
+    subrequest::Subrequest,
+};
+
+/// Elements of the input set changed between the two dates of a `diff`
+/// request, the body runs on both versions.
+#[derive(Derivative)]
+#[derivative(PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryCompare {
+    pub input_set: Option<Box<str>>,
+    pub delta: Option<Evaluator>,
+    pub body: Subrequest,
+    pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryCompare {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let span = pair.as_span();
+        let mut input_set = None;
+        let mut delta = None;
+        let mut asignation = None;
+        let mut body = None;
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::ID => input_set = Some(inner_pair.as_str().into()),
+                Rule::asignation => {
+                    asignation = Some(
+                        inner_pair
+                            .into_inner()
+                            .find(|p| p.as_rule() == Rule::ID)
+                            .map(|p| p.as_str())
+                            .unwrap()
+                            .into(),
+                    );
+                }
+                Rule::eval => delta = Some(Evaluator::from_pest(inner_pair)?),
+                Rule::subrequest => body = Some(Subrequest::from_pest(inner_pair)?),
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryCompare", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        match body {
+            Some(body) => Ok(Box::new(QueryCompare {
+                input_set,
+                delta,
+                body,
+                asignation,
+                span: span.into(),
+            })),
+            None => Err(OverpassError::syntax("Invalid compare statement", span)),
+        }
+    }
+
+    fn to_plan(&self, _default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        // The views only hold the current version of the elements
+        Err(OverpassError::unsupported(
+            "compare is not supported",
+            self.span,
+        ))
+    }
+
+    fn to_overpass(&self) -> String {
+        let mut s = "compare".to_string();
+        if let Some(input_set) = &self.input_set {
+            s.push_str(&format!(".{input_set}"));
+        }
+        let mut s = asignation_to_overpass(s, &self.asignation);
+        if let Some(delta) = &self.delta {
+            s.push_str(&format!("(delta:{})", delta.to_overpass()));
+        }
+        let body = self.body.to_overpass();
+        let body = indent(&body);
+        format!("{s} {{\n{body}\n}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let request = parse_query(
+            "[out:json][diff:\"2020-01-01T00:00:00Z\"]; way->.w; compare.w ->.c(delta:t[name]) { out; };",
+        )
+        .unwrap();
+        assert!(matches!(
+            request.to_sql(d, "9999", None),
+            Err(OverpassError::Unsupported { .. })
+        ));
+        assert_eq!(
+            "way ->.w;
+compare.w ->.c(delta:t[name]) {
+  out geom;
+};",
+            request.subrequest.to_overpass()
+        );
+    }
+}
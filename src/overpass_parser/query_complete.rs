@@ -0,0 +1,207 @@
+use pest::iterators::Pair;
+
+use crate::{
+    error::{OverpassError, Span},
+    plan::Node,
+};
+
+use derivative::Derivative;
+
+use super::{
+    Rule,
+    query::{Query, asignation_to_overpass, indent},
+    subrequest::Subrequest,
+};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Run the body on its own result, from the input set, until no new element
+/// is found.
+#[derive(Derivative)]
+#[derivative(PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryComplete {
+    pub input_set: Option<Box<str>>,
+    /// Maximum number of loops, not supported by SQL: the recursion stops
+    /// on the first loop without new element.
+    pub limit: Option<u64>,
+    pub body: Subrequest,
+    pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryComplete {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let span = pair.as_span();
+        let mut input_set = None;
+        let mut limit = None;
+        let mut asignation = None;
+        let mut body = None;
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::ID => input_set = Some(inner_pair.as_str().into()),
+                Rule::asignation => {
+                    asignation = Some(
+                        inner_pair
+                            .into_inner()
+                            .find(|p| p.as_rule() == Rule::ID)
+                            .map(|p| p.as_str())
+                            .unwrap()
+                            .into(),
+                    );
+                }
+                Rule::query_complete_limit => {
+                    limit = Some(inner_pair.as_str().parse::<u64>().map_err(|e| {
+                        OverpassError::syntax(format!("Invalid limit: {e}"), inner_pair.as_span())
+                    })?);
+                }
+                Rule::subrequest => body = Some(Subrequest::from_pest(inner_pair)?),
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryComplete", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        match body {
+            Some(body) => Ok(Box::new(QueryComplete {
+                input_set,
+                limit,
+                body,
+                asignation,
+                span: span.into(),
+            })),
+            None => Err(OverpassError::syntax("Invalid complete statement", span)),
+        }
+    }
+
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        if self.limit.is_some() {
+            return Err(OverpassError::unsupported(
+                "complete with a loop limit is not supported",
+                self.span,
+            ));
+        }
+        let complete = format!("complete_{}", COUNTER.fetch_add(1, Ordering::SeqCst));
+        // The elements found by the previous loop are the default set of the
+        // body.
+        Ok(vec![Node::Complete {
+            input: self.input_set.as_deref().unwrap_or(default_set).to_string(),
+            body: self.body.to_plan(&format!("{complete}_previous"))?,
+            complete,
+            span: self.span,
+        }])
+    }
+
+    fn to_overpass(&self) -> String {
+        let mut s = "complete".to_string();
+        if let Some(limit) = self.limit {
+            s.push_str(&format!("({limit})"));
+        }
+        if let Some(input_set) = &self.input_set {
+            s.push_str(&format!(".{input_set}"));
+        }
+        let s = asignation_to_overpass(s, &self.asignation);
+        let body = self.body.to_overpass();
+        let body = indent(&body);
+        format!("{s} {{\n{body}\n}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use pretty_assertions::assert_eq;
+    use regex::Regex;
+
+    fn parse(query: &str) -> QueryComplete {
+        match parse_query(query) {
+            Ok(parsed) => match parsed.subrequest.queries.last().unwrap().as_ref() {
+                SubrequestType::QueryType(QueryType::QueryComplete(complete)) => complete.clone(),
+                other => panic!("Expected QueryComplete, found {other:?}"),
+            },
+            Err(e) => panic!("Failed to parse query: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let sql = parse("way[highway]->.w; complete.w ->.r { way(bn)->.n; };")
+            .to_plan("_")
+            .unwrap()[0]
+            .to_sql(d, "9999")
+            .unwrap();
+        let complete = Regex::new(r"_complete_\d+")
+            .unwrap()
+            .replace_all(&sql, "_complete");
+        assert_eq!(
+            "WITH RECURSIVE
+_complete AS (
+    SELECT
+        _w.*
+    FROM
+        _w
+    UNION
+    (
+        WITH
+        _complete_previous AS (
+            SELECT * FROM _complete
+        ),
+        _n AS (
+            SELECT
+                way_by_geom.*
+            FROM
+                way_by_geom
+            WHERE
+                way_by_geom.osm_type = 'w' AND
+                EXISTS (
+                    SELECT
+                        1
+                    FROM
+                        _complete_previous AS bn
+                    WHERE
+                        bn.osm_type = 'n' AND
+                        (way_by_geom.osm_type = 'w' AND
+                        way_by_geom.nodes @> ARRAY[bn.id] OR
+                        way_by_geom.osm_type = 'r' AND
+                        way_by_geom.members @> jsonb_build_array(jsonb_build_object('type', 'n', 'ref', bn.id)))
+                )
+        )
+        SELECT * FROM _n
+    )
+)
+SELECT * FROM _complete",
+            complete
+        );
+    }
+
+    #[test]
+    fn test_limit_to_sql() {
+        let error = parse("node(1); complete(1) { way(bn); node(w); };")
+            .to_plan("_")
+            .unwrap_err();
+        assert_eq!(
+            "complete with a loop limit is not supported",
+            error.message()
+        );
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "complete(10).w ->.r {
+  way(bn);
+}",
+            parse("complete(10).w ->.r(way(bn););").to_overpass()
+        );
+    }
+}
@@ -1,31 +1,82 @@
-use std::collections::HashMap;
-
 use pest::iterators::Pair;
+use regex::Regex;
 
 use derivative::Derivative;
 
-use crate::{overpass_parser::evaluator::Evaluator, sql_dialect::sql_dialect::SqlDialect};
+use crate::{
+    error::OverpassError,
+    overpass_parser::{evaluator::Evaluator, selectors::Selector},
+    plan::{Node, Projection},
+};
 
-use super::{Rule, query::Query, subrequest::SubrequestJoin};
+use super::{Rule, query::Query};
 
 #[derive(Derivative)]
 #[derivative(Default)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct ConvertItem {
     pub target: Box<str>,
     pub source: Option<Evaluator>,
 }
 
+impl ConvertItem {
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
+        let item_pair = pair.into_inner().next().unwrap();
+        match item_pair.as_rule() {
+            Rule::convert_key_eval => {
+                let mut inner = item_pair.into_inner();
+                let target = Selector::unquote(inner.next().unwrap().as_str()).into();
+                let source = Evaluator::from_pest(inner.next().unwrap())?;
+                Ok(ConvertItem {
+                    target,
+                    source: Some(source),
+                })
+            }
+            Rule::convert_generic_copy => Ok(ConvertItem {
+                target: "::".into(),
+                source: None,
+            }),
+            Rule::convert_suppress_key => Ok(ConvertItem {
+                target: Selector::unquote(item_pair.into_inner().next().unwrap().as_str()).into(),
+                source: None,
+            }),
+            _ => Err(OverpassError::syntax(
+                format!("Invalid rule {:?} for ConvertItem", item_pair.as_rule()),
+                item_pair.as_span(),
+            )),
+        }
+    }
+
+    pub fn to_overpass(&self) -> String {
+        match &self.source {
+            Some(source) => {
+                let metadata = Regex::new(
+                    r"^::(id|type|otype|lat|lon|version|timestamp|changeset|uid|user|geom)$",
+                )
+                .unwrap();
+                let target = if metadata.is_match(&self.target) {
+                    self.target.to_string()
+                } else {
+                    Selector::overpass_quote(&self.target)
+                };
+                format!("{target}={}", source.to_overpass())
+            }
+            None if self.target.as_ref() == "::" => "::=::".to_string(),
+            None => format!("!{}", Selector::overpass_quote(&self.target)),
+        }
+    }
+}
+
 #[derive(Derivative)]
 #[derivative(Default)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct QueryConvert {
     pub object_type: Box<str>,
     pub converts: Vec<Box<ConvertItem>>,
 }
 
 impl Query for QueryConvert {
-    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, pest::error::Error<Rule>> {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
         let mut convert = QueryConvert::default();
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
@@ -33,97 +84,13 @@ impl Query for QueryConvert {
                     convert.object_type = inner_pair.as_str().into();
                 }
                 Rule::convert_tag_item => {
-                    for convert_list_item_pair in inner_pair.into_inner() {
-                        match convert_list_item_pair.as_rule() {
-                            Rule::convert_key_eval => {
-                                for convert_item_pair in convert_list_item_pair.into_inner() {
-                                    match convert_item_pair.as_rule() {
-                                        Rule::convert_key => {
-                                            convert.converts.push(Box::new(ConvertItem {
-                                                target: convert_item_pair.as_str().into(),
-                                                source: None,
-                                            }));
-                                        }
-                                        Rule::metadata_key => {
-                                            if let Some(last) = convert.converts.last_mut() {
-                                                last.source =
-                                                    Some(Evaluator::from_pest(convert_item_pair)?);
-                                            } else {
-                                                return Err(pest::error::Error::new_from_span(
-                                                    pest::error::ErrorVariant::CustomError {
-                                                        message:
-                                                            "Metadata key without a convert key"
-                                                                .to_string(),
-                                                    },
-                                                    convert_item_pair.as_span(),
-                                                ));
-                                            }
-                                        }
-                                        Rule::eval => {
-                                            if let Some(last) = convert.converts.last_mut() {
-                                                last.source =
-                                                    Some(Evaluator::from_pest(convert_item_pair)?);
-                                            } else {
-                                                return Err(pest::error::Error::new_from_span(
-                                                    pest::error::ErrorVariant::CustomError {
-                                                        message: "Eval without a convert key"
-                                                            .to_string(),
-                                                    },
-                                                    convert_item_pair.as_span(),
-                                                ));
-                                            }
-                                        }
-                                        _ => {
-                                            return Err(pest::error::Error::new_from_span(
-                                                pest::error::ErrorVariant::CustomError {
-                                                    message: format!(
-                                                        "Invalid rule {:?} for ConvertItem",
-                                                        convert_item_pair.as_rule()
-                                                    ),
-                                                },
-                                                convert_item_pair.as_span(),
-                                            ));
-                                        }
-                                    }
-                                }
-                            }
-                            Rule::convert_generic_copy => {
-                                convert.converts.push(Box::new(ConvertItem {
-                                    target: "::".into(),
-                                    source: None,
-                                }));
-                            }
-                            Rule::convert_suppress_key => {
-                                convert.converts.push(Box::new(ConvertItem {
-                                    target: convert_list_item_pair
-                                        .as_str()
-                                        .trim_start_matches('!')
-                                        .into(),
-                                    source: None,
-                                }));
-                            }
-                            _ => {
-                                return Err(pest::error::Error::new_from_span(
-                                    pest::error::ErrorVariant::CustomError {
-                                        message: format!(
-                                            "Invalid rule {:?} for ConvertItem",
-                                            convert_list_item_pair.as_rule()
-                                        ),
-                                    },
-                                    convert_list_item_pair.as_span(),
-                                ));
-                            }
-                        }
-                    }
+                    convert
+                        .converts
+                        .push(Box::new(ConvertItem::from_pest(inner_pair)?));
                 }
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!(
-                                "Invalid rule {:?} for QueryConvert",
-                                inner_pair.as_rule()
-                            ),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryConvert", inner_pair.as_rule()),
                         inner_pair.as_span(),
                     ));
                 }
@@ -132,84 +99,23 @@ impl Query for QueryConvert {
         Ok(Box::new(convert))
     }
 
-    fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        srid: &str,
-        default_set: &str,
-    ) -> Vec<SubrequestJoin> {
-        let mut keys = "{}::jsonb".to_string();
-
-        // If ::=::, copy all the keys from source to target
-        if self.converts.iter().any(|c| c.target == "::".into()) {
-            keys = "tags".to_string();
-        }
-
-        // Remove keys that are suppressed
-        for convert in &self.converts {
-            if convert.source.is_none() && convert.target != "::".into() {
-                keys = format!("{keys} - {}", sql_dialect.escape_literal(&convert.target));
-            }
-        }
-
-        // Add keys that are converted from metadata
-        let mut metadata = HashMap::from([
-            ("osm_type", "osm_type".to_string()), // osm_type
-            ("id", "id".to_string()),
-            ("lon", "".to_string()),
-            ("lat", "".to_string()),
-            ("created", "created".to_string()),
-            ("version", "version".to_string()),
-            ("changeset", "changeset".to_string()),
-            ("user", "user".to_string()),
-            ("uid", "uid".to_string()),
-        ]);
-        for convert in &self.converts {
-            if let Some(source) = &convert.source {
-                let source_sql = source.to_sql(sql_dialect, srid, default_set);
-                if convert.target.starts_with("::") {
-                    let mut target_key = convert.target.trim_start_matches("::");
-                    if target_key == "type" {
-                        target_key = "osm_type"
-                    } else if target_key == "timestamp" {
-                        target_key = "created"
-                    }
-                    metadata
-                        .entry(target_key)
-                        .and_modify(|v| *v = source_sql.clone())
-                        .or_insert(source_sql);
-                } else {
-                    keys = format!(
-                        "{keys} || jsonb_build_object('{}', {})",
-                        convert.target, source_sql
-                    );
-                }
-            }
-        }
-
-        let mut geom = "geom".to_string();
-        if !metadata["lon"].is_empty() && !metadata["lat"].is_empty() {
-            geom = format!(
-                "ST_SetSRID(ST_MakePoint({}, {}), {})",
-                metadata["lon"], metadata["lat"], srid
-            );
-        }
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        Ok(vec![Node::Project {
+            input: default_set.to_string(),
+            projection: Projection::Convert(self.converts.clone()),
+        }])
+    }
 
-        let mut metadata_sql_parts = metadata
-            .iter()
-            .filter(|(k, _)| **k != "lon" && **k != "lat")
-            .map(|(k, v)| format!("{} AS {}", v, k))
-            .collect::<Vec<_>>();
-        metadata_sql_parts.sort();
-        let metadata_sql = metadata_sql_parts.join(",\n    ");
-        vec![SubrequestJoin {
-            precompute_set: None,
-            precompute: None,
-            from: None,
-            clauses: format!(
-                "SELECT\n    {keys} AS tags,\n    {metadata_sql},\n    nodes,\n    members,\n    {geom} AS geom\nFROM\n    _{default_set}"
-            ),
-        }]
+    fn to_overpass(&self) -> String {
+        format!(
+            "convert {} {}",
+            self.object_type,
+            self.converts
+                .iter()
+                .map(|convert| convert.to_overpass())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )
     }
 }
 
@@ -218,7 +124,10 @@ mod tests {
     use super::*;
     use crate::overpass_parser::parse_query;
     use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::duckdb::duckdb::Duckdb;
     use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use crate::sql_dialect::sqlite::sqlite::Sqlite;
     use pretty_assertions::assert_eq;
 
     fn parse(query: &str) -> QueryConvert {
@@ -247,17 +156,14 @@ mod tests {
 
         assert_eq!(
             "SELECT
-    tags - 'highway' || jsonb_build_object('osm_type', osm_type) AS tags,
-    changeset AS changeset,
+    tags - 'highway' || jsonb_build_object('osm_type', _input.osm_type) AS tags,
+    _input.id AS id,
     created AS created,
-    id AS id,
     osm_type AS osm_type,
-    uid AS uid,
-    user AS user,
     version AS version,
     nodes,
     members,
-    ST_SetSRID(ST_MakePoint(ST_Y(ST_PointOnSurface(geom)), ST_X(ST_PointOnSurface(geom))), 9999) AS geom
+    ST_Transform(ST_SetSRID(ST_MakePoint(ST_X(ST_PointOnSurface(ST_Transform(_input.geom, 4326))), ST_Y(ST_PointOnSurface(ST_Transform(_input.geom, 4326)))), 4326), 9999) AS geom
 FROM
     _input",
             parse(
@@ -269,10 +175,90 @@ FROM
                 ::lon=lon(),
                 osm_type=type(),
                 !highway
-                ;"
+                "
+            )
+            .to_plan("input").unwrap()[0].to_sql(d, "9999").unwrap()
+        );
+
+        assert!(
+            parse("convert node ::geom=center(geom())")
+                .to_plan("input")
+                .unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+                .contains("    ST_PointOnSurface(_input.geom) AS geom\n")
+        );
+
+        assert!(
+            parse("convert node \"a'b\"=\"c\"")
+                .to_plan("input")
+                .unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+                .contains("    '{}'::jsonb || jsonb_build_object('a''b', 'c') AS tags,\n")
+        );
+
+        assert!(
+            parse("convert node ::user=\"u\"").to_plan("input").unwrap()[0]
+                .to_sql(d, "9999")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_convert_to_sql_sqlite() {
+        assert_eq!(
+            "SELECT
+    json_patch(json('{}'), json_object('b', json_extract(_input.tags, '$.\"a\"'))) AS tags,
+    _input.id AS id,
+    created AS created,
+    osm_type AS osm_type,
+    version AS version,
+    nodes,
+    members,
+    geom AS geom
+FROM
+    _input",
+            parse("convert node b=t[\"a\"], ::id=id()")
+                .to_plan("input")
+                .unwrap()[0]
+                .to_sql(&Sqlite, "9999")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_convert_to_sql_dialects() {
+        let query = parse("convert node ::=::, !highway, name=t[\"ref\"]");
+        let tags = |d: &(dyn SqlDialect + Send + Sync)| {
+            query.to_plan("input").unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+                .lines()
+                .nth(1)
+                .unwrap()
+                .trim()
+                .to_string()
+        };
+
+        assert_eq!(
+            "json_merge_patch(json_merge_patch(tags, json_object('highway', NULL)), json_object('name', (_input.tags->>'ref'))) AS tags,",
+            tags(&Duckdb)
+        );
+        assert_eq!(
+            "json_patch(json_remove(tags, '$.\"highway\"'), json_object('name', json_extract(_input.tags, '$.\"ref\"'))) AS tags,",
+            tags(&Sqlite)
+        );
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "convert node ::=::, ::id=id(), osm_type=type(), !highway, ref=t[ref], r=a.set(t[\"addr:street\"])",
+            parse(
+                "convert node ::=::,::id=id(),osm_type=type(),!highway,ref=t['ref'],r=a.set(t['addr:street'])"
             )
-            .to_sql(d, "9999", "input")[0]
-                .clauses
+            .to_overpass()
         );
     }
 }
@@ -0,0 +1,198 @@
+use crate::{
+    error::{OverpassError, Span},
+    plan::{Node, Step},
+};
+use pest::iterators::Pair;
+
+use derivative::Derivative;
+
+use super::{
+    Rule,
+    query::{Query, asignation_to_overpass, indent},
+    subrequest::QueryType,
+};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Elements of the `base` result not in the `minus` result.
+#[derive(Derivative)]
+#[derivative(PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryDifference {
+    pub base: Box<QueryType>,
+    pub minus: Box<QueryType>,
+    pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryDifference {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let span = pair.as_span();
+        let mut asignation = None;
+        let mut queries = Vec::new();
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::query_sequence => {
+                    for query in inner_pair.into_inner() {
+                        queries.push(QueryType::from_pest(query)?);
+                    }
+                }
+                Rule::asignation => {
+                    asignation = Some(
+                        inner_pair
+                            .into_inner()
+                            .find(|p| p.as_rule() == Rule::ID)
+                            .map(|p| p.as_str())
+                            .unwrap()
+                            .into(),
+                    );
+                }
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!(
+                            "Invalid rule {:?} for QueryDifference",
+                            inner_pair.as_rule()
+                        ),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        let mut queries = queries.into_iter();
+        match (queries.next(), queries.next()) {
+            (Some(base), Some(minus)) => Ok(Box::new(QueryDifference {
+                base,
+                minus,
+                asignation,
+                span: span.into(),
+            })),
+            _ => Err(OverpassError::syntax(
+                "Difference expects two queries",
+                span,
+            )),
+        }
+    }
+
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        let mut nodes = Vec::new();
+        let mut steps: Vec<Step> = Vec::new();
+        let mut previous_default_set = default_set.to_string();
+        // Set of the last step of each query
+        let mut result_sets = Vec::new();
+        for query in [&self.base, &self.minus] {
+            for node in query.to_plan(previous_default_set.as_str())? {
+                // Constant nodes are declared by the subrequest
+                if node.constant_set().is_some() {
+                    nodes.push(node);
+                    continue;
+                }
+                let set = match query.asignation() {
+                    Some(asignation) => asignation.to_string(),
+                    None => {
+                        previous_default_set = COUNTER.fetch_add(1, Ordering::SeqCst).to_string();
+                        previous_default_set.clone()
+                    }
+                };
+                steps.push(Step { set, node });
+            }
+            result_sets.push(steps.last().map(|step| step.set.clone()));
+        }
+
+        let (Some(base), Some(minus)) = (result_sets[0].clone(), result_sets[1].clone()) else {
+            return Err(OverpassError::unsupported(
+                "Difference of queries without result",
+                self.span,
+            ));
+        };
+        nodes.push(Node::Difference { steps, base, minus });
+        Ok(nodes)
+    }
+
+    fn to_overpass(&self) -> String {
+        let queries = format!(
+            "{};\n- {};",
+            self.base.to_overpass(),
+            self.minus.to_overpass()
+        );
+        let queries = indent(&queries);
+        asignation_to_overpass(format!("(\n{queries}\n)"), &self.asignation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use crate::{
+        overpass_parser::{parse_query, subrequest::SubrequestType},
+        sql_dialect::postgres::postgres::Postgres,
+    };
+    use pretty_assertions::assert_eq;
+
+    fn parse(query: &str) -> QueryDifference {
+        match parse_query(query) {
+            Ok(parsed) => match parsed.subrequest.queries[0].as_ref() {
+                SubrequestType::QueryType(QueryType::QueryDifference(query_difference)) => {
+                    query_difference.clone()
+                }
+                _ => panic!(
+                    "Expected QueryDifference, found {:?}",
+                    parsed.subrequest.queries[0]
+                ),
+            },
+            Err(e) => panic!("Failed to parse query: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_matches_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+
+        assert_eq!(
+            "WITH
+_a AS (
+SELECT
+    node_by_geom.*
+FROM
+    node_by_geom
+WHERE
+    node_by_geom.osm_type = 'n' AND
+    node_by_geom.tags?'amenity'
+),
+_b AS (
+SELECT
+    _a.*
+FROM
+    _a
+WHERE
+    _a.osm_type = 'n' AND
+    (_a.tags?'amenity' AND _a.tags->>'amenity' = 'bench')
+)
+SELECT
+    *
+FROM
+    _a
+WHERE
+    NOT EXISTS (SELECT 1 FROM _b WHERE _b.osm_type = _a.osm_type AND _b.id = _a.id)",
+            parse("(node[amenity]->.a; - node.a[amenity=bench]->.b;);")
+                .to_plan("_")
+                .unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+        )
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "(
+  nwr[amenity];
+  - nwr[amenity=bench];
+)->.c",
+            parse("(nwr[amenity]; -nwr[amenity=bench];)->.c;").to_overpass()
+        );
+    }
+}
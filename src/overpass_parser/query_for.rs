@@ -0,0 +1,179 @@
+use pest::iterators::Pair;
+
+use crate::{
+    error::{OverpassError, Span},
+    plan::Node,
+};
+
+use derivative::Derivative;
+
+use super::{
+    Rule,
+    evaluator::Evaluator,
+    query::{Query, asignation_to_overpass, indent},
+    subrequest::Subrequest,
+};
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Run the body on each group of elements of the input set with the same
+/// value.
+#[derive(Derivative)]
+#[derivative(PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryFor {
+    pub input_set: Option<Box<str>>,
+    pub loop_var: Option<Box<str>>,
+    pub value: Evaluator,
+    pub body: Subrequest,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryFor {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let span = pair.as_span();
+        let mut input_set = None;
+        let mut loop_var = None;
+        let mut value = None;
+        let mut body = None;
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::ID => input_set = Some(inner_pair.as_str().into()),
+                Rule::asignation => {
+                    loop_var = Some(
+                        inner_pair
+                            .into_inner()
+                            .find(|p| p.as_rule() == Rule::ID)
+                            .map(|p| p.as_str())
+                            .unwrap()
+                            .into(),
+                    );
+                }
+                Rule::eval => value = Some(Evaluator::from_pest(inner_pair)?),
+                Rule::subrequest => body = Some(Subrequest::from_pest(inner_pair)?),
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryFor", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        match (value, body) {
+            (Some(value), Some(body)) => Ok(Box::new(QueryFor {
+                input_set,
+                loop_var,
+                value,
+                body,
+                span: span.into(),
+            })),
+            _ => Err(OverpassError::syntax("Invalid for statement", span)),
+        }
+    }
+
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        // The group is the default set of the body
+        let group = match &self.loop_var {
+            Some(loop_var) => loop_var.to_string(),
+            None => format!("for_{}", COUNTER.fetch_add(1, Ordering::SeqCst)),
+        };
+        Ok(vec![Node::For {
+            input: self.input_set.as_deref().unwrap_or(default_set).to_string(),
+            value: self.value.clone(),
+            body: self.body.to_plan(&group)?,
+            group,
+            span: self.span,
+        }])
+    }
+
+    fn to_overpass(&self) -> String {
+        let mut s = "for".to_string();
+        if let Some(input_set) = &self.input_set {
+            s.push_str(&format!(".{input_set}"));
+        }
+        let s = asignation_to_overpass(s, &self.loop_var);
+        let body = self.body.to_overpass();
+        let body = indent(&body);
+        format!("{s} ({}) {{\n{body}\n}}", self.value.to_overpass())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use pretty_assertions::assert_eq;
+
+    fn parse(query: &str) -> QueryFor {
+        match parse_query(query) {
+            Ok(parsed) => match parsed.subrequest.queries.last().unwrap().as_ref() {
+                SubrequestType::QueryType(QueryType::QueryFor(query_for)) => query_for.clone(),
+                other => panic!("Expected QueryFor, found {other:?}"),
+            },
+            Err(e) => panic!("Failed to parse query: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "SELECT
+    _body.*
+FROM
+    (SELECT coalesce(_w.tags->>'highway', '') AS value FROM _w GROUP BY coalesce(_w.tags->>'highway', '')) AS _group
+    JOIN LATERAL (
+        WITH
+        _g AS (
+            SELECT
+                _w.*
+            FROM
+                _w
+            WHERE
+                coalesce(_w.tags->>'highway', '') = _group.value
+        ),
+        _s AS (
+            SELECT
+                1 AS id,
+                nwr.version,
+                nwr.created,
+                jsonb_build_object(
+                    'count', CAST((SELECT count(*) FROM _g) AS text)
+                ) AS tags,
+                nwr.nodes,
+                nwr.members,
+                nwr.geom AS geom,
+                'stat' AS osm_type
+            FROM
+                (SELECT 1) AS make
+                LEFT JOIN nwr_by_id AS nwr ON false
+        )
+        SELECT * FROM _s
+    ) AS _body ON true",
+            parse("way->.w; for.w ->.g (t[\"highway\"]) { make stat count=count() ->.s; };")
+                .to_plan("_").unwrap()[0].to_sql(d, "9999").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "for.w ->.g (t[highway]) {
+  .g out geom;
+}",
+            parse("for.w ->.g(t['highway'])(.g out;);").to_overpass()
+        );
+        assert_eq!(
+            "for (number(t[lanes]) > 1) {
+  out geom;
+}",
+            parse("for (number(t[lanes]) > 1) { out; };").to_overpass()
+        );
+    }
+}
@@ -1,27 +1,40 @@
 use pest::iterators::Pair;
-use regex::Regex;
 
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{
+    error::{OverpassError, Span},
+    plan::Node,
+};
+
+use derivative::Derivative;
 
 use super::{
     Rule,
-    query::Query,
-    subrequest::{Subrequest, SubrequestJoin},
+    query::{Query, asignation_to_overpass, indent},
+    subrequest::Subrequest,
 };
 
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Derivative)]
+#[derivative(PartialEq)]
 #[derive(Debug, Clone)]
 pub struct QueryForeach {
     pub input_set: Option<Box<str>>,
     pub loop_var: Option<Box<str>>,
     pub body: Subrequest,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
 }
 
 impl Query for QueryForeach {
-    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, pest::error::Error<Rule>> {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
         let mut query_foreach = QueryForeach {
             input_set: None,
             loop_var: None,
             body: Subrequest::default(),
+            span: pair.as_span().into(),
         };
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
@@ -42,13 +55,8 @@ impl Query for QueryForeach {
                     query_foreach.body = Subrequest::from_pest(inner_pair)?;
                 }
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!(
-                                "Invalid rule {:?} for QueryForeach",
-                                inner_pair.as_rule()
-                            ),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryForeach", inner_pair.as_rule()),
                         inner_pair.as_span(),
                     ));
                 }
@@ -57,38 +65,29 @@ impl Query for QueryForeach {
         Ok(Box::new(query_foreach))
     }
 
-    fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        srid: &str,
-        _default_set: &str,
-    ) -> Vec<SubrequestJoin> {
-        let body_sqls = self.body.to_sql(sql_dialect, srid, "input");
-        let mut inner = body_sqls
-            .last()
-            .map(|s| s.trim_end_matches("\n;").trim_end().to_string())
-            .unwrap_or_default()
-            .replace(r"WITH", "WITH _input AS (SELECT _input.*),");
-        inner = Regex::new(r"(?m)^")
-            .unwrap()
-            .replace_all(&inner, "        ")
-            .to_string();
-        let input = self.input_set.as_deref().unwrap_or(_default_set);
-        let clause = format!(
-            "SELECT
-    _body.*
-FROM
-    _{input} AS _input
-    JOIN LATERAL (
-{inner}
-    ) AS _body ON true"
-        );
-        vec![SubrequestJoin {
-            precompute_set: None,
-            precompute: None,
-            from: None,
-            clauses: clause.to_string(),
-        }]
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        // The element is the loop variable and the default set of the body
+        let element = match &self.loop_var {
+            Some(loop_var) => loop_var.to_string(),
+            None => format!("foreach_{}", COUNTER.fetch_add(1, Ordering::SeqCst)),
+        };
+        Ok(vec![Node::Foreach {
+            input: self.input_set.as_deref().unwrap_or(default_set).to_string(),
+            body: self.body.to_plan(&element)?,
+            element,
+            span: self.span,
+        }])
+    }
+
+    fn to_overpass(&self) -> String {
+        let mut s = "foreach".to_string();
+        if let Some(input_set) = &self.input_set {
+            s.push_str(&format!(".{input_set}"));
+        }
+        let s = asignation_to_overpass(s, &self.loop_var);
+        let body = self.body.to_overpass();
+        let body = indent(&body);
+        format!("{s}(\n{body}\n)")
     }
 }
 
@@ -97,19 +96,16 @@ mod tests {
     use super::*;
     use crate::overpass_parser::parse_query;
     use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
-    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use crate::sql_dialect::{duckdb::duckdb::Duckdb, postgres::postgres::Postgres};
     use pretty_assertions::assert_eq;
 
     fn parse(query: &str) -> QueryForeach {
         match parse_query(query) {
             Ok(parsed) => match parsed.subrequest.queries[0].as_ref() {
-                SubrequestType::QueryType(query_type) => match query_type {
-                    QueryType::QueryForeach(query_foreach) => query_foreach.clone(),
-                    _ => panic!(
-                        "Expected QueryForeach, found {:?}",
-                        parsed.subrequest.queries[0]
-                    ),
-                },
+                SubrequestType::QueryType(QueryType::QueryForeach(query_foreach)) => {
+                    query_foreach.clone()
+                }
                 _ => panic!(
                     "Expected QueryForeach, found {:?}",
                     parsed.subrequest.queries[0]
@@ -165,22 +161,34 @@ mod tests {
     fn test_foreach_to_sql() {
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
         let query = "
+            way->.a;
             foreach.a ->.n(
                 node.n ->.m;
             );
             .n out center ids;";
         match parse_query(query) {
             Ok(request) => {
-                let sql = request.to_sql(d, "9999", None)[1].clone();
+                let sql = request.to_sql(d, "9999", None).unwrap()[1].clone();
                 assert_eq!(
                     "WITH
+_a AS (
+    SELECT
+        way_by_geom.*
+    FROM
+        way_by_geom
+    WHERE
+        way_by_geom.osm_type = 'w'
+),
 _n AS (
     SELECT
         _body.*
     FROM
-        _a AS _input
+        _a AS _element
         JOIN LATERAL (
-            WITH _input AS (SELECT _input.*),
+            WITH
+            _n AS (
+                SELECT _element.*
+            ),
             _m AS (
                 SELECT
                     _n.*
@@ -195,7 +203,7 @@ _n AS (
 _out_n AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -228,16 +236,27 @@ SELECT * FROM _out_n
             .n out center ids;";
         match parse_query(query) {
             Ok(request) => {
-                let sql = request.to_sql(d, "9999", None)[1].clone();
+                let sql = request.to_sql(d, "9999", None).unwrap()[1].clone();
                 assert_eq!(
                         "WITH
+__ AS (
+    SELECT
+        nwr_by_id.*
+    FROM
+        nwr_by_id
+    WHERE
+        false
+),
 _n AS (
     SELECT
         _body.*
     FROM
-        __ AS _input
+        __ AS _element
         JOIN LATERAL (
-            WITH _input AS (SELECT _input.*),
+            WITH
+            _n AS (
+                SELECT _element.*
+            ),
             _m AS (
                 SELECT
                     _n.*
@@ -252,7 +271,7 @@ _n AS (
 _out_n AS (
     SELECT
         jsonb_strip_nulls(jsonb_build_object(
-        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' END,
+        'type', CASE osm_type WHEN 'n' THEN 'node' WHEN 'w' THEN 'way' WHEN 'r' THEN 'relation' WHEN 'a' THEN 'area' ELSE osm_type END,
         'id', id,
         'lon', CASE osm_type WHEN 'n' THEN ST_X(ST_Transform(geom, 4326))::numeric END,
         'lat', CASE osm_type WHEN 'n' THEN ST_Y(ST_Transform(geom, 4326))::numeric END,
@@ -273,4 +292,117 @@ SELECT * FROM _out_n
             Err(e) => panic!("Failed to parse query: {e}"),
         }
     }
+
+    #[test]
+    fn test_nested_foreach_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "SELECT
+    _body.*
+FROM
+    _w AS _element
+    JOIN LATERAL (
+        WITH
+        _e AS (
+            SELECT _element.*
+        ),
+        _n AS (
+            SELECT
+                node_by_geom.*
+            FROM
+                node_by_geom
+            WHERE
+                node_by_geom.osm_type = 'n' AND
+                EXISTS (
+                    SELECT
+                        1
+                    FROM
+                        _e AS w
+                    WHERE
+                        w.osm_type = 'w' AND
+                        node_by_geom.osm_type = 'n' AND
+                        w.nodes @> ARRAY[node_by_geom.id]
+                )
+        ),
+        _m AS (
+            SELECT
+                _body.*
+            FROM
+                _n AS _element
+                JOIN LATERAL (
+                    WITH
+                    _m AS (
+                        SELECT _element.*
+                    ),
+                    _b AS (
+                        SELECT
+                            way_by_geom.*
+                        FROM
+                            way_by_geom
+                        WHERE
+                            way_by_geom.osm_type = 'w' AND
+                            (way_by_geom.tags?'name' AND way_by_geom.tags->>'name' = 'WITH') AND
+                            EXISTS (
+                                SELECT
+                                    1
+                                FROM
+                                    _m AS bn
+                                WHERE
+                                    bn.osm_type = 'n' AND
+                                    (way_by_geom.osm_type = 'w' AND
+                                    way_by_geom.nodes @> ARRAY[bn.id] OR
+                                    way_by_geom.osm_type = 'r' AND
+                                    way_by_geom.members @> jsonb_build_array(jsonb_build_object('type', 'n', 'ref', bn.id)))
+                            )
+                    )
+                    SELECT * FROM _b
+                ) AS _body ON true
+        )
+        SELECT * FROM _m
+    ) AS _body ON true",
+            parse("foreach.w ->.e(node(w)->.n; foreach.n ->.m(way[name='WITH'](bn)->.b;););")
+                .to_plan("_").unwrap()[0].to_sql(d, "9999").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_foreach_with_area_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let sql = parse("foreach.a ->.x(node(area.x)->.n;);")
+            .to_plan("_")
+            .unwrap()[0]
+            .to_sql(d, "9999")
+            .unwrap();
+        assert!(sql.contains("JOIN _x ON true"));
+
+        // The outer sets are precomputed before the query
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        let sql = parse_query("area[name=a]->.a; way->.w; foreach.w ->.e(node(area.a)->.n;);")
+            .unwrap()
+            .to_sql(d, "9999", None)
+            .unwrap();
+        assert!(sql[0].starts_with("CREATE TEMP TABLE _a AS\n"));
+        let query = sql.last().unwrap();
+        assert!(query.contains("_e AS NOT MATERIALIZED (\n"));
+        assert!(query.contains("getvariable('_a_bbox')"));
+
+        assert!(matches!(
+            parse("foreach.a ->.x(node(area.x)->.n;);")
+                .to_plan("_")
+                .unwrap()[0]
+                .to_sql(d, "9999"),
+            Err(OverpassError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "foreach.a ->.n(
+  node.n ->.m;
+  .m out geom;
+)",
+            parse("foreach.a ->.n(node.n ->.m; .m out;);").to_overpass()
+        );
+    }
 }
@@ -0,0 +1,173 @@
+use pest::iterators::Pair;
+
+use crate::{
+    error::{OverpassError, Span},
+    plan::Node,
+};
+
+use derivative::Derivative;
+
+use super::{
+    Rule,
+    evaluator::Evaluator,
+    query::{Query, indent},
+    subrequest::Subrequest,
+};
+
+/// Result of the `then` block when the condition is true, else of the
+/// `otherwise` block.
+#[derive(Derivative)]
+#[derivative(PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryIf {
+    pub condition: Evaluator,
+    pub then: Subrequest,
+    pub otherwise: Option<Subrequest>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryIf {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let span = pair.as_span();
+        let mut condition = None;
+        let mut blocks = Vec::new();
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::eval => condition = Some(Evaluator::from_pest(inner_pair)?),
+                Rule::subrequest => blocks.push(Subrequest::from_pest(inner_pair)?),
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryIf", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        let mut blocks = blocks.into_iter();
+        match (condition, blocks.next()) {
+            (Some(condition), Some(then)) => Ok(Box::new(QueryIf {
+                condition,
+                then,
+                otherwise: blocks.next(),
+                span: span.into(),
+            })),
+            _ => Err(OverpassError::syntax("Invalid if statement", span)),
+        }
+    }
+
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        Ok(vec![Node::If {
+            condition: self.condition.clone(),
+            default_set: default_set.to_string(),
+            then: self.then.to_plan(default_set)?,
+            otherwise: self
+                .otherwise
+                .as_ref()
+                .map(|otherwise| otherwise.to_plan(default_set))
+                .transpose()?,
+            span: self.span,
+        }])
+    }
+
+    fn to_overpass(&self) -> String {
+        let block = |block: &Subrequest| {
+            let body = block.to_overpass();
+            let body = indent(&body);
+            format!("{{\n{body}\n}}")
+        };
+        let mut s = format!(
+            "if ({}) {}",
+            self.condition.to_overpass(),
+            block(&self.then)
+        );
+        if let Some(otherwise) = &self.otherwise {
+            s.push_str(&format!(" else {}", block(otherwise)));
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use pretty_assertions::assert_eq;
+
+    fn parse(query: &str) -> QueryIf {
+        match parse_query(query) {
+            Ok(parsed) => match parsed.subrequest.queries.last().unwrap().as_ref() {
+                SubrequestType::QueryType(QueryType::QueryIf(query_if)) => query_if.clone(),
+                other => panic!("Expected QueryIf, found {other:?}"),
+            },
+            Err(e) => panic!("Failed to parse query: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "SELECT
+    *
+FROM (
+    WITH
+    _a AS (
+        SELECT
+            way_by_geom.*
+        FROM
+            way_by_geom
+        WHERE
+            way_by_geom.osm_type = 'w'
+    )
+    SELECT * FROM _a
+) AS _then
+WHERE
+    coalesce((SELECT count(*) FILTER (WHERE osm_type = 'w') FROM _w) > 0, false)
+UNION ALL
+SELECT
+    *
+FROM (
+    WITH
+    _b AS (
+        SELECT
+            node_by_geom.*
+        FROM
+            node_by_geom
+        WHERE
+            node_by_geom.osm_type = 'n'
+    )
+    SELECT * FROM _b
+) AS _else
+WHERE
+    NOT (coalesce((SELECT count(*) FILTER (WHERE osm_type = 'w') FROM _w) > 0, false))",
+            parse("way->.w; if (w.count(ways) > 0) { way->.a; } else { node->.b; };")
+                .to_plan("_")
+                .unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "if (count(ways) > 0) {
+  way;
+  out geom;
+}",
+            parse("if (count(ways) > 0) (way; out;);").to_overpass()
+        );
+        assert_eq!(
+            "if (1) {
+  way;
+} else {
+  node;
+}",
+            parse("if(1){way;}else{node;};").to_overpass()
+        );
+    }
+}
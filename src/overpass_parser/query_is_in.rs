@@ -0,0 +1,192 @@
+use pest::iterators::Pair;
+
+use derivative::Derivative;
+
+use crate::{
+    error::{OverpassError, Span},
+    plan::{IsIn, Node},
+};
+
+use super::{
+    Rule,
+    filters::number_to_overpass,
+    query::{Query, asignation_to_overpass},
+};
+
+#[derive(Derivative)]
+#[derivative(Default, PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryIsIn {
+    pub set: Option<Box<str>>,
+    /// (lat, lon), the nodes of the input set are used when not set.
+    pub coordinates: Option<(f64, f64)>,
+    pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryIsIn {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let mut query_is_in = QueryIsIn {
+            span: pair.as_span().into(),
+            ..QueryIsIn::default()
+        };
+        let mut numbers = Vec::new();
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::ID => {
+                    query_is_in.set = Some(inner_pair.as_str().into());
+                }
+                Rule::number => {
+                    numbers.push(inner_pair.as_str().parse::<f64>().map_err(|_| {
+                        OverpassError::syntax(
+                            format!("Invalid coordinate {:?}", inner_pair.as_str()),
+                            inner_pair.as_span(),
+                        )
+                    })?);
+                }
+                Rule::asignation => {
+                    query_is_in.asignation = Some(
+                        inner_pair
+                            .into_inner()
+                            .find(|p| p.as_rule() == Rule::ID)
+                            .map(|p| p.as_str())
+                            .unwrap()
+                            .into(),
+                    )
+                }
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryIsIn", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        if let [lat, lon] = numbers[..] {
+            if query_is_in.set.is_some() {
+                return Err(OverpassError::semantic(
+                    "is_in with coordinates does not use an input set",
+                    query_is_in.span,
+                ));
+            }
+            query_is_in.coordinates = Some((lat, lon));
+        }
+        Ok(Box::new(query_is_in))
+    }
+
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        let is_in = match self.coordinates {
+            Some((lat, lon)) => IsIn::Point(lat, lon),
+            None => IsIn::Set(self.set.as_deref().unwrap_or(default_set).to_string()),
+        };
+        Ok(vec![Node::IsIn(is_in)])
+    }
+
+    fn to_overpass(&self) -> String {
+        let s = match (&self.set, self.coordinates) {
+            (_, Some((lat, lon))) => format!(
+                "is_in({},{})",
+                number_to_overpass(lat),
+                number_to_overpass(lon)
+            ),
+            (Some(set), None) => format!(".{set} is_in"),
+            (None, None) => "is_in".to_string(),
+        };
+        asignation_to_overpass(s, &self.asignation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+
+    use crate::sql_dialect::{duckdb::duckdb::Duckdb, postgres::postgres::Postgres};
+    use pretty_assertions::assert_eq;
+
+    fn parse(query: &str) -> QueryIsIn {
+        match parse_query(query) {
+            Ok(parsed) => match parsed.subrequest.queries.last().unwrap().as_ref() {
+                SubrequestType::QueryType(QueryType::QueryIsIn(query_is_in)) => query_is_in.clone(),
+                other => panic!("Expected QueryIsIn, found {other:?}"),
+            },
+            Err(e) => panic!("Failed to parse query: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let query = parse("is_in(43.7,-1.5)->.a;");
+        assert_eq!(Some((43.7, -1.5)), query.coordinates);
+        assert_eq!(Some("a".into()), query.asignation);
+
+        let query = parse("node->.x;.x is_in;");
+        assert_eq!(Some("x".into()), query.set);
+        assert_eq!(None, query.coordinates);
+
+        assert!(matches!(
+            parse_query("node->.x;.x is_in(1,2);"),
+            Err(OverpassError::Semantic { .. })
+        ));
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "SELECT
+    area_by_geom.*
+FROM
+    area_by_geom
+WHERE
+    ST_Intersects(
+        ST_Transform(ST_SetSRID(ST_MakePoint(-1.5, 43.7), 4326), 9999),
+        area_by_geom.geom
+    )",
+            parse("is_in(43.7,-1.5);").to_plan("_").unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+        );
+
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "SELECT
+    area_by_geom.*
+FROM
+    area_by_geom
+WHERE
+    EXISTS (
+        SELECT
+            1
+        FROM
+            _x AS is_in
+        WHERE
+            is_in.osm_type = 'n' AND
+            area_by_geom.bbox.xmin <= ST_X(is_in.geom) AND
+            area_by_geom.bbox.xmax >= ST_X(is_in.geom) AND
+            area_by_geom.bbox.ymin <= ST_Y(is_in.geom) AND
+            area_by_geom.bbox.ymax >= ST_Y(is_in.geom) AND
+            ST_Intersects(
+                is_in.geom,
+                area_by_geom.geom
+            )
+    )",
+            parse("node->.x;.x is_in;").to_plan("_").unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "is_in(43.7,-1.0)->.a",
+            parse("is_in(43.7, -1.0)->.a;").to_overpass()
+        );
+        assert_eq!(".x is_in", parse("node->.x;.x is_in;").to_overpass());
+        assert_eq!("is_in", parse("node;is_in;").to_overpass());
+    }
+}
@@ -0,0 +1,156 @@
+use pest::iterators::Pair;
+
+use derivative::Derivative;
+
+use crate::{
+    error::{OverpassError, Span},
+    plan::{Node, Projection},
+};
+
+use super::{
+    Rule,
+    query::{Query, asignation_to_overpass},
+    query_convert::ConvertItem,
+};
+
+/// Create one element, of a free type, with the evaluated tags.
+#[derive(Derivative)]
+#[derivative(Default, PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryMake {
+    pub object_type: Box<str>,
+    pub converts: Vec<Box<ConvertItem>>,
+    pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryMake {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let mut make = QueryMake {
+            span: pair.as_span().into(),
+            ..QueryMake::default()
+        };
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::ID => {
+                    make.object_type = inner_pair.as_str().into();
+                }
+                Rule::convert_tag_item => {
+                    let span = inner_pair.as_span();
+                    let convert = ConvertItem::from_pest(inner_pair)?;
+                    if convert.source.is_none() {
+                        return Err(OverpassError::semantic(
+                            "make only accepts key=value items",
+                            span,
+                        ));
+                    }
+                    make.converts.push(Box::new(convert));
+                }
+                Rule::asignation => {
+                    make.asignation = Some(
+                        inner_pair
+                            .into_inner()
+                            .find(|p| p.as_rule() == Rule::ID)
+                            .map(|p| p.as_str())
+                            .unwrap()
+                            .into(),
+                    )
+                }
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryMake", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        Ok(Box::new(make))
+    }
+
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        Ok(vec![Node::Project {
+            input: default_set.to_string(),
+            projection: Projection::Make {
+                object_type: self.object_type.clone(),
+                converts: self.converts.clone(),
+            },
+        }])
+    }
+
+    fn to_overpass(&self) -> String {
+        asignation_to_overpass(
+            format!(
+                "make {} {}",
+                self.object_type,
+                self.converts
+                    .iter()
+                    .map(|convert| convert.to_overpass())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            &self.asignation,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use pretty_assertions::assert_eq;
+
+    fn parse(query: &str) -> QueryMake {
+        match parse_query(query) {
+            Ok(parsed) => match parsed.subrequest.queries.last().unwrap().as_ref() {
+                SubrequestType::QueryType(QueryType::QueryMake(make)) => make.clone(),
+                other => panic!("Expected QueryMake, found {other:?}"),
+            },
+            Err(e) => panic!("Failed to parse query: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let make = parse("way->.w; make stat ways=w.count(ways), ::id=2 ->.s;");
+        assert_eq!("stat", make.object_type.as_ref());
+        assert_eq!(2, make.converts.len());
+        assert_eq!(Some("s".into()), make.asignation);
+        assert_eq!(
+            "make stat ways=w.count(ways), ::id=2 ->.s",
+            make.to_overpass()
+        );
+
+        assert!(matches!(
+            parse_query("make stat ::=::;"),
+            Err(OverpassError::Semantic { .. })
+        ));
+    }
+
+    #[test]
+    fn test_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "SELECT
+    1 AS id,
+    nwr.version,
+    nwr.created,
+    jsonb_build_object(
+        'total_length', CAST((SELECT coalesce(sum(ST_Length(CAST(ST_Transform(_w.geom, 4326) AS geography))), 0) FROM _w) AS text),
+        'name', 'roads'
+    ) AS tags,
+    nwr.nodes,
+    nwr.members,
+    nwr.geom AS geom,
+    'stat' AS osm_type
+FROM
+    (SELECT 1) AS make
+    LEFT JOIN nwr_by_id AS nwr ON false",
+            parse("way->.w; make stat total_length=w.sum(length()), name=roads;")
+                .to_plan("_").unwrap()[0].to_sql(d, "9999").unwrap()
+        );
+    }
+}
@@ -1,32 +1,43 @@
 use crate::{
+    error::{OverpassError, Span},
     overpass_parser::{
         filters::{Filter, Filters},
         selectors::Selector,
     },
-    sql_dialect::sql_dialect::SqlDialect,
+    plan::{Node, Predicate, Source},
 };
 use pest::iterators::Pair;
 
 use derivative::Derivative;
 
-use super::{Rule, query::Query, selectors::Selectors, subrequest::SubrequestJoin};
+use super::{
+    Rule,
+    query::{Query, asignation_to_overpass},
+    selectors::Selectors,
+};
 
 #[derive(Derivative)]
-#[derivative(Default)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct QueryObjects {
     pub object_type: Box<str>,
     pub selectors: Selectors,
     pub filters: Filters,
-    pub set: Option<Box<str>>,
+    /// Input sets, the elements in all of them.
+    pub sets: Vec<Box<str>>,
     pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
 }
 
 impl Query for QueryObjects {
-    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, pest::error::Error<Rule>> {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
         match pair.as_rule() {
             Rule::query_object => {
-                let mut query_objects = QueryObjects::default();
+                let mut query_objects = QueryObjects {
+                    span: pair.as_span().into(),
+                    ..QueryObjects::default()
+                };
                 for inner_pair in pair.into_inner() {
                     match inner_pair.as_rule() {
                         Rule::object_type => {
@@ -45,7 +56,7 @@ impl Query for QueryObjects {
                                 .push(Filter::from_pest(inner_pair)?);
                         }
                         Rule::ID => {
-                            query_objects.set = Some(inner_pair.as_str().into());
+                            query_objects.sets.push(inner_pair.as_str().into());
                         }
                         Rule::asignation => {
                             query_objects.asignation = Some(
@@ -58,13 +69,8 @@ impl Query for QueryObjects {
                             );
                         }
                         _ => {
-                            return Err(pest::error::Error::new_from_span(
-                                pest::error::ErrorVariant::CustomError {
-                                    message: format!(
-                                        "Invalid rule {:?} for QueryObjects",
-                                        inner_pair.as_rule()
-                                    ),
-                                },
+                            return Err(OverpassError::syntax(
+                                format!("Invalid rule {:?} for QueryObjects", inner_pair.as_rule()),
                                 inner_pair.as_span(),
                             ));
                         }
@@ -72,103 +78,73 @@ impl Query for QueryObjects {
                 }
                 Ok(Box::new(query_objects))
             }
-            _ => Err(pest::error::Error::new_from_span(
-                pest::error::ErrorVariant::CustomError {
-                    message: format!("Invalid rule {:?} for QueryObjects", pair.as_rule()),
-                },
+            _ => Err(OverpassError::syntax(
+                format!("Invalid rule {:?} for QueryObjects", pair.as_rule()),
                 pair.as_span(),
             )),
         }
     }
 
-    fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        srid: &str,
-        default_set: &str,
-    ) -> Vec<SubrequestJoin> {
-        let p: String;
-        let from_table: String = if self.set.is_none() {
-            let mut from: String = self.object_type.clone().into();
-            if from == "rel" {
-                from = "relation".to_string();
-            }
-            if self.filters.has_ids() {
-                format!("{from}_by_id")
-            } else {
-                format!("{from}_by_geom")
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        let set_name = |set: &str| match set {
+            "_" => default_set.to_string(),
+            set => set.to_string(),
+        };
+        let source = match self.sets.first() {
+            None => {
+                let mut from: String = self.object_type.clone().into();
+                if from == "rel" {
+                    from = "relation".to_string();
+                }
+                if self.filters.has_ids() {
+                    Source::View(format!("{from}_by_id"))
+                } else {
+                    Source::View(format!("{from}_by_geom"))
+                }
             }
-        } else if self.set == Some("_".into()) {
-            default_set.into()
-        } else {
-            p = format!("_{}", self.set.as_ref().unwrap());
-            p
+            Some(set) => Source::Set(set_name(set)),
         };
 
-        let mut where_clauses = Vec::new();
-
-        if self.object_type.as_ref() == "nwr" {
-        } else if self.object_type.as_ref() != "area" {
-            where_clauses.push(format!(
-                "{from_table}.osm_type = '{}'",
-                self.object_type.chars().next().unwrap()
-            ));
+        let mut node = Node::Scan(source);
+        if !["nwr", "area"].contains(&self.object_type.as_ref()) {
+            node = node.filter(Predicate::OsmType(self.object_type.chars().next().unwrap()));
         }
 
-        if !self.selectors.selectors.is_empty() {
-            where_clauses.push(
-                self.selectors
-                    .to_sql(sql_dialect, from_table.as_str(), srid),
-            );
+        // Semi-joins with the other input sets
+        for set in self.sets.iter().skip(1) {
+            node = node.filter(Predicate::InSet(set_name(set)));
         }
 
-        let mut pre: Option<SubrequestJoin> = None;
-        let mut precomputed = Vec::new();
-        let mut from = from_table.clone();
-
-        if !self.filters.filters.is_empty() {
-            let (pree, sj) = self.filters.to_sql(sql_dialect, &from, default_set, srid);
-            if pree.is_some() {
-                pre = pree;
-            }
-            precomputed = sj.precompute.unwrap_or_default();
-            if let Some(sj_from) = sj.from {
-                from = format!("{from}\n    {sj_from}");
-            }
-            where_clauses.push(sj.clauses);
+        if !self.selectors.selectors.is_empty() {
+            node = node.filter(Predicate::Selectors(self.selectors.clone()));
         }
 
-        let where_clause = format!("WHERE\n    {}", where_clauses.join(" AND\n    "));
+        let (mut nodes, node) = self.filters.to_plan(node, default_set);
+        nodes.push(node);
+        Ok(nodes)
+    }
 
-        let mut ret = Vec::new();
-        if let Some(r) = pre {
-            ret.push(r);
+    fn to_overpass(&self) -> String {
+        let mut s = self.object_type.to_string();
+        for set in &self.sets {
+            s.push_str(&format!(".{set}"));
         }
-        ret.push(SubrequestJoin {
-            precompute_set: None,
-            precompute: Some(precomputed),
-            from: None,
-            clauses: format!(
-                "SELECT
-    {from_table}.*
-FROM
-    {from}
-{where_clause}"
-            ),
-        });
-        ret
+        s.push_str(&self.selectors.to_overpass());
+        s.push_str(&self.filters.to_overpass());
+        asignation_to_overpass(s, &self.asignation)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
     use crate::{
         overpass_parser::{
             parse_query,
             subrequest::{QueryType, SubrequestType},
         },
-        sql_dialect::postgres::postgres::Postgres,
+        sql_dialect::{duckdb::duckdb::Duckdb, postgres::postgres::Postgres},
     };
     use pretty_assertions::assert_eq;
 
@@ -208,7 +184,42 @@ WHERE
         ST_Transform(ST_Envelope('SRID=4326;LINESTRING(2 1, 4 3)'::geometry), 9999),
         _a.geom
     )",
-            parse("node.a[a=b](1,2,3,4)->.b").to_sql(d, "9999", "_")[0].clauses
+            parse("node.a[a=b](1,2,3,4)->.b").to_plan("_").unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_matches_sets_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+
+        assert_eq!(
+            "SELECT
+    _a.*
+FROM
+    _a
+WHERE
+    EXISTS (SELECT 1 FROM _b WHERE _b.osm_type = _a.osm_type AND _b.id = _a.id) AND
+    EXISTS (SELECT 1 FROM _c WHERE _c.osm_type = _a.osm_type AND _c.id = _a.id) AND
+    (_a.tags?'a' AND _a.tags->>'a' = 'b')",
+            parse("nwr.a.b.c[a=b]").to_plan("_").unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
+        );
+
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            "SELECT
+    _a.*
+FROM
+    _a
+WHERE
+    _a.osm_type = 'n' AND
+    EXISTS (SELECT 1 FROM _b WHERE _b.osm_type = _a.osm_type AND _b.id = _a.id)",
+            parse("node.a.b").to_plan("_").unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
         );
     }
 
@@ -226,19 +237,31 @@ FROM
     _a.*
 FROM
     _a
-    JOIN _poly_15599741043204530343 ON true
+    JOIN _poly_15404996215474975986 ON true
 WHERE
     _a.osm_type = 'n' AND
     ST_Intersects(
-        _poly_15599741043204530343.geom,
+        _poly_15404996215474975986.geom,
         _a.geom
     )"
             ),
             parse("node.a(poly:'1 2 3 4 5 6')")
-                .to_sql(d, "9999", "_")
+                .to_plan("_")
+                .unwrap()
                 .iter()
-                .map(|i| i.clauses.clone())
+                .map(|node| node.to_sql(d, "9999").unwrap())
                 .collect::<Vec<String>>()
         );
     }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "node.a[a=b](1,2,3,4)->.b",
+            parse("node.a(1,2,3,4)[a=b]->.b").to_overpass()
+        );
+        assert_eq!("way.a ->.b", parse("way.a ->.b").to_overpass());
+        assert_eq!("nwr.a.b[c]", parse("nwr.a.b[c]").to_overpass());
+        assert_eq!("rel", parse("rel").to_overpass());
+    }
 }
@@ -2,28 +2,39 @@ use pest::iterators::Pair;
 
 use derivative::Derivative;
 
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{
+    error::{OverpassError, Span},
+    plan::{Node, RecurseDirection},
+};
 
-use super::{Rule, query::Query, subrequest::SubrequestJoin};
+use super::{
+    Rule,
+    query::{Query, asignation_to_overpass},
+};
 
 #[derive(Derivative)]
-#[derivative(Default)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct QueryRecurse {
     pub set: Option<Box<str>>,
     pub recurse: Box<str>,
     pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
 }
 
 impl Query for QueryRecurse {
-    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, pest::error::Error<Rule>> {
-        let mut query_recurse = QueryRecurse::default();
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let mut query_recurse = QueryRecurse {
+            span: pair.as_span().into(),
+            ..QueryRecurse::default()
+        };
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
                 Rule::ID => {
                     query_recurse.set = Some(inner_pair.as_str().into());
                 }
-                Rule::query_recurse => {
+                Rule::recurse => {
                     query_recurse.recurse = inner_pair.as_str().into();
                 }
                 Rule::asignation => {
@@ -37,13 +48,8 @@ impl Query for QueryRecurse {
                     )
                 }
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!(
-                                "Invalid rule {:?} for QueryRecurse",
-                                inner_pair.as_rule()
-                            ),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryRecurse", inner_pair.as_rule()),
                         inner_pair.as_span(),
                     ));
                 }
@@ -52,58 +58,36 @@ impl Query for QueryRecurse {
         Ok(Box::new(query_recurse))
     }
 
-    fn to_sql(
-        &self,
-        _sql_dialect: &(dyn SqlDialect + Send + Sync),
-        _srid: &str,
-        default_set: &str,
-    ) -> Vec<SubrequestJoin> {
-        let from = if self.set.is_none() {
-            default_set
-        } else {
-            self.set.as_ref().unwrap()
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        // `>>` and `<<` follow relation members transitively, `>` and `<`
+        // only follow ways.
+        let (direction, transitive) = match self.recurse.as_ref() {
+            ">" => (RecurseDirection::Down, false),
+            ">>" => (RecurseDirection::Down, true),
+            "<" => (RecurseDirection::Up, false),
+            "<<" => (RecurseDirection::Up, true),
+            recurse => {
+                return Err(OverpassError::syntax(
+                    format!("Invalid recurse {recurse:?}"),
+                    self.span,
+                ));
+            }
         };
+        Ok(vec![Node::Recurse {
+            input: self.set.as_deref().unwrap_or(default_set).to_string(),
+            direction,
+            transitive,
+            span: self.span,
+        }])
+    }
 
-        vec!(SubrequestJoin{
-            precompute_set: None,
-            precompute: None,
-            from: None,
-            clauses: format!("SELECT
-    way.*
-FROM
-    _{from} AS way
-    JOIN node_by_id AS node ON
-        node.id = ANY(way.nodes)
-WHERE
-    way.osm_type = 'w'
-UNION ALL
-SELECT
-    node.*
-FROM
-    _{from} AS relation
-    JOIN LATERAL (
-        SELECT * FROM jsonb_to_recordset(members) AS t(ref bigint, role text, type text) WHERE type = 'n'
-    ) AS members ON
-        members.type = 'w'
-    JOIN node_by_id AS node ON
-        node.id = members.ref
-WHERE
-    relation.osm_type = 'r'
-UNION ALL
-SELECT
-    way.*
-FROM
-    _{from} AS relation
-    JOIN LATERAL (
-        SELECT * FROM jsonb_to_recordset(members) AS t(ref bigint, role text, type text) WHERE type = 'w'
-    ) AS members ON
-        true
-    JOIN way_by_id AS way ON
-        way.id = members.ref
-WHERE
-    relation.osm_type = 'r'"
-           )
-        })
+    fn to_overpass(&self) -> String {
+        let mut s = String::new();
+        if let Some(set) = &self.set {
+            s.push_str(&format!(".{set} "));
+        }
+        s.push_str(&self.recurse);
+        asignation_to_overpass(s, &self.asignation)
     }
 }
 
@@ -112,20 +96,19 @@ mod tests {
     use super::*;
     use crate::overpass_parser::parse_query;
     use crate::overpass_parser::subrequest::{QueryType, SubrequestType};
+    use crate::sql_dialect::sql_dialect::SqlDialect;
 
-    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::{
+        duckdb::duckdb::Duckdb, postgres::postgres::Postgres, sqlite::sqlite::Sqlite,
+    };
     use pretty_assertions::assert_eq;
 
     fn parse(query: &str) -> QueryRecurse {
         match parse_query(query) {
             Ok(parsed) => match parsed.subrequest.queries[1].as_ref() {
-                SubrequestType::QueryType(query_type) => match query_type {
-                    QueryType::QueryRecurse(query_recurse) => query_recurse.clone(),
-                    _ => panic!(
-                        "Expected QueryRecurse, found {:?}",
-                        parsed.subrequest.queries[1]
-                    ),
-                },
+                SubrequestType::QueryType(QueryType::QueryRecurse(query_recurse)) => {
+                    query_recurse.clone()
+                }
                 _ => panic!(
                     "Expected QueryRecurse, found {:?}",
                     parsed.subrequest.queries[1]
@@ -136,45 +119,111 @@ mod tests {
     }
 
     #[test]
-    fn test_matches_to_sql() {
+    fn test_to_sql_down() {
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
 
         assert_eq!(
-            "SELECT
-    way.*
-FROM
-    __ AS way
-    JOIN node_by_id AS node ON
-        node.id = ANY(way.nodes)
-WHERE
-    way.osm_type = 'w'
-UNION ALL
+            "WITH RECURSIVE
+recurse(osm_type, id) AS (
+    SELECT
+        child.osm_type,
+        child.id
+    FROM
+        __ AS recurse
+        JOIN nwr_by_id AS parent ON
+            parent.osm_type = recurse.osm_type AND
+            parent.id = recurse.id
+        JOIN LATERAL (
+            SELECT member.type AS osm_type, member.ref AS id FROM jsonb_to_recordset(parent.members) AS member(type text, ref bigint, role text)
+            UNION ALL
+            SELECT 'n' AS osm_type, way_node.ref AS id FROM unnest(parent.nodes) AS way_node(ref)
+        ) AS child ON
+            true
+    WHERE
+        recurse.osm_type IN ('w', 'r') AND
+        child.osm_type != 'r'
+    UNION
+    SELECT
+        child.osm_type,
+        child.id
+    FROM
+        recurse AS recurse
+        JOIN nwr_by_id AS parent ON
+            parent.osm_type = recurse.osm_type AND
+            parent.id = recurse.id
+        JOIN LATERAL (
+            SELECT member.type AS osm_type, member.ref AS id FROM jsonb_to_recordset(parent.members) AS member(type text, ref bigint, role text)
+            UNION ALL
+            SELECT 'n' AS osm_type, way_node.ref AS id FROM unnest(parent.nodes) AS way_node(ref)
+        ) AS child ON
+            true
+    WHERE
+        recurse.osm_type IN ('w', 'r') AND
+        child.osm_type != 'r'
+)
 SELECT
-    node.*
+    nwr.*
 FROM
-    __ AS relation
-    JOIN LATERAL (
-        SELECT * FROM jsonb_to_recordset(members) AS t(ref bigint, role text, type text) WHERE type = 'n'
-    ) AS members ON
-        members.type = 'w'
-    JOIN node_by_id AS node ON
-        node.id = members.ref
-WHERE
-    relation.osm_type = 'r'
-UNION ALL
-SELECT
-    way.*
-FROM
-    __ AS relation
-    JOIN LATERAL (
-        SELECT * FROM jsonb_to_recordset(members) AS t(ref bigint, role text, type text) WHERE type = 'w'
-    ) AS members ON
-        true
-    JOIN way_by_id AS way ON
-        way.id = members.ref
-WHERE
-    relation.osm_type = 'r'",
-            parse("way;>;")
-                .to_sql(d, "9999", "_")[0].clauses)
+    recurse
+    JOIN nwr_by_id AS nwr ON
+        nwr.osm_type = recurse.osm_type AND
+        nwr.id = recurse.id",
+            parse("way;>;").to_plan("_").unwrap()[0].to_sql(d, "9999").unwrap()
+        );
+
+        let sql = parse("relation->.a;.a >>;").to_plan("_").unwrap()[0]
+            .to_sql(d, "9999")
+            .unwrap();
+        assert!(sql.contains("        _a AS recurse\n"));
+        assert!(!sql.contains("child.osm_type != 'r'"));
+    }
+
+    #[test]
+    fn test_to_sql_up() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+
+        let sql = parse("node;<;").to_plan("_").unwrap()[0]
+            .to_sql(d, "9999")
+            .unwrap();
+        assert!(sql.contains(
+            "        __ AS recurse
+        JOIN LATERAL (
+            SELECT way.osm_type, way.id FROM way_by_id AS way WHERE recurse.osm_type = 'n' AND way.nodes @> ARRAY[recurse.id]
+            UNION ALL
+            SELECT relation.osm_type, relation.id FROM relation_by_id AS relation WHERE relation.members @> jsonb_build_array(jsonb_build_object('type', recurse.osm_type, 'ref', recurse.id))
+        ) AS parent ON
+            true
+    WHERE
+        recurse.osm_type != 'r'
+    UNION
+"
+        ));
+
+        let sql = parse("node;<<;").to_plan("_").unwrap()[0]
+            .to_sql(d, "9999")
+            .unwrap();
+        assert!(!sql.contains("recurse.osm_type != 'r'"));
+
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        let sql = parse("node;<<;").to_plan("_").unwrap()[0]
+            .to_sql(d, "9999")
+            .unwrap();
+        assert!(sql.contains("list_contains(way.nodes, recurse.id)"));
+        assert!(sql.contains("from_json(relation.members, "));
+    }
+
+    #[test]
+    fn test_to_sql_unsupported() {
+        let d = &Sqlite as &(dyn SqlDialect + Send + Sync);
+        assert!(matches!(
+            parse("way;>;").to_plan("_").unwrap()[0].to_sql(d, "9999"),
+            Err(OverpassError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(">", parse("way;>;").to_overpass());
+        assert_eq!(".a <<->.b", parse("way->.a;.a<< ->.b;").to_overpass());
     }
 }
@@ -0,0 +1,94 @@
+use pest::iterators::Pair;
+
+use crate::{
+    error::{OverpassError, Span},
+    plan::Node,
+};
+
+use derivative::Derivative;
+
+use super::{
+    Rule,
+    evaluator::Evaluator,
+    query::{Query, indent},
+    subrequest::Subrequest,
+};
+
+/// Run the body on the data at the evaluated date.
+#[derive(Derivative)]
+#[derivative(PartialEq)]
+#[derive(Debug, Clone)]
+pub struct QueryRetro {
+    pub date: Evaluator,
+    pub body: Subrequest,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+impl Query for QueryRetro {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let span = pair.as_span();
+        let mut date = None;
+        let mut body = None;
+        for inner_pair in pair.into_inner() {
+            match inner_pair.as_rule() {
+                Rule::eval => date = Some(Evaluator::from_pest(inner_pair)?),
+                Rule::subrequest => body = Some(Subrequest::from_pest(inner_pair)?),
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryRetro", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
+            }
+        }
+        match (date, body) {
+            (Some(date), Some(body)) => Ok(Box::new(QueryRetro {
+                date,
+                body,
+                span: span.into(),
+            })),
+            _ => Err(OverpassError::syntax("Invalid retro statement", span)),
+        }
+    }
+
+    fn to_plan(&self, _default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        // The views only hold the current version of the elements
+        Err(OverpassError::unsupported(
+            "retro is not supported",
+            self.span,
+        ))
+    }
+
+    fn to_overpass(&self) -> String {
+        let body = self.body.to_overpass();
+        let body = indent(&body);
+        format!("retro ({}) {{\n{body}\n}}", self.date.to_overpass())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use crate::sql_dialect::postgres::postgres::Postgres;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_to_sql() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let request = parse_query("retro (\"2020-01-01T00:00:00Z\") { node; out; };").unwrap();
+        assert!(matches!(
+            request.to_sql(d, "9999", None),
+            Err(OverpassError::Unsupported { .. })
+        ));
+        assert_eq!(
+            "retro (\"2020-01-01T00:00:00Z\") {
+  node;
+  out geom;
+};",
+            request.subrequest.to_overpass()
+        );
+    }
+}
@@ -1,13 +1,15 @@
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{
+    error::{OverpassError, Span},
+    plan::{Node, Step},
+};
 use pest::iterators::Pair;
 
 use derivative::Derivative;
-use regex::Regex;
 
 use super::{
     Rule,
-    query::Query,
-    subrequest::{QueryType, SubrequestJoin},
+    query::{Query, asignation_to_overpass, indent},
+    subrequest::QueryType,
 };
 
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -15,24 +17,26 @@ use std::sync::atomic::{AtomicU64, Ordering};
 static COUNTER: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Derivative)]
-#[derivative(Default)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct QueryUnion {
     pub queries: Vec<Box<QueryType>>,
     pub asignation: Option<Box<str>>,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
 }
 
 impl Query for QueryUnion {
-    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, pest::error::Error<Rule>> {
-        let mut query_union = QueryUnion::default();
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
+        let mut query_union = QueryUnion {
+            span: pair.as_span().into(),
+            ..QueryUnion::default()
+        };
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
                 Rule::query_sequence => {
                     for query in inner_pair.into_inner() {
-                        match QueryType::from_pest(query) {
-                            Ok(query_type) => query_union.queries.push(query_type),
-                            Err(e) => return Err(e),
-                        }
+                        query_union.queries.push(QueryType::from_pest(query)?);
                     }
                 }
                 Rule::asignation => {
@@ -45,84 +49,61 @@ impl Query for QueryUnion {
                             .into(),
                     );
                 }
-                _ => panic!("Unexpected rule in QueryUnion: {:?}", inner_pair.as_rule()),
+                _ => {
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for QueryUnion", inner_pair.as_rule()),
+                        inner_pair.as_span(),
+                    ));
+                }
             }
         }
         Ok(Box::new(query_union))
     }
 
-    fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        srid: &str,
-        default_set: &str,
-    ) -> Vec<SubrequestJoin> {
-        let mut ret = Vec::new();
-        let mut precomputed = Vec::new();
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        let mut nodes = Vec::new();
+        let mut steps = Vec::new();
         let mut previous_default_set = default_set.to_string();
-        let replace = Regex::new(r"^").unwrap();
-
-        let mut clauses = Vec::new();
-        self.queries.iter().for_each(|query| {
-            let sjs = query.to_sql(sql_dialect, srid, previous_default_set.as_str());
-            sjs.iter().for_each(|sj| {
-                precomputed.extend(sj.precompute.clone().unwrap_or_default());
-                let set = match sj
-                    .precompute_set
-                    .clone()
-                    .or(query.asignation().map(|a| a.to_string()))
-                {
+        for query in &self.queries {
+            for node in query.to_plan(previous_default_set.as_str())? {
+                // Constant nodes are declared by the subrequest
+                if node.constant_set().is_some() {
+                    nodes.push(node);
+                    continue;
+                }
+                let set = match query.asignation() {
                     Some(asignation) => asignation.to_string(),
                     None => {
                         previous_default_set = COUNTER.fetch_add(1, Ordering::SeqCst).to_string();
                         previous_default_set.clone()
                     }
                 };
-                if sj.precompute_set.is_some() {
-                    ret.push(sj.clone());
-                } else {
-                    clauses.push((set, sj.clauses.clone()));
-                }
-            })
-        });
-
-        if !clauses.is_empty() {
-            let with = clauses
-                .iter()
-                .map(|(set, sql)| format!("_{set} AS (\n{}\n)", replace.replace_all(sql, "")))
-                .collect::<Vec<String>>()
-                .join(",\n");
-
-            let asignations = clauses
-                .iter()
-                .map(|(set, _sql)| format!("(SELECT * FROM _{set})"))
-                .collect::<Vec<String>>()
-                .join(" UNION\n    ");
+                steps.push(Step { set, node });
+            }
+        }
 
-            ret.push(SubrequestJoin {
-                precompute_set: None,
-                precompute: Some(precomputed),
-                from: None,
-                clauses: format!(
-                    "WITH
-{with}
-SELECT DISTINCT ON(osm_type, id)
-    *
-FROM (
-    {asignations}
-) AS t
-ORDER BY
-    osm_type, id"
-                ),
-            });
+        if !steps.is_empty() {
+            nodes.push(Node::Union(steps));
         }
-        ret
+        Ok(nodes)
+    }
+
+    fn to_overpass(&self) -> String {
+        let queries = self
+            .queries
+            .iter()
+            .map(|query| format!("{};", query.to_overpass()))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let queries = indent(&queries);
+        asignation_to_overpass(format!("(\n{queries}\n)"), &self.asignation)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sql_dialect::sql_dialect::SqlDialect;
     use crate::{
         overpass_parser::{parse_query, subrequest::SubrequestType},
         sql_dialect::postgres::postgres::Postgres,
@@ -178,7 +159,22 @@ FROM (
 ) AS t
 ORDER BY
     osm_type, id",
-            parse("(node->.a;way->.b;);").to_sql(d, "9999", "_")[0].clauses
+            parse("(node->.a;way->.b;);").to_plan("_").unwrap()[0]
+                .to_sql(d, "9999")
+                .unwrap()
         )
     }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "(
+  node[a];
+  (
+    way.a;
+  )->.b;
+)->.c",
+            parse("(node[a];(way.a;)->.b;)->.c;").to_overpass()
+        );
+    }
 }
@@ -1,42 +1,98 @@
+use std::borrow::Cow;
+
 use pest::iterators::Pair;
 
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{error::OverpassError, plan::Plan, sql_dialect::sql_dialect::SqlDialect};
 
 use derivative::Derivative;
 
-use super::{Rule, subrequest::Subrequest};
+use super::{
+    Rule,
+    filters::{Filter, FilterChanged, date_from_pest, number_to_overpass},
+    out::OutFormat,
+    subrequest::Subrequest,
+    validator::{Validation, Validator},
+};
 
 #[derive(Derivative)]
-#[derivative(Default)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct Request {
     #[derivative(Default(value = "Some(160)"))]
     pub timeout: Option<u32>,
+    pub format: OutFormat,
+    /// `[date:]`, query the state of the data at this date.
+    pub date: Option<Box<str>>,
+    /// `[diff:]`, query the changes between the two dates, the second one
+    /// being now when `None`.
+    pub diff: Option<(Box<str>, Option<Box<str>>)>,
+    /// `[bbox:]`, (south, west, north, east) applied to the queries without
+    /// spatial filter.
+    pub bbox: Option<(f64, f64, f64, f64)>,
     pub subrequest: Subrequest,
 }
 
 impl Request {
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
         let mut request = Request::default();
         for inner in pair.into_inner() {
             match inner.as_rule() {
                 Rule::metadata => {
-                    request.timeout = inner
-                        .into_inner()
-                        .find(|p| p.as_rule() == Rule::number)
-                        .map(|p| p.as_str().parse::<u32>().ok().unwrap());
+                    request.timeout = None;
+                    for setting in inner.into_inner() {
+                        match setting.as_rule() {
+                            Rule::out_format => {
+                                request.format = OutFormat::from_pest(setting)?;
+                            }
+                            Rule::metadata_timeout => {
+                                let p = setting.into_inner().next().unwrap();
+                                request.timeout =
+                                    Some(p.as_str().parse::<u32>().map_err(|_| {
+                                        OverpassError::semantic(
+                                            format!("Invalid timeout {:?}", p.as_str()),
+                                            p.as_span(),
+                                        )
+                                    })?);
+                            }
+                            Rule::metadata_date => {
+                                request.date =
+                                    Some(date_from_pest(setting.into_inner().next().unwrap())?);
+                            }
+                            Rule::metadata_bbox => {
+                                let coords = setting
+                                    .into_inner()
+                                    .map(|p| {
+                                        p.as_str().parse::<f64>().map_err(|_| {
+                                            OverpassError::syntax(
+                                                format!("Invalid coordinate {:?}", p.as_str()),
+                                                p.as_span(),
+                                            )
+                                        })
+                                    })
+                                    .collect::<Result<Vec<f64>, OverpassError>>()?;
+                                request.bbox = Some((coords[0], coords[1], coords[2], coords[3]));
+                            }
+                            Rule::metadata_diff => {
+                                let mut dates = setting.into_inner().map(date_from_pest);
+                                request.diff =
+                                    Some((dates.next().unwrap()?, dates.next().transpose()?));
+                            }
+                            _ => {
+                                return Err(OverpassError::syntax(
+                                    format!("Invalid rule {:?} for metadata", setting.as_rule()),
+                                    setting.as_span(),
+                                ));
+                            }
+                        }
+                    }
                 }
                 Rule::subrequest => {
-                    match Subrequest::from_pest(inner) {
-                        Ok(subrequest) => request.subrequest = subrequest,
-                        Err(e) => return Err(e),
-                    };
+                    request.subrequest = Subrequest::from_pest(inner)?;
                 }
+                Rule::EOI => {}
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!("Invalid rule {:?} for Request", inner.as_rule()),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for Request", inner.as_rule()),
                         inner.as_span(),
                     ));
                 }
@@ -45,24 +101,112 @@ impl Request {
         Ok(request)
     }
 
+    /// Canonical Overpass QL of the request, parsing it back gives the same
+    /// request.
+    pub fn to_overpass(&self) -> String {
+        let mut metadata = format!("[out:{}]", self.format.to_overpass());
+        if let Some(timeout) = self.timeout {
+            metadata.push_str(&format!("[timeout:{timeout}]"));
+        }
+        if let Some(date) = &self.date {
+            metadata.push_str(&format!("[date:\"{date}\"]"));
+        }
+        if let Some((since, until)) = &self.diff {
+            let until = until
+                .as_ref()
+                .map(|until| format!(",\"{until}\""))
+                .unwrap_or_default();
+            metadata.push_str(&format!("[diff:\"{since}\"{until}]"));
+        }
+        if let Some((s_lat, w_lon, n_lat, e_lon)) = self.bbox {
+            metadata.push_str(&format!(
+                "[bbox:{},{},{},{}]",
+                number_to_overpass(s_lat),
+                number_to_overpass(w_lon),
+                number_to_overpass(n_lat),
+                number_to_overpass(e_lon)
+            ));
+        }
+        format!("{metadata};\n{}", self.subrequest.to_overpass())
+    }
+
+    /// The subrequest with the `[date:]`, `[diff:]` and `[bbox:]` settings
+    /// applied as filters to the queries on the database, and the `[out:]`
+    /// format to the `out` statements.
+    pub fn subrequest_with_settings(&self) -> Cow<'_, Subrequest> {
+        let changed = match (&self.date, &self.diff) {
+            (_, Some((since, until))) => Some(FilterChanged {
+                since: Some(since.clone()),
+                until: until.clone(),
+            }),
+            (Some(date), None) => Some(FilterChanged {
+                since: None,
+                until: Some(date.clone()),
+            }),
+            (None, None) => None,
+        };
+        if changed.is_none() && self.bbox.is_none() && self.format == OutFormat::Json {
+            return Cow::Borrowed(&self.subrequest);
+        }
+        let mut subrequest = self.subrequest.clone();
+        subrequest.for_each_out_mut(&|out| out.format = self.format.clone());
+        subrequest.for_each_query_objects_mut(&|query| {
+            if !query.sets.is_empty() {
+                return;
+            }
+            if let Some(bbox) = self.bbox
+                && !query.filters.is_spatial()
+            {
+                query.filters.filters.push(Filter {
+                    bbox: Some(bbox),
+                    span: query.span,
+                    ..Filter::default()
+                });
+            }
+            if let Some(changed) = &changed {
+                query.filters.filters.push(Filter {
+                    changed: Some(changed.clone()),
+                    span: query.span,
+                    ..Filter::default()
+                });
+            }
+        });
+        Cow::Owned(subrequest)
+    }
+
+    pub fn validate(&self) -> Validation {
+        Validator::validate(self)
+    }
+
+    /// Logical plan of the request, with the settings applied.
+    pub fn to_plan(&self) -> Result<Plan, OverpassError> {
+        if let Some(error) = self.validate().errors.into_iter().next() {
+            return Err(error);
+        }
+        let mut plan = self.subrequest_with_settings().to_plan("_")?;
+        plan.define_default_set();
+        Ok(plan)
+    }
+
     pub fn to_sql(
         &self,
         sql_dialect: &(dyn SqlDialect + Send + Sync),
         srid: &str,
         _finalizer: Option<&str>,
-    ) -> Vec<String> {
-        let mut select = self.subrequest.to_sql(sql_dialect, srid, "_");
+    ) -> Result<Vec<String>, OverpassError> {
+        let mut select = self.to_plan()?.to_sql(sql_dialect, srid)?;
         let timeout = sql_dialect.statement_timeout(self.timeout.unwrap_or(180).min(500) * 1000);
         if let Some(t) = timeout {
             select.insert(0, t);
         }
-        select
+        Ok(select)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{overpass_parser::parse_query, sql_dialect::postgres::postgres::Postgres};
+    use proptest::{collection::vec, option, prelude::*, sample::select};
 
     use super::*;
 
@@ -91,12 +235,18 @@ mod tests {
                 [foo~\"bar|baz\"]
                 (1, 2, 3, 4);
             out;",
+            //
+            // Block statements without ;
+            "node[amenity];for(t[\"amenity\"]){out;}",
+            "node[amenity];if (count(nodes) > 1) { out; } else { out count; } out;",
+            "way(1);complete { way(bn); }",
+            "way(1);(complete { way(bn); } node(w););out;",
         ];
         let _ = queries.map(|query| {
             match parse_query(query) {
                 Ok(request) => {
                     let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
-                    let sql = request.to_sql(d, "9999", None);
+                    let sql = request.to_sql(d, "9999", None).unwrap();
                     assert_ne!(vec![""], sql);
                 }
                 Err(e) => {
@@ -106,4 +256,537 @@ mod tests {
             };
         });
     }
+
+    #[test]
+    fn test_parse_metadata() {
+        let request = parse_query("[bbox:0,0,1,1];node[amenity];out;").unwrap();
+        assert_eq!(OutFormat::Json, request.format);
+        assert_eq!(Some((0.0, 0.0, 1.0, 1.0)), request.bbox);
+
+        let request = parse_query("[timeout:25];node[amenity];out;").unwrap();
+        assert_eq!(OutFormat::Json, request.format);
+        assert_eq!(Some(25), request.timeout);
+
+        let request = parse_query("[timeout:25][out:xml];node[amenity];out;").unwrap();
+        assert_eq!(OutFormat::Xml, request.format);
+        assert_eq!(Some(25), request.timeout);
+    }
+
+    #[test]
+    fn test_missing_statement_end() {
+        match parse_query("node[amenity] out;") {
+            Err(OverpassError::Syntax { span, .. }) => assert_eq!(14, span.start),
+            other => panic!("Expected a syntax error, got {other:?}"),
+        }
+        match parse_query("node(1);foreach(out;)") {
+            Err(OverpassError::Syntax { span, .. }) => assert_eq!(20, span.start),
+            other => panic!("Expected a syntax error, got {other:?}"),
+        }
+        match parse_query("node(1);if (1) (out;) out;") {
+            Err(OverpassError::Syntax { span, .. }) => assert_eq!(20, span.start),
+            other => panic!("Expected a syntax error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_timeout() {
+        match parse_query("[out:json][timeout:2.5];node;") {
+            Err(OverpassError::Semantic { span, .. }) => assert_eq!(19, span.start),
+            other => panic!("Expected a semantic error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_date_settings() {
+        let request = parse_query(
+            "[out:json][date:\"2024-01-01T00:00:00Z\"][timeout:25];node[amenity]->.a;(way.a;);",
+        )
+        .unwrap();
+        assert_eq!(Some(25), request.timeout);
+        assert_eq!(Some("2024-01-01T00:00:00Z".into()), request.date);
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let sql = request.to_sql(d, "9999", None).unwrap().join("\n");
+        // Only queries on the database are restricted
+        assert_eq!(
+            1,
+            sql.matches("node_by_geom.created <= '2024-01-01T00:00:00Z'::timestamp")
+                .count()
+        );
+        assert!(!sql.contains("_a.created"));
+
+        let request = parse_query(
+            "[out:json][diff:\"2024-01-01T00:00:00Z\",\"2024-02-01T00:00:00Z\"];(node;way;);",
+        )
+        .unwrap();
+        assert_eq!(
+            Some((
+                "2024-01-01T00:00:00Z".into(),
+                Some("2024-02-01T00:00:00Z".into())
+            )),
+            request.diff
+        );
+        let sql = request.to_sql(d, "9999", None).unwrap().join("\n");
+        assert!(sql.contains(
+            "way_by_geom.created > '2024-01-01T00:00:00Z'::timestamp AND way_by_geom.created <= '2024-02-01T00:00:00Z'::timestamp"
+        ));
+        assert!(
+            request
+                .to_overpass()
+                .starts_with("[out:json][diff:\"2024-01-01T00:00:00Z\",\"2024-02-01T00:00:00Z\"];")
+        );
+    }
+
+    #[test]
+    fn test_bbox_setting() {
+        let request = parse_query(
+            "[out:json][bbox:43.5,-1.5,44,-1.0];node[amenity]->.a;node(around.a:10);way.a;",
+        )
+        .unwrap();
+        assert_eq!(Some((43.5, -1.5, 44.0, -1.0)), request.bbox);
+        assert_eq!(
+            "[out:json][bbox:43.5,-1.5,44,-1.0];",
+            request.to_overpass().lines().next().unwrap()
+        );
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        let sql = request.to_sql(d, "9999", None).unwrap().join("\n");
+        // Only on the query without spatial filter and not from a set
+        assert_eq!(
+            1,
+            sql.matches("ST_Envelope('SRID=4326;LINESTRING(-1.5 43.5, -1 44)'::geometry)")
+                .count()
+        );
+        assert!(sql.contains("node_by_geom.geom"));
+    }
+
+    #[test]
+    fn test_undefined_set() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        match parse_query("nwr(area.b)[amenity];")
+            .unwrap()
+            .to_sql(d, "9999", None)
+        {
+            Err(OverpassError::Semantic { message, .. }) => assert_eq!("Undefined set .b", message),
+            other => panic!("Expected a semantic error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_to_overpass() {
+        assert_eq!(
+            "[out:json][timeout:25];
+node[!loop][amenity=drinking_water][foo~\"bar|baz\"](1,2,3,4)->.a;
+(
+  .a >;
+  way(bn);
+);
+.a out geom meta;",
+            parse_query(
+                "[out:json][timeout:25];
+                node[\"amenity\"=\"drinking_water\"][!loop](1, 2, 3, 4)[foo~'bar|baz']->.a;
+                (.a>;way(bn););
+                .a out meta;"
+            )
+            .unwrap()
+            .to_overpass()
+        );
+        assert_eq!(
+            "[out:json];\nnode;",
+            parse_query("[out:json];node;").unwrap().to_overpass()
+        );
+    }
+
+    fn name() -> impl Strategy<Value = String> {
+        "[a-z][a-z0-9_-]{0,4}"
+    }
+
+    fn quote(value: &str) -> String {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    fn string() -> impl Strategy<Value = String> {
+        prop_oneof![
+            "[a-z_][a-z0-9_]{0,6}",
+            r#"[a-zA-Z0-9 :_'"\\+.-]{0,6}"#.prop_map(|value| quote(&value)),
+        ]
+    }
+
+    fn number() -> impl Strategy<Value = String> {
+        prop_oneof![
+            (0_u8..90).prop_map(|n| n.to_string()),
+            (-90.0_f64..90.0).prop_map(|n| format!("{n:.4}")),
+            (-180.0_f64..180.0).prop_map(|n| format!("{n:.12}")),
+        ]
+    }
+
+    fn selector() -> impl Strategy<Value = String> {
+        prop_oneof![
+            string().prop_map(|key| format!("[{key}]")),
+            string().prop_map(|key| format!("[!{key}]")),
+            (string(), select(vec!["=", "!="]), string())
+                .prop_map(|(key, operator, value)| format!("[{key}{operator}{value}]")),
+            (
+                string(),
+                select(vec!["~", "!~"]),
+                "\\^?[a-z]{1,3}(\\.\\*|\\|[a-z]{1,2})?\\$?"
+            )
+                .prop_map(|(key, operator, value)| format!("[{key}{operator}{}]", quote(&value))),
+            (
+                string(),
+                select(vec!["~", "!~"]),
+                "[a-z]{1,3}\\.?",
+                select(vec!["", ",i", " , i"]),
+            )
+                .prop_map(|(key, operator, value, flag)| format!(
+                    "[{key}{operator}{}{flag}]",
+                    quote(&value)
+                )),
+            ("\\^?[a-z:]{1,4}", "[a-z]{1,3}\\.?", select(vec!["", ",i"])).prop_map(
+                |(key, value, flag)| format!("[~{}~{}{flag}]", quote(&key), quote(&value))
+            ),
+        ]
+    }
+
+    fn filter() -> impl Strategy<Value = String> {
+        prop_oneof![
+            (number(), number(), number(), number())
+                .prop_map(|(s, w, n, e)| format!("({s}, {w},{n},{e})")),
+            vec((number(), number()), 3..5).prop_map(|coords| format!(
+                "(poly:'{}')",
+                coords
+                    .iter()
+                    .map(|(lat, lon)| format!("{lat} {lon}"))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            )),
+            any::<u32>().prop_map(|id| format!("({id})")),
+            vec(any::<u32>(), 1..4).prop_map(|ids| format!(
+                "(id:{})",
+                ids.iter()
+                    .map(|id| id.to_string())
+                    .collect::<Vec<String>>()
+                    .join(",")
+            )),
+            name().prop_map(|set| format!("(area.{set})")),
+            (option::of(name()), number()).prop_map(|(set, radius)| format!(
+                "(around{}:{radius})",
+                set.map(|set| format!(".{set}")).unwrap_or_default()
+            )),
+            (number(), vec((number(), number()), 1..4)).prop_map(|(radius, coords)| format!(
+                "(around:{radius},{})",
+                coords
+                    .iter()
+                    .map(|(lat, lon)| format!("{lat},{lon}"))
+                    .collect::<Vec<String>>()
+                    .join(",")
+            )),
+            date().prop_map(|date| format!("(newer:{date})")),
+            eval().prop_map(|eval| format!("(if:{eval})")),
+            (date(), option::of(date())).prop_map(|(since, until)| format!(
+                "(changed:{since}{})",
+                until.map(|until| format!(", {until}")).unwrap_or_default()
+            )),
+            (
+                select(vec!["bn", "bw", "br", "n", "w", "r"]),
+                input_set(),
+                option::of(prop_oneof![name(), Just("".to_string())])
+            )
+                .prop_map(|(r, set, role)| {
+                    let role = match role {
+                        Some(role) if r != "w" => format!(":\"{role}\""),
+                        _ => "".to_string(),
+                    };
+                    format!("({r}{set}{role})")
+                }),
+        ]
+    }
+
+    fn asignation() -> impl Strategy<Value = String> {
+        option::of(name()).prop_map(|set| set.map(|set| format!(" ->.{set}")).unwrap_or_default())
+    }
+
+    fn input_set() -> impl Strategy<Value = String> {
+        option::of(name()).prop_map(|set| set.map(|set| format!(".{set}")).unwrap_or_default())
+    }
+
+    fn query_objects() -> impl Strategy<Value = String> {
+        (
+            select(vec!["node", "way", "relation", "rel", "area", "nwr"]),
+            vec(name(), 0..3)
+                .prop_map(|sets| sets.iter().map(|set| format!(".{set}")).collect::<String>()),
+            vec(prop_oneof![selector(), filter()], 0..4),
+            asignation(),
+        )
+            .prop_map(|(object_type, set, parts, asignation)| {
+                format!("{object_type}{set}{}{asignation}", parts.concat())
+            })
+    }
+
+    fn query_recurse() -> impl Strategy<Value = String> {
+        (
+            input_set(),
+            select(vec!["<", "<<", ">", ">>"]),
+            asignation(),
+        )
+            .prop_map(|(set, recurse, asignation)| format!("{set} {recurse}{asignation}"))
+    }
+
+    fn query_is_in() -> impl Strategy<Value = String> {
+        prop_oneof![
+            (number(), number(), asignation())
+                .prop_map(|(lat, lon, asignation)| format!("is_in({lat},{lon}){asignation}")),
+            (input_set(), asignation())
+                .prop_map(|(set, asignation)| format!("{set} is_in{asignation}")),
+        ]
+    }
+
+    fn eval() -> impl Strategy<Value = String> {
+        prop_oneof![
+            name().prop_map(|function| format!("{function}()")),
+            string().prop_map(|key| format!("t[{key}]")),
+            "[a-z0-9]{1,4}",
+            "[a-z ]{0,4}".prop_map(|value| format!("'{value}'")),
+        ]
+        .prop_recursive(2, 8, 2, |inner| {
+            prop_oneof![
+                (name(), inner.clone()).prop_map(|(set, param)| format!("{set}.set({param})")),
+                Just("count(ways)".to_string()),
+                (
+                    select(vec!["number", "is_number", "is_tag", "date"]),
+                    inner.clone()
+                )
+                    .prop_map(|(function, param)| format!("{function}({param})")),
+                (select(vec!["!", "-"]), inner.clone())
+                    .prop_map(|(operator, operand)| format!("{operator}({operand})")),
+                (
+                    inner.clone(),
+                    select(vec![
+                        "||", "&&", "==", "!=", "<", "<=", ">", ">=", "+", "-", "*", "/"
+                    ]),
+                    inner.clone()
+                )
+                    .prop_map(|(left, operator, right)| format!("{left} {operator} {right}")),
+                (inner.clone(), inner.clone(), inner).prop_map(
+                    |(condition, then, otherwise)| format!("({condition}) ? {then} : {otherwise}")
+                ),
+            ]
+        })
+    }
+
+    fn query_convert() -> impl Strategy<Value = String> {
+        (
+            select(vec!["node", "way", "relation", "area", "nwr"]),
+            vec(
+                prop_oneof![
+                    Just("::=::".to_string()),
+                    string().prop_map(|key| format!("!{key}")),
+                    (string(), eval()).prop_map(|(key, eval)| format!("{key}={eval}")),
+                    (
+                        select(vec!["id", "type", "lat", "lon", "timestamp", "geom"]),
+                        eval()
+                    )
+                        .prop_map(|(key, eval)| format!("::{key}={eval}")),
+                ],
+                1..4,
+            ),
+        )
+            .prop_map(|(object_type, items)| format!("convert {object_type} {}", items.join(",")))
+    }
+
+    fn query_make() -> impl Strategy<Value = String> {
+        (
+            name(),
+            vec(
+                (string(), eval()).prop_map(|(key, eval)| format!("{key}={eval}")),
+                1..3,
+            ),
+            asignation(),
+        )
+            .prop_map(|(object_type, items, asignation)| {
+                format!("make {object_type} {}{asignation}", items.join(","))
+            })
+    }
+
+    fn out() -> impl Strategy<Value = String> {
+        (
+            input_set(),
+            option::of(prop_oneof![
+                select(vec!["geom", "center", "bb"]).prop_map(|geom| geom.to_string()),
+                (number(), number(), number(), number())
+                    .prop_map(|(s, w, n, e)| format!("geom({s},{w},{n},{e})")),
+            ]),
+            option::of(select(vec!["ids", "skel", "body", "tags", "meta", "count"])),
+            option::of(select(vec!["asc", "qt"])),
+            option::of(0_u64..1000),
+            select(vec!["", "noids"]),
+        )
+            .prop_map(|(set, geom, level_of_details, sort, limit, noids)| {
+                format!(
+                    "{set} out {} {} {} {} {noids}",
+                    geom.unwrap_or_default(),
+                    level_of_details.unwrap_or_default(),
+                    limit.map(|limit| limit.to_string()).unwrap_or_default(),
+                    sort.unwrap_or_default(),
+                )
+            })
+    }
+
+    fn statements(statement: impl Strategy<Value = String>) -> impl Strategy<Value = String> {
+        vec(statement, 1..4).prop_map(|statements| format!("{};", statements.join(";\n")))
+    }
+
+    fn query_sequence() -> impl Strategy<Value = String> {
+        prop_oneof![
+            query_objects(),
+            query_recurse(),
+            query_is_in(),
+            query_convert(),
+            query_make()
+        ]
+        .prop_recursive(3, 16, 3, |inner| {
+            prop_oneof![
+                (statements(inner.clone()), asignation())
+                    .prop_map(|(queries, asignation)| format!("({queries}){asignation}")),
+                (inner.clone(), inner.clone(), asignation()).prop_map(
+                    |(base, minus, asignation)| format!("({base}; - {minus};){asignation}")
+                ),
+                (
+                    input_set(),
+                    asignation(),
+                    statements(prop_oneof![inner.clone(), out()])
+                )
+                    .prop_map(|(set, asignation, body)| format!(
+                        "foreach{set}{asignation}({body})"
+                    )),
+                (
+                    input_set(),
+                    asignation(),
+                    eval(),
+                    statements(prop_oneof![inner.clone(), out()])
+                )
+                    .prop_map(|(set, asignation, value, body)| format!(
+                        "for{set}{asignation} ({value}) {{{body}}}"
+                    )),
+                (
+                    eval(),
+                    statements(prop_oneof![inner.clone(), out()]),
+                    option::of(statements(prop_oneof![inner.clone(), out()]))
+                )
+                    .prop_map(|(condition, then, otherwise)| format!(
+                        "if ({condition}) ({then}){}",
+                        otherwise
+                            .map(|otherwise| format!(" else {{{otherwise}}}"))
+                            .unwrap_or_default()
+                    )),
+                (
+                    input_set(),
+                    asignation(),
+                    option::of(1_u64..100),
+                    statements(inner.clone())
+                )
+                    .prop_map(|(set, asignation, limit, body)| format!(
+                        "complete{}{set}{asignation} {{{body}}}",
+                        limit.map(|limit| format!("({limit})")).unwrap_or_default()
+                    )),
+                (date(), statements(inner.clone()))
+                    .prop_map(|(date, body)| format!("retro ({date}) {{{body}}}")),
+                (
+                    input_set(),
+                    asignation(),
+                    option::of(eval()),
+                    statements(inner)
+                )
+                    .prop_map(|(set, asignation, delta, body)| format!(
+                        "compare{set}{asignation}{} {{{body}}}",
+                        delta
+                            .map(|delta| format!("(delta: {delta})"))
+                            .unwrap_or_default()
+                    )),
+            ]
+        })
+    }
+
+    fn date() -> impl Strategy<Value = String> {
+        (2004_u32..2030, 1_u32..13, 1_u32..29, 0_u32..24)
+            .prop_map(|(y, m, d, h)| format!("\"{y}-{m:02}-{d:02}T{h:02}:00:00Z\""))
+    }
+
+    fn request() -> impl Strategy<Value = String> {
+        (
+            option::of((
+                option::of(prop_oneof![
+                    Just("json".to_string()),
+                    Just("xml".to_string()),
+                    Just("geojson".to_string()),
+                    (
+                        vec(
+                            prop_oneof![
+                                Just("::id".to_string()),
+                                Just("::lat".to_string()),
+                                name()
+                            ],
+                            1..4
+                        ),
+                        option::of((any::<bool>(), option::of(select(vec![",", ";", "|"])))),
+                    )
+                        .prop_map(|(fields, options)| format!(
+                            "csv({}{})",
+                            fields.join(","),
+                            options
+                                .map(|(header, separator)| format!(
+                                    ";{header}{}",
+                                    separator
+                                        .map(|separator| format!(";\"{separator}\""))
+                                        .unwrap_or_default()
+                                ))
+                                .unwrap_or_default()
+                        )),
+                ]),
+                option::of(0_u32..1000),
+                option::of(date()),
+                option::of((date(), option::of(date()))),
+                option::of((number(), number(), number(), number())),
+            )),
+            statements(prop_oneof![query_sequence(), out()]),
+        )
+            .prop_flat_map(|(metadata, subrequest)| {
+                let settings = match metadata {
+                    None => Vec::new(),
+                    Some((format, timeout, date, diff, bbox)) => [
+                        format.map(|format| format!("[out:{format}]")),
+                        timeout.map(|timeout| format!("[timeout:{timeout}]")),
+                        date.map(|date| format!("[date:{date}]")),
+                        diff.map(|(since, until)| {
+                            format!(
+                                "[diff:{since}{}]",
+                                until.map(|until| format!(",{until}")).unwrap_or_default()
+                            )
+                        }),
+                        bbox.map(|(s, w, n, e)| format!("[bbox:{s},{w},{n},{e}]")),
+                    ]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<String>>(),
+                };
+                // In any order
+                Just(settings).prop_shuffle().prop_map(move |settings| {
+                    let metadata = if settings.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{};\n", settings.concat())
+                    };
+                    format!("{metadata}{subrequest}")
+                })
+            })
+    }
+
+    proptest! {
+        #[test]
+        fn test_to_overpass_round_trip(query in request()) {
+            let request = parse_query(&query).unwrap();
+            let overpass = request.to_overpass();
+            let round_trip = parse_query(&overpass).unwrap();
+            prop_assert_eq!(&request, &round_trip);
+            prop_assert_eq!(overpass, round_trip.to_overpass());
+        }
+    }
 }
@@ -1,202 +1,329 @@
 use pest::iterators::Pair;
-use regex::Regex;
+use regex::{Regex, RegexBuilder};
 
 use derivative::Derivative;
 
-use crate::sql_dialect::sql_dialect::SqlDialect;
-use std::collections::HashMap;
+use crate::{
+    error::{OverpassError, Span},
+    sql_dialect::sql_dialect::SqlDialect,
+};
+use std::{borrow::Cow, collections::HashMap};
 
 use super::Rule;
 
 #[derive(Derivative)]
-#[derivative(Default)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct Selector {
     #[derivative(Default(value = "false"))]
     pub not: bool,
     pub key: Box<str>,
+    /// Compiled `key` when matching keys with a regular expression.
+    #[derivative(PartialEq(compare_with = "regex_eq"))]
+    pub key_regex: Option<Regex>,
     pub operator: Option<Box<str>>,
     pub value: Option<Box<str>>,
+    #[derivative(PartialEq(compare_with = "regex_eq"))]
     pub value_regex: Option<Regex>,
+    pub case_insensitive: bool,
+    #[derivative(PartialEq = "ignore")]
+    pub span: Span,
+}
+
+fn regex_eq(a: &Option<Regex>, b: &Option<Regex>) -> bool {
+    a.as_ref().map(|r| r.as_str()) == b.as_ref().map(|r| r.as_str())
 }
 
 impl Selector {
-    pub fn unquote(value: &str) -> &str {
-        if (value.starts_with('"') && value.ends_with('"'))
-            || (value.starts_with('\'') && value.ends_with('\''))
+    /// Remove the quotes around a string and resolve the `\\`, `\"` and
+    /// `\'` escapes. Other backslashes are kept, as they are mostly part of
+    /// regular expressions.
+    pub fn unquote(value: &str) -> Cow<'_, str> {
+        if value.len() < 2
+            || !((value.starts_with('"') && value.ends_with('"'))
+                || (value.starts_with('\'') && value.ends_with('\'')))
         {
-            &value[1..value.len() - 1]
+            return Cow::Borrowed(value);
+        }
+        let value = &value[1..value.len() - 1];
+        if !value.contains('\\') {
+            return Cow::Borrowed(value);
+        }
+        let mut unquoted = String::with_capacity(value.len());
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (c, chars.peek()) {
+                ('\\', Some(&next @ ('\\' | '"' | '\''))) => {
+                    unquoted.push(next);
+                    chars.next();
+                }
+                _ => unquoted.push(c),
+            }
+        }
+        Cow::Owned(unquoted)
+    }
+
+    /// Quote a string when it is not a plain identifier, reverse of `unquote`.
+    pub fn overpass_quote(value: &str) -> String {
+        let id_char = Regex::new(r"^[-_a-zA-Z0-9]+$").unwrap().is_match(value);
+        if id_char {
+            return value.to_string();
+        }
+        let quote = if value.contains('"') && !value.contains('\'') {
+            '\''
         } else {
-            value
+            '"'
+        };
+        let mut quoted = String::with_capacity(value.len() + 2);
+        quoted.push(quote);
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == quote || (c == '\\' && matches!(chars.peek(), None | Some('\\' | '"' | '\''))) {
+                quoted.push('\\');
+            }
+            quoted.push(c);
         }
+        quoted.push(quote);
+        quoted
     }
 
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
-        let mut selector = Selector::default();
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
+        let mut selector = Selector {
+            span: pair.as_span().into(),
+            ..Selector::default()
+        };
+        let mut is_key_regex = false;
+        let mut key_span = selector.span;
+        let mut value = None;
         for inner_pair in pair.into_inner() {
             match inner_pair.as_rule() {
                 Rule::not => {
                     selector.not = inner_pair.as_str() == "!";
                 }
+                Rule::key_regex => {
+                    is_key_regex = true;
+                }
                 Rule::key => {
+                    key_span = inner_pair.as_span().into();
                     selector.key = Self::unquote(inner_pair.as_str()).into();
                 }
                 Rule::operator => {
-                    selector.operator = Some(inner_pair.as_str().into());
+                    let operator = inner_pair.as_str();
+                    if !matches!(operator, "=" | "!=" | "~" | "!~") {
+                        return Err(OverpassError::unsupported(
+                            format!("Unsupported operator {operator:?}"),
+                            inner_pair.as_span(),
+                        ));
+                    }
+                    selector.operator = Some(operator.into());
                 }
                 Rule::value => {
-                    let value = Self::unquote(inner_pair.as_str());
-                    let operator = selector.operator.as_deref().unwrap();
-                    if operator == "~" || operator == "!~" {
-                        selector.value_regex = Regex::new(value).ok();
-                    } else {
-                        selector.value = Some(value.into());
-                    }
+                    value = Some((
+                        Self::unquote(inner_pair.as_str()).into_owned(),
+                        Span::from(inner_pair.as_span()),
+                    ));
+                }
+                Rule::case_insensitive => {
+                    selector.case_insensitive = true;
                 }
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!(
-                                "Invalid rule {:?} for Selector",
-                                inner_pair.as_rule()
-                            ),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for Selector", inner_pair.as_rule()),
                         inner_pair.as_span(),
                     ));
                 }
             }
         }
+
+        let operator = selector.operator.as_deref();
+        if is_key_regex {
+            if selector.not || operator != Some("~") {
+                return Err(OverpassError::semantic(
+                    "Regular expression key requires a regular expression value",
+                    selector.span,
+                ));
+            }
+            selector.key_regex = Some(selector.regex(&selector.key, key_span)?);
+        }
+        if let Some((value, value_span)) = value {
+            if matches!(operator, Some("~" | "!~")) {
+                selector.value_regex = Some(selector.regex(&value, value_span)?);
+            } else if selector.case_insensitive {
+                return Err(OverpassError::semantic(
+                    "Case insensitive flag requires a regular expression value",
+                    selector.span,
+                ));
+            } else {
+                selector.value = Some(value.into());
+            }
+        }
         Ok(selector)
     }
 
-    pub fn matches(&self, tags: &HashMap<&str, &str>) -> Option<Vec<&str>> {
+    fn regex(&self, pattern: &str, span: Span) -> Result<Regex, OverpassError> {
+        RegexBuilder::new(pattern)
+            .case_insensitive(self.case_insensitive)
+            .build()
+            .map_err(|e| {
+                OverpassError::syntax(format!("Invalid regular expression {pattern:?}: {e}"), span)
+            })
+    }
+
+    pub fn matches<'a>(
+        &'a self,
+        tags: &HashMap<&'a str, &'a str>,
+    ) -> Result<Option<Vec<&'a str>>, OverpassError> {
+        if let Some(key_regex) = &self.key_regex {
+            let Some(value_regex) = &self.value_regex else {
+                return Err(self.unsupported_operator());
+            };
+            let keys = tags
+                .iter()
+                .filter(|(key, value)| key_regex.is_match(key) && value_regex.is_match(value))
+                .map(|(key, _)| *key)
+                .collect::<Vec<&str>>();
+            return Ok((!keys.is_empty()).then_some(keys));
+        }
+
         let m = if self.operator.is_none() {
             let mut c = tags.contains_key(self.key.as_ref());
             if self.not {
                 if !c {
-                    return Some(vec![]);
+                    return Ok(Some(vec![]));
                 } else {
                     c = !c;
                 }
             }
             c
         } else if !tags.contains_key(self.key.as_ref()) {
-            false
+            // Negated operators also match elements without the key
+            return Ok(matches!(self.operator.as_deref(), Some("!=" | "!~")).then(Vec::new));
         } else {
             let value = tags[self.key.as_ref()];
-            let operator = self.operator.as_deref().unwrap();
-            if self.value.is_some() {
-                let self_value = self.value.as_deref().unwrap();
-                match operator {
-                    "=" => value == self_value,
-                    "!=" => value != self_value,
-                    _ => panic!("unknow operator {:?}", self.operator),
-                }
-            } else {
-                let self_value = self.value_regex.clone().unwrap();
-                match operator {
-                    "~" => self_value.is_match(value),
-                    "!~" => self_value.is_match(value),
-                    _ => panic!("unknow operator {self:?}"),
-                }
+            let operator = self.operator.as_deref().unwrap_or_default();
+            match (operator, &self.value, &self.value_regex) {
+                ("=", Some(self_value), _) => value == self_value.as_ref(),
+                ("!=", Some(self_value), _) => value != self_value.as_ref(),
+                ("~", _, Some(self_value)) => self_value.is_match(value),
+                ("!~", _, Some(self_value)) => !self_value.is_match(value),
+                _ => return Err(self.unsupported_operator()),
             }
         };
 
-        if m { Some(vec![&self.key]) } else { None }
+        Ok(if m { Some(vec![&self.key]) } else { None })
     }
 
-    pub fn to_sql(&self, sql_dialect: &(dyn SqlDialect + Send + Sync), table: &str, _srid: &str) -> String {
-        let key = sql_dialect.hash_exists(table, &self.key);
-        if self.operator.is_none() {
-            if self.not { format!("NOT {key}") } else { key }
-        } else {
-            let op = self.operator.as_deref().unwrap();
-            let value = match self.value.as_deref() {
-                Some(value) => sql_dialect.escape_literal(value),
-                None => match self.value_regex.as_ref() {
-                    Some(regex) => sql_dialect.escape_literal(regex.as_str()),
-                    None => panic!("Selector without value or value_regex"),
-                },
+    fn unsupported_operator(&self) -> OverpassError {
+        OverpassError::unsupported(
+            format!(
+                "Unsupported operator {:?} for key {:?}",
+                self.operator.as_deref().unwrap_or(""),
+                self.key
+            ),
+            self.span,
+        )
+    }
+
+    pub fn to_sql(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        table: &str,
+        _srid: &str,
+    ) -> Result<String, OverpassError> {
+        if self.key_regex.is_some() {
+            let Some(value_regex) = &self.value_regex else {
+                return Err(self.unsupported_operator());
             };
-            match op {
-                "=" => {
-                    if value.is_empty() {
-                        format!("NOT {key}")
-                    } else {
-                        format!(
-                            "({} AND {} = {})",
-                            key,
-                            sql_dialect.hash_get(table, &self.key),
-                            value
-                        )
-                    }
-                }
-                "!=" => {
-                    format!(
-                        "(NOT {} OR {} != {})",
-                        key,
-                        sql_dialect.hash_get(table, &self.key),
-                        value
-                    )
-                }
-                "~" => {
-                    format!(
-                        "({} AND {} ~ {})",
-                        key,
-                        sql_dialect.hash_get(table, &self.key),
-                        value
-                    )
+            return Ok(format!(
+                "EXISTS (SELECT 1 FROM {} WHERE {} AND {})",
+                sql_dialect.hash_each(table),
+                sql_dialect.regex_match("tag.key", &self.key, false, self.case_insensitive),
+                sql_dialect.regex_match(
+                    "tag.value",
+                    value_regex.as_str(),
+                    false,
+                    self.case_insensitive
+                ),
+            ));
+        }
+
+        let key = sql_dialect.hash_exists(table, &self.key);
+        let Some(op) = self.operator.as_deref() else {
+            return Ok(if self.not { format!("NOT {key}") } else { key });
+        };
+        let value = match self.value.as_deref() {
+            Some(value) => sql_dialect.escape_literal(value),
+            None => match self.value_regex.as_ref() {
+                Some(regex) => sql_dialect.escape_literal(regex.as_str()),
+                None => {
+                    return Err(OverpassError::semantic(
+                        format!("Selector on key {:?} without value", self.key),
+                        self.span,
+                    ));
                 }
-                "!~" => {
+            },
+        };
+        Ok(match op {
+            "=" => {
+                if value.is_empty() {
+                    format!("NOT {key}")
+                } else {
                     format!(
-                        "(NOT {} OR {} !~ {})",
+                        "({} AND {} = {})",
                         key,
                         sql_dialect.hash_get(table, &self.key),
                         value
                     )
                 }
-                _ => {
-                    panic!(
-                        "Unsupported operator '{}' for key '{}'",
-                        self.operator.as_deref().unwrap_or(""),
-                        self.key
-                    )
-                }
             }
-        }
-    }
-
-    fn overpass_quote(&self, value: &str) -> String {
-        let simple_quote = value.contains('\'');
-        let double_quote = value.contains('"');
-        let id_char = Regex::new(r"^[-_a-zA-Z0-9]+$").unwrap().is_match(value);
-        if simple_quote || double_quote || !id_char {
-            if double_quote && !simple_quote {
-                format!("'{value}'")
-            } else {
-                format!("\"{}\"", value.replace('"', "\\\""))
+            "!=" => {
+                format!(
+                    "(NOT {} OR {} != {})",
+                    key,
+                    sql_dialect.hash_get(table, &self.key),
+                    value
+                )
             }
-        } else {
-            value.to_string()
-        }
+            "~" | "!~" => {
+                let regex_match = sql_dialect.regex_match(
+                    &sql_dialect.hash_get(table, &self.key),
+                    self.value_regex
+                        .as_ref()
+                        .map(|r| r.as_str())
+                        .unwrap_or_default(),
+                    op == "!~",
+                    self.case_insensitive,
+                );
+                if op == "~" {
+                    format!("({key} AND {regex_match})")
+                } else {
+                    format!("(NOT {key} OR {regex_match})")
+                }
+            }
+            _ => return Err(self.unsupported_operator()),
+        })
     }
 
     pub fn to_overpass(&self) -> String {
         let mut s = String::new();
         s.push('[');
+        if self.not {
+            s.push('!');
+        }
+        if self.key_regex.is_some() {
+            s.push('~');
+        }
+        s.push_str(Self::overpass_quote(&self.key).as_str());
         if let Some(op) = &self.operator {
-            s.push_str(self.overpass_quote(&self.key).as_str());
             s.push_str(op);
             if let Some(value) = &self.value {
-                s.push_str(self.overpass_quote(value).as_str());
+                s.push_str(Self::overpass_quote(value).as_str());
             } else if let Some(regex) = &self.value_regex {
-                s.push_str(self.overpass_quote(regex.as_str()).as_str())
+                s.push_str(Self::overpass_quote(regex.as_str()).as_str())
             }
-        } else {
-            if self.not {
-                s.push('!');
+            if self.case_insensitive {
+                s.push_str(",i");
             }
-            s.push_str(self.overpass_quote(&self.key).as_str());
         }
         s.push(']');
         s
@@ -210,8 +337,15 @@ pub struct Selectors {
     pub selectors: Vec<Selector>,
 }
 
+/// Selectors are a conjunction, equality does not depend on their order.
+impl PartialEq for Selectors {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_overpass() == other.to_overpass()
+    }
+}
+
 impl Selectors {
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
         let mut selectors = Vec::new();
         for inner_pair in pair.into_inner() {
             selectors.push(Selector::from_pest(inner_pair)?);
@@ -219,31 +353,37 @@ impl Selectors {
         Ok(Selectors { selectors })
     }
 
-    pub fn matches(&self, tags: &HashMap<&str, &str>) -> Option<Vec<&str>> {
+    pub fn matches<'a>(
+        &'a self,
+        tags: &HashMap<&'a str, &'a str>,
+    ) -> Result<Option<Vec<&'a str>>, OverpassError> {
         let m = self
             .selectors
             .iter()
             .map(|selector| selector.matches(tags))
-            .collect::<Vec<Option<Vec<&str>>>>();
-        if m.clone().into_iter().any(|n| n.is_none()) {
-            None
+            .collect::<Result<Vec<Option<Vec<&str>>>, OverpassError>>()?;
+        if m.iter().any(|n| n.is_none()) {
+            Ok(None)
         } else {
-            let mut n = m
-                .into_iter()
-                .flat_map(|n| n.unwrap())
-                .collect::<Vec<&str>>();
+            let mut n = m.into_iter().flatten().flatten().collect::<Vec<&str>>();
             n.sort();
             n.dedup();
-            Some(n)
+            Ok(Some(n))
         }
     }
 
-    pub fn to_sql(&self, sql_dialect: &(dyn SqlDialect + Send + Sync), table: &str, srid: &str) -> String {
-        self.selectors
+    pub fn to_sql(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        table: &str,
+        srid: &str,
+    ) -> Result<String, OverpassError> {
+        Ok(self
+            .selectors
             .iter()
             .map(|selector| selector.to_sql(sql_dialect, table, srid))
-            .collect::<Vec<String>>()
-            .join(" AND ")
+            .collect::<Result<Vec<String>, OverpassError>>()?
+            .join(" AND "))
     }
 
     pub fn to_overpass(&self) -> String {
@@ -260,14 +400,20 @@ impl Selectors {
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
+    use regex::Regex;
+    use rusqlite::{Connection, functions::FunctionFlags};
     use std::collections::HashMap;
 
     use crate::{
+        error::{OverpassError, Span},
         overpass_parser::{
             parse_query,
             subrequest::{QueryType, SubrequestType},
         },
-        sql_dialect::{postgres::postgres::Postgres, sql_dialect::SqlDialect},
+        sql_dialect::{
+            duckdb::duckdb::Duckdb, postgres::postgres::Postgres, sql_dialect::SqlDialect,
+            sqlite::sqlite::Sqlite,
+        },
     };
 
     use super::Selectors;
@@ -275,13 +421,9 @@ mod tests {
     fn parse(query: &str) -> Selectors {
         match parse_query(format!("node{query};").as_str()) {
             Ok(parsed) => match parsed.subrequest.queries[0].as_ref() {
-                SubrequestType::QueryType(query_type) => match query_type {
-                    QueryType::QueryObjects(query_objets) => query_objets.selectors.clone(),
-                    _ => panic!(
-                        "Expected a QueryObjects, got {:?}",
-                        parsed.subrequest.queries[0]
-                    ),
-                },
+                SubrequestType::QueryType(QueryType::QueryObjects(query_objets)) => {
+                    query_objets.selectors.clone()
+                }
                 _ => panic!(
                     "Expected a QueryObjects, got {:?}",
                     parsed.subrequest.queries[0]
@@ -295,52 +437,117 @@ mod tests {
     fn test_match_value() {
         let selector = parse("[p]");
         assert_eq!(
-            selector.matches(&HashMap::from([("p", "+48")])),
+            selector.matches(&HashMap::from([("p", "+48")])).unwrap(),
             Some(vec!["p"])
         );
 
         let selector = parse("[p=\"+48\"]");
         assert_eq!(
-            selector.matches(&HashMap::from([("p", "+48")])),
+            selector.matches(&HashMap::from([("p", "+48")])).unwrap(),
             Some(vec!["p"])
         );
-        assert_eq!(selector.matches(&HashMap::from([("p", "+4")])), None);
+        assert_eq!(
+            selector.matches(&HashMap::from([("p", "+4")])).unwrap(),
+            None
+        );
 
         let selector = parse("[p~4]");
         assert_eq!(
-            selector.matches(&HashMap::from([("p", "+48")])),
+            selector.matches(&HashMap::from([("p", "+48")])).unwrap(),
             Some(vec!["p"])
         );
-        assert_eq!(selector.matches(&HashMap::from([("p", "+5")])), None);
+        assert_eq!(
+            selector.matches(&HashMap::from([("p", "+5")])).unwrap(),
+            None
+        );
+
+        let selector = parse("[p!~4]");
+        assert_eq!(
+            selector.matches(&HashMap::from([("p", "+48")])).unwrap(),
+            None
+        );
+        assert_eq!(
+            selector.matches(&HashMap::from([("p", "+5")])).unwrap(),
+            Some(vec!["p"])
+        );
+        assert_eq!(selector.matches(&HashMap::from([])).unwrap(), Some(vec![]));
 
         let selector = parse("[highway=footway][footway=traffic_island]");
         assert_eq!(
-            selector.matches(&HashMap::from([("footway", "highway")])),
+            selector
+                .matches(&HashMap::from([("footway", "highway")]))
+                .unwrap(),
             None
         );
         assert_eq!(
-            selector.matches(&HashMap::from([
-                ("highway", "footway"),
-                ("footway", "traffic_island")
-            ])),
+            selector
+                .matches(&HashMap::from([
+                    ("highway", "footway"),
+                    ("footway", "traffic_island")
+                ]))
+                .unwrap(),
             Some(vec!["footway", "highway"])
         );
 
         let selector = parse("[highway=footway][!footway]");
         assert_eq!(
-            selector.matches(&HashMap::from([("highway", "footway")])),
+            selector
+                .matches(&HashMap::from([("highway", "footway")]))
+                .unwrap(),
             Some(vec!["highway"])
         );
 
         assert_eq!(
-            selector.matches(&HashMap::from([
-                ("highway", "footway"),
-                ("footway", "traffic_island")
-            ])),
+            selector
+                .matches(&HashMap::from([
+                    ("highway", "footway"),
+                    ("footway", "traffic_island")
+                ]))
+                .unwrap(),
             None
         );
     }
 
+    #[test]
+    fn test_match_case_insensitive_and_key_regex() {
+        let selector = parse("[name~\"^rue\",i]");
+        assert_eq!(
+            selector
+                .matches(&HashMap::from([("name", "Rue de la Paix")]))
+                .unwrap(),
+            Some(vec!["name"])
+        );
+        assert_eq!(
+            parse("[name~\"^rue\"]")
+                .matches(&HashMap::from([("name", "Rue de la Paix")]))
+                .unwrap(),
+            None
+        );
+
+        let selector = parse("[~\"^addr:.*\"~\".\"]");
+        assert_eq!(
+            selector
+                .matches(&HashMap::from([("addr:street", "Rue"), ("name", "A")]))
+                .unwrap(),
+            Some(vec!["addr:street"])
+        );
+        assert_eq!(
+            selector
+                .matches(&HashMap::from([("addr:street", ""), ("name", "A")]))
+                .unwrap(),
+            None
+        );
+
+        assert!(matches!(
+            parse_query("node[~\"^addr\"=a];"),
+            Err(OverpassError::Semantic { .. })
+        ));
+        assert!(matches!(
+            parse_query("node[name=a,i];"),
+            Err(OverpassError::Semantic { .. })
+        ));
+    }
+
     #[test]
     fn test_matches_to_overpass() {
         let selector = parse("[amenity]");
@@ -365,41 +572,97 @@ mod tests {
         assert_eq!(selector.to_overpass(), "[phone=\"+42\"]");
         let selector = parse("[p~4]");
         assert_eq!(selector.to_overpass(), "[p~4]");
+        let selector = parse("[name~\"^rue\" , i][~\"^addr:.*\"~\".\",i]");
+        assert_eq!(
+            selector.to_overpass(),
+            "[name~\"^rue\",i][~\"^addr:.*\"~\".\",i]"
+        );
+        let selector = parse(r#"[name="l\"l'"][a='\d\'"']"#);
+        assert_eq!(selector.selectors[0].value.as_deref(), Some(r#"l"l'"#));
+        assert_eq!(selector.to_overpass(), r#"[a="\d'\""][name="l\"l'"]"#);
     }
 
     #[test]
     fn test_matches_to_sql() {
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
 
-        assert_eq!(parse("[\"amenity\"]").to_sql(d, "_", "9999"), "_.tags?'amenity'");
-        assert_eq!(parse("['amenity']").to_sql(d, "_", "9999"), "_.tags?'amenity'");
         assert_eq!(
-            parse("[shop=florist]").to_sql(d, "_", "9999"),
+            parse("[\"amenity\"]").to_sql(d, "_", "9999").unwrap(),
+            "_.tags?'amenity'"
+        );
+        assert_eq!(
+            parse("['amenity']").to_sql(d, "_", "9999").unwrap(),
+            "_.tags?'amenity'"
+        );
+        assert_eq!(
+            parse("[shop=florist]").to_sql(d, "_", "9999").unwrap(),
             "(_.tags?'shop' AND _.tags->>'shop' = 'florist')"
         );
         assert_eq!(
-            parse("[shop=\"florist\"]").to_sql(d, "_", "9999"),
+            parse("[shop=\"florist\"]").to_sql(d, "_", "9999").unwrap(),
             "(_.tags?'shop' AND _.tags->>'shop' = 'florist')"
         );
         assert_eq!(
-            parse(r#"[shop~"pizza.*"]"#).to_sql(d, "_", "9999"),
+            parse(r#"[shop~"pizza.*"]"#).to_sql(d, "_", "9999").unwrap(),
             "(_.tags?'shop' AND _.tags->>'shop' ~ 'pizza.*')"
         );
         assert_eq!(
-            parse("[highway=footway][footway=traffic_island]").to_sql(d, "_", "9999"),
+            parse("[highway=footway][footway=traffic_island]")
+                .to_sql(d, "_", "9999")
+                .unwrap(),
             "(_.tags?'highway' AND _.tags->>'highway' = 'footway') AND (_.tags?'footway' AND _.tags->>'footway' = 'traffic_island')"
         );
-        assert_eq!(parse("[!amenity]").to_sql(d, "_", "9999"), "NOT _.tags?'amenity'");
+        assert_eq!(
+            parse("[!amenity]").to_sql(d, "_", "9999").unwrap(),
+            "NOT _.tags?'amenity'"
+        );
+    }
+
+    #[test]
+    fn test_matches_to_sql_case_insensitive_and_key_regex() {
+        let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            parse(r#"[name~"^rue",i]"#).to_sql(d, "_", "9999").unwrap(),
+            "(_.tags?'name' AND _.tags->>'name' ~* '^rue')"
+        );
+        assert_eq!(
+            parse(r#"[name!~"^rue"]"#).to_sql(d, "_", "9999").unwrap(),
+            "(NOT _.tags?'name' OR _.tags->>'name' !~ '^rue')"
+        );
+        assert_eq!(
+            parse(r#"[~"^addr:.*"~"."]"#)
+                .to_sql(d, "_", "9999")
+                .unwrap(),
+            "EXISTS (SELECT 1 FROM jsonb_each_text(_.tags) AS tag(key, value) WHERE tag.key ~ '^addr:.*' AND tag.value ~ '.')"
+        );
+
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        assert_eq!(
+            parse(r#"[name~"^rue",i]"#).to_sql(d, "_", "9999").unwrap(),
+            "((_.tags->>'name') IS NOT NULL AND regexp_matches((_.tags->>'name'), '^rue', 'i'))"
+        );
+        assert_eq!(
+            parse(r#"[~"^addr:.*"~"."]"#)
+                .to_sql(d, "_", "9999")
+                .unwrap(),
+            "EXISTS (SELECT 1 FROM (SELECT key, _.tags->>key AS value FROM unnest(json_keys(_.tags)) AS k(key)) AS tag WHERE regexp_matches(tag.key, '^addr:.*') AND regexp_matches(tag.value, '.'))"
+        );
     }
 
     #[test]
     fn test_matches_to_sql_duckdb() {
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
 
-        assert_eq!(parse("[\"amenity\"]").to_sql(d, "_", "9999"), "_.tags?'amenity'");
-        assert_eq!(parse("['amenity']").to_sql(d, "_", "9999"), "_.tags?'amenity'");
         assert_eq!(
-            parse("[shop=florist]").to_sql(d, "_", "9999"),
+            parse("[\"amenity\"]").to_sql(d, "_", "9999").unwrap(),
+            "_.tags?'amenity'"
+        );
+        assert_eq!(
+            parse("['amenity']").to_sql(d, "_", "9999").unwrap(),
+            "_.tags?'amenity'"
+        );
+        assert_eq!(
+            parse("[shop=florist]").to_sql(d, "_", "9999").unwrap(),
             "(_.tags?'shop' AND _.tags->>'shop' = 'florist')"
         );
     }
@@ -408,12 +671,12 @@ mod tests {
     fn test_matches_to_sql_quote() {
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
         assert_eq!(
-            parse(r#"[name="l'l"]"#).to_sql(d, "_", "9999"),
+            parse(r#"[name="l'l"]"#).to_sql(d, "_", "9999").unwrap(),
             "(_.tags?'name' AND _.tags->>'name' = 'l''l')"
         );
         let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
         assert_eq!(
-            parse(r#"[name~"l'l"]"#).to_sql(d, "_", "9999"),
+            parse(r#"[name~"l'l"]"#).to_sql(d, "_", "9999").unwrap(),
             "(_.tags?'name' AND _.tags->>'name' ~ 'l''l')"
         );
 
@@ -421,11 +684,104 @@ mod tests {
             postgres_escape_literal: Some(Box::new(|s| format!("_{s}_"))),
         } as &(dyn SqlDialect + Send + Sync);
         assert_eq!(
-            parse(r#"[name="l'l"]"#).to_sql(d, "_", "9999"),
+            parse(r#"[name="l'l"]"#).to_sql(d, "_", "9999").unwrap(),
             "(_.tags?_name_ AND _.tags->>_name_ = _l'l_)"
         );
     }
 
+    #[test]
+    fn test_invalid_regex() {
+        match parse_query("node[name~\"(\"];") {
+            Err(OverpassError::Syntax { span, .. }) => {
+                assert_eq!(span, Span { start: 10, end: 13 })
+            }
+            other => panic!("Expected a syntax error, got {other:?}"),
+        }
+        match parse_query("node[~\"[\"~a];") {
+            Err(OverpassError::Syntax { span, .. }) => {
+                assert_eq!(span, Span { start: 6, end: 9 })
+            }
+            other => panic!("Expected a syntax error, got {other:?}"),
+        }
+    }
+
+    /// In memory matching and the SQL translation, run on SQLite, select
+    /// the same elements.
+    #[test]
+    fn test_conformance_memory_sql() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .create_scalar_function(
+                "regexp",
+                2,
+                FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+                |ctx| {
+                    let regex = Regex::new(&ctx.get::<String>(0)?)
+                        .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+                    Ok(ctx
+                        .get::<Option<String>>(1)?
+                        .map(|text| regex.is_match(&text)))
+                },
+            )
+            .unwrap();
+
+        let selectors = [
+            "[name]",
+            "[!name]",
+            "[name=rue]",
+            "[name!=rue]",
+            "[name~\"^rue\"]",
+            "[name!~\"^rue\"]",
+            "[name~\"^rue\",i]",
+            "[name!~\"^rue\",i]",
+            "[~\"^addr:\"~\".\"]",
+            "[~\"^ADDR:\"~\"^r\",i]",
+        ];
+        let tags = [
+            vec![],
+            vec![("name", "rue")],
+            vec![("name", "Rue de la Paix")],
+            vec![("name", "Avenue")],
+            vec![("addr:street", "Rue"), ("name", "A")],
+            vec![("addr:street", "")],
+        ];
+        let d = &Sqlite as &(dyn SqlDialect + Send + Sync);
+        for selector in selectors {
+            let selector = parse(selector);
+            let sql = format!(
+                "SELECT coalesce({}, false) FROM (SELECT ? AS tags) AS _",
+                selector.to_sql(d, "_", "4326").unwrap()
+            );
+            for tags in &tags {
+                let json = format!(
+                    "{{{}}}",
+                    tags.iter()
+                        .map(|(key, value)| format!("\"{key}\":\"{value}\""))
+                        .collect::<Vec<String>>()
+                        .join(",")
+                );
+                let in_memory = selector
+                    .matches(&HashMap::from_iter(tags.iter().copied()))
+                    .unwrap()
+                    .is_some();
+                let in_sql = connection
+                    .query_row(&sql, [json], |row| row.get::<_, bool>(0))
+                    .unwrap();
+                assert_eq!(in_memory, in_sql, "{} on {tags:?}", selector.to_overpass());
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsupported_operator() {
+        match parse_query("node[a~=b];") {
+            Err(OverpassError::Unsupported { span, .. }) => {
+                assert_eq!(span, Span { start: 6, end: 8 })
+            }
+            other => panic!("Expected an unsupported operator error, got {other:?}"),
+        }
+    }
+
     // #[test]
     // fn test_sort() {
     // let d = sql_dialect::postgres::postgres::Postgres::default();
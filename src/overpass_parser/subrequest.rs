@@ -1,46 +1,107 @@
-use std::{
-    collections::HashSet,
-    sync::atomic::{AtomicU64, Ordering},
-};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::overpass_parser::out::Out;
 use pest::iterators::Pair;
-use regex::Regex;
 
-use crate::sql_dialect::sql_dialect::SqlDialect;
+use crate::{
+    error::OverpassError,
+    plan::{Node, Plan, Step},
+};
 
 use derivative::Derivative;
 
 use super::{
-    Rule, query::Query, query_convert::QueryConvert, query_foreach::QueryForeach,
-    query_objects::QueryObjects, query_recurse::QueryRecurse, query_union::QueryUnion,
+    Rule, query::Query, query_compare::QueryCompare, query_complete::QueryComplete,
+    query_convert::QueryConvert, query_difference::QueryDifference, query_for::QueryFor,
+    query_foreach::QueryForeach, query_if::QueryIf, query_is_in::QueryIsIn, query_make::QueryMake,
+    query_objects::QueryObjects, query_recurse::QueryRecurse, query_retro::QueryRetro,
+    query_union::QueryUnion,
 };
 
 static COUNTER: AtomicU64 = AtomicU64::new(0);
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum QueryType {
     QueryObjects(QueryObjects),
     QueryUnion(QueryUnion),
+    QueryDifference(QueryDifference),
     QueryRecurse(QueryRecurse),
+    QueryIsIn(QueryIsIn),
     QueryForeach(QueryForeach),
+    QueryFor(QueryFor),
+    QueryIf(QueryIf),
+    QueryComplete(QueryComplete),
+    QueryRetro(QueryRetro),
+    QueryCompare(QueryCompare),
     QueryConvert(QueryConvert),
+    QueryMake(QueryMake),
 }
 
 impl QueryType {
+    /// Apply `f` to this query and the nested `QueryObjects`.
+    pub fn for_each_query_objects_mut(&mut self, f: &impl Fn(&mut QueryObjects)) {
+        match self {
+            QueryType::QueryObjects(query) => f(query),
+            QueryType::QueryUnion(query) => query
+                .queries
+                .iter_mut()
+                .for_each(|query| query.for_each_query_objects_mut(f)),
+            QueryType::QueryDifference(query) => {
+                query.base.for_each_query_objects_mut(f);
+                query.minus.for_each_query_objects_mut(f);
+            }
+            QueryType::QueryForeach(_)
+            | QueryType::QueryFor(_)
+            | QueryType::QueryIf(_)
+            | QueryType::QueryComplete(_)
+            | QueryType::QueryRetro(_)
+            | QueryType::QueryCompare(_) => self
+                .blocks_mut()
+                .into_iter()
+                .for_each(|block| block.for_each_query_objects_mut(f)),
+            QueryType::QueryRecurse(_)
+            | QueryType::QueryIsIn(_)
+            | QueryType::QueryConvert(_)
+            | QueryType::QueryMake(_) => {}
+        }
+    }
+
+    /// Bodies of the block statements.
+    pub fn blocks_mut(&mut self) -> Vec<&mut Subrequest> {
+        match self {
+            QueryType::QueryForeach(query) => vec![&mut query.body],
+            QueryType::QueryFor(query) => vec![&mut query.body],
+            QueryType::QueryIf(query) => {
+                let mut blocks = vec![&mut query.then];
+                blocks.extend(query.otherwise.as_mut());
+                blocks
+            }
+            QueryType::QueryComplete(query) => vec![&mut query.body],
+            QueryType::QueryRetro(query) => vec![&mut query.body],
+            QueryType::QueryCompare(query) => vec![&mut query.body],
+            _ => Vec::new(),
+        }
+    }
+
     pub fn asignation(&self) -> Option<Box<str>> {
         match self {
             QueryType::QueryObjects(query) => query.asignation.clone(),
             QueryType::QueryUnion(query) => query.asignation.clone(),
+            QueryType::QueryDifference(query) => query.asignation.clone(),
             QueryType::QueryRecurse(query) => query.asignation.clone(),
+            QueryType::QueryIsIn(query) => query.asignation.clone(),
             QueryType::QueryForeach(query) => query.loop_var.clone(),
+            QueryType::QueryFor(_) | QueryType::QueryIf(_) | QueryType::QueryRetro(_) => None,
+            QueryType::QueryComplete(query) => query.asignation.clone(),
+            QueryType::QueryCompare(query) => query.asignation.clone(),
             QueryType::QueryConvert(_) => None,
+            QueryType::QueryMake(query) => query.asignation.clone(),
         }
     }
 }
 
 impl Query for QueryType {
-    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, pest::error::Error<Rule>> {
+    fn from_pest(pair: Pair<Rule>) -> Result<Box<Self>, OverpassError> {
         match pair.as_rule() {
             Rule::query_object => {
                 let query_objects = QueryObjects::from_pest(pair)?;
@@ -50,62 +111,106 @@ impl Query for QueryType {
                 let query_union = QueryUnion::from_pest(pair)?;
                 Ok(Box::new(QueryType::QueryUnion(*query_union)))
             }
+            Rule::query_difference => {
+                let query_difference = QueryDifference::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryDifference(*query_difference)))
+            }
             Rule::query_recurse => {
                 let query_recurse = QueryRecurse::from_pest(pair)?;
                 Ok(Box::new(QueryType::QueryRecurse(*query_recurse)))
             }
+            Rule::query_is_in => {
+                let query_is_in = QueryIsIn::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryIsIn(*query_is_in)))
+            }
             Rule::query_foreach => {
                 let query_foreach = QueryForeach::from_pest(pair)?;
                 Ok(Box::new(QueryType::QueryForeach(*query_foreach)))
             }
+            Rule::query_for => {
+                let query_for = QueryFor::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryFor(*query_for)))
+            }
+            Rule::query_if => {
+                let query_if = QueryIf::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryIf(*query_if)))
+            }
+            Rule::query_complete => {
+                let query_complete = QueryComplete::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryComplete(*query_complete)))
+            }
+            Rule::query_retro => {
+                let query_retro = QueryRetro::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryRetro(*query_retro)))
+            }
+            Rule::query_compare => {
+                let query_compare = QueryCompare::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryCompare(*query_compare)))
+            }
             Rule::convert => {
                 let convert = QueryConvert::from_pest(pair)?;
                 Ok(Box::new(QueryType::QueryConvert(*convert)))
             }
-            _ => Err(pest::error::Error::new_from_span(
-                pest::error::ErrorVariant::CustomError {
-                    message: format!("Invalid rule {:?} for QueryType", pair.as_rule()),
-                },
+            Rule::make => {
+                let make = QueryMake::from_pest(pair)?;
+                Ok(Box::new(QueryType::QueryMake(*make)))
+            }
+            _ => Err(OverpassError::syntax(
+                format!("Invalid rule {:?} for QueryType", pair.as_rule()),
                 pair.as_span(),
             )),
         }
     }
 
-    fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        srid: &str,
-        default_set: &str,
-    ) -> Vec<SubrequestJoin> {
+    fn to_plan(&self, default_set: &str) -> Result<Vec<Node>, OverpassError> {
+        match self {
+            QueryType::QueryObjects(query) => query.to_plan(default_set),
+            QueryType::QueryUnion(query) => query.to_plan(default_set),
+            QueryType::QueryDifference(query) => query.to_plan(default_set),
+            QueryType::QueryRecurse(query) => query.to_plan(default_set),
+            QueryType::QueryIsIn(query) => query.to_plan(default_set),
+            QueryType::QueryForeach(query) => query.to_plan(default_set),
+            QueryType::QueryFor(query) => query.to_plan(default_set),
+            QueryType::QueryIf(query) => query.to_plan(default_set),
+            QueryType::QueryComplete(query) => query.to_plan(default_set),
+            QueryType::QueryRetro(query) => query.to_plan(default_set),
+            QueryType::QueryCompare(query) => query.to_plan(default_set),
+            QueryType::QueryConvert(query) => query.to_plan(default_set),
+            QueryType::QueryMake(query) => query.to_plan(default_set),
+        }
+    }
+
+    fn to_overpass(&self) -> String {
         match self {
-            QueryType::QueryObjects(query) => query.to_sql(sql_dialect, srid, default_set),
-            QueryType::QueryUnion(query) => query.to_sql(sql_dialect, srid, default_set),
-            QueryType::QueryRecurse(query) => query.to_sql(sql_dialect, srid, default_set),
-            QueryType::QueryForeach(query) => query.to_sql(sql_dialect, srid, default_set),
-            QueryType::QueryConvert(query) => query.to_sql(sql_dialect, srid, default_set),
+            QueryType::QueryObjects(query) => query.to_overpass(),
+            QueryType::QueryUnion(query) => query.to_overpass(),
+            QueryType::QueryDifference(query) => query.to_overpass(),
+            QueryType::QueryRecurse(query) => query.to_overpass(),
+            QueryType::QueryIsIn(query) => query.to_overpass(),
+            QueryType::QueryForeach(query) => query.to_overpass(),
+            QueryType::QueryFor(query) => query.to_overpass(),
+            QueryType::QueryIf(query) => query.to_overpass(),
+            QueryType::QueryComplete(query) => query.to_overpass(),
+            QueryType::QueryRetro(query) => query.to_overpass(),
+            QueryType::QueryCompare(query) => query.to_overpass(),
+            QueryType::QueryConvert(query) => query.to_overpass(),
+            QueryType::QueryMake(query) => query.to_overpass(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SubrequestType {
     QueryType(QueryType),
     Out(Out),
 }
 
-#[derive(Debug, Clone)]
-pub struct SubrequestJoin {
-    pub precompute_set: Option<String>,
-    pub precompute: Option<Vec<String>>,
-    pub from: Option<String>,
-    pub clauses: String,
-}
-
 #[derive(Derivative)]
-#[derivative(Default)]
+#[derivative(Default, PartialEq)]
 #[derive(Debug, Clone)]
 pub struct Subrequest {
     pub queries: Vec<Box<SubrequestType>>,
+    #[derivative(PartialEq = "ignore")]
     #[derivative(Default(
         value = "COUNTER.fetch_add(1, Ordering::SeqCst).to_string().as_str().into()"
     ))]
@@ -113,29 +218,47 @@ pub struct Subrequest {
 }
 
 impl Subrequest {
-    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, pest::error::Error<Rule>> {
+    /// Apply `f` to all the `Out`, including nested ones.
+    pub fn for_each_out_mut(&mut self, f: &impl Fn(&mut Out)) {
+        for query in self.queries.iter_mut() {
+            match query.as_mut() {
+                SubrequestType::Out(out) => f(out),
+                SubrequestType::QueryType(query) => query
+                    .blocks_mut()
+                    .into_iter()
+                    .for_each(|block| block.for_each_out_mut(f)),
+            }
+        }
+    }
+
+    /// Apply `f` to all the `QueryObjects`, including nested ones.
+    pub fn for_each_query_objects_mut(&mut self, f: &impl Fn(&mut QueryObjects)) {
+        for query in self.queries.iter_mut() {
+            if let SubrequestType::QueryType(query_type) = query.as_mut() {
+                query_type.for_each_query_objects_mut(f);
+            }
+        }
+    }
+
+    pub fn from_pest(pair: Pair<Rule>) -> Result<Self, OverpassError> {
         let mut subrequest = Subrequest::default();
         for inner in pair.into_inner() {
             match inner.as_rule() {
                 Rule::query_sequence => {
                     for query in inner.into_inner() {
-                        match QueryType::from_pest(query) {
-                            Ok(query_type) => subrequest
-                                .queries
-                                .push(Box::new(SubrequestType::QueryType(*query_type))),
-                            Err(e) => return Err(e),
-                        }
+                        let query_type = QueryType::from_pest(query)?;
+                        subrequest
+                            .queries
+                            .push(Box::new(SubrequestType::QueryType(*query_type)));
                     }
                 }
-                Rule::out => match Out::from_pest(inner) {
-                    Ok(out) => subrequest.queries.push(Box::new(SubrequestType::Out(out))),
-                    Err(e) => return Err(e),
-                },
+                Rule::out => {
+                    let out = Out::from_pest(inner)?;
+                    subrequest.queries.push(Box::new(SubrequestType::Out(out)));
+                }
                 _ => {
-                    return Err(pest::error::Error::new_from_span(
-                        pest::error::ErrorVariant::CustomError {
-                            message: format!("Invalid rule {:?} for Subrequest", inner.as_rule()),
-                        },
+                    return Err(OverpassError::syntax(
+                        format!("Invalid rule {:?} for Subrequest", inner.as_rule()),
                         inner.as_span(),
                     ));
                 }
@@ -144,117 +267,71 @@ impl Subrequest {
         Ok(subrequest)
     }
 
-    pub fn to_sql(
-        &self,
-        sql_dialect: &(dyn SqlDialect + Send + Sync),
-        srid: &str,
-        default_set: &str,
-    ) -> Vec<String> {
-        let mut precomputed = Vec::new();
-        let mut previous_default_set: String = default_set.to_string();
-        let replace = Regex::new(r"(?m)^").unwrap();
-        let mut clauses = Vec::new();
-        self.queries.iter().for_each(|query| match query.as_ref() {
-            SubrequestType::QueryType(query_type) => {
-                let sjs = query_type.to_sql(sql_dialect, srid, previous_default_set.as_str());
-                sjs.iter().for_each(|sj| {
-                    precomputed.extend(sj.precompute.clone().unwrap_or_default());
-                    let set: String = match sj
-                        .precompute_set
-                        .clone()
-                        .or(query_type.asignation().map(|a| a.to_string()))
-                    {
-                        Some(asignation) => asignation.to_string(),
-                        None => {
-                            previous_default_set =
-                                COUNTER.fetch_add(1, Ordering::SeqCst).to_string();
-                            previous_default_set.clone()
-                        }
-                    };
-                    clauses.push((false, set, sj.clauses.clone()))
-                });
-            }
-            SubrequestType::Out(out) => clauses.push((
-                true,
-                format!(
-                    "out_{}",
-                    out.set
-                        .clone()
-                        .unwrap_or(previous_default_set.as_str().into())
-                ),
-                out.to_sql(sql_dialect, srid, previous_default_set.as_str()),
-            )),
-        });
-        let mut precomputed_sql = Vec::new();
-        clauses = clauses
+    pub fn to_overpass(&self) -> String {
+        self.queries
             .iter()
-            .filter(|(is_out, set, sql)| {
-                if *is_out || !precomputed.contains(set) {
-                    true
-                } else {
-                    let p = sql_dialect.precompute(set, sql);
-                    if p.is_some() {
-                        precomputed_sql.append(&mut p.unwrap());
-                        false
-                    } else {
-                        true
-                    }
-                }
+            .map(|query| match query.as_ref() {
+                SubrequestType::QueryType(query_type) => format!("{};", query_type.to_overpass()),
+                SubrequestType::Out(out) => format!("{};", out.to_overpass()),
             })
-            .map(|(is_out, set, sql)| (*is_out, set.clone(), sql.clone()))
-            .collect::<Vec<(bool, String, String)>>();
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
 
-        let mut declared_sets: HashSet<String> = HashSet::new();
-        let with_join = clauses
-            .iter()
-            .filter_map(|(_, set, sql)| {
-                let already_declared = declared_sets.contains(set);
-                declared_sets.insert(set.clone());
-                if !already_declared {
-                    Some(format!(
-                        "_{set} AS (\n{}\n)",
-                        replace.replace_all(sql, "    ")
-                    ))
-                } else {
-                    Option::None
+    /// Plan of the queries and outs, in order. The queries without
+    /// asignation store their result in a new default set.
+    pub fn to_plan(&self, default_set: &str) -> Result<Plan, OverpassError> {
+        let mut previous_default_set: String = default_set.to_string();
+        let mut steps = Vec::new();
+        for query in &self.queries {
+            match query.as_ref() {
+                SubrequestType::QueryType(query_type) => {
+                    for node in query_type.to_plan(previous_default_set.as_str())? {
+                        let set = match node
+                            .constant_set()
+                            .or(query_type.asignation().map(|a| a.to_string()))
+                        {
+                            Some(asignation) => asignation,
+                            None => {
+                                previous_default_set =
+                                    COUNTER.fetch_add(1, Ordering::SeqCst).to_string();
+                                previous_default_set.clone()
+                            }
+                        };
+                        steps.push(Step { set, node });
+                    }
                 }
-            })
-            .collect::<Vec<String>>()
-            .join(",\n");
-        let mut select_out = clauses
-            .iter()
-            .filter(|(is_out, _, _)| *is_out)
-            .collect::<Vec<&(bool, String, String)>>();
-        let d = (
-            true,
-            clauses
-                .last()
-                .map(|(_, set, _)| set.clone())
-                .unwrap_or(default_set.to_string()),
-            // previous_default_set.strip_prefix('_').unwrap().to_string(),
-            "".to_string(),
-        );
-        if select_out.is_empty() {
-            select_out.push(&d);
+                SubrequestType::Out(out) => {
+                    let input = out
+                        .set
+                        .as_deref()
+                        .unwrap_or(previous_default_set.as_str())
+                        .to_string();
+                    steps.push(Step {
+                        set: format!("out_{input}"),
+                        node: Node::Out {
+                            input,
+                            out: out.clone(),
+                        },
+                    });
+                }
+            }
         }
-        let select = select_out
-            .into_iter()
-            .map(|(_, set, _sql)| format!("SELECT * FROM _{set}"))
-            .collect::<Vec<String>>()
-            .join("\nUNION ALL\n");
-
-        precomputed_sql.push(format!("WITH\n{with_join}\n{select}\n;"));
-        precomputed_sql
+        Ok(Plan {
+            steps,
+            default_set: default_set.to_string(),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{overpass_parser::parse_query, sql_dialect::postgres::postgres::Postgres};
+    use crate::{
+        overpass_parser::parse_query,
+        sql_dialect::{postgres::postgres::Postgres, sql_dialect::SqlDialect},
+    };
     use pretty_assertions::assert_eq;
 
-    use super::*;
-
     #[test]
     fn test_parse() {
         let query = "
@@ -267,7 +344,7 @@ mod tests {
         match parse_query(query) {
             Ok(request) => {
                 let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
-                let sql = request.to_sql(d, "9999", None);
+                let sql = request.to_sql(d, "9999", None).unwrap();
                 assert_ne!(vec![""], sql);
             }
             Err(e) => {
@@ -285,7 +362,7 @@ mod tests {
         match parse_query(query) {
             Ok(request) => {
                 let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
-                let sql = request.to_sql(d, "9999", None);
+                let sql = request.to_sql(d, "9999", None).unwrap();
                 assert_eq!(vec!["SET statement_timeout = 160000;",
                 "WITH
 _a AS (
@@ -298,40 +375,51 @@ _a AS (
         node_by_id.id = ANY (ARRAY[1])
 ),
 _b AS (
+    WITH RECURSIVE
+    recurse(osm_type, id) AS (
+        SELECT
+            child.osm_type,
+            child.id
+        FROM
+            _a AS recurse
+            JOIN nwr_by_id AS parent ON
+                parent.osm_type = recurse.osm_type AND
+                parent.id = recurse.id
+            JOIN LATERAL (
+                SELECT member.type AS osm_type, member.ref AS id FROM jsonb_to_recordset(parent.members) AS member(type text, ref bigint, role text)
+                UNION ALL
+                SELECT 'n' AS osm_type, way_node.ref AS id FROM unnest(parent.nodes) AS way_node(ref)
+            ) AS child ON
+                true
+        WHERE
+            recurse.osm_type IN ('w', 'r') AND
+            child.osm_type != 'r'
+        UNION
+        SELECT
+            child.osm_type,
+            child.id
+        FROM
+            recurse AS recurse
+            JOIN nwr_by_id AS parent ON
+                parent.osm_type = recurse.osm_type AND
+                parent.id = recurse.id
+            JOIN LATERAL (
+                SELECT member.type AS osm_type, member.ref AS id FROM jsonb_to_recordset(parent.members) AS member(type text, ref bigint, role text)
+                UNION ALL
+                SELECT 'n' AS osm_type, way_node.ref AS id FROM unnest(parent.nodes) AS way_node(ref)
+            ) AS child ON
+                true
+        WHERE
+            recurse.osm_type IN ('w', 'r') AND
+            child.osm_type != 'r'
+    )
     SELECT
-        way.*
-    FROM
-        _a AS way
-        JOIN node_by_id AS node ON
-            node.id = ANY(way.nodes)
-    WHERE
-        way.osm_type = 'w'
-    UNION ALL
-    SELECT
-        node.*
+        nwr.*
     FROM
-        _a AS relation
-        JOIN LATERAL (
-            SELECT * FROM jsonb_to_recordset(members) AS t(ref bigint, role text, type text) WHERE type = 'n'
-        ) AS members ON
-            members.type = 'w'
-        JOIN node_by_id AS node ON
-            node.id = members.ref
-    WHERE
-        relation.osm_type = 'r'
-    UNION ALL
-    SELECT
-        way.*
-    FROM
-        _a AS relation
-        JOIN LATERAL (
-            SELECT * FROM jsonb_to_recordset(members) AS t(ref bigint, role text, type text) WHERE type = 'w'
-        ) AS members ON
-            true
-        JOIN way_by_id AS way ON
-            way.id = members.ref
-    WHERE
-        relation.osm_type = 'r'
+        recurse
+        JOIN nwr_by_id AS nwr ON
+            nwr.osm_type = recurse.osm_type AND
+            nwr.id = recurse.id
 )
 SELECT * FROM _b
 ;"], sql);
@@ -352,12 +440,12 @@ SELECT * FROM _b
         match parse_query(query) {
             Ok(request) => {
                 let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
-                let sql = request.to_sql(d, "9999", None);
+                let sql = request.to_sql(d, "9999", None).unwrap();
                 assert_eq!(
                     vec![
                         "SET statement_timeout = 160000;",
                         "WITH
-_poly_11689077968748950118 AS (
+_poly_17101990801976832561 AS (
     SELECT
         geom
     FROM
@@ -368,11 +456,11 @@ _n AS (
         node_by_geom.*
     FROM
         node_by_geom
-        JOIN _poly_11689077968748950118 ON true
+        JOIN _poly_17101990801976832561 ON true
     WHERE
         node_by_geom.osm_type = 'n' AND
         ST_Intersects(
-            _poly_11689077968748950118.geom,
+            _poly_17101990801976832561.geom,
             node_by_geom.geom
         )
 ),
@@ -381,11 +469,11 @@ _w AS (
         way_by_geom.*
     FROM
         way_by_geom
-        JOIN _poly_11689077968748950118 ON true
+        JOIN _poly_17101990801976832561 ON true
     WHERE
         way_by_geom.osm_type = 'w' AND
         ST_Intersects(
-            _poly_11689077968748950118.geom,
+            _poly_17101990801976832561.geom,
             way_by_geom.geom
         )
 )
@@ -410,7 +498,7 @@ SELECT * FROM _w
         match parse_query(query) {
             Ok(request) => {
                 let d = &Postgres::default() as &(dyn SqlDialect + Send + Sync);
-                let sql = request.to_sql(d, "9999", None);
+                let sql = request.to_sql(d, "9999", None).unwrap();
                 assert_ne!(vec![""], sql);
             }
             Err(e) => {
@@ -0,0 +1,462 @@
+use std::collections::{HashMap, HashSet};
+
+use derivative::Derivative;
+
+use crate::error::{OverpassError, Span};
+
+use super::{
+    evaluator::Evaluator,
+    request::Request,
+    subrequest::{QueryType, Subrequest, SubrequestType},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Warning {
+    pub message: String,
+    pub span: Span,
+}
+
+#[derive(Derivative)]
+#[derivative(Default)]
+#[derive(Debug, Clone)]
+pub struct Validation {
+    pub errors: Vec<OverpassError>,
+    pub warnings: Vec<Warning>,
+}
+
+impl Validation {
+    pub fn is_valid(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Assignment {
+    span: Span,
+    used: bool,
+}
+
+/// Resolve named set references against assignments.
+///
+/// Sets assigned inside a union or a block statement body are only visible
+/// within that block, as they are nested CTEs in the generated SQL. Using
+/// them after the block is unsupported.
+#[derive(Derivative)]
+#[derivative(Default)]
+pub struct Validator {
+    scopes: Vec<HashMap<Box<str>, Assignment>>,
+    /// Sets assigned in the closed blocks.
+    block_sets: HashSet<Box<str>>,
+    validation: Validation,
+}
+
+impl Validator {
+    pub fn validate(request: &Request) -> Validation {
+        let mut validator = Validator::default();
+        validator.push_scope();
+        validator.subrequest(&request.subrequest);
+        validator.pop_scope();
+        validator
+            .validation
+            .warnings
+            .sort_by_key(|warning| warning.span.start);
+        validator.validation
+    }
+
+    fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    fn pop_scope(&mut self) {
+        if let Some(scope) = self.scopes.pop() {
+            self.block_sets.extend(scope.keys().cloned());
+            let mut unused = scope
+                .into_iter()
+                .filter(|(_, assignment)| !assignment.used)
+                .collect::<Vec<(Box<str>, Assignment)>>();
+            unused.sort_by_key(|(_, assignment)| assignment.span.start);
+            for (set, assignment) in unused {
+                self.unused(&set, assignment.span);
+            }
+        }
+    }
+
+    fn unused(&mut self, set: &str, span: Span) {
+        self.validation.warnings.push(Warning {
+            message: format!("Set .{set} is assigned but never used"),
+            span,
+        });
+    }
+
+    fn reference(&mut self, set: &str, span: Span) {
+        if set == "_" {
+            return;
+        }
+        match self
+            .scopes
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(set))
+        {
+            Some(assignment) => assignment.used = true,
+            None if self.block_sets.contains(set) => {
+                self.validation.errors.push(OverpassError::unsupported(
+                    format!("Set .{set} is assigned in a block, it can not be used after it"),
+                    span,
+                ))
+            }
+            None => self.validation.errors.push(OverpassError::semantic(
+                format!("Undefined set .{set}"),
+                span,
+            )),
+        }
+    }
+
+    fn assign(&mut self, set: &str, span: Span, used: bool) {
+        if set == "_" {
+            return;
+        }
+        if let Some(scope) = self.scopes.last_mut()
+            && let Some(previous) = scope.insert(set.into(), Assignment { span, used })
+            && !previous.used
+        {
+            self.unused(set, previous.span);
+        }
+    }
+
+    fn subrequest(&mut self, subrequest: &Subrequest) {
+        for query in &subrequest.queries {
+            match query.as_ref() {
+                SubrequestType::QueryType(query_type) => self.query(query_type, false),
+                SubrequestType::Out(out) => {
+                    self.reference(out.set.as_deref().unwrap_or("_"), out.span)
+                }
+            }
+        }
+
+        // Without out statement, the result of the last query is the output
+        let has_out = subrequest
+            .queries
+            .iter()
+            .any(|query| matches!(query.as_ref(), SubrequestType::Out(_)));
+        if !has_out
+            && let Some(SubrequestType::QueryType(query_type)) =
+                subrequest.queries.last().map(|query| query.as_ref())
+            && let Some(set) = query_type.asignation()
+        {
+            self.reference(&set, Span::default());
+        }
+    }
+
+    fn query(&mut self, query: &QueryType, used: bool) {
+        match query {
+            QueryType::QueryObjects(query) => {
+                for set in &query.sets {
+                    self.reference(set, query.span);
+                }
+                for filter in &query.filters.filters {
+                    if let Some(area_id) = &filter.area_id {
+                        self.reference(area_id, filter.span);
+                    }
+                    if let Some(around) = &filter.around
+                        && around.coordinates.is_none()
+                    {
+                        self.reference(around.core.as_deref().unwrap_or("_"), filter.span);
+                    }
+                    if let Some(recurse) = &filter.recurse {
+                        self.reference(recurse.set.as_deref().unwrap_or("_"), filter.span);
+                    }
+                    if let Some(condition) = &filter.condition {
+                        self.evaluator(condition);
+                    }
+                }
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+            QueryType::QueryUnion(query) => {
+                self.push_scope();
+                for member in &query.queries {
+                    self.query(member, true);
+                }
+                self.pop_scope();
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+            QueryType::QueryDifference(query) => {
+                self.push_scope();
+                self.query(&query.base, true);
+                self.query(&query.minus, true);
+                self.pop_scope();
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+            QueryType::QueryRecurse(query) => {
+                self.reference(query.set.as_deref().unwrap_or("_"), query.span);
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+            QueryType::QueryIsIn(query) => {
+                if query.coordinates.is_none() {
+                    self.reference(query.set.as_deref().unwrap_or("_"), query.span);
+                }
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+            QueryType::QueryForeach(query) => {
+                self.reference(query.input_set.as_deref().unwrap_or("_"), query.span);
+                self.push_scope();
+                if let Some(loop_var) = &query.loop_var {
+                    self.assign(loop_var, query.span, true);
+                }
+                self.subrequest(&query.body);
+                self.pop_scope();
+                if let Some(loop_var) = &query.loop_var {
+                    self.assign(loop_var, query.span, used);
+                }
+            }
+            QueryType::QueryFor(query) => {
+                self.reference(query.input_set.as_deref().unwrap_or("_"), query.span);
+                self.evaluator(&query.value);
+                self.push_scope();
+                if let Some(loop_var) = &query.loop_var {
+                    self.assign(loop_var, query.span, true);
+                }
+                self.subrequest(&query.body);
+                self.pop_scope();
+            }
+            QueryType::QueryIf(query) => {
+                self.evaluator(&query.condition);
+                for block in [Some(&query.then), query.otherwise.as_ref()]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.push_scope();
+                    self.subrequest(block);
+                    self.pop_scope();
+                }
+            }
+            QueryType::QueryComplete(query) => {
+                self.reference(query.input_set.as_deref().unwrap_or("_"), query.span);
+                self.push_scope();
+                self.subrequest(&query.body);
+                self.pop_scope();
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+            QueryType::QueryRetro(query) => {
+                self.evaluator(&query.date);
+                self.push_scope();
+                self.subrequest(&query.body);
+                self.pop_scope();
+            }
+            QueryType::QueryCompare(query) => {
+                self.reference(query.input_set.as_deref().unwrap_or("_"), query.span);
+                if let Some(delta) = &query.delta {
+                    self.evaluator(delta);
+                }
+                self.push_scope();
+                self.subrequest(&query.body);
+                self.pop_scope();
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+            QueryType::QueryConvert(query) => {
+                for convert in &query.converts {
+                    if let Some(source) = &convert.source {
+                        self.evaluator(source);
+                    }
+                }
+            }
+            QueryType::QueryMake(query) => {
+                for convert in &query.converts {
+                    if let Some(source) = &convert.source {
+                        self.evaluator(source);
+                        if let Some(span) = source.element_span() {
+                            self.validation.errors.push(OverpassError::semantic(
+                                "make can only read elements through an aggregate",
+                                span,
+                            ));
+                        }
+                    }
+                }
+                if let Some(asignation) = &query.asignation {
+                    self.assign(asignation, query.span, used);
+                }
+            }
+        }
+    }
+
+    fn evaluator(&mut self, evaluator: &Evaluator) {
+        let mut sets = Vec::new();
+        evaluator.sets(&mut sets);
+        for (set, span) in sets {
+            self.reference(set, span);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overpass_parser::parse_query;
+    use pretty_assertions::assert_eq;
+
+    fn validate(query: &str) -> Validation {
+        match parse_query(query) {
+            Ok(request) => request.validate(),
+            Err(e) => panic!("Failed to parse query: {e}"),
+        }
+    }
+
+    #[test]
+    fn test_undefined() {
+        let validation = validate("nwr(area.b)[amenity];");
+        assert_eq!(
+            vec![OverpassError::semantic(
+                "Undefined set .b",
+                Span { start: 3, end: 11 }
+            )],
+            validation.errors
+        );
+
+        let validation = validate(
+            "node->.a;
+            .a out;
+            .b out;
+            node.c;
+            way(around.d:10);
+            .e >;
+            foreach.f(out;);
+            convert node r=g.set(t['ref']);
+            node(r.h:\"stop\");",
+        );
+        assert_eq!(
+            vec![
+                "Undefined set .b",
+                "Undefined set .c",
+                "Undefined set .d",
+                "Undefined set .e",
+                "Undefined set .f",
+                "Undefined set .g",
+                "Undefined set .h",
+            ],
+            validation
+                .errors
+                .iter()
+                .map(|e| e.message())
+                .collect::<Vec<&str>>()
+        );
+    }
+
+    #[test]
+    fn test_scopes() {
+        let validation = validate(
+            "(node->.a; way.a ->.b;)->.u;
+            .b out;
+            foreach.u ->.f(.f out; node->.i;);
+            .f out;
+            .i out;",
+        );
+        assert_eq!(
+            vec![
+                "Set .b is assigned in a block, it can not be used after it",
+                "Set .i is assigned in a block, it can not be used after it"
+            ],
+            validation
+                .errors
+                .iter()
+                .map(|e| e.message())
+                .collect::<Vec<&str>>()
+        );
+
+        let validation = validate(
+            "way->.w;
+            for.w ->.g (t[highway]) { .g out; };
+            if (x.count(ways) > 0) { node->.n; } else { .n out; };
+            complete.w ->.c { way(bn); };
+            .g out;
+            .c out;",
+        );
+        assert_eq!(
+            vec![
+                "Undefined set .x",
+                "Set .n is assigned in a block, it can not be used after it",
+                "Set .g is assigned in a block, it can not be used after it"
+            ],
+            validation
+                .errors
+                .iter()
+                .map(|e| e.message())
+                .collect::<Vec<&str>>()
+        );
+    }
+
+    #[test]
+    fn test_block_set_after_block() {
+        let validation = validate("(node(1)->.x;node(2););node.x;out;");
+        assert_eq!(
+            vec![OverpassError::unsupported(
+                "Set .x is assigned in a block, it can not be used after it",
+                Span { start: 23, end: 29 }
+            )],
+            validation.errors
+        );
+    }
+
+    #[test]
+    fn test_unused() {
+        let validation = validate(
+            "node->.a;
+            node->.b;
+            node->.b;
+            .b out;
+            way->.c;",
+        );
+        assert!(validation.is_valid());
+        assert_eq!(
+            vec![
+                Warning {
+                    message: "Set .a is assigned but never used".to_string(),
+                    span: Span { start: 0, end: 8 }
+                },
+                Warning {
+                    message: "Set .b is assigned but never used".to_string(),
+                    span: Span { start: 22, end: 30 }
+                },
+                Warning {
+                    message: "Set .c is assigned but never used".to_string(),
+                    span: Span { start: 86, end: 93 }
+                },
+            ],
+            validation.warnings
+        );
+    }
+
+    #[test]
+    fn test_make_element() {
+        let validation = validate(
+            "node[amenity];
+            make stat c=t[\"x\"]+\"a\", l=is_tag(\"name\")?\"y\":\"n\", n=count(nodes)+number(\"1\");",
+        );
+        assert_eq!(
+            vec![
+                OverpassError::semantic(
+                    "make can only read elements through an aggregate",
+                    Span { start: 39, end: 45 }
+                ),
+                OverpassError::semantic(
+                    "make can only read elements through an aggregate",
+                    Span { start: 53, end: 67 }
+                ),
+            ],
+            validation.errors
+        );
+    }
+}
@@ -0,0 +1,564 @@
+//! Logical plan of a request, between the AST and the SQL.
+//!
+//! Each statement of a subrequest is a `Step`, a `Node` stored as a named
+//! set. The plan does not depend on the SQL dialect, it is rendered by `sql`.
+
+pub mod sql;
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use crate::{
+    error::Span,
+    overpass_parser::{
+        evaluator::Evaluator,
+        filters::{FilterChanged, FilterRecurse},
+        out::Out,
+        query_convert::ConvertItem,
+        selectors::Selectors,
+    },
+};
+
+/// Sets of a subrequest, in order of evaluation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub steps: Vec<Step>,
+    /// Set of the result when there is no step.
+    pub default_set: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    /// Set of the node result, `out_{set}` for the outputs.
+    pub set: String,
+    pub node: Node,
+}
+
+/// Elements read by a `Scan`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    /// View of the elements, as `node_by_geom`.
+    View(String),
+    Set(String),
+    /// No element, the default set before the first statement.
+    Empty,
+}
+
+/// Elements of a set, or (lat, lon) coordinates.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Set(String),
+    Coordinates(Vec<(f64, f64)>),
+}
+
+/// Geometry intersecting the elements of a `SpatialJoin`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Geometry {
+    /// (south, west, north, east).
+    Bbox((f64, f64, f64, f64)),
+    /// Union of the geometries of a set, precomputed by some dialects.
+    Set(String),
+    /// Buffer of `radius` metres.
+    Around { location: Location, radius: f64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    /// First letter of the type, as `n`.
+    OsmType(char),
+    /// Elements also in the set.
+    InSet(String),
+    Selectors(Selectors),
+    Ids(Vec<i64>),
+    /// Elements changed after the date.
+    Newer(Box<str>),
+    Changed(FilterChanged),
+    /// Elements related to the ones of `input`, as `(bn)`.
+    Recurse {
+        recurse: FilterRecurse,
+        input: String,
+        span: Span,
+    },
+    /// Elements for which the expression is true.
+    Condition {
+        condition: Evaluator,
+        default_set: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RecurseDirection {
+    /// Members of the relations and nodes of the ways, as `>`.
+    Down,
+    /// Ways and relations having the elements, as `<`.
+    Up,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IsIn {
+    /// Areas containing the (lat, lon) point.
+    Point(f64, f64),
+    /// Areas containing the nodes of the set.
+    Set(String),
+}
+
+/// New elements computed from the ones of a set.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Projection {
+    /// One element per element of the set, with converted tags.
+    Convert(Vec<Box<ConvertItem>>),
+    /// One element of the type, the evaluators aggregating the set.
+    Make {
+        object_type: Box<str>,
+        converts: Vec<Box<ConvertItem>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    /// Constant polygon, from (lat, lon) coordinates, stored as `set`.
+    Polygon {
+        set: String,
+        coordinates: Vec<(f64, f64)>,
+    },
+    /// All the elements of the source.
+    Scan(Source),
+    /// Elements of `input` matching all the predicates.
+    Filter {
+        input: Box<Node>,
+        predicates: Vec<Predicate>,
+    },
+    /// Elements of `input` intersecting the geometry.
+    SpatialJoin {
+        input: Box<Node>,
+        geometry: Geometry,
+    },
+    /// Elements of all the steps results, without duplicates.
+    Union(Vec<Step>),
+    /// Elements of the `base` set not in the `minus` set, both from steps.
+    Difference {
+        steps: Vec<Step>,
+        base: String,
+        minus: String,
+    },
+    /// Elements related to the ones of `input`, transitively through the
+    /// relations or not.
+    Recurse {
+        input: String,
+        direction: RecurseDirection,
+        transitive: bool,
+        span: Span,
+    },
+    IsIn(IsIn),
+    Project {
+        input: String,
+        projection: Projection,
+    },
+    /// Union of the body results, run on each element of `input`, as the
+    /// `element` set.
+    Foreach {
+        input: String,
+        element: String,
+        body: Plan,
+        span: Span,
+    },
+    /// Union of the body results, run on each group of elements of `input`
+    /// with the same value, as the `group` set.
+    For {
+        input: String,
+        group: String,
+        value: Evaluator,
+        body: Plan,
+        span: Span,
+    },
+    /// Result of `then` when the condition is true, else of `otherwise`.
+    If {
+        condition: Evaluator,
+        default_set: String,
+        then: Plan,
+        otherwise: Option<Plan>,
+        span: Span,
+    },
+    /// Body run on its own result, from `input`, until no new element. The
+    /// result of the previous run is the `{complete}_previous` set.
+    Complete {
+        input: String,
+        complete: String,
+        body: Plan,
+        span: Span,
+    },
+    /// Output of the elements of `input`.
+    Out {
+        input: String,
+        out: Out,
+    },
+}
+
+impl Plan {
+    /// Move the constant steps of the nested plans to this plan, before the
+    /// step using them.
+    pub fn hoist_constants(&mut self) {
+        let mut steps: Vec<Step> = Vec::new();
+        for mut step in std::mem::take(&mut self.steps) {
+            for constant in step.node.take_constants() {
+                if !steps.iter().any(|s| s.set == constant.set) {
+                    steps.push(constant);
+                }
+            }
+            steps.push(step);
+        }
+        self.steps = steps;
+    }
+
+    /// Define the default set as empty when it is read before being set.
+    pub fn define_default_set(&mut self) {
+        let read = self
+            .steps
+            .iter()
+            .take_while(|step| step.set != "_")
+            .any(|step| {
+                let mut sets = Vec::new();
+                step.node.sets(&mut sets);
+                sets.iter().any(|set| set == "_")
+            });
+        if read {
+            self.steps.insert(
+                0,
+                Step {
+                    set: "_".to_string(),
+                    node: Node::Scan(Source::Empty),
+                },
+            );
+        }
+    }
+
+    /// Sets intersected by a `SpatialJoin`, including in the nested plans.
+    pub fn spatial_sets(&self) -> Vec<String> {
+        let mut sets = Vec::new();
+        self.steps
+            .iter()
+            .for_each(|step| step.node.collect_spatial_sets(&mut sets));
+        sets
+    }
+}
+
+impl Step {
+    pub fn is_out(&self) -> bool {
+        matches!(self.node, Node::Out { .. })
+    }
+}
+
+impl Node {
+    /// Polygon of (lat, lon) coordinates, named after them.
+    pub fn polygon(coordinates: Vec<(f64, f64)>) -> Node {
+        let mut hasher = DefaultHasher::new();
+        for (lat, lon) in &coordinates {
+            lat.to_bits().hash(&mut hasher);
+            lon.to_bits().hash(&mut hasher);
+        }
+        Node::Polygon {
+            set: format!("poly_{}", hasher.finish()),
+            coordinates,
+        }
+    }
+
+    /// Set of the constant nodes, not depending on other sets.
+    pub fn constant_set(&self) -> Option<String> {
+        match self {
+            Node::Polygon { set, .. } => Some(set.clone()),
+            _ => None,
+        }
+    }
+
+    /// Add the predicate to the `Filter`, or filter the node.
+    pub fn filter(self, predicate: Predicate) -> Node {
+        match self {
+            Node::Filter {
+                input,
+                mut predicates,
+            } => {
+                predicates.push(predicate);
+                Node::Filter { input, predicates }
+            }
+            input => Node::Filter {
+                input: Box::new(input),
+                predicates: vec![predicate],
+            },
+        }
+    }
+
+    pub fn spatial_join(self, geometry: Geometry) -> Node {
+        Node::SpatialJoin {
+            input: Box::new(self),
+            geometry,
+        }
+    }
+
+    /// Plans of the block statements.
+    fn blocks_mut(&mut self) -> Vec<&mut Plan> {
+        match self {
+            Node::Foreach { body, .. } | Node::For { body, .. } | Node::Complete { body, .. } => {
+                vec![body]
+            }
+            Node::If {
+                then, otherwise, ..
+            } => {
+                let mut blocks = vec![then];
+                blocks.extend(otherwise.as_mut());
+                blocks
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Remove the constant steps of the nested plans.
+    fn take_constants(&mut self) -> Vec<Step> {
+        let mut constants = Vec::new();
+        if let Node::Union(steps) | Node::Difference { steps, .. } = self {
+            steps
+                .iter_mut()
+                .for_each(|step| constants.extend(step.node.take_constants()));
+        }
+        for block in self.blocks_mut() {
+            let (constant, steps) = std::mem::take(&mut block.steps)
+                .into_iter()
+                .partition::<Vec<Step>, _>(|step| step.node.constant_set().is_some());
+            block.steps = steps;
+            constants.extend(constant);
+            block
+                .steps
+                .iter_mut()
+                .for_each(|step| constants.extend(step.node.take_constants()));
+        }
+        constants
+    }
+
+    /// Sets read by the node, including by the nested plans.
+    pub fn sets(&self, sets: &mut Vec<String>) {
+        // The aggregates without set read `default_set`
+        let evaluator = |evaluator: &Evaluator, default_set: &str, sets: &mut Vec<String>| {
+            let mut evaluator_sets = Vec::new();
+            evaluator.sets(&mut evaluator_sets);
+            sets.extend(
+                evaluator_sets
+                    .into_iter()
+                    .map(|(set, _)| if set == "_" { default_set } else { set }.to_string()),
+            );
+        };
+        let plans = |plans: &[&Plan], sets: &mut Vec<String>| {
+            plans
+                .iter()
+                .flat_map(|plan| plan.steps.iter())
+                .for_each(|step| step.node.sets(sets))
+        };
+        match self {
+            Node::Polygon { .. } | Node::IsIn(IsIn::Point(..)) => {}
+            Node::Scan(source) => {
+                if let Source::Set(set) = source {
+                    sets.push(set.clone());
+                }
+            }
+            Node::Filter { input, predicates } => {
+                input.sets(sets);
+                for predicate in predicates {
+                    match predicate {
+                        Predicate::InSet(set) | Predicate::Recurse { input: set, .. } => {
+                            sets.push(set.clone())
+                        }
+                        Predicate::Condition {
+                            condition,
+                            default_set,
+                        } => evaluator(condition, default_set, sets),
+                        _ => {}
+                    }
+                }
+            }
+            Node::SpatialJoin { input, geometry } => {
+                input.sets(sets);
+                if let Geometry::Set(set)
+                | Geometry::Around {
+                    location: Location::Set(set),
+                    ..
+                } = geometry
+                {
+                    sets.push(set.clone());
+                }
+            }
+            Node::Union(steps) | Node::Difference { steps, .. } => {
+                steps.iter().for_each(|step| step.node.sets(sets))
+            }
+            Node::Recurse { input, .. }
+            | Node::IsIn(IsIn::Set(input))
+            | Node::Out { input, .. } => sets.push(input.clone()),
+            Node::Project { input, projection } => {
+                sets.push(input.clone());
+                let (Projection::Convert(converts) | Projection::Make { converts, .. }) =
+                    projection;
+                converts
+                    .iter()
+                    .filter_map(|convert| convert.source.as_ref())
+                    .for_each(|source| evaluator(source, input, sets));
+            }
+            Node::Foreach { input, body, .. } | Node::Complete { input, body, .. } => {
+                sets.push(input.clone());
+                plans(&[body], sets);
+            }
+            Node::For {
+                input, value, body, ..
+            } => {
+                sets.push(input.clone());
+                evaluator(value, input, sets);
+                plans(&[body], sets);
+            }
+            Node::If {
+                condition,
+                default_set,
+                then,
+                otherwise,
+                ..
+            } => {
+                evaluator(condition, default_set, sets);
+                let mut blocks = vec![then];
+                blocks.extend(otherwise.as_ref());
+                plans(&blocks, sets);
+            }
+        }
+    }
+
+    fn collect_spatial_sets(&self, sets: &mut Vec<String>) {
+        match self {
+            Node::Filter { input, .. } => input.collect_spatial_sets(sets),
+            Node::SpatialJoin { input, geometry } => {
+                input.collect_spatial_sets(sets);
+                if let Geometry::Set(set) = geometry
+                    && !sets.contains(set)
+                {
+                    sets.push(set.clone());
+                }
+            }
+            Node::Union(steps) | Node::Difference { steps, .. } => steps
+                .iter()
+                .for_each(|step| step.node.collect_spatial_sets(sets)),
+            Node::Foreach { body, .. } | Node::For { body, .. } | Node::Complete { body, .. } => {
+                body.steps
+                    .iter()
+                    .for_each(|step| step.node.collect_spatial_sets(sets))
+            }
+            Node::If {
+                then, otherwise, ..
+            } => then
+                .steps
+                .iter()
+                .chain(
+                    otherwise
+                        .iter()
+                        .flat_map(|otherwise| otherwise.steps.iter()),
+                )
+                .for_each(|step| step.node.collect_spatial_sets(sets)),
+            Node::Polygon { .. }
+            | Node::Scan(_)
+            | Node::Recurse { .. }
+            | Node::IsIn(_)
+            | Node::Project { .. }
+            | Node::Out { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        overpass_parser::parse_query,
+        sql_dialect::{duckdb::duckdb::Duckdb, sql_dialect::SqlDialect},
+    };
+    use pretty_assertions::assert_eq;
+
+    fn plan(query: &str) -> Plan {
+        parse_query(query).unwrap().to_plan().unwrap()
+    }
+
+    #[test]
+    fn test_to_plan() {
+        let plan = plan("way[highway](id:1)->.w; .w >->.n; .n out;");
+        assert_eq!(3, plan.steps.len());
+        match &plan.steps[0].node {
+            Node::Filter { input, predicates } => {
+                assert_eq!(Node::Scan(Source::View("way_by_id".to_string())), **input);
+                assert_eq!(Predicate::OsmType('w'), predicates[0]);
+                assert_eq!(Predicate::Ids(vec![1]), predicates[2]);
+            }
+            node => panic!("Expected a Filter, got {node:?}"),
+        }
+        assert!(matches!(
+            &plan.steps[1].node,
+            Node::Recurse {
+                input,
+                direction: RecurseDirection::Down,
+                transitive: false,
+                ..
+            } if input == "w"
+        ));
+        assert_eq!("out_n", plan.steps[2].set);
+        assert!(plan.steps[2].is_out());
+    }
+
+    #[test]
+    fn test_define_default_set() {
+        let empty = Step {
+            set: "_".to_string(),
+            node: Node::Scan(Source::Empty),
+        };
+        assert_eq!(empty, plan("make stat n=count(nodes); out;").steps[0]);
+        assert!(
+            !plan("node; make stat n=count(nodes); out;")
+                .steps
+                .iter()
+                .any(|step| step.node == Node::Scan(Source::Empty))
+        );
+    }
+
+    #[test]
+    fn test_hoist_constants() {
+        let mut plan = plan(
+            "way->.w; foreach.w ->.e(node(poly:\"1 2 3 4 5 6\")->.a; node(poly:\"1 2 3 4 5 6\")->.b;);",
+        );
+        let poly = Node::polygon(vec![(1.0, 2.0), (3.0, 4.0), (5.0, 6.0)])
+            .constant_set()
+            .unwrap();
+        assert_eq!(vec![poly.clone()], plan.spatial_sets());
+
+        plan.hoist_constants();
+        assert_eq!(
+            vec!["w", poly.as_str()],
+            plan.steps[..2]
+                .iter()
+                .map(|step| step.set.as_str())
+                .collect::<Vec<&str>>()
+        );
+        match &plan.steps[2].node {
+            Node::Foreach { body, .. } => assert!(
+                body.steps
+                    .iter()
+                    .all(|step| step.node.constant_set().is_none())
+            ),
+            node => panic!("Expected a Foreach, got {node:?}"),
+        }
+    }
+
+    #[test]
+    fn test_to_sql_precompute() {
+        let d = &Duckdb as &(dyn SqlDialect + Send + Sync);
+        let sql = plan("area[name=a]->.a; node(area.a)(1,2,3,4)->.n; .n out count;")
+            .to_sql(d, "4326")
+            .unwrap();
+        assert_eq!(3, sql.len());
+        assert!(sql[0].starts_with("CREATE TEMP TABLE _a AS\n"));
+        assert!(sql[1].starts_with("SET variable _a_bbox = ("));
+        assert!(!sql[2].contains("JOIN _a ON true"));
+        assert!(sql[2].contains("node_by_geom.bbox.xmin <= getvariable('_a_bbox').xmax AND"));
+        assert!(sql[2].contains(
+            "node_by_geom.bbox.xmin <= ST_XMax(ST_Transform(ST_Envelope('SRID=4326;LINESTRING(2 1, 4 3)'::geometry), 'EPSG:4326', 'EPSG:4326')) AND"
+        ));
+    }
+}
@@ -0,0 +1,944 @@
+//! SQL rendering of the plan, for a dialect.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    error::{OverpassError, Span},
+    overpass_parser::{filters::Filter, query_convert::ConvertItem},
+    sql_dialect::sql_dialect::{GeomRef, SqlDialect},
+};
+
+use super::{
+    Geometry, IsIn, Location, Node, Plan, Predicate, Projection, RecurseDirection, Source, Step,
+};
+
+/// Prefix each line of the SQL.
+fn indent(sql: &str, prefix: &str) -> String {
+    sql.split('\n')
+        .map(|line| format!("{prefix}{line}"))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// `WITH` query of the steps, selecting the outs, or the last set.
+fn with_select(
+    steps: &[(&Step, String)],
+    with: Vec<String>,
+    materialization: &str,
+    default_set: &str,
+) -> String {
+    let mut declared_sets: HashSet<&str> = HashSet::new();
+    let with_join = with
+        .into_iter()
+        .chain(
+            steps
+                .iter()
+                .filter(|(step, _)| declared_sets.insert(&step.set))
+                .map(|(step, sql)| {
+                    format!(
+                        "_{} AS {materialization}(\n{}\n)",
+                        step.set,
+                        indent(sql, "    ")
+                    )
+                }),
+        )
+        .collect::<Vec<String>>()
+        .join(",\n");
+    let mut select_out = steps
+        .iter()
+        .filter(|(step, _)| step.is_out())
+        .map(|(step, _)| step.set.as_str())
+        .collect::<Vec<&str>>();
+    if select_out.is_empty() {
+        select_out.push(
+            steps
+                .last()
+                .map(|(step, _)| step.set.as_str())
+                .unwrap_or(default_set),
+        );
+    }
+    let select = select_out
+        .into_iter()
+        .map(|set| format!("SELECT * FROM _{set}"))
+        .collect::<Vec<String>>()
+        .join("\nUNION ALL\n");
+
+    format!("WITH\n{with_join}\n{select}")
+}
+
+impl Plan {
+    /// SQL statements of the plan: the precomputed sets, then the query
+    /// selecting the outs.
+    pub fn to_sql(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+    ) -> Result<Vec<String>, OverpassError> {
+        let mut plan = self.clone();
+        let mut precomputed = Vec::new();
+        if sql_dialect.is_precompute() {
+            plan.hoist_constants();
+            precomputed = plan.spatial_sets();
+        }
+
+        let mut statements = Vec::new();
+        let mut steps = Vec::new();
+        for step in &plan.steps {
+            let sql = step.node.to_sql(sql_dialect, srid)?;
+            if !step.is_out()
+                && precomputed.contains(&step.set)
+                && let Some(precompute) = sql_dialect.precompute(&step.set, &sql)
+            {
+                statements.extend(precompute);
+            } else {
+                steps.push((step, sql));
+            }
+        }
+        statements.push(format!(
+            "{}\n;",
+            with_select(&steps, Vec::new(), "", &plan.default_set)
+        ));
+        Ok(statements)
+    }
+
+    /// Query of the plan, nested in a block statement, after the `with`
+    /// `(set, sql)` CTEs. In a lateral join, the CTEs are correlated with
+    /// the joined row.
+    ///
+    /// The sets of the block can not be precomputed, the constant ones are
+    /// moved out of the block by `Plan::to_sql`.
+    fn to_sql_nested(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        with: &[(String, String)],
+        lateral: bool,
+        span: Span,
+    ) -> Result<String, OverpassError> {
+        if sql_dialect.is_precompute() {
+            let spatial_sets = self.spatial_sets();
+            if let Some(set) = with
+                .iter()
+                .map(|(set, _)| set)
+                .chain(
+                    self.steps
+                        .iter()
+                        .filter(|step| !step.is_out())
+                        .map(|step| &step.set),
+                )
+                .find(|set| spatial_sets.contains(set))
+            {
+                return Err(OverpassError::unsupported(
+                    format!("Set .{set} of a block statement can not be precomputed"),
+                    span,
+                ));
+            }
+        }
+
+        let steps = self
+            .steps
+            .iter()
+            .map(|step| Ok((step, step.node.to_sql(sql_dialect, srid)?)))
+            .collect::<Result<Vec<(&Step, String)>, OverpassError>>()?;
+        let materialization = if lateral {
+            sql_dialect.lateral_cte_materialization()
+        } else {
+            "".to_string()
+        };
+        let with = with
+            .iter()
+            .map(|(set, sql)| format!("_{set} AS {materialization}(\n{}\n)", indent(sql, "    ")))
+            .collect::<Vec<String>>();
+        Ok(with_select(
+            &steps,
+            with,
+            &materialization,
+            &self.default_set,
+        ))
+    }
+}
+
+/// `SELECT` of the elements of a `Scan`, with the joins and the predicates
+/// of the `SpatialJoin` and `Filter` nodes on it.
+#[derive(Debug)]
+pub struct Select {
+    pub from: String,
+    /// Table of the elements, used by the predicates.
+    pub table: String,
+    pub joins: Vec<String>,
+    pub predicates: Vec<String>,
+}
+
+impl Select {
+    pub fn from_node(
+        node: &Node,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+    ) -> Result<Select, OverpassError> {
+        match node {
+            Node::Scan(source) => {
+                let (table, predicates) = match source {
+                    Source::View(view) => (view.clone(), Vec::new()),
+                    Source::Set(set) => (format!("_{set}"), Vec::new()),
+                    Source::Empty => ("nwr_by_id".to_string(), vec!["false".to_string()]),
+                };
+                Ok(Select {
+                    from: table.clone(),
+                    table,
+                    joins: Vec::new(),
+                    predicates,
+                })
+            }
+            Node::Filter { input, predicates } => {
+                let mut select = Self::from_node(input, sql_dialect, srid)?;
+                for predicate in predicates {
+                    let sql = predicate.to_sql(sql_dialect, srid, &select.table)?;
+                    select.predicates.push(sql);
+                }
+                Ok(select)
+            }
+            Node::SpatialJoin { input, geometry } => {
+                let mut select = Self::from_node(input, sql_dialect, srid)?;
+                select.spatial_join(sql_dialect, srid, geometry);
+                Ok(select)
+            }
+            node => Ok(Select {
+                from: format!(
+                    "(\n{}\n) AS _input",
+                    indent(&node.to_sql(sql_dialect, srid)?, "    ")
+                ),
+                table: "_input".to_string(),
+                joins: Vec::new(),
+                predicates: Vec::new(),
+            }),
+        }
+    }
+
+    fn spatial_join(
+        &mut self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        geometry: &Geometry,
+    ) {
+        let table = &self.table;
+        match geometry {
+            Geometry::Bbox(bbox) => {
+                let bbox = Filter::bbox_geom(sql_dialect, *bbox, srid);
+                self.predicates
+                    .push(sql_dialect.st_intersects_extent_with_geom(table, &GeomRef::Expr(&bbox)));
+            }
+            // Joined, as a single row, when not precomputed
+            Geometry::Set(set) => {
+                if !sql_dialect.is_precompute() {
+                    self.joins.push(format!("JOIN _{set} ON true"));
+                }
+                self.predicates
+                    .push(sql_dialect.st_intersects_with_geom(table, &GeomRef::Set(set)));
+            }
+            Geometry::Around { location, radius } => {
+                let (geom, from) = match location {
+                    Location::Coordinates(coordinates) => {
+                        let coords = coordinates
+                            .iter()
+                            .map(|&(lat, lon)| format!("{lon} {lat}"))
+                            .collect::<Vec<String>>()
+                            .join(", ");
+                        let wkt = if coordinates.len() == 1 {
+                            format!("POINT({coords})")
+                        } else {
+                            format!("LINESTRING({coords})")
+                        };
+                        (sql_dialect.geom_from_text(&wkt), "".to_string())
+                    }
+                    Location::Set(set) => {
+                        (sql_dialect.st_union_agg("geom"), format!(" FROM _{set}"))
+                    }
+                };
+                let join = sql_dialect.st_subdivide(
+                    &sql_dialect.st_dump_geom(&sql_dialect.st_transform_reverse(
+                        &sql_dialect.st_buffer(&sql_dialect.st_transform(&geom, srid), *radius),
+                        srid,
+                    )),
+                    1000,
+                );
+                let on = sql_dialect
+                    .st_intersects_with_geom(table, &GeomRef::Expr("subdivided_geom.geom"));
+                self.joins.push(format!(
+                    "JOIN (SELECT {join} AS geom{from}) AS subdivided_geom ON\n{}",
+                    indent(&on, "    ")
+                ));
+            }
+        }
+    }
+
+    pub fn to_sql(&self) -> String {
+        let mut sql = format!(
+            "SELECT
+    {}.*
+FROM
+    {}",
+            self.table,
+            self.from.replace('\n', "\n    ")
+        );
+        for join in &self.joins {
+            sql.push_str(&format!("\n    {}", join.replace('\n', "\n    ")));
+        }
+        if !self.predicates.is_empty() {
+            sql.push_str(&format!("\nWHERE\n    {}", self.conditions()));
+        }
+        sql
+    }
+
+    /// Predicates of the `WHERE` clause, indented in it.
+    pub fn conditions(&self) -> String {
+        self.predicates
+            .iter()
+            .map(|predicate| predicate.replace('\n', "\n    "))
+            .collect::<Vec<String>>()
+            .join(" AND\n    ")
+    }
+}
+
+impl Predicate {
+    /// SQL condition on the elements of `table`.
+    pub fn to_sql(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+        table: &str,
+    ) -> Result<String, OverpassError> {
+        Ok(match self {
+            Predicate::OsmType(osm_type) => format!("{table}.osm_type = '{osm_type}'"),
+            Predicate::InSet(set) => format!(
+                "EXISTS (SELECT 1 FROM _{set} WHERE _{set}.osm_type = {table}.osm_type AND _{set}.id = {table}.id)"
+            ),
+            Predicate::Selectors(selectors) => selectors.to_sql(sql_dialect, table, srid)?,
+            Predicate::Ids(ids) => sql_dialect.id_in_list(table, "id", ids),
+            Predicate::Newer(newer) => {
+                format!("{table}.created > {}", sql_dialect.timestamp(newer))
+            }
+            Predicate::Changed(changed) => {
+                let since = changed
+                    .since
+                    .iter()
+                    .map(|since| format!("{table}.created > {}", sql_dialect.timestamp(since)));
+                let until = changed
+                    .until
+                    .iter()
+                    .map(|until| format!("{table}.created <= {}", sql_dialect.timestamp(until)));
+                since.chain(until).collect::<Vec<String>>().join(" AND ")
+            }
+            Predicate::Recurse {
+                recurse,
+                input,
+                span,
+            } => {
+                let unsupported = || {
+                    OverpassError::unsupported(
+                        format!("Recurse filter ({}) is not supported", recurse.recurse_type),
+                        *span,
+                    )
+                };
+                let role = recurse
+                    .role
+                    .as_ref()
+                    .map(|role| sql_dialect.escape_literal(role));
+                let role = role.as_deref();
+                let (alias, input_type, clause) = match recurse.recurse_type.as_ref() {
+                    // Nodes of the input ways
+                    "w" => (
+                        "w",
+                        "w",
+                        format!(
+                            "{table}.osm_type = 'n' AND
+        {}",
+                            sql_dialect
+                                .way_has_node("w", &format!("{table}.id"))
+                                .ok_or_else(unsupported)?
+                        ),
+                    ),
+                    // Members of the input relations
+                    "r" => (
+                        "r",
+                        "r",
+                        sql_dialect
+                            .relation_has_member(
+                                "r",
+                                &format!("{table}.osm_type"),
+                                &format!("{table}.id"),
+                                role,
+                            )
+                            .ok_or_else(unsupported)?,
+                    ),
+                    // Ways and relations having the input nodes
+                    "bn" => {
+                        let relation = format!(
+                            "{table}.osm_type = 'r' AND
+        {}",
+                            sql_dialect
+                                .relation_has_member(table, "'n'", "bn.id", role)
+                                .ok_or_else(unsupported)?
+                        );
+                        let clause = if role.is_some() {
+                            // Way nodes have no role
+                            relation
+                        } else {
+                            format!(
+                                "({table}.osm_type = 'w' AND
+        {} OR
+        {relation})",
+                                sql_dialect
+                                    .way_has_node(table, "bn.id")
+                                    .ok_or_else(unsupported)?
+                            )
+                        };
+                        ("bn", "n", clause)
+                    }
+                    // Relations having the input ways or relations
+                    recurse_type @ ("bw" | "br") => {
+                        let input_type = &recurse_type[1..];
+                        (
+                            recurse_type,
+                            input_type,
+                            format!(
+                                "{table}.osm_type = 'r' AND
+        {}",
+                                sql_dialect
+                                    .relation_has_member(
+                                        table,
+                                        &format!("'{input_type}'"),
+                                        &format!("{recurse_type}.id"),
+                                        role,
+                                    )
+                                    .ok_or_else(unsupported)?
+                            ),
+                        )
+                    }
+                    _ => return Err(unsupported()),
+                };
+                format!(
+                    "EXISTS (
+    SELECT
+        1
+    FROM
+        _{input} AS {alias}
+    WHERE
+        {alias}.osm_type = '{input_type}' AND
+        {clause}
+)"
+                )
+            }
+            Predicate::Condition {
+                condition,
+                default_set,
+            } => condition.to_sql_condition(sql_dialect, srid, table, default_set)?,
+        })
+    }
+}
+
+impl Node {
+    /// SQL query of the elements of the node.
+    pub fn to_sql(
+        &self,
+        sql_dialect: &(dyn SqlDialect + Send + Sync),
+        srid: &str,
+    ) -> Result<String, OverpassError> {
+        match self {
+            Node::Polygon { coordinates, .. } => {
+                let coords = coordinates
+                    .iter()
+                    .map(|&(lat, lon)| format!("{lon} {lat}"))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                let poly = sql_dialect.st_transform(
+                    &sql_dialect.geom_from_text(&format!("POLYGON(({coords}))")),
+                    srid,
+                );
+                Ok(format!(
+                    "SELECT
+    {}
+FROM
+    {}",
+                    sql_dialect.make_geom_fields(),
+                    sql_dialect.geom_values(&poly)
+                ))
+            }
+            Node::Scan(_) | Node::Filter { .. } | Node::SpatialJoin { .. } => {
+                Ok(Select::from_node(self, sql_dialect, srid)?.to_sql())
+            }
+            Node::Union(steps) => {
+                let with = steps
+                    .iter()
+                    .map(|step| {
+                        Ok(format!(
+                            "_{} AS (\n{}\n)",
+                            step.set,
+                            step.node.to_sql(sql_dialect, srid)?
+                        ))
+                    })
+                    .collect::<Result<Vec<String>, OverpassError>>()?
+                    .join(",\n");
+                let sets = steps
+                    .iter()
+                    .map(|step| step.set.clone())
+                    .collect::<Vec<String>>();
+                Ok(format!(
+                    "WITH\n{with}\n{}",
+                    sql_dialect.union_distinct(&sets)
+                ))
+            }
+            Node::Difference { steps, base, minus } => {
+                let with = steps
+                    .iter()
+                    .map(|step| {
+                        Ok(format!(
+                            "_{} AS (\n{}\n)",
+                            step.set,
+                            step.node.to_sql(sql_dialect, srid)?
+                        ))
+                    })
+                    .collect::<Result<Vec<String>, OverpassError>>()?
+                    .join(",\n");
+                // Anti-join on the element key, the columns are not all comparable
+                Ok(format!(
+                    "WITH
+{with}
+SELECT
+    *
+FROM
+    _{base}
+WHERE
+    NOT EXISTS (SELECT 1 FROM _{minus} WHERE _{minus}.osm_type = _{base}.osm_type AND _{minus}.id = _{base}.id)"
+                ))
+            }
+            Node::Recurse {
+                input,
+                direction,
+                transitive,
+                span,
+            } => {
+                let from = format!("_{input}");
+                let recurse = match (direction, transitive) {
+                    (RecurseDirection::Down, false) => ">",
+                    (RecurseDirection::Down, true) => ">>",
+                    (RecurseDirection::Up, false) => "<",
+                    (RecurseDirection::Up, true) => "<<",
+                };
+                let step = |source: &str| {
+                    match direction {
+                        RecurseDirection::Down => recurse_down(sql_dialect, source, *transitive),
+                        RecurseDirection::Up => recurse_up(sql_dialect, source, *transitive),
+                    }
+                    .ok_or_else(|| {
+                        OverpassError::unsupported(
+                            format!("Recurse {recurse} is not supported by this SQL dialect"),
+                            *span,
+                        )
+                    })
+                };
+                let initial = step(&from)?;
+                let recursive = step("recurse")?;
+                Ok(format!(
+                    "WITH RECURSIVE
+recurse(osm_type, id) AS (
+{initial}
+    UNION
+{recursive}
+)
+SELECT
+    nwr.*
+FROM
+    recurse
+    JOIN nwr_by_id AS nwr ON
+        nwr.osm_type = recurse.osm_type AND
+        nwr.id = recurse.id"
+                ))
+            }
+            Node::IsIn(is_in) => {
+                let where_clause = match is_in {
+                    IsIn::Point(lat, lon) => sql_dialect.st_intersects_point(
+                        "area_by_geom",
+                        &sql_dialect.st_transform(&sql_dialect.st_point(*lon, *lat), srid),
+                    ),
+                    IsIn::Set(set) => format!(
+                        "EXISTS (
+    SELECT
+        1
+    FROM
+        _{set} AS is_in
+    WHERE
+        is_in.osm_type = 'n' AND
+        {}
+)",
+                        sql_dialect
+                            .st_intersects_point("area_by_geom", "is_in.geom")
+                            .replace('\n', "\n        ")
+                    ),
+                };
+                Ok(format!(
+                    "SELECT
+    area_by_geom.*
+FROM
+    area_by_geom
+WHERE
+    {}",
+                    where_clause.replace('\n', "\n    ")
+                ))
+            }
+            Node::Project { input, projection } => match projection {
+                Projection::Convert(converts) => convert_sql(sql_dialect, srid, input, converts),
+                Projection::Make {
+                    object_type,
+                    converts,
+                } => make_sql(sql_dialect, srid, input, object_type, converts),
+            },
+            Node::Foreach {
+                input,
+                element,
+                body,
+                span,
+            } => {
+                let inner = body.to_sql_nested(
+                    sql_dialect,
+                    srid,
+                    &[(element.clone(), "SELECT _element.*".to_string())],
+                    true,
+                    *span,
+                )?;
+                Ok(format!(
+                    "SELECT
+    _body.*
+FROM
+    _{input} AS _element
+    JOIN LATERAL (
+{}
+    ) AS _body ON true",
+                    indent(&inner, "        ")
+                ))
+            }
+            Node::For {
+                input,
+                group,
+                value,
+                body,
+                span,
+            } => {
+                let value = format!(
+                    "coalesce({}, '')",
+                    value.to_sql_text(sql_dialect, srid, input)?
+                );
+                let group_cte = format!(
+                    "SELECT
+    _{input}.*
+FROM
+    _{input}
+WHERE
+    {value} = _group.value"
+                );
+                let inner = body.to_sql_nested(
+                    sql_dialect,
+                    srid,
+                    &[(group.clone(), group_cte)],
+                    true,
+                    *span,
+                )?;
+                Ok(format!(
+                    "SELECT
+    _body.*
+FROM
+    (SELECT {value} AS value FROM _{input} GROUP BY {value}) AS _group
+    JOIN LATERAL (
+{}
+    ) AS _body ON true",
+                    indent(&inner, "        ")
+                ))
+            }
+            Node::If {
+                condition,
+                default_set,
+                then,
+                otherwise,
+                span,
+            } => {
+                let condition = condition.to_sql_condition(
+                    sql_dialect,
+                    srid,
+                    &format!("_{default_set}"),
+                    default_set,
+                )?;
+                let block = |block: &Plan, alias: &str, condition: &str| {
+                    let inner = block.to_sql_nested(sql_dialect, srid, &[], false, *span)?;
+                    Ok::<String, OverpassError>(format!(
+                        "SELECT
+    *
+FROM (
+{}
+) AS {alias}
+WHERE
+    {condition}",
+                        indent(&inner, "    ")
+                    ))
+                };
+
+                // Both blocks are evaluated, only one is selected
+                let mut sql = block(then, "_then", &condition)?;
+                if let Some(otherwise) = otherwise {
+                    sql = format!(
+                        "{sql}\nUNION ALL\n{}",
+                        block(otherwise, "_else", &format!("NOT ({condition})"))?
+                    );
+                }
+                Ok(sql)
+            }
+            Node::Complete {
+                input,
+                complete,
+                body,
+                span,
+            } => {
+                // PostgreSQL only allows the recursive reference out of
+                // subqueries, so it is read once, in a CTE.
+                let inner = body.to_sql_nested(
+                    sql_dialect,
+                    srid,
+                    &[(
+                        format!("{complete}_previous"),
+                        format!("SELECT * FROM _{complete}"),
+                    )],
+                    false,
+                    *span,
+                )?;
+                Ok(format!(
+                    "WITH RECURSIVE
+_{complete} AS (
+    SELECT
+        _{input}.*
+    FROM
+        _{input}
+    UNION
+    (
+{}
+    )
+)
+SELECT * FROM _{complete}",
+                    indent(&inner, "        ")
+                ))
+            }
+            Node::Out { input, out } => out.to_sql(sql_dialect, srid, input),
+        }
+    }
+}
+
+/// Members of the relations and nodes of the ways from `source`. `None`
+/// when not supported by the dialect.
+fn recurse_down(
+    sql_dialect: &(dyn SqlDialect + Send + Sync),
+    source: &str,
+    transitive: bool,
+) -> Option<String> {
+    let relation_members = sql_dialect.relation_members("parent")?;
+    let way_nodes = sql_dialect.way_nodes("parent")?;
+    let relation_filter = if transitive {
+        ""
+    } else {
+        " AND\n        child.osm_type != 'r'"
+    };
+    Some(format!(
+        "    SELECT
+        child.osm_type,
+        child.id
+    FROM
+        {source} AS recurse
+        JOIN nwr_by_id AS parent ON
+            parent.osm_type = recurse.osm_type AND
+            parent.id = recurse.id
+        JOIN LATERAL (
+            SELECT member.type AS osm_type, member.ref AS id FROM {relation_members}
+            UNION ALL
+            SELECT 'n' AS osm_type, way_node.ref AS id FROM {way_nodes}
+        ) AS child ON
+            true
+    WHERE
+        recurse.osm_type IN ('w', 'r'){relation_filter}"
+    ))
+}
+
+/// Ways and relations having elements from `source` as node or member.
+/// `None` when not supported by the dialect.
+fn recurse_up(
+    sql_dialect: &(dyn SqlDialect + Send + Sync),
+    source: &str,
+    transitive: bool,
+) -> Option<String> {
+    let way_has_node = sql_dialect.way_has_node("way", "recurse.id")?;
+    let relation_has_member =
+        sql_dialect.relation_has_member("relation", "recurse.osm_type", "recurse.id", None)?;
+    let relation_filter = if transitive {
+        ""
+    } else {
+        "\n    WHERE\n        recurse.osm_type != 'r'"
+    };
+    Some(format!(
+        "    SELECT
+        CAST(parent.osm_type AS text) AS osm_type,
+        parent.id
+    FROM
+        {source} AS recurse
+        JOIN LATERAL (
+            SELECT way.osm_type, way.id FROM way_by_id AS way WHERE recurse.osm_type = 'n' AND {way_has_node}
+            UNION ALL
+            SELECT relation.osm_type, relation.id FROM relation_by_id AS relation WHERE {relation_has_member}
+        ) AS parent ON
+            true{relation_filter}"
+    ))
+}
+
+/// Elements of `input` with the converted tags and metadata.
+fn convert_sql(
+    sql_dialect: &(dyn SqlDialect + Send + Sync),
+    srid: &str,
+    input: &str,
+    converts: &[Box<ConvertItem>],
+) -> Result<String, OverpassError> {
+    // If ::=::, copy all the keys from source to target
+    let mut keys = if converts.iter().any(|c| c.target == "::".into()) {
+        "tags".to_string()
+    } else {
+        sql_dialect.json_parse("'{}'")
+    };
+
+    // Remove keys that are suppressed
+    let suppressed = converts
+        .iter()
+        .filter(|c| c.source.is_none() && c.target != "::".into())
+        .map(|c| c.target.as_ref())
+        .collect::<Vec<_>>();
+    if !suppressed.is_empty() {
+        keys = sql_dialect.json_remove_keys(&keys, &suppressed);
+    }
+
+    // Add keys that are converted from metadata
+    let mut metadata = HashMap::from([
+        ("osm_type", "osm_type".to_string()),
+        ("id", "id".to_string()),
+        ("lon", "".to_string()),
+        ("lat", "".to_string()),
+        ("created", "created".to_string()),
+        ("version", "version".to_string()),
+        ("geom", "".to_string()),
+    ]);
+    let mut tags = Vec::new();
+    for convert in converts {
+        let Some(source) = &convert.source else {
+            continue;
+        };
+        match convert.target.strip_prefix("::") {
+            Some("geom") => {
+                metadata.insert("geom", source.to_sql_geom(sql_dialect, srid, input)?);
+            }
+            Some(metadata_key) => {
+                let column = match metadata_key {
+                    "type" => "osm_type",
+                    "timestamp" => "created",
+                    "id" | "lon" | "lat" | "version" => metadata_key,
+                    _ => {
+                        return Err(OverpassError::unsupported(
+                            format!("convert does not support ::{metadata_key}"),
+                            source.span,
+                        ));
+                    }
+                };
+                metadata.insert(column, source.to_sql(sql_dialect, srid, input)?);
+            }
+            None => tags.push(format!(
+                "{}, {}",
+                sql_dialect.escape_literal(&convert.target),
+                source.to_sql_text(sql_dialect, srid, input)?
+            )),
+        }
+    }
+    if !tags.is_empty() {
+        keys = sql_dialect.json_merge(
+            &keys,
+            &format!("{}({})", sql_dialect.json_build_object(), tags.join(", ")),
+        );
+    }
+
+    let mut geom = "geom".to_string();
+    if !metadata["geom"].is_empty() {
+        geom = metadata["geom"].clone();
+    } else if !metadata["lon"].is_empty() && !metadata["lat"].is_empty() {
+        geom = sql_dialect.st_transform(
+            &format!(
+                "ST_SetSRID(ST_MakePoint({}, {}), 4326)",
+                metadata["lon"], metadata["lat"]
+            ),
+            srid,
+        );
+    }
+
+    let mut metadata_sql_parts = metadata
+        .iter()
+        .filter(|(k, _)| !["lon", "lat", "geom"].contains(*k))
+        .map(|(k, v)| format!("{} AS {}", v, k))
+        .collect::<Vec<_>>();
+    metadata_sql_parts.sort();
+    let metadata_sql = metadata_sql_parts.join(",\n    ");
+    Ok(format!(
+        "SELECT\n    {keys} AS tags,\n    {metadata_sql},\n    nodes,\n    members,\n    {geom} AS geom\nFROM\n    _{input}"
+    ))
+}
+
+/// One element of `object_type`, the evaluators aggregating `input`.
+fn make_sql(
+    sql_dialect: &(dyn SqlDialect + Send + Sync),
+    srid: &str,
+    input: &str,
+    object_type: &str,
+    converts: &[Box<ConvertItem>],
+) -> Result<String, OverpassError> {
+    let mut id = "1".to_string();
+    let mut geom = "nwr.geom".to_string();
+    let mut tags = Vec::new();
+    for convert in converts {
+        let Some(source) = &convert.source else {
+            continue;
+        };
+        match convert.target.strip_prefix("::") {
+            Some("id") => id = source.to_sql(sql_dialect, srid, input)?,
+            Some("geom") => geom = source.to_sql_geom(sql_dialect, srid, input)?,
+            Some(metadata_key) => {
+                return Err(OverpassError::unsupported(
+                    format!("make does not support ::{metadata_key}"),
+                    source.span,
+                ));
+            }
+            None => tags.push(format!(
+                "{}, {}",
+                sql_dialect.escape_literal(&convert.target),
+                source.to_sql_text(sql_dialect, srid, input)?
+            )),
+        }
+    }
+
+    // Typed NULL columns from the join without match
+    Ok(format!(
+        "SELECT
+    {id} AS id,
+    nwr.version,
+    nwr.created,
+    {}(
+        {}
+    ) AS tags,
+    nwr.nodes,
+    nwr.members,
+    {geom} AS geom,
+    {} AS osm_type
+FROM
+    (SELECT 1) AS make
+    LEFT JOIN nwr_by_id AS nwr ON false",
+        sql_dialect.json_build_object(),
+        tags.join(",\n        "),
+        sql_dialect.escape_literal(object_type),
+    ))
+}
@@ -1,5 +1,5 @@
 pub mod duckdb {
-    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use crate::sql_dialect::sql_dialect::{GeomRef, SqlDialect};
 
     use derivative::Derivative;
 
@@ -16,6 +16,20 @@ pub mod duckdb {
             None
         }
 
+        fn try_numeric(&self, expr: &str) -> String {
+            format!("TRY_CAST({expr} AS DOUBLE)")
+        }
+
+        fn date_to_number(&self, expr: &str) -> String {
+            format!("epoch(CAST({expr} AS TIMESTAMP))")
+        }
+
+        // Inlined, as correlated subqueries, DuckDB does not correlate
+        // materialized CTEs.
+        fn lateral_cte_materialization(&self) -> String {
+            "NOT MATERIALIZED ".to_string()
+        }
+
         fn make_geom_fields(&self) -> String {
             "geom,
     STRUCT_PACK(
@@ -52,7 +66,7 @@ pub mod duckdb {
             ])
         }
 
-        fn id_in_list(&self, table: &str, field: &str, values: &Vec<i64>) -> String {
+        fn id_in_list(&self, table: &str, field: &str, values: &[i64]) -> String {
             let sql = values
                 .iter()
                 .map(|value| format!("{table}.{field} = {value}"))
@@ -69,6 +83,45 @@ pub mod duckdb {
             format!("({table}.tags->>{})", self.escape_literal(key))
         }
 
+        fn hash_each(&self, table: &str) -> String {
+            format!(
+                "(SELECT key, {table}.tags->>key AS value FROM unnest(json_keys({table}.tags)) AS k(key)) AS tag"
+            )
+        }
+
+        fn regex_match(
+            &self,
+            expr: &str,
+            pattern: &str,
+            negate: bool,
+            case_insensitive: bool,
+        ) -> String {
+            let not = if negate { "NOT " } else { "" };
+            let options = if case_insensitive { ", 'i'" } else { "" };
+            format!(
+                "{not}regexp_matches({expr}, {}{options})",
+                self.escape_literal(pattern)
+            )
+        }
+
+        fn way_nodes(&self, way: &str) -> Option<String> {
+            Some(format!("unnest({way}.nodes) AS way_node(ref)"))
+        }
+
+        fn relation_members(&self, relation: &str) -> Option<String> {
+            Some(format!(
+                "(SELECT unnest(from_json({relation}.members, '[{{\"type\":\"VARCHAR\",\"ref\":\"BIGINT\",\"role\":\"VARCHAR\"}}]'), recursive := true)) AS member"
+            ))
+        }
+
+        fn way_has_node(&self, way: &str, node_id: &str) -> Option<String> {
+            Some(format!("list_contains({way}.nodes, {node_id})"))
+        }
+
+        fn json_parse(&self, expr: &str) -> String {
+            format!("{expr}::JSON")
+        }
+
         fn json_strip_nulls(&self) -> String {
             "".to_string()
         }
@@ -77,6 +130,20 @@ pub mod duckdb {
             "json_object".to_string()
         }
 
+        // The null values of a merge patch remove the keys.
+        fn json_remove_keys(&self, json: &str, keys: &[&str]) -> String {
+            let nulls = keys
+                .iter()
+                .map(|key| format!("{}, NULL", self.escape_literal(key)))
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("json_merge_patch({json}, json_object({nulls}))")
+        }
+
+        fn json_merge(&self, json: &str, other: &str) -> String {
+            format!("json_merge_patch({json}, {other})")
+        }
+
         fn json_build_bbox(&self, geom: &str, srid: &str) -> String {
             let g = self.st_transform_reverse(geom, srid);
             if g == "geom" {
@@ -118,25 +185,51 @@ pub mod duckdb {
             format!("getvariable('_{other}_bbox').geom")
         }
 
-        fn st_intersects_with_geom(&self, table: &str, other: &str) -> String {
+        fn st_intersects_with_geom(&self, table: &str, geom: &GeomRef) -> String {
             [
-                self.st_intersects_extent_with_geom(table, other.replace(".geom", "").as_str()),
+                self.st_intersects_extent_with_geom(table, geom),
                 format!(
                     "ST_Intersects(
-    {other},
+    {},
     {table}.geom
-)"
+)",
+                    self.geom_ref(geom)
                 ),
             ]
             .join(" AND\n")
         }
 
-        fn st_intersects_extent_with_geom(&self, table: &str, other: &str) -> String {
+        // The precomputed sets have their bbox in the variable.
+        fn st_intersects_extent_with_geom(&self, table: &str, geom: &GeomRef) -> String {
+            let [xmin, ymin, xmax, ymax] = match geom {
+                GeomRef::Expr(expr) => {
+                    ["XMin", "YMin", "XMax", "YMax"].map(|f| format!("ST_{f}({expr})"))
+                }
+                GeomRef::Set(set) => ["xmin", "ymin", "xmax", "ymax"]
+                    .map(|f| format!("getvariable('_{set}_bbox').{f}")),
+            };
             format!(
-                "{table}.bbox.xmin <= {other}.xmax AND
-{table}.bbox.xmax >= {other}.xmin AND
-{table}.bbox.ymin <= {other}.ymax AND
-{table}.bbox.ymax >= {other}.ymin"
+                "{table}.bbox.xmin <= {xmax} AND
+{table}.bbox.xmax >= {xmin} AND
+{table}.bbox.ymin <= {ymax} AND
+{table}.bbox.ymax >= {ymin}"
+            )
+        }
+
+        fn st_point(&self, lon: f64, lat: f64) -> String {
+            format!("ST_Point({lon}, {lat})")
+        }
+
+        fn st_intersects_point(&self, table: &str, point: &str) -> String {
+            format!(
+                "{table}.bbox.xmin <= ST_X({point}) AND
+{table}.bbox.xmax >= ST_X({point}) AND
+{table}.bbox.ymin <= ST_Y({point}) AND
+{table}.bbox.ymax >= ST_Y({point}) AND
+ST_Intersects(
+    {point},
+    {table}.geom
+)"
             )
         }
 
@@ -148,6 +241,29 @@ pub mod duckdb {
             format!("ST_Transform({geom}, 'EPSG:{srid}', 'EPSG:4326')")
         }
 
+        // The spheroid functions expect (lat, lon) coordinates.
+        fn st_length_meters(&self, geom: &str, srid: &str) -> String {
+            format!(
+                "ST_Length_Spheroid(ST_FlipCoordinates({}))",
+                self.st_transform_reverse(geom, srid)
+            )
+        }
+
+        fn st_area_meters(&self, geom: &str, srid: &str) -> String {
+            format!(
+                "ST_Area_Spheroid(ST_FlipCoordinates({}))",
+                self.st_transform_reverse(geom, srid)
+            )
+        }
+
+        fn st_subdivide(&self, geom: &str, _max_vertices: usize) -> String {
+            geom.to_string()
+        }
+
+        fn st_dump_geom(&self, geom: &str) -> String {
+            geom.to_string()
+        }
+
         fn st_asgeojson(&self, geom: &str, _max_decimal_digits: usize) -> String {
             format!("ST_AsGeoJSON({geom})")
         }
@@ -1,3 +1,6 @@
+#![allow(clippy::module_inception)]
+
 pub mod duckdb;
 pub mod postgres;
 pub mod sql_dialect;
+pub mod sqlite;
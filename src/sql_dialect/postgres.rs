@@ -1,19 +1,21 @@
 pub mod postgres {
-    use crate::sql_dialect::sql_dialect::SqlDialect;
+    use crate::sql_dialect::sql_dialect::{GeomRef, SqlDialect};
 
     use derivative::Derivative;
 
+    pub type EscapeLiteral = Box<dyn Fn(&str) -> String + Send + Sync>;
+
     #[derive(Derivative)]
     #[derivative(Default)]
     // #[derive(Debug)]
     pub struct Postgres {
-        pub postgres_escape_literal: Option<Box<dyn Fn(&str) -> String + Send + Sync>>,
+        pub postgres_escape_literal: Option<EscapeLiteral>,
     }
 
     impl SqlDialect for Postgres {
         fn escape_literal(&self, string: &str) -> String {
-            if self.postgres_escape_literal.is_some() {
-                (self.postgres_escape_literal.as_ref().unwrap())(string)
+            if let Some(postgres_escape_literal) = &self.postgres_escape_literal {
+                postgres_escape_literal(string)
             } else {
                 format!("'{}'", string.replace('\'', "''"))
             }
@@ -35,7 +37,7 @@ pub mod postgres {
             None
         }
 
-        fn id_in_list(&self, table: &str, field: &str, values: &Vec<i64>) -> String {
+        fn id_in_list(&self, table: &str, field: &str, values: &[i64]) -> String {
             format!(
                 "{table}.{field} = ANY (ARRAY[{}])",
                 values
@@ -54,6 +56,55 @@ pub mod postgres {
             format!("{table}.tags->>{}", self.escape_literal(key))
         }
 
+        fn hash_each(&self, table: &str) -> String {
+            format!("jsonb_each_text({table}.tags) AS tag(key, value)")
+        }
+
+        fn regex_match(
+            &self,
+            expr: &str,
+            pattern: &str,
+            negate: bool,
+            case_insensitive: bool,
+        ) -> String {
+            let operator = match (negate, case_insensitive) {
+                (false, false) => "~",
+                (false, true) => "~*",
+                (true, false) => "!~",
+                (true, true) => "!~*",
+            };
+            format!("{expr} {operator} {}", self.escape_literal(pattern))
+        }
+
+        fn way_nodes(&self, way: &str) -> Option<String> {
+            Some(format!("unnest({way}.nodes) AS way_node(ref)"))
+        }
+
+        fn relation_members(&self, relation: &str) -> Option<String> {
+            Some(format!(
+                "jsonb_to_recordset({relation}.members) AS member(type text, ref bigint, role text)"
+            ))
+        }
+
+        fn way_has_node(&self, way: &str, node_id: &str) -> Option<String> {
+            Some(format!("{way}.nodes @> ARRAY[{node_id}]"))
+        }
+
+        fn relation_has_member(
+            &self,
+            relation: &str,
+            member_type: &str,
+            member_id: &str,
+            role: Option<&str>,
+        ) -> Option<String> {
+            let role = role
+                .map(|role| format!(", 'role', {role}"))
+                .unwrap_or_default();
+            Some(format!(
+                "{relation}.members @> jsonb_build_array(jsonb_build_object('type', {member_type}, 'ref', {member_id}{role}))"
+            ))
+        }
+
         fn json_strip_nulls(&self) -> String {
             "jsonb_strip_nulls".to_string()
         }
@@ -83,6 +134,10 @@ pub mod postgres {
             "ST_Union".to_string()
         }
 
+        fn st_clip_by_box(&self, geom: &str, envelope: &str) -> String {
+            format!("ST_ClipByBox2D({geom}, {envelope})")
+        }
+
         fn st_dump_points(&self) -> Option<String> {
             Some("ST_DumpPoints".to_string())
         }
@@ -91,19 +146,28 @@ pub mod postgres {
             format!("_{other}.geom")
         }
 
-        fn st_intersects_with_geom(&self, table: &str, other: &str) -> String {
+        fn st_intersects_with_geom(&self, table: &str, geom: &GeomRef) -> String {
             format!(
                 "ST_Intersects(
-    {other},
+    {},
     {table}.geom
-)"
+)",
+                self.geom_ref(geom)
             )
         }
 
-        fn st_intersects_extent_with_geom(&self, table: &str, other: &str) -> String {
+        fn st_intersects_extent_with_geom(&self, table: &str, geom: &GeomRef) -> String {
+            self.st_intersects_with_geom(table, geom)
+        }
+
+        fn st_point(&self, lon: f64, lat: f64) -> String {
+            format!("ST_SetSRID(ST_MakePoint({lon}, {lat}), 4326)")
+        }
+
+        fn st_intersects_point(&self, table: &str, point: &str) -> String {
             format!(
                 "ST_Intersects(
-    {other},
+    {point},
     {table}.geom
 )"
             )
@@ -1,3 +1,11 @@
+/// Geometry intersected with the elements of a table.
+pub enum GeomRef<'a> {
+    /// SQL expression of a geometry.
+    Expr(&'a str),
+    /// Union of the geometries of a set, precomputed or joined.
+    Set(&'a str),
+}
+
 pub trait SqlDialect: Send + Sync {
     fn escape_literal(&self, string: &str) -> String {
         format!("'{}'", string.replace('\'', "''"))
@@ -5,22 +13,162 @@ pub trait SqlDialect: Send + Sync {
 
     fn statement_timeout(&self, timeout: u32) -> Option<String>;
 
+    fn to_numeric(&self, expr: &str) -> String {
+        format!("{expr}::numeric")
+    }
+
+    /// Timestamp literal, comparable with the `created` column, from an ISO
+    /// 8601 UTC date.
+    fn timestamp(&self, date: &str) -> String {
+        format!("{}::timestamp", self.escape_literal(date))
+    }
+
+    /// Geometry literal from WKT in EPSG:4326.
+    fn geom_from_text(&self, wkt: &str) -> String {
+        format!("'SRID=4326;{wkt}'::geometry")
+    }
+
+    /// Table expression of a single geometry row, as `geom` column.
+    fn geom_values(&self, geom: &str) -> String {
+        format!("(VALUES ({geom})) AS p(geom)")
+    }
+
+    /// Union of sets, without duplicate elements, ordered by type and id.
+    fn union_distinct(&self, sets: &[String]) -> String {
+        let selects = sets
+            .iter()
+            .map(|set| format!("(SELECT * FROM _{set})"))
+            .collect::<Vec<String>>()
+            .join(" UNION\n    ");
+        format!(
+            "SELECT DISTINCT ON(osm_type, id)
+    *
+FROM (
+    {selects}
+) AS t
+ORDER BY
+    osm_type, id"
+        )
+    }
+
+    /// JSON value from a JSON text.
+    fn json_parse(&self, expr: &str) -> String {
+        format!("{expr}::jsonb")
+    }
+
+    /// Escape a text for XML attribute values.
+    fn xml_escape(&self, expr: &str) -> String {
+        format!(
+            "replace(replace(replace(replace({expr}, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'), '\"', '&quot;')"
+        )
+    }
+
+    /// Concatenation of the texts of the group.
+    fn string_agg(&self, expr: &str) -> String {
+        format!("string_agg({expr}, '')")
+    }
+
+    /// Query of the sorted distinct texts of `table`, `;` separated.
+    fn set_agg(&self, expr: &str, table: &str) -> String {
+        format!("SELECT string_agg(DISTINCT {expr}, ';' ORDER BY {expr}) FROM {table}")
+    }
+
+    /// Number from a text, NULL when it is not a number.
+    fn try_numeric(&self, expr: &str) -> String {
+        format!(
+            "CASE WHEN {expr} ~ '^\\s*-?[0-9]+(\\.[0-9]+)?([eE][-+]?[0-9]+)?\\s*$' THEN CAST({expr} AS numeric) END"
+        )
+    }
+
+    /// Seconds since the epoch of a date text.
+    fn date_to_number(&self, expr: &str) -> String {
+        format!("extract(epoch FROM CAST({expr} AS timestamp))")
+    }
+
+    /// JSON value of a `tags`, `nodes` or `members` column.
+    fn json_column(&self, column: &str) -> String {
+        column.to_string()
+    }
+
+    /// Materialization of the CTEs correlated with the row of a lateral
+    /// join, as `NOT MATERIALIZED `.
+    fn lateral_cte_materialization(&self) -> String {
+        "".to_string()
+    }
+
     fn make_geom_fields(&self) -> String;
 
     fn is_precompute(&self) -> bool;
 
     fn precompute(&self, set: &str, sql: &str) -> Option<Vec<String>>;
 
-    fn id_in_list(&self, table: &str, field: &str, values: &Vec<i64>) -> String;
+    fn id_in_list(&self, table: &str, field: &str, values: &[i64]) -> String;
 
     fn hash_exists(&self, table: &str, key: &str) -> String;
 
     fn hash_get(&self, table: &str, key: &str) -> String;
 
+    /// Table of the tags as `tag.key` and `tag.value` text columns.
+    fn hash_each(&self, table: &str) -> String;
+
+    /// Partial match of `expr` with the regular expression `pattern`.
+    fn regex_match(
+        &self,
+        expr: &str,
+        pattern: &str,
+        negate: bool,
+        case_insensitive: bool,
+    ) -> String;
+
+    /// Table of the node ids of a `way`, as `way_node.ref`. `None` when not
+    /// supported.
+    fn way_nodes(&self, way: &str) -> Option<String>;
+
+    /// Table of the members of a `relation`, as `member.type`, `member.ref`
+    /// and `member.role`. `None` when not supported.
+    fn relation_members(&self, relation: &str) -> Option<String>;
+
+    fn way_has_node(&self, way: &str, node_id: &str) -> Option<String> {
+        self.way_nodes(way).map(|way_nodes| {
+            format!("EXISTS (SELECT 1 FROM {way_nodes} WHERE way_node.ref = {node_id})")
+        })
+    }
+
+    /// Test if the `relation` has the member, with the `role` when set.
+    /// Arguments are SQL expressions.
+    fn relation_has_member(
+        &self,
+        relation: &str,
+        member_type: &str,
+        member_id: &str,
+        role: Option<&str>,
+    ) -> Option<String> {
+        let role = role
+            .map(|role| format!(" AND member.role = {role}"))
+            .unwrap_or_default();
+        self.relation_members(relation).map(|members| {
+            format!(
+                "EXISTS (SELECT 1 FROM {members} WHERE member.type = {member_type} AND member.ref = {member_id}{role})"
+            )
+        })
+    }
+
     fn json_strip_nulls(&self) -> String;
 
     fn json_build_object(&self) -> String;
 
+    /// JSON object without the keys.
+    fn json_remove_keys(&self, json: &str, keys: &[&str]) -> String {
+        keys.iter().fold(json.to_string(), |json, key| {
+            format!("{json} - {}", self.escape_literal(key))
+        })
+    }
+
+    /// JSON object with the keys of `other` added or replaced.
+    fn json_merge(&self, json: &str, other: &str) -> String {
+        format!("{json} || {other}")
+    }
+
     fn json_build_bbox(&self, geom: &str, srid: &str) -> String;
 
     fn jsonb_agg(&self) -> String;
@@ -31,9 +179,25 @@ pub trait SqlDialect: Send + Sync {
 
     fn table_precompute_geom(&self, other: &str) -> String;
 
-    fn st_intersects_with_geom(&self, table: &str, other: &str) -> String;
+    /// SQL expression of the geometry.
+    fn geom_ref(&self, geom: &GeomRef) -> String {
+        match geom {
+            GeomRef::Expr(expr) => expr.to_string(),
+            GeomRef::Set(set) => self.table_precompute_geom(set),
+        }
+    }
 
-    fn st_intersects_extent_with_geom(&self, table: &str, other: &str) -> String;
+    fn st_intersects_with_geom(&self, table: &str, geom: &GeomRef) -> String;
+
+    /// Test the intersection of the `table` extent with the geometry, as a
+    /// spatial index pre-filter.
+    fn st_intersects_extent_with_geom(&self, table: &str, geom: &GeomRef) -> String;
+
+    /// Point geometry in EPSG:4326.
+    fn st_point(&self, lon: f64, lat: f64) -> String;
+
+    /// Test if the point is in the `table` geometry.
+    fn st_intersects_point(&self, table: &str, point: &str) -> String;
 
     fn st_transform(&self, geom: &str, srid: &str) -> String;
 
@@ -56,4 +220,61 @@ pub trait SqlDialect: Send + Sync {
     }
 
     fn st_asgeojson(&self, geom: &str, max_decimal_digits: usize) -> String;
+
+    /// Geohash text of a geometry in EPSG:4326, ordered as quadtiles.
+    fn st_geohash(&self, geom: &str) -> String {
+        format!("ST_GeoHash({geom})")
+    }
+
+    /// Length in metres of a `geom` in `srid`.
+    fn st_length_meters(&self, geom: &str, srid: &str) -> String {
+        format!(
+            "ST_Length(CAST({} AS geography))",
+            self.st_transform_reverse(geom, srid)
+        )
+    }
+
+    /// Area in square metres of a `geom` in `srid`.
+    fn st_area_meters(&self, geom: &str, srid: &str) -> String {
+        format!(
+            "ST_Area(CAST({} AS geography))",
+            self.st_transform_reverse(geom, srid)
+        )
+    }
+
+    /// Part of `geom` inside the `envelope`.
+    fn st_clip_by_box(&self, geom: &str, envelope: &str) -> String {
+        format!("ST_Intersection({geom}, {envelope})")
+    }
+
+    /// JSON array of the `{lon, lat}` points of a way `geom`.
+    fn json_way_geometry(&self, geom: &str, srid: &str) -> String {
+        if let Some(st_dump_points) = self.st_dump_points() {
+            // `geom` of the dumped points
+            let st_transform_reverse = self.st_transform_reverse("geom", srid);
+            format!(
+                "(SELECT {}({}('lon', {}, 'lat', {})) FROM {st_dump_points}({geom}))",
+                self.jsonb_agg(),
+                self.json_build_object(),
+                self.to_numeric(&format!("ST_X({st_transform_reverse})")),
+                self.to_numeric(&format!("ST_Y({st_transform_reverse})")),
+            )
+        } else {
+            let st_asgeojson = self.st_asgeojson(&self.st_transform_reverse(geom, srid), 7);
+            format!(
+                "
+        replace(replace(replace(replace(replace((
+            CASE ST_GeometryType({geom})
+            WHEN 'LINESTRING' THEN {st_asgeojson}->'coordinates'
+            ELSE {st_asgeojson}->'coordinates'->0
+            END
+        )::text, {}",
+                " '[', '{\"lon\":'), \
+',', ',\"lat\":'), \
+'{\"lon\":{\"lon\":', '[{\"lon\":'), \
+'],\"lat\":{\"lon\":', '},{\"lon\":'), \
+']]', '}]')::json"
+            )
+        }
+    }
 }
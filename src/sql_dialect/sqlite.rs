@@ -0,0 +1,279 @@
+pub mod sqlite {
+    use crate::sql_dialect::sql_dialect::{GeomRef, SqlDialect};
+
+    use derivative::Derivative;
+
+    #[derive(Derivative)]
+    #[derivative(Default)]
+    pub struct Sqlite;
+
+    impl SqlDialect for Sqlite {
+        fn statement_timeout(&self, _timeout: u32) -> Option<String> {
+            None
+        }
+
+        fn to_numeric(&self, expr: &str) -> String {
+            expr.to_string()
+        }
+
+        // `created` is stored as ISO 8601 text.
+        fn timestamp(&self, date: &str) -> String {
+            self.escape_literal(date)
+        }
+
+        fn geom_from_text(&self, wkt: &str) -> String {
+            format!("GeomFromText('{wkt}', 4326)")
+        }
+
+        fn geom_values(&self, geom: &str) -> String {
+            format!("(SELECT {geom} AS geom) AS p")
+        }
+
+        fn union_distinct(&self, sets: &[String]) -> String {
+            let selects = sets
+                .iter()
+                .map(|set| format!("SELECT * FROM _{set}"))
+                .collect::<Vec<String>>()
+                .join(" UNION\n    ");
+            format!(
+                "SELECT
+    *
+FROM (
+    {selects}
+) AS t
+GROUP BY
+    osm_type, id
+ORDER BY
+    osm_type, id"
+            )
+        }
+
+        fn json_parse(&self, expr: &str) -> String {
+            format!("json({expr})")
+        }
+
+        fn string_agg(&self, expr: &str) -> String {
+            format!("group_concat({expr}, '')")
+        }
+
+        // No separator argument with DISTINCT, the values are made distinct
+        // and sorted first.
+        fn set_agg(&self, expr: &str, table: &str) -> String {
+            format!(
+                "SELECT group_concat(value, ';') FROM (SELECT DISTINCT {expr} AS value FROM {table} ORDER BY value)"
+            )
+        }
+
+        fn try_numeric(&self, expr: &str) -> String {
+            format!(
+                "CASE WHEN trim({expr}) GLOB '*[0-9]*' AND NOT trim({expr}) GLOB '*[^-+.eE0-9]*' THEN CAST({expr} AS REAL) END"
+            )
+        }
+
+        fn date_to_number(&self, expr: &str) -> String {
+            format!("unixepoch({expr})")
+        }
+
+        fn json_column(&self, column: &str) -> String {
+            format!("json({column})")
+        }
+
+        fn make_geom_fields(&self) -> String {
+            "geom".to_string()
+        }
+
+        fn is_precompute(&self) -> bool {
+            true
+        }
+
+        fn precompute(&self, set: &str, sql: &str) -> Option<Vec<String>> {
+            Some(vec![
+                format!("CREATE TEMP TABLE _{set} AS\n{sql}\n;"),
+                format!(
+                    "CREATE TEMP TABLE _{set}_geom AS
+SELECT
+    ST_Union(geom) AS geom
+FROM
+    _{set}
+;"
+                ),
+            ])
+        }
+
+        fn id_in_list(&self, table: &str, field: &str, values: &[i64]) -> String {
+            format!(
+                "{table}.{field} IN ({})",
+                values
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            )
+        }
+
+        fn hash_exists(&self, table: &str, key: &str) -> String {
+            format!("{} IS NOT NULL", self.hash_get(table, key))
+        }
+
+        fn hash_get(&self, table: &str, key: &str) -> String {
+            let path = format!("$.\"{}\"", key.replace('"', "\\\""));
+            format!("json_extract({table}.tags, {})", self.escape_literal(&path))
+        }
+
+        fn hash_each(&self, table: &str) -> String {
+            format!("json_each({table}.tags) AS tag")
+        }
+
+        fn regex_match(
+            &self,
+            expr: &str,
+            pattern: &str,
+            negate: bool,
+            case_insensitive: bool,
+        ) -> String {
+            let not = if negate { "NOT " } else { "" };
+            let flags = if case_insensitive { "(?i)" } else { "" };
+            format!(
+                "{expr} {not}REGEXP {}",
+                self.escape_literal(&format!("{flags}{pattern}"))
+            )
+        }
+
+        fn way_nodes(&self, _way: &str) -> Option<String> {
+            None
+        }
+
+        fn relation_members(&self, _relation: &str) -> Option<String> {
+            None
+        }
+
+        fn json_strip_nulls(&self) -> String {
+            "".to_string()
+        }
+
+        fn json_build_object(&self) -> String {
+            "json_object".to_string()
+        }
+
+        fn json_remove_keys(&self, json: &str, keys: &[&str]) -> String {
+            let paths = keys
+                .iter()
+                .map(|key| self.escape_literal(&format!("$.\"{}\"", key.replace('"', "\\\""))))
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("json_remove({json}, {paths})")
+        }
+
+        fn json_merge(&self, json: &str, other: &str) -> String {
+            format!("json_patch({json}, {other})")
+        }
+
+        fn json_build_bbox(&self, geom: &str, srid: &str) -> String {
+            let g = self.st_transform_reverse(geom, srid);
+            format!(
+                "{}(
+    'minlon', MbrMinX({g}),
+    'minlat', MbrMinY({g}),
+    'maxlon', MbrMaxX({g}),
+    'maxlat', MbrMaxY({g})
+)",
+                self.json_build_object()
+            )
+        }
+
+        fn jsonb_agg(&self) -> String {
+            "json_group_array".to_string()
+        }
+
+        fn st_union(&self) -> String {
+            "ST_Union".to_string()
+        }
+
+        fn st_dump_points(&self) -> Option<String> {
+            None
+        }
+
+        fn table_precompute_geom(&self, other: &str) -> String {
+            format!("(SELECT geom FROM _{other}_geom)")
+        }
+
+        // Compare first with the R*Tree columns of the views, to use the
+        // spatial index.
+        fn st_intersects_with_geom(&self, table: &str, geom: &GeomRef) -> String {
+            let other = self.geom_ref(geom);
+            format!(
+                "{table}.xmin <= MbrMaxX({other}) AND
+{table}.xmax >= MbrMinX({other}) AND
+{table}.ymin <= MbrMaxY({other}) AND
+{table}.ymax >= MbrMinY({other}) AND
+ST_Intersects(
+    {other},
+    {table}.geom
+)"
+            )
+        }
+
+        fn st_intersects_extent_with_geom(&self, table: &str, geom: &GeomRef) -> String {
+            self.st_intersects_with_geom(table, geom)
+        }
+
+        fn st_point(&self, lon: f64, lat: f64) -> String {
+            format!("MakePoint({lon}, {lat}, 4326)")
+        }
+
+        fn st_intersects_point(&self, table: &str, point: &str) -> String {
+            format!(
+                "{table}.xmin <= ST_X({point}) AND
+{table}.xmax >= ST_X({point}) AND
+{table}.ymin <= ST_Y({point}) AND
+{table}.ymax >= ST_Y({point}) AND
+ST_Intersects(
+    {point},
+    {table}.geom
+)"
+            )
+        }
+
+        fn st_transform(&self, geom: &str, srid: &str) -> String {
+            format!("Transform({geom}, {srid})")
+        }
+
+        fn st_transform_reverse(&self, geom: &str, _srid: &str) -> String {
+            format!("Transform({geom}, 4326)")
+        }
+
+        fn st_dump_geom(&self, geom: &str) -> String {
+            geom.to_string()
+        }
+
+        fn st_asgeojson(&self, geom: &str, max_decimal_digits: usize) -> String {
+            format!("AsGeoJSON({geom}, {max_decimal_digits})")
+        }
+
+        // On the ellipsoid
+        fn st_length_meters(&self, geom: &str, srid: &str) -> String {
+            format!("ST_Length({}, 1)", self.st_transform_reverse(geom, srid))
+        }
+
+        fn st_area_meters(&self, geom: &str, srid: &str) -> String {
+            format!("ST_Area({}, 1)", self.st_transform_reverse(geom, srid))
+        }
+
+        fn st_geohash(&self, geom: &str) -> String {
+            format!("GeoHash({geom})")
+        }
+
+        fn json_way_geometry(&self, geom: &str, srid: &str) -> String {
+            let st_asgeojson = self.st_asgeojson(&self.st_transform_reverse(geom, srid), 7);
+            format!(
+                "(SELECT json_group_array(json_object(
+            'lon', json_extract(p.value, '$[0]'),
+            'lat', json_extract(p.value, '$[1]')
+        )) FROM json_each(
+            {st_asgeojson},
+            CASE GeometryType({geom}) WHEN 'LINESTRING' THEN '$.coordinates' ELSE '$.coordinates[0]' END
+        ) AS p)"
+            )
+        }
+    }
+}